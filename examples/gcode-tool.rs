@@ -0,0 +1,365 @@
+//! Parse, reformat, lint, and summarize GCode files from the command line.
+//!
+//! ```text
+//! cargo run --example gcode-tool -- check ./tests/double_offset_start.gcode
+//! cargo run --example gcode-tool -- fmt --checksums --line-numbers ./tests/square.gcode
+//! cargo run --example gcode-tool -- stats < ./tests/vandy_commodores_logo.gcode
+//! ```
+use codespan_reporting::files::SimpleFile;
+use codespan_reporting::term::{
+    self,
+    termcolor::{ColorChoice, StandardStream, WriteColor},
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use g_code::emit::{
+    format_gcode_io, CommentPreservePolicy, Field, FormatOptions, InlineCommentHandling,
+    MachineLimits,
+};
+use g_code::interpret::{estimate_duration, State};
+use g_code::lint::{arc_consistency, conflicting_offsets};
+use g_code::parse::{
+    file_parser_with_options, into_diagnostic_with_source, Diagnostic, ParseOptions,
+};
+
+const USAGE: &str = "\
+usage: gcode-tool <command> [options] [file]
+
+Reads the file, or standard input if none is given.
+
+commands:
+    check    report parse errors and lint warnings
+    fmt      write the file back out, formatted by the options below
+    stats    count fields and estimate the bounding box and duration
+
+parse options:
+    --grbl             accept GRBL system and realtime commands
+    --klipper          accept Klipper extended commands
+    --rrf              accept RepRapFirmware meta commands
+    --comma-decimal    accept a comma as the decimal point
+
+fmt options:
+    --checksums                 checksum every line
+    --line-numbers              number every line
+    --percent                   wrap the program in % delimiters
+    --preserve-blank-lines      keep blank lines
+    --newline-before-comment    move end-of-line comments onto their own line
+    --inline-comments <keep|eol|strip>
+    --strip-comments <all|keep-actions|keep-markers>
+";
+
+/// Maximum tolerated difference between the radii of an arc, in program units.
+const ARC_TOLERANCE: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Subcommand {
+    Check,
+    Fmt,
+    Stats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Args {
+    subcommand: Subcommand,
+    parse: ParseOptions,
+    format: FormatOptions,
+    path: Option<String>,
+}
+
+/// Why the tool stopped.
+#[derive(Debug)]
+enum Failure {
+    /// The arguments made no sense, so usage is printed.
+    Usage(String),
+    /// The input had errors, which have already been reported.
+    Invalid,
+    Io(io::Error),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            Self::Invalid => write!(f, "the input has errors"),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, Failure> {
+    let mut args = args.iter();
+    let subcommand = match args.next().map(String::as_str) {
+        Some("check") => Subcommand::Check,
+        Some("fmt") => Subcommand::Fmt,
+        Some("stats") => Subcommand::Stats,
+        Some(other) => return Err(Failure::Usage(format!("unknown command `{}`", other))),
+        None => return Err(Failure::Usage("missing command".to_string())),
+    };
+    let mut parsed = Args {
+        subcommand,
+        parse: ParseOptions::default(),
+        format: FormatOptions::default(),
+        path: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--grbl" => parsed.parse.allow_grbl_system_commands = true,
+            "--klipper" => parsed.parse.allow_extended_commands = true,
+            "--rrf" => parsed.parse.allow_rrf_meta = true,
+            "--comma-decimal" => parsed.parse.accept_comma_decimal = true,
+            "--checksums" => parsed.format.checksums = true,
+            "--line-numbers" => parsed.format.line_numbers = true,
+            "--percent" => parsed.format.delimit_with_percent = true,
+            "--preserve-blank-lines" => parsed.format.preserve_blank_lines = true,
+            "--newline-before-comment" => parsed.format.newline_before_comment = true,
+            "--inline-comments" => {
+                parsed.format.inline_comment_handling = match args.next().map(String::as_str) {
+                    Some("keep") => InlineCommentHandling::Keep,
+                    Some("eol") => InlineCommentHandling::ConvertToEol,
+                    Some("strip") => InlineCommentHandling::Strip,
+                    _ => return Err(Failure::Usage(format!("{} takes keep, eol, or strip", arg))),
+                }
+            }
+            "--strip-comments" => {
+                parsed.format.strip_comments = Some(match args.next().map(String::as_str) {
+                    Some("all") => CommentPreservePolicy::Nothing,
+                    Some("keep-actions") => CommentPreservePolicy::ActionCommands,
+                    Some("keep-markers") => CommentPreservePolicy::ActionCommandsAndMarkers,
+                    _ => {
+                        return Err(Failure::Usage(format!(
+                            "{} takes all, keep-actions, or keep-markers",
+                            arg
+                        )))
+                    }
+                })
+            }
+            flag if flag.starts_with("--") => {
+                return Err(Failure::Usage(format!("unknown option `{}`", flag)))
+            }
+            path if parsed.path.is_none() => parsed.path = Some(path.to_string()),
+            extra => return Err(Failure::Usage(format!("unexpected argument `{}`", extra))),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Run a subcommand over the source, writing its results to `out` and any diagnostics to `diagnostics`.
+fn run(
+    args: &Args,
+    name: &str,
+    src: &str,
+    out: &mut dyn Write,
+    diagnostics: &mut dyn WriteColor,
+) -> Result<(), Failure> {
+    let files = SimpleFile::new(name, src);
+    let mut report = |diagnostic: &Diagnostic| {
+        term::emit(diagnostics, &term::Config::default(), &files, diagnostic)
+            .map_err(|err| Failure::Io(io::Error::other(err)))
+    };
+    let file = match file_parser_with_options(src, &args.parse) {
+        Ok(file) => file,
+        Err(err) => {
+            report(&into_diagnostic_with_source(&err, src))?;
+            return Err(Failure::Invalid);
+        }
+    };
+    match args.subcommand {
+        Subcommand::Check => {
+            let warnings = conflicting_offsets(&file)
+                .into_iter()
+                .chain(arc_consistency(&file, ARC_TOLERANCE))
+                .collect::<Vec<_>>();
+            for warning in warnings.iter() {
+                report(warning)?;
+            }
+            writeln!(out, "{}: {} warning(s)", name, warnings.len())?;
+        }
+        Subcommand::Fmt => format_gcode_io(file.iter_emit_tokens(), args.format, &mut *out)?,
+        Subcommand::Stats => {
+            let mut fields = BTreeMap::new();
+            for field in file.iter_fields().map(Field::from) {
+                *fields
+                    .entry(field.letters.to_ascii_uppercase())
+                    .or_insert(0usize) += 1;
+            }
+            for (letters, count) in fields.iter() {
+                writeln!(out, "{}\t{}", letters, count)?;
+            }
+
+            let mut state = State::default();
+            let mut bounds: Option<[(f64, f64); 3]> = None;
+            for line in file.iter() {
+                let before = state.position;
+                state.step(line);
+                if state.position == before {
+                    continue;
+                }
+                let position = state
+                    .position
+                    .map(|p| *p.numer() as f64 / *p.denom() as f64);
+                let bounds = bounds.get_or_insert([(f64::INFINITY, f64::NEG_INFINITY); 3]);
+                for (axis, p) in bounds.iter_mut().zip(position.iter()) {
+                    *axis = (axis.0.min(*p), axis.1.max(*p));
+                }
+            }
+            match bounds {
+                Some(bounds) => {
+                    for (axis, (min, max)) in ["X", "Y", "Z"].iter().zip(bounds.iter()) {
+                        writeln!(out, "{} range\t{} to {}", axis, min, max)?;
+                    }
+                }
+                None => writeln!(out, "no moves")?,
+            }
+
+            let seconds = estimate_duration(&file, &MachineLimits::default()).total();
+            writeln!(out, "estimated time\t{:.1}s", seconds)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = parse_args(&args).and_then(|args| {
+        let mut src = String::new();
+        let name = match &args.path {
+            Some(path) => {
+                std::fs::File::open(path)?.read_to_string(&mut src)?;
+                path.as_str()
+            }
+            None => {
+                io::stdin().read_to_string(&mut src)?;
+                "<stdin>"
+            }
+        };
+        let stdout = io::stdout();
+        let mut diagnostics = StandardStream::stderr(ColorChoice::Auto);
+        run(&args, name, &src, &mut stdout.lock(), &mut diagnostics)
+    });
+    match result {
+        Ok(()) => {}
+        Err(Failure::Invalid) => std::process::exit(1),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codespan_reporting::term::termcolor::NoColor;
+    use pretty_assertions::assert_eq;
+
+    /// Run the tool as if from the command line, returning its output and diagnostics.
+    fn tool(args: &str, src: &str) -> (Result<(), Failure>, String, String) {
+        let args = args
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let mut out = vec![];
+        let mut diagnostics = NoColor::new(vec![]);
+        let result = parse_args(&args)
+            .and_then(|args| run(&args, "test.gcode", src, &mut out, &mut diagnostics));
+        (
+            result,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(diagnostics.into_inner()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn flags_set_options() {
+        let args = [
+            "fmt",
+            "--checksums",
+            "--comma-decimal",
+            "--strip-comments",
+            "all",
+            "a.gcode",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+        let args = parse_args(&args).unwrap();
+        assert_eq!(args.subcommand, Subcommand::Fmt);
+        assert!(args.parse.accept_comma_decimal);
+        assert_eq!(
+            args.format,
+            FormatOptions {
+                checksums: true,
+                strip_comments: Some(CommentPreservePolicy::Nothing),
+                ..Default::default()
+            }
+        );
+        assert_eq!(args.path.as_deref(), Some("a.gcode"));
+        for bad in [
+            "",
+            "frobnicate",
+            "fmt --inline-comments",
+            "fmt --nope",
+            "fmt a b",
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(tool(bad, "").0, Err(Failure::Usage(_))),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn fmt_applies_format_options() {
+        let (result, out, _) = tool(
+            "fmt --line-numbers --checksums --strip-comments all",
+            "G28 ; home\nG1 X1,5\n",
+        );
+        assert!(matches!(result, Err(Failure::Invalid)));
+        assert_eq!(out, "");
+
+        let (result, out, _) = tool(
+            "fmt --comma-decimal --line-numbers --checksums --strip-comments all",
+            "G28 ; home\nG1 X1,5\n",
+        );
+        assert!(result.is_ok());
+        assert_eq!(out, "N1 G28*18\nN2 G1 X1.5*120\n");
+    }
+
+    #[test]
+    fn check_renders_parse_errors_and_lints() {
+        let (result, out, diagnostics) = tool("check", "G1 X§");
+        assert!(matches!(result, Err(Failure::Invalid)));
+        assert_eq!(out, "");
+        assert!(diagnostics.starts_with("error: could not parse gcode\n  ┌─ test.gcode:1:5"));
+
+        let (result, out, diagnostics) =
+            tool("check", include_str!("../tests/double_offset_start.gcode"));
+        assert!(result.is_ok());
+        assert!(diagnostics
+            .starts_with("warning: both a home offset (M206/M428) and a G92 offset are in effect"));
+        assert_eq!(out, "test.gcode: 1 warning(s)\n");
+    }
+
+    #[test]
+    fn stats_counts_fields_and_moves() {
+        let (result, out, _) = tool("stats", "G1 X10 Y-5 F600\nG1 X0 Z2\nM2\n");
+        assert!(result.is_ok());
+        assert_eq!(
+            out,
+            "F\t1\nG\t2\nM\t1\nX\t2\nY\t1\nZ\t1\n\
+             X range\t0 to 10\nY range\t-5 to -5\nZ range\t0 to 2\n\
+             estimated time\t2.1s\n"
+        );
+    }
+}
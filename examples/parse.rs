@@ -7,7 +7,7 @@ use std::io::Read;
 use g_code::parse::file_parser;
 
 fn main() {
-    let filename = std::env::args().skip(1).next().expect("specify a filename");
+    let filename = std::env::args().nth(1).expect("specify a filename");
 
     let gcode: String = match filename.as_ref() {
         "-" => {
@@ -15,7 +15,7 @@ fn main() {
             std::io::stdin().read_to_string(&mut acc).unwrap();
             acc
         }
-        filename => std::fs::read_to_string(&filename).expect("file isn't readable"),
+        filename => std::fs::read_to_string(filename).expect("file isn't readable"),
     };
 
     match file_parser(&gcode) {
@@ -0,0 +1,106 @@
+//! Formats an arbitrary stream of tokens and checks that parsing the output gives back the same fields.
+#![no_main]
+use g_code::emit::{format_gcode_fmt, Field, FormatOptions, Token, Value};
+use g_code::parse::file_parser;
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use num_rational::Ratio;
+use std::borrow::Cow;
+
+/// Letters that are never rewritten by the formatter, unlike `N`.
+const LETTERS: [&str; 16] = [
+    "G", "M", "T", "X", "Y", "Z", "E", "F", "S", "P", "I", "J", "R", "O", "A", "GOTO",
+];
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    checksums: bool,
+    line_numbers: bool,
+    delimit_with_percent: bool,
+    newline_before_comment: bool,
+    preserve_blank_lines: bool,
+    tokens: Vec<FuzzToken>,
+}
+
+#[derive(Arbitrary, Debug)]
+enum FuzzToken {
+    Field { letters: u8, value: FuzzValue },
+    Comment { is_inline: bool, text: Vec<u8> },
+    Checksum(u8),
+    BlankLine,
+    Newline,
+}
+
+#[derive(Arbitrary, Debug)]
+enum FuzzValue {
+    Integer(u64),
+    /// A decimal with up to four fractional digits, as slicers write them
+    Decimal {
+        numerator: i32,
+        places: u8,
+    },
+    String(Vec<u8>),
+}
+
+/// Printable ASCII for the text of a comment or string, leaving out the characters that would end it.
+fn printable(bytes: &[u8], excluded: char) -> String {
+    bytes
+        .iter()
+        .map(|b| char::from(b' ' + b % 95))
+        .filter(|c| *c != excluded)
+        .collect()
+}
+
+impl FuzzToken {
+    fn to_token(&self) -> Token<'static> {
+        match self {
+            Self::Field { letters, value } => {
+                let value = match value {
+                    FuzzValue::Integer(i) => Value::Integer(*i),
+                    FuzzValue::Decimal { numerator, places } => Value::Rational(Ratio::new(
+                        i64::from(*numerator),
+                        10i64.pow(u32::from(places % 5)),
+                    )),
+                    FuzzValue::String(bytes) => Value::String(Cow::Owned(printable(bytes, '"'))),
+                };
+                let letters = LETTERS[usize::from(*letters) % LETTERS.len()];
+                Token::Field(Field::new(letters, value).unwrap())
+            }
+            Self::Comment { is_inline, text } => Token::Comment {
+                is_inline: *is_inline,
+                inner: Cow::Owned(printable(text, if *is_inline { ')' } else { '\n' })),
+            },
+            Self::Checksum(checksum) => Token::Checksum(*checksum),
+            Self::BlankLine => Token::BlankLine,
+            Self::Newline => Token::Newline,
+        }
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let tokens = input
+        .tokens
+        .iter()
+        .map(FuzzToken::to_token)
+        .collect::<Vec<_>>();
+    let opts = FormatOptions {
+        checksums: input.checksums,
+        line_numbers: input.line_numbers,
+        delimit_with_percent: input.delimit_with_percent,
+        newline_before_comment: input.newline_before_comment,
+        preserve_blank_lines: input.preserve_blank_lines,
+        ..Default::default()
+    };
+    let mut gcode = String::new();
+    format_gcode_fmt(&tokens, opts, &mut gcode).unwrap();
+
+    let file = file_parser(&gcode).unwrap_or_else(|err| panic!("{:?}\n{}", err, gcode));
+    let fields = tokens.iter().filter_map(|token| match token {
+        Token::Field(field) => Some(field),
+        _ => None,
+    });
+    let parsed = file
+        .iter_fields()
+        .filter(|field| !(input.line_numbers && Field::from(*field).letters == "N"));
+    assert!(fields.eq(parsed), "{}", gcode);
+});
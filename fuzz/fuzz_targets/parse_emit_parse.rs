@@ -0,0 +1,44 @@
+//! Parses arbitrary text, formats it, and checks that parsing the output gives back
+//! the same fields and comments in the same order.
+//!
+//! Fields are compared by meaning, since formatting writes `X-0` as `X0`.
+//! Formatting may also move a comment onto a different line or drop blank lines,
+//! so lines themselves are not compared.
+#![no_main]
+use g_code::emit::{format_gcode_fmt, Field, FormatOptions, Token};
+use g_code::parse::{ast::File, file_parser};
+use libfuzzer_sys::fuzz_target;
+
+fn comments<'a>(file: &File<'a>) -> Vec<Token<'a>> {
+    file.iter_emit_tokens()
+        .filter(|token| matches!(token, Token::Comment { .. }))
+        .collect()
+}
+
+fuzz_target!(|text: &str| {
+    let file = match file_parser(text) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    let mut gcode = String::new();
+    format_gcode_fmt(
+        file.iter_emit_tokens(),
+        FormatOptions::default(),
+        &mut gcode,
+    )
+    .unwrap();
+    let reparsed = file_parser(&gcode).unwrap_or_else(|err| panic!("{:?}\n{}", err, gcode));
+    let fields = file.iter_fields().map(Field::from).collect::<Vec<_>>();
+    let reparsed_fields = reparsed.iter_fields().collect::<Vec<_>>();
+    assert_eq!(fields.len(), reparsed_fields.len(), "{}", gcode);
+    for (field, reparsed_field) in fields.iter().zip(reparsed_fields) {
+        assert!(
+            field == reparsed_field,
+            "{} {:?}\n{}",
+            field,
+            reparsed_field,
+            gcode
+        );
+    }
+    assert_eq!(comments(&reparsed), comments(&file), "{}", gcode);
+});
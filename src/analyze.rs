@@ -0,0 +1,91 @@
+//! Inspecting how a parsed program is put together.
+use crate::parse::ast::{File, Line};
+use crate::parse::token::{Field, Value};
+
+/// How a program is wrapped, as returned by [program_envelope].
+///
+/// Fanuc "tape" programs are wrapped in `%` delimiters, start with an `O` program number,
+/// and end with `M30`:
+///
+/// ```text
+/// %
+/// O1234
+/// G0 X0 Y0
+/// M30
+/// %
+/// ```
+///
+/// Printer firmware takes plain files with none of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope {
+    /// The file is wrapped in `%` delimiters.
+    pub percent_delimited: bool,
+    /// The number of an `O` field that comes first in the first line with fields, after any `N` line number.
+    pub program_number: Option<u64>,
+    /// The code that ends the program, if it is on the last line with fields.
+    pub end_code: Option<EndCode>,
+}
+
+/// The `M` codes that end a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndCode {
+    /// `M2`: end the program.
+    M2,
+    /// `M30`: end the program and rewind it, which is what tape programs use.
+    M30,
+}
+
+/// Describe how a file is wrapped.
+///
+/// ```
+/// use g_code::analyze::{program_envelope, EndCode, Envelope};
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("%\nO1234 (BRACKET)\nG0 X0 Y0\nM30\n%").unwrap();
+/// assert_eq!(
+///     program_envelope(&file),
+///     Envelope { percent_delimited: true, program_number: Some(1234), end_code: Some(EndCode::M30) }
+/// );
+/// ```
+pub fn program_envelope(file: &File) -> Envelope {
+    let mut lines_with_fields = file
+        .iter()
+        .filter(|line| line.iter_fields().next().is_some());
+    let first = lines_with_fields.next();
+    let last = lines_with_fields.last().or(first);
+    let program_number = first
+        .and_then(program_number_field)
+        .and_then(|field| match field.value {
+            Value::Integer(number) => Some(number),
+            _ => None,
+        });
+    let end_code = last.and_then(|line| line.iter_fields().find_map(end_code));
+    Envelope {
+        percent_delimited: file.has_percent_delimiters(),
+        program_number,
+        end_code,
+    }
+}
+
+/// The `O` field giving the program number, if it comes first on the line after any `N` line number.
+pub(crate) fn program_number_field<'a, 'input>(
+    line: &'a Line<'input>,
+) -> Option<&'a Field<'input>> {
+    line.iter_fields()
+        .find(|field| !field.letters.eq_ignore_ascii_case("N"))
+        .filter(|field| {
+            field.letters.eq_ignore_ascii_case("O") && matches!(field.value, Value::Integer(_))
+        })
+}
+
+/// The end code a field is, if it is one.
+pub(crate) fn end_code(field: &Field) -> Option<EndCode> {
+    if !field.letters.eq_ignore_ascii_case("M") {
+        return None;
+    }
+    match field.value {
+        Value::Integer(2) => Some(EndCode::M2),
+        Value::Integer(30) => Some(EndCode::M30),
+        _ => None,
+    }
+}
@@ -0,0 +1,156 @@
+use std::borrow::Borrow;
+use std::fmt;
+
+use super::format::Layout;
+use super::{FormatOptions, Token};
+
+/// Measurements of a line as [format_gcode_fmt](super::format_gcode_fmt) would write it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineStats {
+    /// Length of the line in bytes, not counting the newline or a byte order mark.
+    pub bytes: usize,
+    /// Number of fields and flags on the line, including a generated line number.
+    pub fields: usize,
+    /// True if the line has an end-of-line comment.
+    pub has_comment: bool,
+}
+
+/// Measure each line that formatting the tokens with these options would produce,
+/// without writing the output anywhere.
+///
+/// Lines come out in order, including blank lines and `%` delimiters,
+/// so they can be matched up with the lines of the formatted output:
+///
+/// ```
+/// use g_code::emit::{analyze::line_lengths, FormatOptions};
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("G28\nG1 X10 Y10 ; go").unwrap();
+/// let too_long = line_lengths(file.iter_emit_tokens(), FormatOptions::default())
+///     .position(|line| line.bytes > 10);
+/// assert_eq!(too_long, Some(1));
+/// ```
+pub fn line_lengths<'b, I>(tokens: I, opts: FormatOptions) -> impl Iterator<Item = LineStats>
+where
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut layout = Layout::new(opts, true);
+    let mut tokens = tokens.into_iter().peekable();
+    let mut started = false;
+    let mut finished = false;
+    std::iter::from_fn(move || loop {
+        if let Some(line) = layout
+            .written
+            .as_mut()
+            .and_then(|written| written.pop_front())
+        {
+            return Some(line.stats);
+        }
+        // Discarding output cannot fail
+        let _ = if !started {
+            started = true;
+            layout.start(&mut Discard)
+        } else if let Some(token) = tokens.next() {
+            layout.feed(token.borrow(), tokens.peek().is_some(), &mut Discard)
+        } else if !finished {
+            finished = true;
+            layout.finish(&mut Discard)
+        } else {
+            return None;
+        };
+    })
+}
+
+/// A [fmt::Write] that drops everything written to it.
+struct Discard;
+
+impl fmt::Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::format_gcode_fmt;
+    use crate::parse::file_parser;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn lengths_match_formatted_lines() {
+        for src in [
+            include_str!("../../tests/vandy_commodores_logo.gcode"),
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/blank_lines.gcode"),
+            include_str!("../../tests/square.gcode"),
+            "%\nN1 G28*18;home\nG1 X1 (inline) ;eol\n%",
+        ]
+        .iter()
+        {
+            let tokens = file_parser(src)
+                .unwrap()
+                .iter_emit_tokens()
+                .collect::<Vec<_>>();
+            for bits in 0..64u8 {
+                let opts = FormatOptions {
+                    checksums: bits & 1 != 0,
+                    line_numbers: bits & 2 != 0,
+                    delimit_with_percent: bits & 4 != 0,
+                    newline_before_comment: bits & 8 != 0,
+                    preserve_blank_lines: bits & 16 != 0,
+                    number_blank_lines: bits & 32 != 0,
+                    ..Default::default()
+                };
+                let mut formatted = String::new();
+                format_gcode_fmt(&tokens, opts, &mut formatted).unwrap();
+                let stats = line_lengths(&tokens, opts).collect::<Vec<_>>();
+                assert_eq!(
+                    stats.iter().map(|line| line.bytes).collect::<Vec<_>>(),
+                    formatted.lines().map(str::len).collect::<Vec<_>>(),
+                    "{:?}",
+                    opts
+                );
+                for (line, text) in stats.iter().zip(formatted.lines()) {
+                    assert_eq!(line.has_comment, text.contains(';'), "{:?}", text);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn counts_fields_and_generated_line_numbers() {
+        let tokens = file_parser("G1 X1 Y2 ;move\nM107\n\n")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let stats = |opts| line_lengths(&tokens, opts).collect::<Vec<_>>();
+        assert_eq!(
+            stats(FormatOptions::default()),
+            [
+                LineStats {
+                    bytes: 14,
+                    fields: 3,
+                    has_comment: true,
+                },
+                LineStats {
+                    bytes: 4,
+                    fields: 1,
+                    has_comment: false,
+                },
+            ]
+        );
+        assert_eq!(
+            stats(FormatOptions {
+                line_numbers: true,
+                newline_before_comment: true,
+                ..Default::default()
+            })
+            .iter()
+            .map(|line| (line.bytes, line.fields, line.has_comment))
+            .collect::<Vec<_>>(),
+            [(11, 4, false), (5, 0, true), (7, 2, false)]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use super::Token;
+
+/// What a comment means to the software that reads it, as decided by [comment_kind].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentKind<'a> {
+    /// A comment meant for people, or one that isn't recognized.
+    Plain,
+    /// A command for the host sending the program, like `;@pause` for OctoPrint.
+    ///
+    /// This is the command and its parameters, without the `@`.
+    ActionCommand(&'a str),
+    /// A marker a slicer leaves for viewers and post-processors, like Cura's `;TYPE:WALL-OUTER` or `;LAYER:12`.
+    SlicerMarker { key: &'a str, value: &'a str },
+}
+
+/// Classify a comment by what hosts and slicers use it for.
+///
+/// Inline and end-of-line comments are recognized the same way:
+///
+/// * An action command starts with `@`, which may be preceded by whitespace,
+///   and then a letter: `;@pause` and `; @resume` are both commands.
+/// * A slicer marker starts right away with a key of uppercase letters, digits, and underscores,
+///   which begins with a letter and ends with a `:`. The rest is its value, without surrounding whitespace.
+///
+/// Anything else, including tokens that aren't comments, is [CommentKind::Plain].
+///
+/// ```
+/// use g_code::emit::{comment_kind, CommentKind, Token};
+///
+/// let comment = |inner: &'static str| Token::Comment { is_inline: false, inner: inner.into() };
+/// assert_eq!(comment_kind(&comment(" @pause")), CommentKind::ActionCommand("pause"));
+/// assert_eq!(
+///     comment_kind(&comment("TYPE:WALL-OUTER")),
+///     CommentKind::SlicerMarker { key: "TYPE", value: "WALL-OUTER" }
+/// );
+/// assert_eq!(comment_kind(&comment("Generated with Cura_SteamEngine 5.2.1")), CommentKind::Plain);
+/// ```
+pub fn comment_kind<'a>(token: &'a Token) -> CommentKind<'a> {
+    let inner = match token {
+        Token::Comment { inner, .. } => inner,
+        _ => return CommentKind::Plain,
+    };
+    if let Some(command) = inner.trim_start().strip_prefix('@') {
+        if command.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return CommentKind::ActionCommand(command.trim_end());
+        }
+    }
+    if let Some((key, value)) = inner.split_once(':') {
+        let is_key = key.starts_with(|c: char| c.is_ascii_uppercase())
+            && key
+                .bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+        if is_key {
+            return CommentKind::SlicerMarker {
+                key,
+                value: value.trim(),
+            };
+        }
+    }
+    CommentKind::Plain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn assert_kind(is_inline: bool, inner: &str, expected: CommentKind) {
+        let token = Token::Comment {
+            is_inline,
+            inner: inner.into(),
+        };
+        assert_eq!(comment_kind(&token), expected, "{:?}", inner);
+    }
+
+    #[test]
+    fn recognizes_action_commands() {
+        assert_kind(false, "@pause", CommentKind::ActionCommand("pause"));
+        assert_kind(false, "  @resume ", CommentKind::ActionCommand("resume"));
+        assert_kind(
+            true,
+            "@OCTOLAPSE TAKE-SNAPSHOT",
+            CommentKind::ActionCommand("OCTOLAPSE TAKE-SNAPSHOT"),
+        );
+        for plain in ["@", "@ 5mm", "email me@example.com"] {
+            assert_kind(false, plain, CommentKind::Plain);
+        }
+    }
+
+    #[test]
+    fn recognizes_slicer_markers() {
+        let marker = |key, value| CommentKind::SlicerMarker { key, value };
+        assert_kind(false, "LAYER:0", marker("LAYER", "0"));
+        assert_kind(
+            false,
+            "TYPE:External perimeter",
+            marker("TYPE", "External perimeter"),
+        );
+        assert_kind(true, "TIME_ELAPSED: 12.5", marker("TIME_ELAPSED", "12.5"));
+        assert_kind(false, "MESH:NONMESH", marker("MESH", "NONMESH"));
+        for plain in [
+            " TYPE:SKIN",
+            "Layer height: 0.2",
+            "_TYPE:SKIN",
+            "2ND:x",
+            "TYPE",
+            "",
+        ] {
+            assert_kind(false, plain, CommentKind::Plain);
+        }
+        assert_eq!(comment_kind(&Token::Percent), CommentKind::Plain);
+    }
+}
@@ -0,0 +1,2209 @@
+use std::borrow::{Borrow, Cow};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::io;
+
+use super::analyze::LineStats;
+use super::{comment_kind, CommentKind, Field, Token, Value};
+
+/// Controls how [format_gcode_fmt] and [format_gcode_io] lay out a token stream.
+///
+/// The default is the most compact output: no checksums, no line numbers, no delimiters.
+///
+/// Some options contradict each other, which [FormatOptions::validate] checks for.
+/// Options built with [FormatOptions::builder] or deserialized are always valid,
+/// while the formatters only check them in debug builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "UncheckedFormatOptions")
+)]
+pub struct FormatOptions {
+    /// Append a `*` checksum to every line containing a field.
+    ///
+    /// Regardless of this option, lines followed by a [Token::Checksum] get a freshly computed checksum.
+    /// See [FormatOptions::checksum_policy] for overriding either.
+    pub checksums: bool,
+    /// Prefix every line containing a field with an `N` line number, starting at 1.
+    ///
+    /// Any `N` fields already present in the token stream are replaced.
+    /// Lines without a field, like comment-only lines, are skipped by the numbering
+    /// just as firmware doesn't count them: see [Line::is_executable](crate::parse::ast::Line::is_executable).
+    /// Only [FormatOptions::number_blank_lines] numbers lines without one.
+    pub line_numbers: bool,
+    /// Wrap the program in `%` delimiters.
+    ///
+    /// When unset, a [Token::Percent] in the token stream is still written,
+    /// so a parsed file keeps whatever delimiters it had.
+    pub delimit_with_percent: bool,
+    /// Start the output with a UTF-8 byte order mark.
+    ///
+    /// Only useful for matching an input that had one: see [File::has_byte_order_mark](crate::parse::ast::File::has_byte_order_mark).
+    pub byte_order_mark: bool,
+    /// Move end-of-line comments onto their own line.
+    pub newline_before_comment: bool,
+    /// Write [Token::BlankLine] as an empty line instead of dropping it.
+    pub preserve_blank_lines: bool,
+    /// Give each preserved blank line a line number (and checksum) of its own.
+    ///
+    /// When unset, blank lines are written empty and skipped by the numbering.
+    pub number_blank_lines: bool,
+    /// Which bytes of a line contribute to its checksum.
+    pub checksum_style: ChecksumStyle,
+    /// Which lines get a checksum, and whether given checksums are trusted.
+    pub checksum_policy: ChecksumPolicy,
+    /// What to do with inline `(...)` comments.
+    pub inline_comment_handling: InlineCommentHandling,
+    /// Drop comments, apart from those the policy keeps.
+    ///
+    /// When unset, every comment is kept. A line that loses its end-of-line comment still ends there,
+    /// and a line that had nothing but a comment is dropped.
+    /// [InlineCommentHandling::Strip] drops inline comments whatever the policy.
+    pub strip_comments: Option<CommentPreservePolicy>,
+    /// Which comment syntax the target accepts.
+    pub comment_style: CommentStyle,
+    /// How to write a [Value::Bool].
+    pub bool_style: BoolStyle,
+}
+
+/// Ways of writing a [Value::Bool].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum BoolStyle {
+    /// `1` or `0`, which any firmware taking a flag understands.
+    #[default]
+    Digits,
+    /// `TRUE` or `FALSE`, as accepted by RepRapFirmware meta commands.
+    ///
+    /// This crate's parser does not accept these, so the output can't be parsed back.
+    Words,
+}
+
+/// Firmware disagrees on exactly which bytes of a line are XORed into its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ChecksumStyle {
+    /// Every byte leading up to the asterisk, as done by Marlin and most other firmware.
+    #[default]
+    Classic,
+    /// Like [ChecksumStyle::Classic], but the whitespace separating an `N` line number
+    /// from the rest of the line is skipped.
+    ExcludeSpaceAfterLineNumber,
+    /// Like [ChecksumStyle::Classic], but the asterisk itself is included.
+    IncludeAsterisk,
+    /// Like [ChecksumStyle::Classic], but inline `(...)` comments are left out,
+    /// as RepRapFirmware strips them before checking the checksum.
+    ///
+    /// The whitespace around the comments is still included.
+    ExcludeInlineComments,
+}
+
+/// Decides which lines are checksummed, and how.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ChecksumPolicy {
+    /// Every checksum written is computed from the line as written.
+    ///
+    /// Lines get one if [FormatOptions::checksums] is set or they are followed by a [Token::Checksum].
+    #[default]
+    Always,
+    /// Never write a checksum, even for a [Token::Checksum].
+    Never,
+    /// Write the value of a [Token::Checksum] as is if the line is written as it was checksummed,
+    /// and give every other line containing a field a freshly computed checksum.
+    ///
+    /// This is meant for retransmitting a checksummed file with a few lines changed:
+    /// the tokens of [Line::emit_plan](crate::parse::ast::Line::emit_plan) only keep checksums that were valid,
+    /// so unchanged lines keep their original checksum byte for byte and invalid ones are recomputed.
+    /// A given checksum that doesn't match the bytes written, because the line was changed
+    /// or a value like `X1.50` was written as `X1.5`, is recomputed as well.
+    ///
+    /// The given value is only right if the line is written as it was checksummed,
+    /// so this does not combine with [FormatOptions::line_numbers], which rewrites `N` fields:
+    /// see [OptionsError::PreservedChecksumsRenumbered].
+    PreserveValid,
+}
+
+/// Some controls only accept one of the two comment syntaxes, so comments can be rewritten to suit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CommentStyle {
+    /// Write comments in whichever syntax they came in.
+    #[default]
+    Auto,
+    /// Only use `;` comments, as Marlin expects.
+    ///
+    /// Inline comments are moved to the end of their line,
+    /// as if [InlineCommentHandling::ConvertToEol] was set.
+    /// [InlineCommentHandling::Strip] still takes precedence.
+    SemicolonOnly,
+    /// Only use `(...)` comments, as some Fanuc controls expect.
+    ///
+    /// End-of-line comments are wrapped in parentheses and kept at the end of their line.
+    /// Since nothing can follow a checksum, they come before it and are part of it.
+    /// With [FormatOptions::newline_before_comment] they are still moved onto their own line.
+    ///
+    /// Parentheses can't be nested in a comment, so any `(` or `)` in a comment
+    /// is replaced by `[` or `]` respectively.
+    ParenthesesOnly,
+}
+
+/// Wrap a comment in parentheses, replacing any parentheses inside it with brackets.
+fn parenthesize(comment: &str) -> String {
+    let escaped = comment.replace('(', "[").replace(')', "]");
+    format!("({})", escaped)
+}
+
+/// Many viewers and some firmware mishandle inline comments, so they can be rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum InlineCommentHandling {
+    /// Write inline comments where they are.
+    #[default]
+    Keep,
+    /// Turn inline comments into an end-of-line comment on the same line.
+    ///
+    /// Several comments on one line, including an existing end-of-line comment,
+    /// are merged into one, separated by spaces.
+    /// Like any other end-of-line comment, the result is not part of the line's checksum
+    /// and is moved onto its own line by [FormatOptions::newline_before_comment].
+    ConvertToEol,
+    /// Drop inline comments.
+    Strip,
+}
+
+/// Which comments survive [FormatOptions::strip_comments], as classified by [comment_kind].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CommentPreservePolicy {
+    /// Strip every comment.
+    Nothing,
+    /// Keep host action commands like `;@pause`, since hosts stop acting on them once they are stripped.
+    #[default]
+    ActionCommands,
+    /// Keep action commands and slicer markers like `;TYPE:WALL-OUTER`,
+    /// which viewers and post-processors rely on.
+    ActionCommandsAndMarkers,
+}
+
+impl CommentPreservePolicy {
+    fn keeps(self, kind: CommentKind) -> bool {
+        match kind {
+            CommentKind::Plain => false,
+            CommentKind::ActionCommand(_) => self != Self::Nothing,
+            CommentKind::SlicerMarker { .. } => self == Self::ActionCommandsAndMarkers,
+        }
+    }
+}
+
+/// Sets each field of the options, for use in [FormatOptionsBuilder].
+macro_rules! setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Set [FormatOptions::", stringify!($field), "].")]
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.0.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+/// Builds [FormatOptions] one option at a time, starting from the default,
+/// and checks that they don't contradict each other.
+///
+/// ```
+/// use g_code::emit::{ChecksumPolicy, FormatOptions, OptionsError};
+///
+/// let opts = FormatOptions::builder().checksums(true).line_numbers(true).build()?;
+/// assert!(opts.checksums && opts.line_numbers);
+///
+/// let renumbered = FormatOptions::builder()
+///     .line_numbers(true)
+///     .checksum_policy(ChecksumPolicy::PreserveValid)
+///     .build();
+/// assert_eq!(renumbered, Err(OptionsError::PreservedChecksumsRenumbered));
+/// # Ok::<(), OptionsError>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FormatOptionsBuilder(FormatOptions);
+
+impl FormatOptionsBuilder {
+    setters! {
+        checksums: bool,
+        line_numbers: bool,
+        delimit_with_percent: bool,
+        byte_order_mark: bool,
+        newline_before_comment: bool,
+        preserve_blank_lines: bool,
+        number_blank_lines: bool,
+        checksum_style: ChecksumStyle,
+        checksum_policy: ChecksumPolicy,
+        inline_comment_handling: InlineCommentHandling,
+        strip_comments: Option<CommentPreservePolicy>,
+        comment_style: CommentStyle,
+        bool_style: BoolStyle,
+    }
+
+    /// The options, if they are [valid](FormatOptions::validate).
+    pub fn build(self) -> Result<FormatOptions, OptionsError> {
+        self.0.validate()?;
+        Ok(self.0)
+    }
+}
+
+/// Combinations of [FormatOptions] that can't be honored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionsError {
+    /// [FormatOptions::checksums] is set, but [ChecksumPolicy::Never] writes no checksums.
+    ChecksumsNeverWritten,
+    /// A [ChecksumStyle] other than the default was chosen, but [ChecksumPolicy::Never] writes no checksums.
+    ChecksumStyleUnused,
+    /// [ChecksumPolicy::PreserveValid] keeps checksums that were computed with the original line numbers,
+    /// which [FormatOptions::line_numbers] replaces.
+    PreservedChecksumsRenumbered,
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChecksumsNeverWritten => write!(
+                f,
+                "checksums are enabled, but the checksum policy never writes them"
+            ),
+            Self::ChecksumStyleUnused => write!(
+                f,
+                "a checksum style is set, but the checksum policy never writes checksums"
+            ),
+            Self::PreservedChecksumsRenumbered => write!(
+                f,
+                "preserved checksums would no longer match once line numbers are rewritten; \
+                 use the `always` checksum policy with line numbers"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+impl FormatOptions {
+    /// Start building options from the default.
+    pub fn builder() -> FormatOptionsBuilder {
+        FormatOptionsBuilder::default()
+    }
+
+    /// Check that no option contradicts another.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.checksum_policy == ChecksumPolicy::Never {
+            if self.checksums {
+                return Err(OptionsError::ChecksumsNeverWritten);
+            }
+            if self.checksum_style != ChecksumStyle::Classic {
+                return Err(OptionsError::ChecksumStyleUnused);
+            }
+        }
+        if self.checksum_policy == ChecksumPolicy::PreserveValid && self.line_numbers {
+            return Err(OptionsError::PreservedChecksumsRenumbered);
+        }
+        Ok(())
+    }
+
+    /// The comment is dropped by [FormatOptions::strip_comments].
+    fn strips(&self, comment: &Token) -> bool {
+        self.strip_comments
+            .is_some_and(|policy| !policy.keeps(comment_kind(comment)))
+    }
+}
+
+/// [FormatOptions] as deserialized, before they are validated.
+///
+/// Missing fields take their default, as in [FormatOptions::default].
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct UncheckedFormatOptions {
+    checksums: bool,
+    line_numbers: bool,
+    delimit_with_percent: bool,
+    byte_order_mark: bool,
+    newline_before_comment: bool,
+    preserve_blank_lines: bool,
+    number_blank_lines: bool,
+    checksum_style: ChecksumStyle,
+    checksum_policy: ChecksumPolicy,
+    inline_comment_handling: InlineCommentHandling,
+    strip_comments: Option<CommentPreservePolicy>,
+    comment_style: CommentStyle,
+    bool_style: BoolStyle,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<UncheckedFormatOptions> for FormatOptions {
+    type Error = OptionsError;
+
+    fn try_from(unchecked: UncheckedFormatOptions) -> Result<Self, Self::Error> {
+        let opts = Self {
+            checksums: unchecked.checksums,
+            line_numbers: unchecked.line_numbers,
+            delimit_with_percent: unchecked.delimit_with_percent,
+            byte_order_mark: unchecked.byte_order_mark,
+            newline_before_comment: unchecked.newline_before_comment,
+            preserve_blank_lines: unchecked.preserve_blank_lines,
+            number_blank_lines: unchecked.number_blank_lines,
+            checksum_style: unchecked.checksum_style,
+            checksum_policy: unchecked.checksum_policy,
+            inline_comment_handling: unchecked.inline_comment_handling,
+            strip_comments: unchecked.strip_comments,
+            comment_style: unchecked.comment_style,
+            bool_style: unchecked.bool_style,
+        };
+        opts.validate()?;
+        Ok(opts)
+    }
+}
+
+/// Write a sequence of tokens as GCode to a [fmt::Write].
+///
+/// A [Token::Newline] ends the current line, as given between the lines of a parsed file.
+/// Apart from that, a new line is started before
+/// every `G`, `M`, `T`, `O`, or `N` field unless the current line only holds a line number.
+/// A `T` field directly after an `M` command is taken as its argument (`M104 T0 S200`) instead.
+/// End-of-line comments always terminate the line they are on,
+/// and [Token::Checksum] terminates its line unless an end-of-line comment follows.
+///
+/// Tokens can be given by reference or by value, so a parsed file can be formatted
+/// straight from [File::iter_emit_tokens](crate::parse::ast::File::iter_emit_tokens)
+/// without collecting its tokens first.
+pub fn format_gcode_fmt<'b, W, I>(tokens: I, opts: FormatOptions, mut w: W) -> fmt::Result
+where
+    W: Write,
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut layout = Layout::new(opts, false);
+    let mut tokens = tokens.into_iter().peekable();
+    layout.start(&mut w)?;
+    while let Some(token) = tokens.next() {
+        layout.feed(token.borrow(), tokens.peek().is_some(), &mut w)?;
+    }
+    layout.finish(&mut w)
+}
+
+/// Write a sequence of tokens as GCode to a [fmt::Write] like [format_gcode_fmt],
+/// writing numbers with a custom [ValueFormatter].
+///
+/// Checksums are computed from the line as it is written, custom numbers included.
+///
+/// ```
+/// use g_code::emit::{format_gcode_fmt_with, FixedDecimals, FormatOptions};
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("G1 X1.5 Y-.25").unwrap();
+/// let mut output = String::new();
+/// format_gcode_fmt_with(file.iter_emit_tokens(), FormatOptions::default(), &FixedDecimals(4), &mut output).unwrap();
+/// assert_eq!(output, "G1 X1.5000 Y-0.2500\n");
+/// ```
+pub fn format_gcode_fmt_with<'b, W, I>(
+    tokens: I,
+    opts: FormatOptions,
+    formatter: &dyn ValueFormatter,
+    mut w: W,
+) -> fmt::Result
+where
+    W: Write,
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut layout = Layout::new(opts, false).with_formatter(formatter);
+    let mut tokens = tokens.into_iter().peekable();
+    layout.start(&mut w)?;
+    while let Some(token) = tokens.next() {
+        layout.feed(token.borrow(), tokens.peek().is_some(), &mut w)?;
+    }
+    layout.finish(&mut w)
+}
+
+/// Write a sequence of tokens as GCode to an [io::Write].
+///
+/// See [format_gcode_fmt] for the layout rules.
+///
+/// Lines are written in several small pieces, so output is buffered
+/// and flushed before returning.
+pub fn format_gcode_io<'b, W, I>(tokens: I, opts: FormatOptions, w: W) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut layout = Layout::new(opts, false);
+    layout.write_io(tokens, w)
+}
+
+/// Write a sequence of tokens as GCode to an [io::Write] like [format_gcode_io],
+/// writing numbers with a custom [ValueFormatter].
+pub fn format_gcode_io_with<'b, W, I>(
+    tokens: I,
+    opts: FormatOptions,
+    formatter: &dyn ValueFormatter,
+    w: W,
+) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut layout = Layout::new(opts, false).with_formatter(formatter);
+    layout.write_io(tokens, w)
+}
+
+/// Decides how the value of a field is written, for shops that want a particular numeric style.
+///
+/// Only the value is up to the formatter: letters, comments, line numbers and checksums
+/// are written the same way whatever it does.
+/// A [Value::Bool] written as words by [BoolStyle::Words] doesn't reach the formatter either.
+///
+/// Output must still parse as GCode, so a formatter should stick to digits,
+/// an optional leading `-`, and an optional decimal point for numbers.
+pub trait ValueFormatter {
+    /// Write a value, which is the same as its [Display](fmt::Display) implementation by default.
+    fn fmt_value(&self, v: &Value, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(v, f)
+    }
+}
+
+/// Writes values the same way as their [Display](fmt::Display) implementation,
+/// which is what [format_gcode_fmt] and [format_gcode_io] do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DisplayValues;
+
+impl ValueFormatter for DisplayValues {}
+
+/// Writes [Value::Rational] and [Value::Float] with exactly this many decimal places, rounding if needed,
+/// like the `X10.0000` that Fanuc controls expect.
+///
+/// [Value::Integer] is written without a decimal point, so commands like `G1` and line numbers are left alone.
+/// A value that rounds to zero is written without a minus sign.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedDecimals(pub u8);
+
+impl ValueFormatter for FixedDecimals {
+    fn fmt_value(&self, v: &Value, f: &mut fmt::Formatter) -> fmt::Result {
+        let float = match v {
+            Value::Rational(_) | Value::Float(_) => v.as_f64().ok_or(fmt::Error)?,
+            _ => return fmt::Display::fmt(v, f),
+        };
+        let text = format!("{:.*}", usize::from(self.0), float);
+        match text.strip_prefix('-') {
+            Some(magnitude) if magnitude.bytes().all(|b| b == b'0' || b == b'.') => {
+                f.write_str(magnitude)
+            }
+            _ => f.write_str(&text),
+        }
+    }
+}
+
+/// Writes numbers as briefly as they can be parsed back, like the `X.5` that Marlin accepts for `X0.5`.
+///
+/// Trailing zeros are never written, whole numbers have no decimal point,
+/// and the zero before the decimal point of a number smaller than one is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Shortest;
+
+impl ValueFormatter for Shortest {
+    fn fmt_value(&self, v: &Value, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Value::Rational(_) | Value::Float(_) = v {
+            let text = v.to_string();
+            if let Some(fraction) = text.strip_prefix("0.") {
+                return write!(f, ".{}", fraction);
+            } else if let Some(fraction) = text.strip_prefix("-0.") {
+                return write!(f, "-.{}", fraction);
+            }
+        }
+        fmt::Display::fmt(v, f)
+    }
+}
+
+/// A field written with a [ValueFormatter].
+struct FormattedField<'a, 'b> {
+    field: &'a Field<'b>,
+    formatter: &'a dyn ValueFormatter,
+}
+
+impl fmt::Display for FormattedField<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.field.letters)?;
+        self.formatter.fmt_value(&self.field.value, f)
+    }
+}
+
+/// Where a line starts in the output of [format_gcode_io_indexed].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineIndexEntry {
+    /// Offset of the start of the line in bytes, counting a byte order mark.
+    pub offset: usize,
+    /// The line number written on the line, whether generated by
+    /// [FormatOptions::line_numbers] or given as an `N` field before any other field.
+    pub line_number: Option<u64>,
+    /// Position in the token sequence of the first field or command on the line.
+    ///
+    /// Emitted tokens do not carry source spans, so this is how a line is traced back to its source:
+    /// for a parsed file, it is an index into [File::iter_emit_tokens](crate::parse::ast::File::iter_emit_tokens).
+    pub first_token: Option<usize>,
+}
+
+/// Write a sequence of tokens as GCode to an [io::Write] like [format_gcode_io],
+/// returning where each line of the output starts.
+///
+/// There is an entry for every line written, including blank lines, `%` delimiters
+/// and comments moved onto their own line by [FormatOptions::newline_before_comment].
+/// Persisted next to the output, the index lets a sender resume an interrupted program
+/// from a line number or a byte offset:
+///
+/// ```
+/// use g_code::emit::{format_gcode_io_indexed, FormatOptions};
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("G28\nG1 X10\nG1 Y10").unwrap();
+/// let opts = FormatOptions { line_numbers: true, ..Default::default() };
+/// let mut output = vec![];
+/// let index = format_gcode_io_indexed(file.iter_emit_tokens(), opts, &mut output).unwrap();
+/// let resume_from = index.iter().find(|entry| entry.line_number == Some(2)).unwrap();
+/// assert_eq!(&output[resume_from.offset..], b"N2 G1 X10\nN3 G1 Y10\n");
+/// ```
+pub fn format_gcode_io_indexed<'b, W, I>(
+    tokens: I,
+    opts: FormatOptions,
+    w: W,
+) -> io::Result<Vec<LineIndexEntry>>
+where
+    W: io::Write,
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut layout = Layout::new(opts, true);
+    layout.write_io(tokens, w)?;
+    let mut offset = if opts.byte_order_mark {
+        '\u{feff}'.len_utf8()
+    } else {
+        0
+    };
+    Ok(layout
+        .written
+        .unwrap_or_default()
+        .into_iter()
+        .map(|line| {
+            let entry = LineIndexEntry {
+                offset,
+                line_number: line.number,
+                first_token: line.first_token,
+            };
+            offset += line.stats.bytes + 1;
+            entry
+        })
+        .collect())
+}
+
+/// What [format_gcode_fmt] would write for a sequence of tokens, as returned by [format_stats].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FormatStats {
+    /// Number of lines, counting a last line that has no trailing newline.
+    pub lines: usize,
+    /// Length of the output in bytes, including newlines and a byte order mark.
+    pub bytes: usize,
+    /// The last line number generated by [FormatOptions::line_numbers], if any.
+    ///
+    /// Line numbers already present in the token stream are not counted.
+    pub last_line_number: Option<u64>,
+}
+
+/// Work out the size of the output of [format_gcode_fmt] without writing it anywhere.
+///
+/// Useful for sizing a progress bar before streaming a program to a machine:
+///
+/// ```
+/// use g_code::emit::{format_stats, FormatOptions, FormatStats};
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("G28\nG1 X10 ;go").unwrap();
+/// let opts = FormatOptions { line_numbers: true, ..Default::default() };
+/// assert_eq!(
+///     format_stats(file.iter_emit_tokens(), opts),
+///     FormatStats { lines: 2, bytes: 21, last_line_number: Some(2) }
+/// );
+/// ```
+pub fn format_stats<'b, I>(tokens: I, opts: FormatOptions) -> FormatStats
+where
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut tally = Tally::default();
+    let mut layout = Layout::new(opts, false);
+    let mut tokens = tokens.into_iter().peekable();
+    // Tallying output cannot fail
+    let _ = layout.start(&mut tally);
+    while let Some(token) = tokens.next() {
+        let _ = layout.feed(token.borrow(), tokens.peek().is_some(), &mut tally);
+    }
+    let _ = layout.finish(&mut tally);
+    FormatStats {
+        lines: tally.newlines + usize::from(tally.in_line),
+        bytes: tally.bytes,
+        last_line_number: Some(layout.line.number).filter(|&number| number > 0),
+    }
+}
+
+fn starts_new_line(field: &Field) -> bool {
+    matches!(
+        field.letters.as_ref(),
+        "G" | "g" | "M" | "m" | "T" | "t" | "O" | "o" | "N" | "n"
+    )
+}
+
+/// A line written by [Layout], as recorded.
+pub(crate) struct WrittenLine {
+    pub(crate) stats: LineStats,
+    /// The line number written at its start
+    pub(crate) number: Option<u64>,
+    /// Position of the token that put the first field or command on it
+    pub(crate) first_token: Option<usize>,
+}
+
+/// The layout decisions behind [format_gcode_fmt], fed one token at a time.
+///
+/// Output is written to whatever [fmt::Write] is given at each step.
+/// When recording, every line written is queued up as well,
+/// which is how [line_lengths](super::analyze::line_lengths) measures lines without keeping them.
+pub(crate) struct Layout<'f> {
+    opts: FormatOptions,
+    formatter: &'f dyn ValueFormatter,
+    line: LineState,
+    /// The last token other than whitespace ended its line, leaving a [Token::Newline] nothing to end
+    line_broken: bool,
+    /// Number of tokens fed so far
+    fed: usize,
+    /// Lines that have been written, when recording
+    pub(crate) written: Option<VecDeque<WrittenLine>>,
+}
+
+impl<'f> Layout<'f> {
+    pub(crate) fn new(opts: FormatOptions, record: bool) -> Self {
+        debug_assert_eq!(opts.validate(), Ok(()), "{:?}", opts);
+        Self {
+            opts,
+            formatter: &DisplayValues,
+            line: LineState::default(),
+            line_broken: false,
+            fed: 0,
+            written: if record { Some(VecDeque::new()) } else { None },
+        }
+    }
+
+    fn with_formatter(self, formatter: &'f dyn ValueFormatter) -> Self {
+        Self { formatter, ..self }
+    }
+
+    /// Lay out all of the tokens, buffering and flushing the output.
+    fn write_io<'b, W, I>(&mut self, tokens: I, w: W) -> io::Result<()>
+    where
+        W: io::Write,
+        I: IntoIterator,
+        I::Item: Borrow<Token<'b>>,
+    {
+        let mut adapter = IoAdapter {
+            inner: io::BufWriter::new(w),
+            error: Ok(()),
+        };
+        let mut tokens = tokens.into_iter().peekable();
+        let mut result = self.start(&mut adapter);
+        while let (Ok(()), Some(token)) = (result, tokens.next()) {
+            result = self.feed(token.borrow(), tokens.peek().is_some(), &mut adapter);
+        }
+        if result.and_then(|()| self.finish(&mut adapter)).is_err() {
+            return Err(match adapter.error {
+                Err(e) => e,
+                Ok(()) => io::Error::other("formatter error"),
+            });
+        }
+        io::Write::flush(&mut adapter.inner)
+    }
+
+    /// Write anything that comes before the first token.
+    pub(crate) fn start<W: Write>(&mut self, w: &mut W) -> fmt::Result {
+        if self.opts.byte_order_mark {
+            w.write_char('\u{feff}')?;
+        }
+        if self.opts.delimit_with_percent {
+            w.write_str("%\n")?;
+            self.record(1, 0, false);
+        }
+        Ok(())
+    }
+
+    /// Lay out the next token, where `more` tells whether any tokens follow it.
+    pub(crate) fn feed<W: Write>(&mut self, token: &Token, more: bool, w: &mut W) -> fmt::Result {
+        let opts = &self.opts;
+        let line = &mut self.line;
+        let written = &mut self.written;
+        let index = self.fed;
+        self.fed += 1;
+        if let Token::Whitespace(_) = token {
+            return Ok(());
+        }
+        let line_broken = std::mem::replace(
+            &mut self.line_broken,
+            matches!(
+                token,
+                Token::Comment {
+                    is_inline: false,
+                    ..
+                } | Token::BlankLine
+                    | Token::Percent
+                    | Token::Raw(_)
+            ),
+        );
+        match token {
+            Token::Field(field) => {
+                if opts.line_numbers && field.letters.eq_ignore_ascii_case("N") {
+                    return Ok(());
+                }
+                let is_line_number = field.letters.eq_ignore_ascii_case("N");
+                let is_tool_argument =
+                    line.after_m_command && field.letters.eq_ignore_ascii_case("T");
+                if line.closed
+                    || line.has_fields
+                        && starts_new_line(field)
+                        && !is_tool_argument
+                        && (is_line_number || !line.only_line_number)
+                {
+                    line.end(opts, w, written, None)?;
+                }
+                line.after_m_command = field.letters.eq_ignore_ascii_case("M");
+                match (&field.value, opts.bool_style) {
+                    (Value::Bool(b), BoolStyle::Words) => line.push(format_args!(
+                        "{}{}",
+                        field.letters,
+                        if *b { "TRUE" } else { "FALSE" }
+                    )),
+                    _ => line.push(FormattedField {
+                        field,
+                        formatter: self.formatter,
+                    }),
+                }
+                if is_line_number && !line.has_fields {
+                    line.given_number = field
+                        .value
+                        .as_decimal()
+                        .filter(|n| n.is_integer())
+                        .and_then(|n| u64::try_from(n.to_integer()).ok());
+                }
+                line.first_token.get_or_insert(index);
+                line.fields += 1;
+                line.only_line_number = is_line_number && !line.has_fields;
+                line.has_fields = true;
+                // Anything after text on the same line would become part of it
+                line.closed |= matches!(field.value, Value::Text(_));
+            }
+            // Flags only ever follow a command, so they never start a new line
+            Token::Flag(flag) => {
+                if line.closed {
+                    line.end(opts, w, written, None)?;
+                }
+                line.push(flag);
+                line.first_token.get_or_insert(index);
+                line.fields += 1;
+                line.only_line_number = false;
+                line.has_fields = true;
+            }
+            // Extended commands take up the whole line, apart from a comment.
+            // They are never numbered or checksummed, since Klipper doesn't expect either.
+            Token::ExtendedCommand(command) => {
+                if !line.is_empty() {
+                    line.end(opts, w, written, None)?;
+                }
+                line.push(command);
+                line.first_token = Some(index);
+                line.fields += 1;
+                line.closed = true;
+            }
+            // Meta commands are written as they are, since their indentation is significant
+            Token::MetaCommand(command) => {
+                if !line.is_empty() {
+                    line.end(opts, w, written, None)?;
+                }
+                // Pushed directly, so that the indentation isn't preceded by a space
+                let _ = write!(line.content, "{}", command);
+                line.first_token = Some(index);
+                line.fields += 1;
+                line.closed = true;
+            }
+            Token::Comment {
+                is_inline: true,
+                inner,
+            } => {
+                if opts.inline_comment_handling == InlineCommentHandling::Strip
+                    || opts.strips(token)
+                {
+                    return Ok(());
+                }
+                if line.closed {
+                    line.end(opts, w, written, None)?;
+                }
+                match (opts.inline_comment_handling, opts.comment_style) {
+                    (InlineCommentHandling::ConvertToEol, _)
+                    | (InlineCommentHandling::Keep, CommentStyle::SemicolonOnly) => {
+                        line.convert_comment(inner)
+                    }
+                    (_, CommentStyle::ParenthesesOnly) => {
+                        line.push_inline_comment(parenthesize(inner))
+                    }
+                    _ => line.push_inline_comment(token),
+                }
+            }
+            Token::Comment {
+                is_inline: false,
+                inner,
+            } => {
+                if !opts.strips(token) {
+                    line.end(opts, w, written, Some(inner))?;
+                } else if !line.is_empty() {
+                    line.end(opts, w, written, None)?;
+                }
+            }
+            // The checksum's value is only meaningful for the line it was computed on,
+            // so only its presence is kept unless it is to be preserved
+            Token::Checksum(checksum) => {
+                line.checksummed = true;
+                line.given_checksum = Some(*checksum);
+                line.closed = true;
+            }
+            // Ending the line writes its checksum first, since checksums only close a line
+            Token::Newline if !line.is_empty() => line.end(opts, w, written, None)?,
+            Token::Newline if line_broken => {}
+            Token::BlankLine | Token::Newline => {
+                if !line.is_empty() {
+                    line.end(opts, w, written, None)?;
+                }
+                if opts.preserve_blank_lines {
+                    line.has_fields = opts.number_blank_lines && opts.line_numbers;
+                    line.end(opts, w, written, None)?;
+                }
+            }
+            Token::Percent => {
+                if opts.delimit_with_percent {
+                    return Ok(());
+                }
+                if !line.is_empty() {
+                    line.end(opts, w, written, None)?;
+                }
+                w.write_char('%')?;
+                if more {
+                    w.write_char('\n')?;
+                }
+                self.record(1, 0, false);
+            }
+            // Spacing is up to the formatter
+            Token::Whitespace(_) => {}
+            Token::Raw(text) => {
+                if !line.is_empty() {
+                    line.end(opts, w, written, None)?;
+                }
+                w.write_str(text)?;
+                w.write_char('\n')?;
+                let stats = LineStats {
+                    bytes: text.len(),
+                    fields: 0,
+                    has_comment: false,
+                };
+                record(written, stats, None, Some(index));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the last line and anything that comes after it.
+    pub(crate) fn finish<W: Write>(&mut self, w: &mut W) -> fmt::Result {
+        if !self.line.is_empty() {
+            self.line.end(&self.opts, w, &mut self.written, None)?;
+        }
+        if self.opts.delimit_with_percent {
+            w.write_char('%')?;
+            self.record(1, 0, false);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, bytes: usize, fields: usize, has_comment: bool) {
+        let stats = LineStats {
+            bytes,
+            fields,
+            has_comment,
+        };
+        record(&mut self.written, stats, None, None);
+    }
+}
+
+fn record(
+    written: &mut Option<VecDeque<WrittenLine>>,
+    stats: LineStats,
+    number: Option<u64>,
+    first_token: Option<usize>,
+) {
+    if let Some(written) = written {
+        written.push_back(WrittenLine {
+            stats,
+            number,
+            first_token,
+        });
+    }
+}
+
+/// The line currently being laid out.
+///
+/// Lines are buffered so that the line number and checksum can be decided
+/// once everything on the line is known.
+#[derive(Default)]
+struct LineState {
+    number: u64,
+    content: String,
+    /// Inline comments waiting to be merged into the end-of-line comment, separated by spaces
+    converted_comments: String,
+    /// The bytes of the inline comments in [LineState::content], XORed together
+    inline_comment_checksum: u8,
+    has_fields: bool,
+    /// Fields and flags pushed onto the line
+    fields: usize,
+    only_line_number: bool,
+    /// The last field on the line was an `M` command
+    after_m_command: bool,
+    /// A [Token::Checksum] was seen for this line
+    checksummed: bool,
+    /// The value of that [Token::Checksum]
+    given_checksum: Option<u8>,
+    /// The value of an `N` field at the start of the line
+    given_number: Option<u64>,
+    /// Position of the token that put the first field or command on the line
+    first_token: Option<usize>,
+    /// Nothing but an end-of-line comment can be added to this line
+    closed: bool,
+}
+
+impl LineState {
+    fn is_empty(&self) -> bool {
+        self.content.is_empty() && self.converted_comments.is_empty() && !self.checksummed
+    }
+
+    fn push(&mut self, token: impl fmt::Display) {
+        if !self.content.is_empty() {
+            self.content.push(' ');
+        }
+        // Writing to a String cannot fail
+        let _ = write!(self.content, "{}", token);
+    }
+
+    /// Push an inline comment, remembering its bytes so that they can be left out of the checksum.
+    fn push_inline_comment(&mut self, comment: impl fmt::Display) {
+        let start = match self.content.len() {
+            0 => 0,
+            len => len + 1,
+        };
+        self.push(comment);
+        self.inline_comment_checksum = self.content[start..]
+            .bytes()
+            .fold(self.inline_comment_checksum, |acc, b| acc ^ b);
+    }
+
+    fn convert_comment(&mut self, comment: &str) {
+        if !self.converted_comments.is_empty() {
+            self.converted_comments.push(' ');
+        }
+        self.converted_comments.push_str(comment);
+    }
+
+    /// Write out the line with its end-of-line comment, if any,
+    /// recording the stats of each line written.
+    ///
+    /// This is more than one line if the end-of-line comment is moved onto its own.
+    fn end<W: Write>(
+        &mut self,
+        opts: &FormatOptions,
+        w: &mut W,
+        written: &mut Option<VecDeque<WrittenLine>>,
+        eol_comment: Option<&str>,
+    ) -> fmt::Result {
+        let converted = std::mem::take(&mut self.converted_comments);
+        let comment = match (converted.is_empty(), eol_comment) {
+            (true, comment) => comment.map(Cow::Borrowed),
+            (false, None) => Some(Cow::Owned(converted)),
+            (false, Some(comment)) => Some(Cow::Owned(format!("{} {}", converted, comment))),
+        };
+        let has_code = !self.content.is_empty() || self.has_fields && opts.line_numbers;
+        let wrote_checksum = match opts.checksum_policy {
+            ChecksumPolicy::Always => self.has_fields && opts.checksums || self.checksummed,
+            ChecksumPolicy::Never => false,
+            ChecksumPolicy::PreserveValid => self.has_fields || self.checksummed,
+        };
+        let own_line = opts.newline_before_comment && (has_code || wrote_checksum);
+        let mut has_comment = false;
+        let comment = match comment {
+            Some(comment) if opts.comment_style == CommentStyle::ParenthesesOnly && !own_line => {
+                self.push_inline_comment(parenthesize(&comment));
+                has_comment = true;
+                None
+            }
+            comment => comment,
+        };
+        let mut w = Counter { inner: w, bytes: 0 };
+        let mut fields = self.fields;
+        let mut checksum = 0u8;
+        // The classic checksum of everything written before the `*`
+        let mut written_checksum = 0u8;
+        let mut number = self.given_number;
+        let mut first_token = self.first_token;
+        if self.has_fields && opts.line_numbers {
+            self.number += 1;
+            number = Some(self.number);
+            fields += 1;
+            let prefix = format!("N{}", self.number);
+            checksum = prefix.bytes().fold(checksum, |acc, b| acc ^ b);
+            written_checksum = checksum;
+            w.write_str(&prefix)?;
+            if !self.content.is_empty() {
+                if opts.checksum_style != ChecksumStyle::ExcludeSpaceAfterLineNumber {
+                    checksum ^= b' ';
+                }
+                written_checksum ^= b' ';
+                w.write_char(' ')?;
+            }
+        }
+        checksum = self.content.bytes().fold(checksum, |acc, b| acc ^ b);
+        written_checksum = self
+            .content
+            .bytes()
+            .fold(written_checksum, |acc, b| acc ^ b);
+        w.write_str(&self.content)?;
+        if wrote_checksum {
+            match opts.checksum_style {
+                ChecksumStyle::IncludeAsterisk => checksum ^= b'*',
+                ChecksumStyle::ExcludeInlineComments => checksum ^= self.inline_comment_checksum,
+                ChecksumStyle::Classic | ChecksumStyle::ExcludeSpaceAfterLineNumber => {}
+            }
+            // A given checksum was computed from the line as it was read, so it is only kept
+            // if the line is written the same way, rather than with a value normalized like `X1.50`
+            if opts.checksum_policy == ChecksumPolicy::PreserveValid
+                && self.given_checksum == Some(written_checksum)
+            {
+                checksum = written_checksum;
+            }
+            write!(w, "*{}", checksum)?;
+        }
+        if let Some(comment) = comment {
+            if own_line {
+                let stats = LineStats {
+                    bytes: w.bytes,
+                    fields,
+                    has_comment: false,
+                };
+                record(written, stats, number.take(), first_token.take());
+                w.write_char('\n')?;
+                w.bytes = 0;
+                fields = 0;
+            } else if !self.content.is_empty() && !wrote_checksum {
+                // Whitespace is not allowed between a checksum and a comment
+                w.write_char(' ')?;
+            }
+            if opts.comment_style == CommentStyle::ParenthesesOnly {
+                w.write_str(&parenthesize(&comment))?;
+            } else {
+                write!(w, ";{}", comment)?;
+            }
+            has_comment = true;
+        }
+        let stats = LineStats {
+            bytes: w.bytes,
+            fields,
+            has_comment,
+        };
+        w.write_char('\n')?;
+        record(written, stats, number, first_token);
+
+        self.content.clear();
+        self.inline_comment_checksum = 0;
+        self.has_fields = false;
+        self.fields = 0;
+        self.only_line_number = false;
+        self.after_m_command = false;
+        self.checksummed = false;
+        self.given_checksum = None;
+        self.given_number = None;
+        self.first_token = None;
+        self.closed = false;
+        Ok(())
+    }
+}
+
+/// Counts the bytes and lines written to it, dropping the output.
+#[derive(Default)]
+struct Tally {
+    bytes: usize,
+    newlines: usize,
+    /// Something has been written since the last newline
+    in_line: bool,
+}
+
+impl Write for Tally {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes += s.len();
+        if let Some(last) = s.rfind('\n') {
+            self.newlines += s.matches('\n').count();
+            self.in_line = last + 1 < s.len();
+        } else if !s.is_empty() {
+            self.in_line = true;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the bytes written through it since the last line break.
+struct Counter<'w, W> {
+    inner: &'w mut W,
+    bytes: usize,
+}
+
+impl<W: Write> Write for Counter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes += s.len();
+        self.inner.write_str(s)
+    }
+}
+
+/// Forwards [fmt::Write] calls to an [io::Write], holding on to the
+/// [io::Error] that [fmt::Error] cannot carry.
+struct IoAdapter<W> {
+    inner: W,
+    error: io::Result<()>,
+}
+
+impl<W: io::Write> Write for IoAdapter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Err(e);
+            fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use pretty_assertions::assert_eq;
+
+    fn format(tokens: &[Token], opts: FormatOptions) -> String {
+        let mut acc = String::new();
+        format_gcode_fmt(tokens, opts, &mut acc).unwrap();
+        acc
+    }
+
+    #[test]
+    fn blank_lines_round_trip_when_preserved() {
+        let gcode = include_str!("../../tests/blank_lines.gcode");
+        let tokens = file_parser(gcode)
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            format(
+                &tokens,
+                FormatOptions {
+                    preserve_blank_lines: true,
+                    ..Default::default()
+                }
+            ),
+            gcode
+        );
+    }
+
+    #[test]
+    fn blank_lines_are_dropped_by_default() {
+        let gcode = include_str!("../../tests/blank_lines.gcode");
+        let tokens = file_parser(gcode)
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            format(&tokens, FormatOptions::default()),
+            gcode.replace("\n\n", "\n")
+        );
+    }
+
+    #[test]
+    fn blank_lines_are_skipped_by_line_numbering() {
+        let tokens = file_parser("G0 X1\n\nG0 X2")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let opts = FormatOptions {
+            line_numbers: true,
+            preserve_blank_lines: true,
+            ..Default::default()
+        };
+        assert_eq!(format(&tokens, opts), "N1 G0 X1\n\nN2 G0 X2\n");
+        assert_eq!(
+            format(
+                &tokens,
+                FormatOptions {
+                    number_blank_lines: true,
+                    ..opts
+                }
+            ),
+            "N1 G0 X1\nN2\nN3 G0 X2\n"
+        );
+    }
+
+    #[test]
+    fn only_executable_lines_are_numbered() {
+        let file = file_parser("; start\nG0 X1\n(wipe)\n\n; layer 2\nG0 X2\n\nM2").unwrap();
+        let tokens = file.iter_emit_tokens().collect::<Vec<_>>();
+        let opts = FormatOptions {
+            line_numbers: true,
+            preserve_blank_lines: true,
+            ..Default::default()
+        };
+        let skipped = format(&tokens, opts);
+        assert_eq!(
+            skipped,
+            "; start\nN1 G0 X1\n(wipe)\n\n; layer 2\nN2 G0 X2\n\nN3 M2\n"
+        );
+        let numbers = file_parser(&skipped)
+            .unwrap()
+            .iter()
+            .filter_map(|line| line.line_number())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            numbers,
+            (1..=file.iter_executable().count() as u64).collect::<Vec<_>>()
+        );
+        // The inline comment on a line of its own stays there, without a number
+        let wipe = skipped.lines().find(|line| line.contains("(wipe)"));
+        assert_eq!(wipe, Some("(wipe)"));
+
+        // Blank lines can be numbered too, but comment-only lines never are
+        assert_eq!(
+            format(
+                &tokens,
+                FormatOptions {
+                    number_blank_lines: true,
+                    ..opts
+                }
+            ),
+            "; start\nN1 G0 X1\n(wipe)\nN2\n; layer 2\nN3 G0 X2\nN4\nN5 M2\n"
+        );
+    }
+
+    #[test]
+    fn contradictory_options_are_refused() {
+        let never = FormatOptions::builder().checksum_policy(ChecksumPolicy::Never);
+        assert_eq!(
+            never.checksums(true).build(),
+            Err(OptionsError::ChecksumsNeverWritten)
+        );
+        assert_eq!(
+            never.checksum_style(ChecksumStyle::IncludeAsterisk).build(),
+            Err(OptionsError::ChecksumStyleUnused)
+        );
+        assert_eq!(
+            FormatOptions::builder()
+                .checksum_policy(ChecksumPolicy::PreserveValid)
+                .line_numbers(true)
+                .build(),
+            Err(OptionsError::PreservedChecksumsRenumbered)
+        );
+
+        let opts = FormatOptions::builder()
+            .checksums(true)
+            .line_numbers(true)
+            .checksum_style(ChecksumStyle::ExcludeSpaceAfterLineNumber)
+            .strip_comments(Some(CommentPreservePolicy::Nothing))
+            .build()
+            .unwrap();
+        assert_eq!(
+            opts,
+            FormatOptions {
+                checksums: true,
+                line_numbers: true,
+                checksum_style: ChecksumStyle::ExcludeSpaceAfterLineNumber,
+                strip_comments: Some(CommentPreservePolicy::Nothing),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "ChecksumsNeverWritten")]
+    fn formatting_with_contradictory_options_panics_in_debug_builds() {
+        let opts = FormatOptions {
+            checksums: true,
+            checksum_policy: ChecksumPolicy::Never,
+            ..Default::default()
+        };
+        format(&[], opts);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn deserialized_options_are_validated() {
+        let opts: FormatOptions = serde_json::from_str(
+            r#"{"checksums": true, "checksum_style": "include_asterisk", "strip_comments": "action_commands"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            opts,
+            FormatOptions {
+                checksums: true,
+                checksum_style: ChecksumStyle::IncludeAsterisk,
+                strip_comments: Some(CommentPreservePolicy::ActionCommands),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<FormatOptions>(&serde_json::to_string(&opts).unwrap()).unwrap(),
+            opts
+        );
+
+        let err = serde_json::from_str::<FormatOptions>(
+            r#"{"line_numbers": true, "checksum_policy": "preserve_valid"}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "preserved checksums would no longer match once line numbers are rewritten; \
+             use the `always` checksum policy with line numbers"
+        );
+        let err = serde_json::from_str::<FormatOptions>(r#"{"checksum": true}"#).unwrap_err();
+        assert!(
+            err.to_string().starts_with("unknown field `checksum`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn formatted_checksums_are_valid() {
+        let gcode = include_str!("../../tests/blank_lines.gcode");
+        let tokens = file_parser(gcode)
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let formatted = format(
+            &tokens,
+            FormatOptions {
+                checksums: true,
+                line_numbers: true,
+                delimit_with_percent: true,
+                preserve_blank_lines: true,
+                number_blank_lines: true,
+                ..Default::default()
+            },
+        );
+        let reparsed = file_parser(&formatted).unwrap();
+        let mut checksummed_lines = 0;
+        for line in reparsed.iter() {
+            if let Some(validation) = line.validate_checksum() {
+                assert_eq!(validation, Ok(()));
+                checksummed_lines += 1;
+            }
+        }
+        assert_eq!(checksummed_lines, 10);
+    }
+
+    #[test]
+    fn checksum_style_changes_checksummed_bytes() {
+        let xor = |text: &str| text.bytes().fold(0u8, |acc, b| acc ^ b);
+        let tokens = file_parser("G28")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let opts = FormatOptions {
+            checksums: true,
+            line_numbers: true,
+            ..Default::default()
+        };
+        // Checked against the text with the bytes each style leaves out or puts in
+        let styles = [
+            (ChecksumStyle::Classic, xor("N1 G28")),
+            (ChecksumStyle::ExcludeSpaceAfterLineNumber, xor("N1G28")),
+            (ChecksumStyle::IncludeAsterisk, xor("N1 G28*")),
+        ];
+        for (checksum_style, checksum) in styles.iter() {
+            assert_eq!(
+                format(
+                    &tokens,
+                    FormatOptions {
+                        checksum_style: *checksum_style,
+                        ..opts
+                    }
+                ),
+                format!("N1 G28*{}\n", checksum)
+            );
+        }
+    }
+
+    #[test]
+    fn checksums_can_leave_out_inline_comments() {
+        let tokens = file_parser("G28 (home) X0")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let opts = FormatOptions {
+            checksums: true,
+            line_numbers: true,
+            ..Default::default()
+        };
+        let xor = |text: &str| text.bytes().fold(0u8, |acc, b| acc ^ b);
+        assert_eq!((xor("N1 G28 (home) X0"), xor("N1 G28  X0")), (116, 122));
+        assert_eq!(format(&tokens, opts), "N1 G28 (home) X0*116\n");
+        let gcode = format(
+            &tokens,
+            FormatOptions {
+                checksum_style: ChecksumStyle::ExcludeInlineComments,
+                ..opts
+            },
+        );
+        assert_eq!(gcode, "N1 G28 (home) X0*122\n");
+        let line = file_parser(&gcode).unwrap().iter().next().cloned().unwrap();
+        assert_eq!(
+            line.validate_checksum_with(ChecksumStyle::ExcludeInlineComments),
+            Some(Ok(()))
+        );
+    }
+
+    #[test]
+    fn value_formatters_change_numbers_and_their_checksums() {
+        let gcode = "G1 X0.5 Y-0.25 Z10 F1500.\nG4 P0.33333 X-0.0001";
+        let file = file_parser(gcode).unwrap();
+        let tokens = file.iter_emit_tokens().collect::<Vec<_>>();
+        let opts = FormatOptions {
+            checksums: true,
+            ..Default::default()
+        };
+        let cases: [(&dyn ValueFormatter, &str, f64); 3] = [
+            (
+                &DisplayValues,
+                "G1 X0.5 Y-0.25 Z10 F1500*113\nG4 P0.33333 X-0.0001*100\n",
+                0.,
+            ),
+            (
+                &FixedDecimals(3),
+                "G1 X0.500 Y-0.250 Z10 F1500.000*95\nG4 P0.333 X0.000*120\n",
+                0.0005,
+            ),
+            (
+                &Shortest,
+                "G1 X.5 Y-.25 Z10 F1500*113\nG4 P.33333 X-.0001*100\n",
+                0.,
+            ),
+        ];
+        for (formatter, expected, tolerance) in cases.iter() {
+            let mut output = String::new();
+            format_gcode_fmt_with(&tokens, opts, *formatter, &mut output).unwrap();
+            assert_eq!(&output, expected);
+
+            let reparsed = file_parser(&output).unwrap();
+            for (original, line) in file.iter().zip(reparsed.iter()) {
+                assert_eq!(line.validate_checksum(), Some(Ok(())));
+                for (a, b) in original.iter_fields().zip(line.iter_fields()) {
+                    let (a, b) = (Value::from(&a.value), Value::from(&b.value));
+                    assert!(
+                        (a.as_f64().unwrap() - b.as_f64().unwrap()).abs() <= *tolerance,
+                        "{} and {} differ",
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+        let mut io_output = vec![];
+        format_gcode_io_with(&tokens, opts, &Shortest, &mut io_output).unwrap();
+        assert_eq!(io_output, cases[2].1.as_bytes());
+    }
+
+    #[test]
+    fn checksum_token_terminates_its_line() {
+        let tokens = file_parser("X1*0\nY2")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        assert_eq!(tokens[1], Token::Checksum(0));
+        assert_eq!(format(&tokens, FormatOptions::default()), "X1*105\nY2\n");
+    }
+
+    #[test]
+    fn verbatim_tokens_format_like_emit_tokens() {
+        for gcode in [
+            include_str!("../../tests/blank_lines.gcode"),
+            include_str!("../../tests/square.gcode"),
+        ] {
+            let file = file_parser(gcode).unwrap();
+            let tokens = file.iter_emit_tokens().collect::<Vec<_>>();
+            let verbatim = file.iter_verbatim_tokens().collect::<Vec<_>>();
+            assert!(verbatim.contains(&Token::Newline));
+            for preserve_blank_lines in [false, true] {
+                let opts = FormatOptions {
+                    preserve_blank_lines,
+                    checksums: true,
+                    line_numbers: true,
+                    ..Default::default()
+                };
+                assert_eq!(format(&verbatim, opts), format(&tokens, opts));
+            }
+        }
+    }
+
+    #[test]
+    fn explicit_newlines_end_lines_after_their_checksum() {
+        let field =
+            |letters, value| Token::Field(Field::new(letters, Value::Integer(value)).unwrap());
+        let tokens = [
+            field("G", 1),
+            Token::Whitespace(" ".into()),
+            field("X", 1),
+            Token::Whitespace("  ".into()),
+            Token::Newline,
+            field("Y", 2),
+            Token::Checksum(0),
+            Token::Newline,
+            Token::Newline,
+            Token::Comment {
+                is_inline: false,
+                inner: "done".into(),
+            },
+            Token::Newline,
+            field("M", 2),
+        ];
+        let opts = FormatOptions {
+            checksums: true,
+            line_numbers: true,
+            ..Default::default()
+        };
+        let formatted = format(&tokens, opts);
+        assert_eq!(formatted, "N1 G1 X1*96\nN2 Y2*55\n;done\nN3 M2*34\n");
+        for line in file_parser(&formatted).unwrap().iter() {
+            if let Some(validation) = line.validate_checksum() {
+                assert_eq!(validation, Ok(()));
+            }
+        }
+        assert_eq!(
+            format(
+                &tokens,
+                FormatOptions {
+                    preserve_blank_lines: true,
+                    ..opts
+                }
+            ),
+            "N1 G1 X1*96\nN2 Y2*55\n\n;done\nN3 M2*34\n"
+        );
+    }
+
+    #[test]
+    fn raw_lines_pass_through_untouched() {
+        use crate::emit::{Field, Value};
+
+        let source =
+            "N1 G28*18;home\r\nn2 g1 x1.000 y2. f1500*99\nG1X10Y10(tight)\nN3 M104 S200*97\n\nM107";
+        let file = file_parser(source).unwrap();
+        let mut tokens = vec![];
+        for (i, line) in file.iter().enumerate() {
+            if i == 3 {
+                let field = |letters, value| {
+                    Token::Field(Field::new(letters, Value::Integer(value)).unwrap())
+                };
+                tokens.extend([field("M", 104), field("S", 210)]);
+            } else {
+                tokens.push(line.as_raw_token(source).unwrap());
+            }
+        }
+        let opts = FormatOptions {
+            checksums: true,
+            ..Default::default()
+        };
+        let written = format(&tokens, opts);
+        let original = source.lines().map(|line| line.trim_end_matches('\r'));
+        let written_lines = written.lines().collect::<Vec<_>>();
+        assert_eq!(written_lines.len(), 6);
+        for (i, (written, original)) in written_lines.iter().zip(original).enumerate() {
+            if i == 3 {
+                assert_eq!(*written, "M104 S210*56");
+            } else {
+                assert_eq!(written, &original);
+            }
+        }
+        assert!(written.ends_with("M107\n"));
+
+        // A line from a longer source has no text in a shorter one
+        let last = file.iter().last().unwrap();
+        assert_eq!(last.as_raw_token(&source[..10]), None);
+
+        assert_eq!(Token::raw("G1 X1*0"), Ok(Token::Raw("G1 X1*0".into())));
+        assert!(Token::raw("G1\nG2").is_err());
+        assert!(Token::raw("G1\r").is_err());
+    }
+
+    #[test]
+    fn eol_comment_can_be_moved_to_its_own_line() {
+        let tokens = file_parser("G0 X1 ;move")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        assert_eq!(format(&tokens, FormatOptions::default()), "G0 X1 ;move\n");
+        assert_eq!(
+            format(
+                &tokens,
+                FormatOptions {
+                    newline_before_comment: true,
+                    ..Default::default()
+                }
+            ),
+            "G0 X1\n;move\n"
+        );
+    }
+
+    #[test]
+    fn inline_comments_can_be_converted_or_stripped() {
+        let gcode = "G0 X1 (rapid) Y2 (fast);move\nG1 X2 (cut)\n(header)\nG4 P1";
+        let parsed = file_parser(gcode).unwrap();
+        let tokens = parsed.iter_emit_tokens().collect::<Vec<_>>();
+        let with = |inline_comment_handling, newline_before_comment| FormatOptions {
+            inline_comment_handling,
+            newline_before_comment,
+            ..Default::default()
+        };
+        assert_eq!(
+            format(&tokens, with(InlineCommentHandling::Strip, false)),
+            "G0 X1 Y2 ;move\nG1 X2\nG4 P1\n"
+        );
+        assert_eq!(
+            format(&tokens, with(InlineCommentHandling::ConvertToEol, false)),
+            "G0 X1 Y2 ;rapid fast move\nG1 X2 ;cut\n;header\nG4 P1\n"
+        );
+        assert_eq!(
+            format(&tokens, with(InlineCommentHandling::ConvertToEol, true)),
+            "G0 X1 Y2\n;rapid fast move\nG1 X2\n;cut\n;header\nG4 P1\n"
+        );
+
+        let opts = FormatOptions {
+            checksums: true,
+            line_numbers: true,
+            ..with(InlineCommentHandling::ConvertToEol, false)
+        };
+        let reparsed_gcode = format(&tokens, opts);
+        let reparsed = file_parser(&reparsed_gcode).unwrap();
+        assert!(parsed
+            .iter_fields()
+            .map(|f| Field::from(f).to_string())
+            .eq(reparsed
+                .iter_fields()
+                .filter(|f| f.letters != "N")
+                .map(|f| Field::from(f).to_string())));
+        assert_eq!(reparsed.iter().count(), 4);
+        for line in reparsed.iter() {
+            // The comment-only line keeps to itself, so it has nothing to checksum
+            if line.is_executable() {
+                assert_eq!(line.validate_checksum(), Some(Ok(())));
+            } else {
+                assert_eq!(line.validate_checksum(), None);
+            }
+            assert!(!line.iter_emit_tokens().any(|t| matches!(
+                t,
+                Token::Comment {
+                    is_inline: true,
+                    ..
+                }
+            )));
+        }
+    }
+
+    #[test]
+    fn stripped_comments_keep_markers_and_action_commands() {
+        let gcode = include_str!("../../tests/cura_sample.gcode");
+        let file = file_parser(gcode).unwrap();
+        let strip = |policy| FormatOptions {
+            strip_comments: Some(policy),
+            ..Default::default()
+        };
+        // Verbatim tokens keep comments that had a line of their own from joining the line before
+        let verbatim = file.iter_verbatim_tokens().collect::<Vec<_>>();
+        assert_eq!(
+            format(&verbatim, strip(CommentPreservePolicy::ActionCommandsAndMarkers)),
+            ";FLAVOR:Marlin\n;TIME:1234\nM140 S60\nM104 S200\nM109 S200\nT0\nG28\nG92 E0\n\
+             ;LAYER_COUNT:2\n;LAYER:0\nM107\n;MESH:cube.stl\nG0 F6000 X10 Y10 Z0.2\n\
+             ;TYPE:WALL-OUTER\nG1 F1500 X20 Y10 E0.33\nG1 X20 Y20 E0.66\n;TYPE:FILL\nG1 X10 Y20 E1\n\
+             ;@pause\n;LAYER:1\nG0 X10 Y10 Z0.4\n; @resume\n;TIME_ELAPSED:30.5\nM140 S0\n"
+        );
+        assert_eq!(
+            format(&verbatim, strip(CommentPreservePolicy::ActionCommands)),
+            "M140 S60\nM104 S200\nM109 S200\nT0\nG28\nG92 E0\nM107\nG0 F6000 X10 Y10 Z0.2\n\
+             G1 F1500 X20 Y10 E0.33\nG1 X20 Y20 E0.66\nG1 X10 Y20 E1\n;@pause\nG0 X10 Y10 Z0.4\n\
+             ; @resume\nM140 S0\n"
+        );
+
+        // Lines that only had a comment leave nothing behind
+        let tokens = file.iter_emit_tokens().collect::<Vec<_>>();
+        let opts = FormatOptions {
+            preserve_blank_lines: true,
+            ..strip(CommentPreservePolicy::Nothing)
+        };
+        let stripped = format(&verbatim, opts);
+        assert_eq!(stripped, format(&tokens, opts));
+        assert!(!stripped.contains(';'));
+        assert!(!stripped.contains("\n\n"));
+    }
+
+    #[test]
+    fn percent_delimiters_are_inherited_from_the_source() {
+        let gcode = include_str!("../../tests/square.gcode");
+        let parsed = file_parser(gcode).unwrap();
+        assert!(parsed.has_percent_delimiters());
+        let tokens = parsed.iter_emit_tokens().collect::<Vec<_>>();
+
+        let formatted = format(&tokens, FormatOptions::default());
+        assert_eq!(
+            formatted,
+            "%\nG21 (millimeters)\nG90\nG0 X0 Y0 ;start\nN10 G1 X10 Y0 F300*124\nG1 X10 Y10.5\nG1 X0 Y10.5*93\nG1 X0 Y0\nM2\n%"
+        );
+        assert!(file_parser(&formatted).unwrap().has_percent_delimiters());
+
+        let delimited = FormatOptions {
+            delimit_with_percent: true,
+            ..Default::default()
+        };
+        assert_eq!(format(&tokens, delimited), formatted);
+
+        let undelimited = file_parser("G1 X1\n").unwrap();
+        assert!(!undelimited.has_percent_delimiters());
+        let tokens = undelimited.iter_emit_tokens().collect::<Vec<_>>();
+        assert_eq!(format(&tokens, FormatOptions::default()), "G1 X1\n");
+    }
+
+    #[test]
+    fn flags_stay_on_their_line_and_are_checksummed() {
+        use crate::emit::{Flag, Value};
+
+        let field = |letters, value| Token::Field(Field::new(letters, value).unwrap());
+        let flag = |letters| Token::Flag(Flag::new(letters).unwrap());
+        let tokens = vec![
+            field("G", Value::Integer(28)),
+            flag("X"),
+            flag("Y"),
+            field("M", Value::Integer(6)),
+            flag("T"),
+            field("G", Value::Integer(1)),
+            field("X", Value::Integer(1)),
+        ];
+        assert_eq!(
+            format(&tokens, FormatOptions::default()),
+            "G28 X Y\nM6 T\nG1 X1\n"
+        );
+
+        let numbered = format(
+            &tokens,
+            FormatOptions {
+                checksums: true,
+                line_numbers: true,
+                ..Default::default()
+            },
+        );
+        let first_line = numbered.lines().next().unwrap();
+        let (body, checksum) = first_line.split_at(first_line.find('*').unwrap());
+        assert_eq!(body, "N1 G28 X Y");
+        assert_eq!(
+            checksum[1..].parse::<u8>().unwrap(),
+            body.bytes().fold(0, |acc, b| acc ^ b)
+        );
+
+        let reparsed = crate::parse::file_parser(&numbered).unwrap();
+        let first = reparsed.iter().next().unwrap();
+        assert_eq!(
+            first.iter_flags().map(|f| f.letters()).collect::<Vec<_>>(),
+            vec!["X", "Y"]
+        );
+        assert_eq!(first.validate_checksum(), Some(Ok(())));
+        let plain = format(&tokens, FormatOptions::default());
+        let reformatted = format(
+            &crate::parse::file_parser(&plain)
+                .unwrap()
+                .iter_emit_tokens()
+                .collect::<Vec<_>>(),
+            FormatOptions::default(),
+        );
+        assert_eq!(reformatted, plain);
+
+        assert!(Flag::new("G").is_err());
+        assert!(Flag::new("n").is_err());
+        assert!(Flag::new("X1").is_err());
+    }
+
+    #[test]
+    fn tool_numbers_after_m_commands_are_arguments() {
+        let tokens = file_parser("M104 T0 S200\nT1\nG1 X1\nT2\nM6 T3\nM104 S200\nT0")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            format(&tokens, FormatOptions::default()),
+            "M104 T0 S200\nT1\nG1 X1\nT2\nM6 T3\nM104 S200\nT0\n"
+        );
+    }
+
+    #[test]
+    fn io_and_fmt_output_are_identical() {
+        let tokens = file_parser(include_str!("../../tests/vandy_commodores_logo.gcode"))
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let opts = FormatOptions {
+            checksums: true,
+            line_numbers: true,
+            ..Default::default()
+        };
+        let mut io_output = vec![];
+        format_gcode_io(&tokens, opts, &mut io_output).unwrap();
+        assert_eq!(String::from_utf8(io_output).unwrap(), format(&tokens, opts));
+    }
+
+    #[test]
+    fn stats_agree_with_formatted_output() {
+        for src in [
+            include_str!("../../tests/vandy_commodores_logo.gcode"),
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/blank_lines.gcode"),
+            include_str!("../../tests/bom_crlf.gcode"),
+            "%\nN1 G28*18;home\nG1 X1 (inline) ;eol\n%",
+            "",
+        ]
+        .iter()
+        {
+            let file = file_parser(src).unwrap();
+            for bits in 0..128u8 {
+                let opts = FormatOptions {
+                    checksums: bits & 1 != 0,
+                    line_numbers: bits & 2 != 0,
+                    delimit_with_percent: bits & 4 != 0,
+                    newline_before_comment: bits & 8 != 0,
+                    preserve_blank_lines: bits & 16 != 0,
+                    number_blank_lines: bits & 32 != 0,
+                    byte_order_mark: bits & 64 != 0,
+                    ..Default::default()
+                };
+                let mut formatted = String::new();
+                format_gcode_fmt(file.iter_emit_tokens(), opts, &mut formatted).unwrap();
+                let last_line_number = formatted
+                    .lines()
+                    .rev()
+                    .find_map(|line| {
+                        let digits = line.trim_start_matches('\u{feff}').strip_prefix('N')?;
+                        let end = digits
+                            .find(|c: char| !c.is_ascii_digit())
+                            .unwrap_or(digits.len());
+                        digits[..end].parse().ok()
+                    })
+                    .filter(|_| opts.line_numbers);
+                assert_eq!(
+                    format_stats(file.iter_emit_tokens(), opts),
+                    FormatStats {
+                        lines: formatted.lines().count(),
+                        bytes: formatted.len(),
+                        last_line_number,
+                    },
+                    "{:?}",
+                    opts
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn index_offsets_point_at_line_starts() {
+        for src in [
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/blank_lines.gcode"),
+            include_str!("../../tests/bom_crlf.gcode"),
+            include_str!("../../tests/corrupted_checksum.gcode"),
+            "%\nN1 G28*18;home\nG1 X1 (inline) ;eol\n%",
+            "",
+        ]
+        .iter()
+        {
+            let tokens = file_parser(src)
+                .unwrap()
+                .iter_emit_tokens()
+                .collect::<Vec<_>>();
+            for bits in 0..64u8 {
+                let opts = FormatOptions {
+                    checksums: bits & 1 != 0,
+                    line_numbers: bits & 2 != 0,
+                    delimit_with_percent: bits & 4 != 0,
+                    newline_before_comment: bits & 8 != 0,
+                    preserve_blank_lines: bits & 16 != 0,
+                    byte_order_mark: bits & 32 != 0,
+                    number_blank_lines: true,
+                    ..Default::default()
+                };
+                let mut output = vec![];
+                let index = format_gcode_io_indexed(&tokens, opts, &mut output).unwrap();
+                let formatted = String::from_utf8(output).unwrap();
+                assert_eq!(formatted, format(&tokens, opts));
+
+                let bom = if opts.byte_order_mark { 3 } else { 0 };
+                let line_starts = formatted[bom..]
+                    .lines()
+                    .scan(bom, |offset, line| {
+                        let start = *offset;
+                        *offset += line.len() + 1;
+                        Some(start)
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    index.iter().map(|entry| entry.offset).collect::<Vec<_>>(),
+                    line_starts,
+                    "{:?}",
+                    opts
+                );
+
+                for entry in index.iter() {
+                    let line = formatted[entry.offset..].lines().next().unwrap_or("");
+                    if let Some(number) = entry.line_number {
+                        let field = format!("N{}", number);
+                        if opts.line_numbers {
+                            assert_eq!(line.split(&[' ', '*'][..]).next(), Some(&*field));
+                        } else {
+                            // A given line number can follow inline comments
+                            assert!(line.split(&[' ', '*'][..]).any(|word| word == field));
+                        }
+                    } else {
+                        assert!(!line.starts_with('N'), "{:?} {:?}", line, entry);
+                    }
+                    if let Some(Token::Field(field)) = entry.first_token.map(|i| &tokens[i]) {
+                        assert!(line.contains(&field.to_string()), "{:?} {:?}", line, entry);
+                    }
+                }
+                if opts.line_numbers {
+                    let numbers = index.iter().filter_map(|entry| entry.line_number);
+                    assert!(numbers
+                        .zip(1..)
+                        .all(|(number, expected)| number == expected));
+                }
+            }
+        }
+    }
+
+    /// Words of every comment in a file, and whether any comment of each kind was found.
+    fn comment_words(file: &crate::parse::ast::File) -> (Vec<String>, bool, bool) {
+        let (mut words, mut any_inline, mut any_eol) = (vec![], false, false);
+        for token in file.iter_emit_tokens() {
+            if let Token::Comment { is_inline, inner } = token {
+                any_inline |= is_inline;
+                any_eol |= !is_inline;
+                words.extend(inner.split_whitespace().map(String::from));
+            }
+        }
+        (words, any_inline, any_eol)
+    }
+
+    fn comment_only_lines(file: &crate::parse::ast::File) -> usize {
+        file.iter()
+            .filter(|line| !line.is_executable() && !line.is_empty())
+            .count()
+    }
+
+    #[test]
+    fn comments_are_converted_to_the_target_style() {
+        let tokens = file_parser("G1 X1 (fast) Y2 ;move (diagonal)\n(setup)\n;done")
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let style = |comment_style| FormatOptions {
+            comment_style,
+            ..Default::default()
+        };
+        assert_eq!(
+            format(&tokens, style(CommentStyle::SemicolonOnly)),
+            "G1 X1 Y2 ;fast move (diagonal)\n;setup\n;done\n"
+        );
+        assert_eq!(
+            format(&tokens, style(CommentStyle::ParenthesesOnly)),
+            "G1 X1 (fast) Y2 (move [diagonal])\n(setup)\n(done)\n"
+        );
+        assert_eq!(
+            format(
+                &tokens,
+                FormatOptions {
+                    checksums: true,
+                    newline_before_comment: true,
+                    ..style(CommentStyle::ParenthesesOnly)
+                }
+            ),
+            "G1 X1 (fast) Y2*85\n(move [diagonal])\n(setup)\n(done)\n"
+        );
+    }
+
+    #[test]
+    fn corpus_comments_survive_style_conversion() {
+        for src in [
+            include_str!("../../tests/vandy_commodores_logo.gcode"),
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/square.gcode"),
+            include_str!("../../tests/blank_lines.gcode"),
+        ]
+        .iter()
+        {
+            let file = file_parser(src).unwrap();
+            let (words, _, _) = comment_words(&file);
+            for bits in 0..8u8 {
+                let opts = FormatOptions {
+                    checksums: bits & 1 != 0,
+                    line_numbers: bits & 2 != 0,
+                    newline_before_comment: bits & 4 != 0,
+                    ..Default::default()
+                };
+                for &comment_style in
+                    [CommentStyle::SemicolonOnly, CommentStyle::ParenthesesOnly].iter()
+                {
+                    let mut formatted = String::new();
+                    let opts = FormatOptions {
+                        comment_style,
+                        ..opts
+                    };
+                    format_gcode_fmt(file.iter_emit_tokens(), opts, &mut formatted).unwrap();
+                    let reparsed = file_parser(&formatted).unwrap();
+                    let (reparsed_words, any_inline, any_eol) = comment_words(&reparsed);
+                    if comment_style == CommentStyle::SemicolonOnly {
+                        assert!(!any_inline, "{:?}", opts);
+                        assert_eq!(reparsed_words, words);
+                    } else {
+                        assert!(!any_eol, "{:?}", opts);
+                        let escaped = words
+                            .iter()
+                            .map(|word| word.replace('(', "[").replace(')', "]"))
+                            .collect::<Vec<_>>();
+                        assert_eq!(reparsed_words, escaped);
+                    }
+                    for line in reparsed.iter() {
+                        assert_ne!(line.validate_checksum(), Some(Err(line.compute_checksum())));
+                    }
+                    // Converting comments never joins lines, so comment-only lines stay apart
+                    if !opts.newline_before_comment {
+                        assert_eq!(
+                            comment_only_lines(&reparsed),
+                            comment_only_lines(&file),
+                            "{:?}",
+                            opts
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn valid_checksums_are_preserved_and_the_rest_recomputed() {
+        let file = file_parser(include_str!("../../tests/corrupted_checksum.gcode")).unwrap();
+        let plans = file.iter().map(|line| line.emit_plan()).collect::<Vec<_>>();
+        assert_eq!(
+            plans
+                .iter()
+                .map(|plan| plan.original_checksum)
+                .collect::<Vec<_>>(),
+            [
+                Some((18, true)),
+                Some((43, true)),
+                Some((14, false)),
+                Some((45, true)),
+                Some((32, true)),
+            ]
+        );
+        assert!(!plans[2]
+            .tokens
+            .iter()
+            .any(|token| matches!(token, Token::Checksum(_))));
+
+        let mut tokens = plans
+            .into_iter()
+            .flat_map(|plan| plan.tokens)
+            .collect::<Vec<_>>();
+        let policy = |checksum_policy| FormatOptions {
+            checksum_policy,
+            ..Default::default()
+        };
+        assert_eq!(
+            format(&tokens, policy(ChecksumPolicy::PreserveValid)),
+            "N1 G28*18\nN2 G1 X10 Y10*43\nN3 G1 X20 Y10*41\nN4 G1 X20 Y20*45;corner\nN5 M107*32\n"
+        );
+        assert_eq!(
+            format(&tokens, policy(ChecksumPolicy::Never)),
+            "N1 G28\nN2 G1 X10 Y10\nN3 G1 X20 Y10\nN4 G1 X20 Y20 ;corner\nN5 M107\n"
+        );
+
+        // A given checksum is not kept for a line that changed
+        tokens[1] = Token::Field(Field {
+            letters: "G".into(),
+            value: Value::Integer(0),
+        });
+        assert_eq!(
+            format(&tokens, policy(ChecksumPolicy::PreserveValid)),
+            "N1 G0*40\nN2 G1 X10 Y10*43\nN3 G1 X20 Y10*41\nN4 G1 X20 Y20*45;corner\nN5 M107*32\n"
+        );
+        assert_eq!(
+            format(&tokens, policy(ChecksumPolicy::Always)),
+            "N1 G0*40\nN2 G1 X10 Y10*43\nN3 G1 X20 Y10\nN4 G1 X20 Y20*45;corner\nN5 M107*32\n"
+        );
+    }
+
+    #[test]
+    fn preserved_checksums_are_recomputed_for_normalized_values() {
+        let file = file_parser("N1 G1 X1.50*75\nN2 G1 X2*96").unwrap();
+        assert!(file
+            .iter()
+            .all(|line| line.validate_checksum() == Some(Ok(()))));
+        let tokens = file
+            .iter()
+            .flat_map(|line| line.emit_plan().tokens)
+            .collect::<Vec<_>>();
+        let opts = FormatOptions {
+            checksum_policy: ChecksumPolicy::PreserveValid,
+            ..Default::default()
+        };
+        let formatted = format(&tokens, opts);
+        assert_eq!(formatted, "N1 G1 X1.5*123\nN2 G1 X2*96\n");
+        assert!(file_parser(&formatted)
+            .unwrap()
+            .iter()
+            .all(|line| line.validate_checksum() == Some(Ok(()))));
+    }
+}
@@ -0,0 +1,2204 @@
+use num::ToPrimitive;
+use num_rational::Ratio;
+use paste::paste;
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use crate::parse::token::Checksum as ParsedChecksum;
+use crate::parse::token::Comment as ParsedComment;
+use crate::parse::token::ExtendedCommand as ParsedExtendedCommand;
+use crate::parse::token::Field as ParsedField;
+use crate::parse::token::Flag as ParsedFlag;
+use crate::parse::token::InlineComment as ParsedInlineComment;
+use crate::parse::token::MetaCommand as ParsedMetaCommand;
+use crate::parse::token::Newline as ParsedNewline;
+use crate::parse::token::Value as ParsedValue;
+use crate::parse::token::Whitespace as ParsedWhitespace;
+
+/// Measuring formatted output without writing it
+pub mod analyze;
+mod comment;
+mod format;
+/// Splitting output into checksummed packets for upload protocols
+pub mod packet;
+mod path;
+mod program;
+mod resend;
+mod validate;
+pub use comment::{comment_kind, CommentKind};
+pub use format::{
+    format_gcode_fmt, format_gcode_fmt_with, format_gcode_io, format_gcode_io_indexed,
+    format_gcode_io_with, format_stats, BoolStyle, ChecksumPolicy, ChecksumStyle,
+    CommentPreservePolicy, CommentStyle, DisplayValues, FixedDecimals, FormatOptions,
+    FormatOptionsBuilder, FormatStats, InlineCommentHandling, LineIndexEntry, OptionsError,
+    Shortest, ValueFormatter,
+};
+#[cfg(feature = "lyon")]
+pub use path::from_path;
+pub use path::{from_polyline, PathToGcode};
+pub use program::{Program, ProgramError};
+pub use resend::{format_gcode_io_resendable, ResendBuffer, ResendError};
+pub use validate::{ArgError, ArgRule, Flavor, MachineLimits};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Token<'a> {
+    Field(Field<'a>),
+    Flag(Flag<'a>),
+    /// A Klipper extended command, which formatters always write on a line of its own,
+    /// without a line number or checksum.
+    ExtendedCommand(ExtendedCommand<'a>),
+    /// A RepRapFirmware meta command, which formatters write verbatim on a line of its own,
+    /// indentation included, without a line number or checksum.
+    ///
+    /// Other lines are not indented by formatters, so the block structure of a
+    /// RepRapFirmware file only survives formatting if its blocks hold nothing but meta commands.
+    MetaCommand(MetaCommand<'a>),
+    Comment {
+        is_inline: bool,
+        inner: Cow<'a, str>,
+    },
+    Checksum(u8),
+    /// An empty line, such as the ones slicers use to separate layers.
+    ///
+    /// Formatters drop these unless [FormatOptions::preserve_blank_lines] is set.
+    BlankLine,
+    /// A `%` on its own line, delimiting the start or end of a program.
+    ///
+    /// Formatters drop these when [FormatOptions::delimit_with_percent] is set,
+    /// since the program is already delimited.
+    Percent,
+    /// Whitespace as it was written, which only
+    /// [File::iter_verbatim_tokens](crate::parse::ast::File::iter_verbatim_tokens) emits.
+    ///
+    /// Formatters drop these, since they space out tokens themselves.
+    Whitespace(Cow<'a, str>),
+    /// The end of a line. [File::iter_verbatim_tokens](crate::parse::ast::File::iter_verbatim_tokens)
+    /// emits one after every line, while [File::iter_emit_tokens](crate::parse::ast::File::iter_emit_tokens)
+    /// and [Snippet::iter_emit_tokens](crate::parse::ast::Snippet::iter_emit_tokens) emit one between lines.
+    ///
+    /// Formatters end the line here, writing its checksum before the newline.
+    /// A newline that follows a token which already ended its line, like an end-of-line comment,
+    /// ends nothing more. One on an otherwise empty line is treated like a [Token::BlankLine].
+    Newline,
+    /// A whole line written exactly as it is, like one from [Line::as_raw_token](crate::parse::ast::Line::as_raw_token).
+    ///
+    /// Formatters write it on a line of its own without adding a line number or checksum,
+    /// so any it already has are kept byte for byte. It takes no number from [FormatOptions::line_numbers] either,
+    /// which only suits raw lines without line numbers of their own.
+    /// The text must not contain a newline: [Token::raw] checks this.
+    Raw(Cow<'a, str>),
+}
+
+impl<'input> From<&ParsedField<'input>> for Token<'input> {
+    fn from(field: &ParsedField<'input>) -> Self {
+        Self::Field(field.into())
+    }
+}
+
+impl<'input> From<&ParsedFlag<'input>> for Token<'input> {
+    fn from(flag: &ParsedFlag<'input>) -> Self {
+        Self::Flag(Flag {
+            letters: slice_cow(&flag.letters, 0..flag.letters.len()),
+        })
+    }
+}
+
+impl<'input> From<&ParsedComment<'input>> for Token<'input> {
+    fn from(comment: &ParsedComment<'input>) -> Self {
+        Self::Comment {
+            is_inline: false,
+            inner: slice_cow(&comment.inner, 1..comment.inner.len()),
+        }
+    }
+}
+
+impl<'input> From<&ParsedInlineComment<'input>> for Token<'input> {
+    fn from(comment: &ParsedInlineComment<'input>) -> Self {
+        Self::Comment {
+            is_inline: true,
+            inner: slice_cow(&comment.inner, 1..comment.inner.len() - 1),
+        }
+    }
+}
+
+impl<'input> From<&ParsedWhitespace<'input>> for Token<'input> {
+    fn from(whitespace: &ParsedWhitespace<'input>) -> Self {
+        Self::Whitespace(slice_cow(&whitespace.inner, 0..whitespace.inner.len()))
+    }
+}
+
+impl From<&ParsedNewline> for Token<'_> {
+    fn from(_: &ParsedNewline) -> Self {
+        Self::Newline
+    }
+}
+
+/// Checksums are a single byte, so only parsed checksums from 0 to 255 can be written.
+impl<'input> TryFrom<&ParsedChecksum> for Token<'input> {
+    type Error = ChecksumOutOfRange;
+
+    fn try_from(checksum: &ParsedChecksum) -> Result<Self, Self::Error> {
+        u8::try_from(checksum.inner)
+            .map(Self::Checksum)
+            .map_err(|_| ChecksumOutOfRange(checksum.inner))
+    }
+}
+
+/// A parsed checksum that is too large to be written, since checksums are a single byte.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChecksumOutOfRange(pub u16);
+
+impl fmt::Display for ChecksumOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checksum {} is not between 0 and 255", self.0)
+    }
+}
+
+impl std::error::Error for ChecksumOutOfRange {}
+
+impl<'a> Token<'a> {
+    /// A [Token::Raw] line, checking that the text is a single line.
+    pub fn raw(line: impl Into<Cow<'a, str>>) -> Result<Self, NewlineInRawLine> {
+        let line = line.into();
+        if line.contains(['\n', '\r']) {
+            return Err(NewlineInRawLine(line.into_owned()));
+        }
+        Ok(Self::Raw(line))
+    }
+}
+
+/// The text given for a [Token::Raw] has a newline in it, so it is more than one line.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NewlineInRawLine(pub String);
+
+impl fmt::Display for NewlineInRawLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "raw line contains a newline: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for NewlineInRawLine {}
+
+impl Token<'_> {
+    /// Detach the token from the input it borrows from.
+    pub fn into_owned(self) -> Token<'static> {
+        match self {
+            Self::Field(field) => Token::Field(field.into_owned()),
+            Self::Flag(flag) => Token::Flag(flag.into_owned()),
+            Self::ExtendedCommand(command) => Token::ExtendedCommand(command.into_owned()),
+            Self::MetaCommand(command) => Token::MetaCommand(command.into_owned()),
+            Self::Comment { is_inline, inner } => Token::Comment {
+                is_inline,
+                inner: Cow::Owned(inner.into_owned()),
+            },
+            Self::Checksum(c) => Token::Checksum(c),
+            Self::BlankLine => Token::BlankLine,
+            Self::Percent => Token::Percent,
+            Self::Whitespace(whitespace) => Token::Whitespace(Cow::Owned(whitespace.into_owned())),
+            Self::Newline => Token::Newline,
+            Self::Raw(line) => Token::Raw(Cow::Owned(line.into_owned())),
+        }
+    }
+}
+
+/// Slice a [Cow] without giving up the borrow, if there is one.
+fn slice_cow<'a>(cow: &Cow<'a, str>, range: std::ops::Range<usize>) -> Cow<'a, str> {
+    match cow {
+        Cow::Borrowed(s) => Cow::Borrowed(&s[range]),
+        Cow::Owned(s) => Cow::Owned(s[range].to_string()),
+    }
+}
+
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Token::*;
+        match self {
+            Field(field) => write!(f, "{}", field),
+            Flag(flag) => write!(f, "{}", flag),
+            MetaCommand(command) => write!(f, "{}", command),
+            ExtendedCommand(command) => write!(f, "{}", command),
+            Comment { is_inline, inner } => match is_inline {
+                true => write!(f, "({})", inner),
+                false => write!(f, ";{}", inner),
+            },
+            Checksum(c) => write!(f, "*{}", c),
+            BlankLine => Ok(()),
+            Percent => write!(f, "%"),
+            Whitespace(whitespace) => write!(f, "{}", whitespace),
+            Newline => writeln!(f),
+            Raw(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+/// Letters without a value, like the `X` and `Y` in `G28 X Y`,
+/// which some firmware takes as selecting an axis or enabling an option.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Flag<'a> {
+    pub letters: Cow<'a, str>,
+}
+
+impl<'a> Flag<'a> {
+    /// Create a flag, checking that it can be written as valid GCode.
+    ///
+    /// Letters must be non-empty and ASCII alphabetic.
+    /// `G`, `M`, and `N` are rejected, since commands and line numbers always have a value.
+    pub fn new(letters: impl Into<Cow<'a, str>>) -> Result<Self, FieldError> {
+        let letters = letters.into();
+        if letters.is_empty()
+            || !letters.bytes().all(|b| b.is_ascii_alphabetic())
+            || ["G", "M", "N"]
+                .iter()
+                .any(|l| letters.eq_ignore_ascii_case(l))
+        {
+            return Err(FieldError::InvalidLetters(letters.into_owned()));
+        }
+        Ok(Self { letters })
+    }
+
+    /// Detach the flag from the input it borrows from.
+    pub fn into_owned(self) -> Flag<'static> {
+        Flag {
+            letters: Cow::Owned(self.letters.into_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Flag<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.letters)
+    }
+}
+
+impl<'a> From<Flag<'a>> for Token<'a> {
+    fn from(flag: Flag<'a>) -> Self {
+        Self::Flag(flag)
+    }
+}
+
+/// A Klipper extended command, like `SET_PRESSURE_ADVANCE ADVANCE=0.05 EXTRUDER=extruder`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExtendedCommand<'a> {
+    pub name: Cow<'a, str>,
+    pub params: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+}
+
+impl<'a> ExtendedCommand<'a> {
+    /// Create a command without parameters, checking that the name can be parsed back.
+    ///
+    /// The name must start with an uppercase ASCII letter, be made of uppercase ASCII letters,
+    /// digits, and underscores, and contain at least one underscore.
+    pub fn new(name: impl Into<Cow<'a, str>>) -> Result<Self, FieldError> {
+        let name = name.into();
+        if !name.starts_with(|c: char| c.is_ascii_uppercase())
+            || !name.contains('_')
+            || !name
+                .bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+        {
+            return Err(FieldError::InvalidLetters(name.into_owned()));
+        }
+        Ok(Self {
+            name,
+            params: vec![],
+        })
+    }
+
+    /// Add a `KEY=VALUE` parameter.
+    ///
+    /// Keys must be non-empty ASCII letters, digits, and underscores.
+    /// Values must be printable ASCII without a semicolon,
+    /// and may only contain spaces if they are wrapped in quotes.
+    pub fn param(
+        mut self,
+        key: impl Into<Cow<'a, str>>,
+        value: impl Into<Cow<'a, str>>,
+    ) -> Result<Self, FieldError> {
+        let (key, value) = (key.into(), value.into());
+        if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            return Err(FieldError::InvalidLetters(key.into_owned()));
+        }
+        let quoted = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+        if !value
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b';' || quoted && b == b' ')
+        {
+            return Err(FieldError::InvalidString(value.into_owned()));
+        }
+        self.params.push((key, value));
+        Ok(self)
+    }
+
+    /// Detach the command from the input it borrows from.
+    pub fn into_owned(self) -> ExtendedCommand<'static> {
+        ExtendedCommand {
+            name: Cow::Owned(self.name.into_owned()),
+            params: self
+                .params
+                .into_iter()
+                .map(|(k, v)| (Cow::Owned(k.into_owned()), Cow::Owned(v.into_owned())))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for ExtendedCommand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (key, value) in &self.params {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'input> From<&ParsedExtendedCommand<'input>> for ExtendedCommand<'input> {
+    fn from(command: &ParsedExtendedCommand<'input>) -> Self {
+        Self {
+            name: command.name.clone(),
+            params: command
+                .params
+                .iter()
+                .map(|p| (p.key.clone(), p.value.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl<'a> From<ExtendedCommand<'a>> for Token<'a> {
+    fn from(command: ExtendedCommand<'a>) -> Self {
+        Self::ExtendedCommand(command)
+    }
+}
+
+/// A RepRapFirmware meta command, like `if move.axes[0].homed` or `set var.x = 3`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MetaCommand<'a> {
+    /// Spaces and tabs written before the keyword
+    pub indent: Cow<'a, str>,
+    pub keyword: Cow<'a, str>,
+    /// Everything written after the keyword, including the whitespace separating them
+    pub expression: Cow<'a, str>,
+}
+
+impl<'a> MetaCommand<'a> {
+    /// Create an unindented command, checking that it can be parsed back.
+    ///
+    /// The keyword must be one of [KEYWORDS](ParsedMetaCommand::KEYWORDS).
+    /// The expression, which may be empty, must be printable ASCII, with semicolons only inside
+    /// `"..."` strings, and must not start or end with whitespace.
+    pub fn new(
+        keyword: impl Into<Cow<'a, str>>,
+        expression: impl Into<Cow<'a, str>>,
+    ) -> Result<Self, FieldError> {
+        let (keyword, expression) = (keyword.into(), expression.into());
+        if !ParsedMetaCommand::KEYWORDS.contains(&keyword.as_ref()) {
+            return Err(FieldError::InvalidLetters(keyword.into_owned()));
+        }
+        let mut in_string = false;
+        let parses = expression.bytes().all(|b| {
+            in_string ^= b == b'"';
+            b == b' ' || b.is_ascii_graphic() && (in_string || b != b';')
+        }) && !in_string
+            && expression.trim() == expression;
+        if !parses {
+            return Err(FieldError::InvalidString(expression.into_owned()));
+        }
+        Ok(Self {
+            indent: Cow::Borrowed(""),
+            expression: if expression.is_empty() {
+                expression
+            } else {
+                Cow::Owned(format!(" {}", expression))
+            },
+            keyword,
+        })
+    }
+
+    /// Indent the command by this many spaces.
+    pub fn indented(mut self, spaces: usize) -> Self {
+        self.indent = Cow::Owned(" ".repeat(spaces));
+        self
+    }
+
+    /// Detach the command from the input it borrows from.
+    pub fn into_owned(self) -> MetaCommand<'static> {
+        MetaCommand {
+            indent: Cow::Owned(self.indent.into_owned()),
+            keyword: Cow::Owned(self.keyword.into_owned()),
+            expression: Cow::Owned(self.expression.into_owned()),
+        }
+    }
+}
+
+impl fmt::Display for MetaCommand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.indent, self.keyword, self.expression)
+    }
+}
+
+impl<'input> From<&ParsedMetaCommand<'input>> for MetaCommand<'input> {
+    fn from(command: &ParsedMetaCommand<'input>) -> Self {
+        Self {
+            indent: command.indent.clone(),
+            keyword: command.keyword.clone(),
+            expression: command.expression.clone(),
+        }
+    }
+}
+
+impl<'a> From<MetaCommand<'a>> for Token<'a> {
+    fn from(command: MetaCommand<'a>) -> Self {
+        Self::MetaCommand(command)
+    }
+}
+
+/// Fundamental unit of GCode: a value preceded by a descriptive letter.
+///
+/// Fields can be hashed, for sets of fields and deduplication: see the notes on hashing a [Value].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Field<'a> {
+    pub letters: Cow<'a, str>,
+    pub value: Value<'a>,
+}
+
+impl<'a> Field<'a> {
+    /// Create a field, checking that it can be written as valid GCode.
+    ///
+    /// Letters must be ASCII alphabetic, and non-empty unless the value is [Value::Text].
+    /// String values must be ASCII and must not contain a newline.
+    /// Text must not contain a `;` or `*` either, since firmware would take those as the end of it.
+    pub fn new(letters: impl Into<Cow<'a, str>>, value: Value<'a>) -> Result<Self, FieldError> {
+        let letters = letters.into();
+        let is_text = matches!(value, Value::Text(_));
+        if letters.is_empty() && !is_text || !letters.bytes().all(|b| b.is_ascii_alphabetic()) {
+            return Err(FieldError::InvalidLetters(letters.into_owned()));
+        }
+        match &value {
+            Value::String(s) if !s.is_ascii() || s.contains(['\n', '\r']) => {
+                return Err(FieldError::InvalidString(s.to_string()));
+            }
+            Value::Text(s) if !s.is_ascii() || s.contains(['\n', '\r', ';', '*']) => {
+                return Err(FieldError::InvalidText(s.to_string()));
+            }
+            _ => {}
+        }
+        Ok(Self { letters, value })
+    }
+
+    /// Detach the field from the input it borrows from.
+    pub fn into_owned(self) -> Field<'static> {
+        Field {
+            letters: Cow::Owned(self.letters.into_owned()),
+            value: self.value.into_owned(),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Field<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.letters, self.value)
+    }
+}
+
+impl<'input> From<&ParsedField<'input>> for Field<'input> {
+    fn from(field: &ParsedField<'input>) -> Self {
+        Self {
+            letters: field.letters.clone(),
+            value: Value::from(&field.value),
+        }
+    }
+}
+
+/// Compares an emitted field with a parsed one by meaning rather than spelling:
+///
+/// * letters are compared ignoring ASCII case, so `g1` matches `G1`
+/// * numbers are compared by exact value whatever their variant, so [Value::Integer] `5` matches a parsed `5.0`,
+///   with a [Value::Float] taken at its shortest decimal representation (see [Value::as_decimal])
+/// * strings are compared by their contents, as written between the quotes
+/// * a number never matches a string
+///
+/// Since the value is compared as a whole, `G1` does not match `G10`.
+impl PartialEq<ParsedField<'_>> for Field<'_> {
+    fn eq(&self, other: &ParsedField<'_>) -> bool {
+        self.letters.eq_ignore_ascii_case(&other.letters)
+            && self.value.same_as(&Value::from(&other.value))
+    }
+}
+
+/// See the comparison of emitted and parsed fields above.
+impl PartialEq<Field<'_>> for ParsedField<'_> {
+    fn eq(&self, other: &Field<'_>) -> bool {
+        other == self
+    }
+}
+
+impl Field<'_> {
+    /// Compare fields by where their letters come in the order, ignoring case.
+    ///
+    /// Fields whose letters aren't in the order come after those that are, and compare equal to each other,
+    /// so a stable sort keeps them as they were.
+    pub fn canonical_cmp(&self, other: &Self, order: &ArgOrder) -> Ordering {
+        order.rank(&self.letters).cmp(&order.rank(&other.letters))
+    }
+}
+
+/// An order for the arguments of a [Command], as used by [Command::sort_args].
+///
+/// The default puts the axes first, in the order `X`, `Y`, `Z`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgOrder<'o> {
+    /// Letters in the order that arguments with them come in.
+    pub letters: &'o [&'o str],
+}
+
+impl Default for ArgOrder<'_> {
+    fn default() -> Self {
+        Self {
+            letters: &["X", "Y", "Z"],
+        }
+    }
+}
+
+impl ArgOrder<'_> {
+    fn rank(&self, letters: &str) -> usize {
+        self.letters
+            .iter()
+            .position(|ordered| ordered.eq_ignore_ascii_case(letters))
+            .unwrap_or(self.letters.len())
+    }
+}
+
+/// Reasons that [Field::new] can reject a field.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FieldError {
+    /// Letters were empty or contained something other than ASCII letters.
+    InvalidLetters(String),
+    /// A string value contained a newline or a non-ASCII character.
+    InvalidString(String),
+    /// A text value contained a newline, a `;`, a `*`, or a non-ASCII character.
+    InvalidText(String),
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLetters(letters) => {
+                write!(f, "field letters must be ASCII letters: {:?}", letters)
+            }
+            Self::InvalidString(s) => {
+                write!(f, "string values must be ASCII without newlines: {:?}", s)
+            }
+            Self::InvalidText(s) => write!(
+                f,
+                "text values must be ASCII without newlines, `;` or `*`: {:?}",
+                s
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FieldError {}
+
+impl<'a> From<Field<'a>> for Token<'a> {
+    fn from(field: Field<'a>) -> Token<'a> {
+        Token::Field(field)
+    }
+}
+
+/// All the possible variations of a field's value.
+/// Some flavors of GCode also allow for strings.
+#[derive(Clone, Debug)]
+pub enum Value<'a> {
+    /// A fraction, written exactly if it is a terminating decimal like `1/8` (`0.125`),
+    /// and rounded to the nearest [f64] otherwise.
+    Rational(Ratio<i64>),
+    Float(f64),
+    Integer(u64),
+    /// A flag like the `S1` of `M569 S1`, written as `1` or `0`.
+    ///
+    /// [FormatOptions::bool_style] can write it as `TRUE` or `FALSE` instead.
+    /// Parsing never produces one: `1` and `0` are read back as a [Value::Integer].
+    Bool(bool),
+    /// The contents of a string, without delimiting quotes.
+    ///
+    /// Quotes inside the string must already be escaped by doubling them (`""`).
+    String(Cow<'a, str>),
+    /// Free text written as it is, without quotes, like the message of `M117 Hello`.
+    ///
+    /// Text runs to the end of its line, so the formatter puts anything after it but a comment on a new line.
+    /// It is made by [display_message] as a field without letters. Parsing never produces one.
+    Text(Cow<'a, str>),
+}
+
+/// Values are equal if they are the same variant with the same contents.
+///
+/// Floats are compared as numbers, so `0.0` equals `-0.0`, except that every `NaN` equals every other,
+/// which keeps `==` an equivalence so that any value can be found in a set or map.
+impl PartialEq for Value<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Rational(a), Self::Rational(b)) => a == b,
+            (Self::Float(a), Self::Float(b)) => float_bits(*a) == float_bits(*b),
+            (Self::Integer(a), Self::Integer(b)) => a == b,
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::String(a), Self::String(b)) | (Self::Text(a), Self::Text(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value<'_> {}
+
+/// Hashes agree with `==`.
+impl Hash for Value<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Rational(r) => r.hash(state),
+            Self::Float(f) => float_bits(*f).hash(state),
+            Self::Integer(i) => i.hash(state),
+            Self::Bool(b) => b.hash(state),
+            Self::String(s) | Self::Text(s) => s.hash(state),
+        }
+    }
+}
+
+/// The bits of a float, with every zero and every `NaN` made the same.
+fn float_bits(f: f64) -> u64 {
+    if f == 0. {
+        0f64.to_bits()
+    } else if f.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        f.to_bits()
+    }
+}
+
+impl Value<'_> {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Rational(r) => r.to_f64(),
+            Self::Integer(i) => Some(*i as f64),
+            Self::Float(f) => Some(*f),
+            Self::Bool(b) => Some(f64::from(u8::from(*b))),
+            Self::String(_) | Self::Text(_) => None,
+        }
+    }
+
+    /// The exact decimal value, as it would be written.
+    ///
+    /// A [Value::Float] is taken at its shortest decimal representation, so `0.1` is exactly `1/10`.
+    pub fn as_decimal(&self) -> Option<Ratio<i64>> {
+        match self {
+            Self::Rational(r) => Some(*r),
+            Self::Integer(i) => i64::try_from(*i).ok().map(Ratio::from_integer),
+            Self::Bool(b) => Some(Ratio::from_integer(i64::from(*b))),
+            Self::Float(f) if f.is_finite() => match f.to_string().parse::<Value>() {
+                Ok(Value::Rational(r)) => Some(r),
+                Ok(Value::Integer(i)) => i64::try_from(i).ok().map(Ratio::from_integer),
+                _ => None,
+            },
+            Self::Float(_) | Self::String(_) | Self::Text(_) => None,
+        }
+    }
+
+    /// Equality by meaning, where numbers of any variant are compared by exact value.
+    fn same_as(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Self::String(a), Value::String(b)) | (Self::Text(a), Value::Text(b)) => a == b,
+            (Self::String(_) | Self::Text(_), _) | (_, Value::String(_) | Value::Text(_)) => false,
+            // Compared directly, since these may not fit in a Ratio<i64>
+            (Self::Integer(a), Value::Integer(b)) => a == b,
+            _ => match (self.as_decimal(), other.as_decimal()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+
+    /// The value as an integer, if it is a whole number.
+    pub fn as_int(&self) -> Option<i64> {
+        self.as_decimal()
+            .filter(Ratio::is_integer)
+            .map(|r| r.to_integer())
+    }
+
+    /// Detach the value from the input it borrows from.
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Self::Rational(r) => Value::Rational(r),
+            Self::Float(f) => Value::Float(f),
+            Self::Integer(i) => Value::Integer(i),
+            Self::Bool(b) => Value::Bool(b),
+            Self::String(s) => Value::String(Cow::Owned(s.into_owned())),
+            Self::Text(s) => Value::Text(Cow::Owned(s.into_owned())),
+        }
+    }
+}
+
+impl<'input> From<&ParsedValue<'input>> for Value<'input> {
+    fn from(val: &ParsedValue<'input>) -> Self {
+        use ParsedValue::*;
+        match val {
+            #[cfg(not(feature = "float-values"))]
+            Rational(r) => Self::Rational(*r),
+            #[cfg(feature = "float-values")]
+            Rational(r) => Self::Float(*r),
+            Integer(i) => Self::Integer(*i),
+            String(s) => Self::String(slice_cow(s, 1..s.len() - 1)),
+            // No other value holds a number this large, so it is rounded to the nearest float
+            Raw(s) => Self::Float(s.parse().unwrap_or(f64::NAN)),
+        }
+    }
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rational(r) => match write_terminating_decimal(r, f) {
+                Some(result) => result,
+                None => write!(f, "{}", r.to_f64().ok_or(fmt::Error)?),
+            },
+            Self::Float(float) => write!(f, "{}", float),
+            Self::Integer(i) => write!(f, "{}", i),
+            Self::Bool(b) => write!(f, "{}", u8::from(*b)),
+            Self::String(s) => write!(f, "\"{}\"", s),
+            Self::Text(s) => f.write_str(s),
+        }
+    }
+}
+
+/// Write a fraction whose denominator has no factors but 2 and 5 as the exact decimal it is,
+/// so that parsing it back gives the same fraction.
+///
+/// Any other fraction has no exact decimal form, and is [None] so that it is rounded instead.
+/// Going through [f64] would round too, since it only holds about 17 significant digits.
+fn write_terminating_decimal(r: &Ratio<i64>, f: &mut fmt::Formatter) -> Option<fmt::Result> {
+    let mut rest = i128::from(*r.denom());
+    let (mut twos, mut fives) = (0, 0);
+    while rest % 2 == 0 {
+        rest /= 2;
+        twos += 1;
+    }
+    while rest % 5 == 0 {
+        rest /= 5;
+        fives += 1;
+    }
+    if rest != 1 {
+        return None;
+    }
+    // Scale the fraction up to a power of ten, then drop trailing zeros of fractions that weren't reduced
+    let mut places = u32::max(twos, fives);
+    let mut scaled = i128::from(*r.numer())
+        .checked_mul(2i128.checked_pow(places - twos)?)?
+        .checked_mul(5i128.checked_pow(places - fives)?)?;
+    while places > 0 && scaled % 10 == 0 {
+        scaled /= 10;
+        places -= 1;
+    }
+    let unit = 10i128.checked_pow(places)?;
+    let sign = if scaled < 0 { "-" } else { "" };
+    let (whole, fraction) = (scaled.abs() / unit, scaled.abs() % unit);
+    Some(if places == 0 {
+        write!(f, "{}{}", sign, whole)
+    } else {
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            whole,
+            fraction,
+            width = places as usize
+        )
+    })
+}
+
+/// Classifies a value the same way the parser does:
+///
+/// * `"..."` is a [Value::String], with inner quotes escaped as `""`
+/// * digits with a decimal point (`1.5`, `1.`, `.5`) are a [Value::Rational]
+/// * negative digits (`-5`) are a [Value::Rational]
+/// * anything else made of digits is a [Value::Integer]
+///
+/// [Value::Float] is never produced, so formatting a float and parsing it back yields a [Value::Rational].
+/// Likewise, a whole, positive [Value::Rational] is formatted without a decimal point and comes back as a [Value::Integer].
+impl FromStr for Value<'static> {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(ValueParseError::Empty);
+        }
+        if let Some(quoted) = s.strip_prefix('"') {
+            let inner = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| ValueParseError::InvalidString(s.to_string()))?;
+            let quotes_are_escaped = inner.split("\"\"").all(|part| !part.contains('"'));
+            if !quotes_are_escaped || !inner.is_ascii() || inner.contains(['\n', '\r']) {
+                return Err(ValueParseError::InvalidString(s.to_string()));
+            }
+            return Ok(Self::String(Cow::Owned(inner.to_string())));
+        }
+
+        let (neg, unsigned) = match s.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, s),
+        };
+        let is_digits = |x: &str| x.bytes().all(|b| b.is_ascii_digit());
+        let out_of_range = || ValueParseError::OutOfRange(s.to_string());
+        match unsigned.split_once('.') {
+            Some((lhs, rhs))
+                if is_digits(lhs) && is_digits(rhs) && !(lhs.is_empty() && rhs.is_empty()) =>
+            {
+                let lhs = if lhs.is_empty() {
+                    Ratio::from_integer(0)
+                } else {
+                    lhs.parse::<Ratio<i64>>().map_err(|_| out_of_range())?
+                };
+                let rhs = if rhs.is_empty() {
+                    Ratio::from_integer(0)
+                } else {
+                    let denominator = 10i64
+                        .checked_pow(rhs.len() as u32)
+                        .ok_or_else(out_of_range)?;
+                    Ratio::new(rhs.parse::<i64>().map_err(|_| out_of_range())?, denominator)
+                };
+                let value = lhs + rhs;
+                Ok(Self::Rational(if neg { -value } else { value }))
+            }
+            None if !unsigned.is_empty() && is_digits(unsigned) => {
+                if neg {
+                    Ok(Self::Rational(
+                        -unsigned.parse::<Ratio<i64>>().map_err(|_| out_of_range())?,
+                    ))
+                } else {
+                    Ok(Self::Integer(
+                        unsigned.parse::<u64>().map_err(|_| out_of_range())?,
+                    ))
+                }
+            }
+            _ => Err(ValueParseError::InvalidNumber(s.to_string())),
+        }
+    }
+}
+
+/// Splits a field like `X12.5` at its first non-alphabetic character,
+/// parsing the remainder with [Value::from_str].
+impl FromStr for Field<'static> {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len());
+        let (letters, value) = s.split_at(split);
+        if letters.is_empty() {
+            return Err(ValueParseError::MissingLetters(s.to_string()));
+        }
+        let value = value.parse::<Value>().map_err(|err| match err {
+            ValueParseError::Empty => ValueParseError::MissingValue(s.to_string()),
+            other => other,
+        })?;
+        Ok(Self::new(letters.to_string(), value)?)
+    }
+}
+
+/// Reasons that a [Value] or [Field] could not be parsed from a string.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ValueParseError {
+    /// There was nothing to parse.
+    Empty,
+    /// The text is not a number in any form GCode accepts.
+    InvalidNumber(String),
+    /// The number does not fit in the value it would be parsed as.
+    OutOfRange(String),
+    /// A string is unterminated, has an unescaped quote, or contains a newline or non-ASCII character.
+    InvalidString(String),
+    /// A field did not start with letters.
+    MissingLetters(String),
+    /// A field had letters, but no value after them.
+    MissingValue(String),
+    Field(FieldError),
+}
+
+impl From<FieldError> for ValueParseError {
+    fn from(err: FieldError) -> Self {
+        Self::Field(err)
+    }
+}
+
+impl fmt::Display for ValueParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "value is empty"),
+            Self::InvalidNumber(s) => write!(
+                f,
+                "{:?} is not a number: expected digits with an optional leading minus sign and decimal point",
+                s
+            ),
+            Self::OutOfRange(s) => write!(f, "{:?} is too large to be represented", s),
+            Self::InvalidString(s) => write!(
+                f,
+                "{:?} is not a valid string: expected ASCII text enclosed in quotes, with inner quotes doubled",
+                s
+            ),
+            Self::MissingLetters(s) => write!(f, "{:?} does not start with a letter", s),
+            Self::MissingValue(s) => write!(f, "{:?} has no value after its letters", s),
+            Self::Field(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ValueParseError {}
+
+/// A macro for quickly instantiating a float-valued command
+#[macro_export]
+macro_rules! command {
+    ($commandName: ident {
+        $($arg: ident : $value: expr,)*
+    }) => {
+        {
+            use g_code::emit::*;
+            use paste::paste;
+            paste::expr!{
+                [<$commandName:snake:lower>](
+                    vec![$(
+                        Field {
+                            letters: Cow::Borrowed(stringify!([<$arg:upper>])),
+                            value: Value::Float($value),
+                        }
+                    ,)*].drain(..)
+                )
+            }
+        }
+    };
+}
+
+macro_rules! impl_commands {
+    ($($(#[doc = $doc: literal])* $commandName: ident {$letters: expr, $value: expr, {$($(#[$inner:meta])* $arg: ident $(| $alias: ident)*), *}, [$($rule: expr),*] },)*) => {
+
+        paste! {
+            $(
+                $(#[doc = $doc])*
+                pub fn [<$commandName:snake:lower>]<'a, I: Iterator<Item = Field<'a>>>(args: I) -> Command<'a> {
+                    Command {
+                        name: [<$commandName:snake:upper _FIELD>].clone(),
+                        args: args.filter(|arg| {
+                            match arg.letters.to_ascii_uppercase().as_str() {
+                                $(stringify!($arg) $(| stringify!($alias))* => true,)*
+                                _ => false
+                            }
+                        }).collect(),
+                    }
+                }
+                #[doc = "Like [" [<$commandName:snake:lower>] "], resolving repeated arguments with a [DuplicatePolicy]."]
+                pub fn [<$commandName:snake:lower _with>]<'a, I: Iterator<Item = Field<'a>>>(
+                    args: I,
+                    policy: DuplicatePolicy,
+                ) -> Result<Command<'a>, DuplicateArgument> {
+                    let mut command = Command {
+                        name: [<$commandName:snake:upper _FIELD>].clone(),
+                        args: vec![],
+                    };
+                    for arg in args {
+                        command.push_with(arg, policy)?;
+                    }
+                    Ok(command)
+                }
+                pub const [<$commandName:snake:upper _FIELD>]: Field<'static> = Field {
+                    letters: Cow::Borrowed($letters),
+                    value: $value,
+                };
+            )*
+
+            static COMMANDS: &[CommandDescriptor] = &[$(
+                CommandDescriptor {
+                    name: stringify!([<$commandName:snake:lower>]),
+                    field: [<$commandName:snake:upper _FIELD>],
+                    allowed_args: &[$(stringify!($arg) $(, stringify!($alias))*),*],
+                    doc: concat!($($doc, "\n"),*),
+                },
+            )*];
+        }
+
+        /// Commands are the operational unit of GCode
+        /// They consist of a G, M, or other top-level field followed by field arguments
+        #[derive(Clone, PartialEq, Debug)]
+        pub struct Command<'a> {
+            name: Field<'a>,
+            args: Vec<Field<'a>>,
+        }
+
+        impl<'a> Command<'a> {
+            /// Add an argument, if the command takes one with its letters.
+            ///
+            /// Arguments are kept even if the command already has one with the same letters,
+            /// as in [DuplicatePolicy::KeepAll]: see [Command::push_with] for the alternatives.
+            pub fn push(&mut self, arg: Field<'a>) {
+                match &self.name {
+                    $(x if *x == paste!{[<$commandName:snake:upper _FIELD>]}.clone() => {
+                        if match arg.letters.to_ascii_uppercase().as_str() {
+                            $(stringify!($arg) $(| stringify!($alias))* => {true},)*
+                            _ => false,
+                        } {
+                            self.args.push(arg);
+                        } else {
+                        }
+                    },)*
+                    _ => {
+                        dbg!(&self.name);
+                        dbg!(&arg);
+                    }
+                }
+            }
+
+            pub fn iter(&self) -> impl Iterator<Item = &Field<'_>> {
+                std::iter::once(&self.name).chain(self.args.iter())
+            }
+
+            /// Whether the command takes an argument with these letters.
+            fn accepts(&self, letters: &str) -> bool {
+                self.canonical(letters).is_some()
+            }
+
+            /// The letters that an argument is declared as, resolving aliases.
+            fn canonical(&self, letters: &str) -> Option<&'static str> {
+                match &self.name {
+                    $(x if *x == paste!{[<$commandName:snake:upper _FIELD>]} => {
+                        match letters.to_ascii_uppercase().as_str() {
+                            $(stringify!($arg) $(| stringify!($alias))* => Some(stringify!($arg)),)*
+                            _ => None,
+                        }
+                    },)*
+                    _ => None,
+                }
+            }
+
+            /// Letters of the arguments that [Command::push] accepts for this command, aliases included.
+            ///
+            /// These are uppercase, though arguments are accepted in either case.
+            pub fn allowed_letters(&self) -> &'static [&'static str] {
+                match &self.name {
+                    $(x if *x == paste!{[<$commandName:snake:upper _FIELD>]} => {
+                        &[$(stringify!($arg) $(, stringify!($alias))*),*]
+                    },)*
+                    _ => &[],
+                }
+            }
+
+            /// The rules that [Command::validate] checks for this command.
+            fn rules(&self) -> &'static [ArgRule] {
+                use ArgRule::*;
+                match &self.name {
+                    $(x if *x == paste!{[<$commandName:snake:upper _FIELD>]} => &[$($rule),*],)*
+                    _ => &[],
+                }
+            }
+
+            pub fn into_token_vec(mut self) -> Vec<Token<'a>> {
+                std::iter::once(self.name).chain(self.args.drain(..)).map(|f| f.into()).collect()
+            }
+
+            pub fn iter_args(&self) -> impl Iterator<Item = &Field<'_>> {
+                self.iter().skip(1)
+            }
+
+            pub fn iter_mut_args(&mut self) -> impl Iterator<Item = &mut Field<'a>> {
+                self.args.iter_mut()
+            }
+
+            /// The argument with these letters, ignoring case.
+            ///
+            /// Aliases declared for the command are interchangeable,
+            /// so `get("E")` finds an `A` argument on commands that accept `A` for `E`.
+            pub fn get(&'_ self, letters: &str) -> Option<&'_ Field<'_>> {
+                let position = self.position(letters)?;
+                self.args.get(position)
+            }
+
+            /// Replace the value of the argument with these letters, found as in [Command::get].
+            ///
+            /// If the command has no such argument, one is added with the letters uppercased,
+            /// as long as the command takes it. Otherwise, nothing happens.
+            pub fn set(&mut self, letters: &str, value: Value<'a>) {
+                if let Some(position) = self.position(letters) {
+                    self.args[position].value = value;
+                } else if self.accepts(letters) {
+                    self.args.push(Field {
+                        letters: Cow::Owned(letters.to_ascii_uppercase()),
+                        value,
+                    });
+                }
+            }
+
+            fn position(&self, letters: &str) -> Option<usize> {
+                let canonical = self.canonical(letters);
+                self.args.iter().position(|arg| {
+                    arg.letters.eq_ignore_ascii_case(letters)
+                        || canonical.is_some() && self.canonical(&arg.letters) == canonical
+                })
+            }
+        }
+    };
+}
+
+impl_commands!(
+    /// Moves the head at the fastest possible speed to the desired speed
+    /// Never enter a cut with rapid positioning
+    /// Some older machines may "dog leg" rapid positioning, moving one axis at a time
+    RapidPositioning {
+        "G", Value::Integer(0), {
+            X,
+            Y,
+            Z,
+            E,
+            F,
+            H,
+            R,
+            S,
+            A,
+            B,
+            C
+        }, [Positive("F")]
+    },
+    /// Typically used for "cutting" motion
+    LinearInterpolation {
+        "G", Value::Integer(1), {
+            X,
+            Y,
+            Z,
+            E,
+            F,
+            H,
+            R,
+            S,
+            A,
+            B,
+            C
+        }, [Positive("F")]
+    },
+    /// Clockwise arc to `X`/`Y`/`Z`, around either a center offset `I`/`J`/`K` or a radius `R`
+    ClockwiseCircularInterpolation {
+        "G", Value::Integer(2), {
+            X,
+            Y,
+            Z,
+            /// Extruder, written as `A` by some firmware
+            E | A,
+            F,
+            I,
+            J,
+            K,
+            R,
+            P,
+            S
+        }, [Positive("F"), RadiusXorCenter]
+    },
+    /// Counterclockwise arc to `X`/`Y`/`Z`, around either a center offset `I`/`J`/`K` or a radius `R`
+    CounterclockwiseCircularInterpolation {
+        "G", Value::Integer(3), {
+            X,
+            Y,
+            Z,
+            /// Extruder, written as `A` by some firmware
+            E | A,
+            F,
+            I,
+            J,
+            K,
+            R,
+            P,
+            S
+        }, [Positive("F"), RadiusXorCenter]
+    },
+    /// This will keep the axes unmoving for the period of time in seconds specified by the P number
+    Dwell {
+        "G", Value::Integer(4), {
+            /// Time in seconds
+            P
+        }, [NonNegative("P")]
+    },
+    /// Cubic Bézier move to `X`/`Y`, with `I`/`J` offsetting the first control point from the start
+    /// and `P`/`Q` offsetting the second control point from the end
+    BezierSpline {
+        "G", Value::Integer(5), {
+            X,
+            Y,
+            /// Extruder, written as `A` by some firmware
+            E | A,
+            F,
+            I,
+            J,
+            P,
+            Q,
+            S
+        }, [Positive("F")]
+    },
+    /// Quadratic B-spline move to `X`/`Y`, with `I`/`J` offsetting the control point from the start
+    QuadraticSpline {
+        "G", Value::Rational(Ratio::new_raw(51, 10)), {
+            X,
+            Y,
+            F,
+            I,
+            J
+        }, [Positive("F")]
+    },
+    /// A control point of a NURBS curve, weighted by `P`, with the curve's order set by `L`
+    /// on the first point of the block
+    NurbsBlock {
+        "G", Value::Rational(Ratio::new_raw(31, 5)), {
+            X,
+            Y,
+            F,
+            P,
+            L
+        }, [Positive("F"), Positive("P"), Positive("L")]
+    },
+    /// Use inches for length units
+    UnitsInches {
+        "G", Value::Integer(20), {}, []
+    },
+    /// Use millimeters for length units
+    UnitsMillimeters {
+        "G", Value::Integer(21), {}, []
+    },
+    /// In absolute distance mode, axis numbers usually represent positions in terms of the currently active coordinate system.
+    AbsoluteDistanceMode {
+        "G", Value::Integer(90), {}, []
+    },
+    /// In relative distance mode, axis numbers usually represent increments from the current values of the numbers
+    RelativeDistanceMode {
+        "G", Value::Integer(91), {}, []
+    },
+    FeedRateUnitsPerMinute {
+        "G", Value::Integer(94), {}, []
+    },
+    /// Start spinning the spindle clockwise with speed `p`
+    StartSpindleClockwise {
+        "M", Value::Integer(3), {
+            /// Speed
+            P
+        }, [SpindleSpeed("P")]
+    },
+    /// Start spinning the spindle counterclockwise with speed `p`
+    StartSpindleCounterclockwise {
+        "M", Value::Integer(4), {
+            /// Speed
+            P
+        }, [SpindleSpeed("P")]
+    },
+    /// Stop spinning the spindle
+    StopSpindle {
+        "M", Value::Integer(5), {}, []
+    },
+    /// Signals the end of a program
+    ProgramEnd {
+        "M", Value::Integer(2), {}, []
+    },
+    /// Stop and wait for the user to resume, or until `P` milliseconds or `S` seconds pass
+    ///
+    /// Marlin also shows any text after `M0` as a message, but the parser does not accept such text,
+    /// so put messages in a comment or an [M117](https://marlinfw.org/docs/gcode/M117.html) instead.
+    UnconditionalStop {
+        "M", Value::Integer(0), {
+            /// Time in milliseconds
+            P,
+            /// Time in seconds
+            S
+        }, [NonNegative("P"), NonNegative("S")]
+    },
+    /// Like [unconditional_stop], but machines with an optional stop switch only stop when it is on
+    OptionalStop {
+        "M", Value::Integer(1), {
+            /// Time in milliseconds
+            P,
+            /// Time in seconds
+            S
+        }, [NonNegative("P"), NonNegative("S")]
+    },
+    /// Park the head at `X`/`Y`/`Z`, unload `U` and load `L` filament after retracting `E`,
+    /// then wait for the user, beeping `B` times
+    FilamentChange {
+        "M", Value::Integer(600), {
+            X,
+            Y,
+            Z,
+            /// Retraction before parking
+            E,
+            /// Length of the final load
+            L,
+            /// Length to unload
+            U,
+            /// Number of beeps
+            B
+        }, [NonNegative("L"), NonNegative("U"), NonNegative("B")]
+    },
+    /// Pause the print, as done by Prusa firmware and Marlin
+    PausePrint {
+        "M", Value::Integer(601), {}, []
+    },
+    /// Resume a print paused by [pause_print]
+    ResumePrint {
+        "M", Value::Integer(602), {}, []
+    },
+    /// Show a message on the machine's display
+    ///
+    /// The message is text rather than a field, so make one with [display_message] instead.
+    SetLcdMessage {
+        "M", Value::Integer(117), {}, [MessageLength]
+    },
+    /// Play a tone of `S` hertz for `P` milliseconds, as made by [beep]
+    PlayTone {
+        "M", Value::Integer(300), {
+            /// Frequency in hertz
+            S,
+            /// Duration in milliseconds
+            P
+        }, [NonNegative("S"), NonNegative("P")]
+    },
+    /// Set the color of an LED strip, as made by [set_led]
+    SetRgbColor {
+        "M", Value::Integer(150), {
+            /// Red
+            R,
+            /// Green
+            U,
+            /// Blue
+            B,
+            /// White, on RGBW strips
+            W,
+            /// Brightness
+            P,
+            /// Index of a single LED to set
+            I
+        }, [NonNegative("R"), NonNegative("U"), NonNegative("B"), NonNegative("W"), NonNegative("P")]
+    },
+);
+
+/// `M117 <text>`, which shows the text on the machine's display.
+///
+/// The text is written without quotes, so it is checked as in [Field::new] for a [Value::Text].
+/// Its length depends on the display, and is checked by [Command::validate]
+/// against the [MachineLimits::max_message_length] of the flavor.
+///
+/// ```
+/// use g_code::emit::{display_message, format_gcode_fmt, FormatOptions};
+///
+/// let mut gcode = String::new();
+/// let tokens = display_message("Layer 2 of 10").unwrap().into_token_vec();
+/// format_gcode_fmt(&tokens, FormatOptions::default(), &mut gcode).unwrap();
+/// assert_eq!(gcode, "M117 Layer 2 of 10\n");
+/// assert!(display_message("50%; done").is_err());
+/// ```
+pub fn display_message(text: &str) -> Result<Command<'_>, FieldError> {
+    Ok(Command {
+        name: SET_LCD_MESSAGE_FIELD,
+        args: vec![Field::new("", Value::Text(Cow::Borrowed(text)))?],
+    })
+}
+
+/// `M300 S<frequency> P<duration>`, which plays a tone of the frequency in hertz
+/// for the duration in milliseconds.
+pub fn beep(frequency: u64, duration: u64) -> Command<'static> {
+    Command {
+        name: PLAY_TONE_FIELD,
+        args: vec![
+            Field {
+                letters: Cow::Borrowed("S"),
+                value: Value::Integer(frequency),
+            },
+            Field {
+                letters: Cow::Borrowed("P"),
+                value: Value::Integer(duration),
+            },
+        ],
+    }
+}
+
+/// `M150 R<r> U<g> B<b>`, which sets the color of an LED strip.
+///
+/// Marlin takes green as `U`, since `G` would be read as a command.
+pub fn set_led(r: u8, g: u8, b: u8) -> Command<'static> {
+    let channel = |letters, value| Field {
+        letters: Cow::Borrowed(letters),
+        value: Value::Integer(u64::from(value)),
+    };
+    Command {
+        name: SET_RGB_COLOR_FIELD,
+        args: vec![channel("R", r), channel("U", g), channel("B", b)],
+    }
+}
+
+/// A command that the crate can construct, as listed by [all_commands].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandDescriptor {
+    /// Name of the constructor, like `linear_interpolation`.
+    pub name: &'static str,
+    /// The field that the command starts with, like `G1`.
+    pub field: Field<'static>,
+    /// Letters of the arguments it takes, as in [Command::allowed_letters].
+    pub allowed_args: &'static [&'static str],
+    /// Documentation of the constructor, one line of the doc comment per line,
+    /// each with the leading space it was written with.
+    pub doc: &'static str,
+}
+
+/// Every command that the crate has a constructor for, in the order they are declared.
+///
+/// This is meant for checking user-supplied arguments and building interfaces
+/// without hard-coding what each command takes.
+///
+/// ```
+/// use g_code::emit::all_commands;
+///
+/// let dwell = all_commands().iter().find(|c| c.name == "dwell").unwrap();
+/// assert_eq!(dwell.field.to_string(), "G4");
+/// assert_eq!(dwell.allowed_args, ["P"]);
+/// ```
+pub fn all_commands() -> &'static [CommandDescriptor] {
+    COMMANDS
+}
+
+/// What to do with an argument whose letters are already on a [Command], or are an alias for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Keep both, writing the letters twice.
+    ///
+    /// This is what [Command::push] and constructors like [rapid_positioning] do,
+    /// though most firmware only takes one of the values and they don't agree on which.
+    #[default]
+    KeepAll,
+    /// Keep the argument that was there first, dropping the new one.
+    KeepFirst,
+    /// Replace the value of the argument that was there first with the new one.
+    KeepLast,
+    /// Fail with a [DuplicateArgument].
+    Error,
+}
+
+/// An argument was given twice under [DuplicatePolicy::Error].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateArgument {
+    /// The letters of the second argument, as given.
+    pub letters: String,
+}
+
+impl fmt::Display for DuplicateArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "argument {} was given more than once", self.letters)
+    }
+}
+
+impl std::error::Error for DuplicateArgument {}
+
+impl<'a> Command<'a> {
+    /// Add an argument like [Command::push], resolving a repeated argument with the policy.
+    ///
+    /// On an error, the command is left as it was.
+    pub fn push_with(
+        &mut self,
+        arg: Field<'a>,
+        policy: DuplicatePolicy,
+    ) -> Result<(), DuplicateArgument> {
+        let existing = if self.accepts(&arg.letters) {
+            self.position(&arg.letters)
+        } else {
+            None
+        };
+        match (existing, policy) {
+            (None, _) | (Some(_), DuplicatePolicy::KeepAll) => self.push(arg),
+            (Some(_), DuplicatePolicy::KeepFirst) => {}
+            (Some(position), DuplicatePolicy::KeepLast) => self.args[position].value = arg.value,
+            (Some(_), DuplicatePolicy::Error) => {
+                return Err(DuplicateArgument {
+                    letters: arg.letters.into_owned(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve repeated arguments already on the command with the policy, as if they were pushed again in order.
+    ///
+    /// On an error, the command is left as it was.
+    pub fn dedup(&mut self, policy: DuplicatePolicy) -> Result<(), DuplicateArgument> {
+        let args = std::mem::take(&mut self.args);
+        for arg in args.iter().cloned() {
+            if let Err(err) = self.push_with(arg, policy) {
+                self.args = args;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sort the arguments by [Field::canonical_cmp], keeping arguments that compare equal in the order they were in.
+    ///
+    /// The command stays first, so the result can be written as it is.
+    ///
+    /// ```
+    /// use g_code::emit::{linear_interpolation, ArgOrder, Field, Value};
+    ///
+    /// let field = |letters, value| Field::new(letters, Value::Integer(value)).unwrap();
+    /// let mut command = linear_interpolation(
+    ///     vec![field("F", 300), field("Z", 1), field("E", 2), field("X", 5)].into_iter(),
+    /// );
+    /// command.sort_args(&ArgOrder::default());
+    /// let text = command.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().join(" ");
+    /// assert_eq!(text, "G1 X5 Z1 F300 E2");
+    /// ```
+    pub fn sort_args(&mut self, order: &ArgOrder) {
+        self.args.sort_by(|a, b| a.canonical_cmp(b, order));
+    }
+
+    /// The letter and number of the command, like `('G', 1)`, for use in match statements.
+    ///
+    /// Commands with a fractional number, like `G5.1`, return [None].
+    pub fn name_value(&self) -> Option<(char, u64)> {
+        let letter = self.name.letters.chars().next()?.to_ascii_uppercase();
+        match self.name.value {
+            Value::Integer(number) => Some((letter, number)),
+            _ => None,
+        }
+    }
+
+    /// The numeric value of an argument, found as in [Command::get].
+    pub fn get_f64(&self, letters: &str) -> Option<f64> {
+        self.get(letters)?.value.as_f64()
+    }
+
+    /// The exact decimal value of an argument, found as in [Command::get].
+    ///
+    /// See [Value::as_decimal].
+    pub fn get_decimal(&self, letters: &str) -> Option<Ratio<i64>> {
+        self.get(letters)?.value.as_decimal()
+    }
+
+    /// The value of an argument that is a whole number, found as in [Command::get].
+    pub fn get_int(&self, letters: &str) -> Option<i64> {
+        self.get(letters)?.value.as_int()
+    }
+
+    /// Replace the value of an argument with a [Value::Float], as in [Command::set].
+    pub fn set_f64(&mut self, letters: &str, value: f64) {
+        self.set(letters, Value::Float(value));
+    }
+
+    /// Replace the value of an argument with a [Value::Bool], as in [Command::set].
+    pub fn set_bool(&mut self, letters: &str, value: bool) {
+        self.set(letters, Value::Bool(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn values_round_trip_through_strings() {
+        let values = vec![
+            Value::Integer(0),
+            Value::Integer(1234567890),
+            Value::Rational(Ratio::new(1, 2)),
+            Value::Rational(Ratio::new(-1, 2)),
+            Value::Rational(Ratio::new(-5, 1)),
+            Value::Rational(Ratio::new(12345, 1000)),
+            Value::Rational(Ratio::new(-1, 1000)),
+            Value::String(Cow::Borrowed("MYROUTER")),
+            Value::String(Cow::Borrowed("ABCxyz;\"\" 123")),
+            Value::String(Cow::Borrowed("")),
+        ];
+        for value in values {
+            assert_eq!(value.to_string().parse::<Value>(), Ok(value.clone()));
+        }
+    }
+
+    #[test]
+    fn rationals_are_written_exactly_when_they_can_be() {
+        for (value, text) in [
+            (
+                Ratio::new(17206685981491806, 1000000000000000),
+                "17.206685981491806",
+            ),
+            (Ratio::new(-1, 8), "-0.125"),
+            (Ratio::new_raw(50, 10), "5"),
+            (Ratio::new_raw(51, 10), "5.1"),
+            (
+                Ratio::from_integer(123456789012345678),
+                "123456789012345678",
+            ),
+            (Ratio::new(1, 3), "0.3333333333333333"),
+        ]
+        .iter()
+        {
+            assert_eq!(Value::Rational(*value).to_string(), *text);
+        }
+    }
+
+    #[test]
+    fn values_are_classified_like_the_parser() {
+        assert_eq!(
+            "1.".parse::<Value>(),
+            Ok(Value::Rational(Ratio::from_integer(1)))
+        );
+        assert_eq!(
+            ".25".parse::<Value>(),
+            Ok(Value::Rational(Ratio::new(1, 4)))
+        );
+        assert_eq!(
+            "-.25".parse::<Value>(),
+            Ok(Value::Rational(Ratio::new(-1, 4)))
+        );
+        assert_eq!("7".parse::<Value>(), Ok(Value::Integer(7)));
+        assert_eq!(
+            Value::Float(1.5).to_string().parse::<Value>(),
+            Ok(Value::Rational(Ratio::new(3, 2)))
+        );
+    }
+
+    #[test]
+    fn extended_commands_are_checked_and_parse_back() {
+        let command = ExtendedCommand::new("SET_DISPLAY_TEXT")
+            .and_then(|c| c.param("MSG", "\"Layer 2\""))
+            .and_then(|c| c.param("index", "1"))
+            .unwrap();
+        let text = command.to_string();
+        assert_eq!(text, "SET_DISPLAY_TEXT MSG=\"Layer 2\" index=1");
+        let opts = crate::parse::ParseOptions {
+            allow_extended_commands: true,
+            ..Default::default()
+        };
+        let file = crate::parse::file_parser_with_options(&text, &opts).unwrap();
+        assert_eq!(
+            file.iter_emit_tokens().collect::<Vec<_>>(),
+            [Token::ExtendedCommand(command.clone())]
+        );
+
+        for name in ["G1", "SET", "set_pa", "_SET", ""].iter() {
+            assert!(ExtendedCommand::new(*name).is_err(), "{}", name);
+        }
+        for (key, value) in [("", "1"), ("A=", "1"), ("MSG", "a b"), ("MSG", "a;b")].iter() {
+            assert!(command.clone().param(*key, *value).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn fields_match_parsed_fields_by_value() {
+        fn parsed(src: &str) -> ParsedField<'_> {
+            crate::parse::field(src).unwrap()
+        }
+        let field = |letters, value| Field::new(letters, value).unwrap();
+
+        assert_eq!(LINEAR_INTERPOLATION_FIELD, parsed("G1"));
+        assert_eq!(LINEAR_INTERPOLATION_FIELD, parsed("g1"));
+        assert_eq!(LINEAR_INTERPOLATION_FIELD, parsed("G1.0"));
+        assert_eq!(parsed("G01"), LINEAR_INTERPOLATION_FIELD);
+        assert_ne!(LINEAR_INTERPOLATION_FIELD, parsed("G10"));
+        assert_ne!(LINEAR_INTERPOLATION_FIELD, parsed("G1.1"));
+        assert_ne!(LINEAR_INTERPOLATION_FIELD, parsed("M1"));
+        assert_ne!(LINEAR_INTERPOLATION_FIELD, parsed("GG1"));
+
+        assert_eq!(field("X", Value::Float(0.1)), parsed("X.1"));
+        assert_eq!(field("X", Value::Float(-5.)), parsed("X-5"));
+        assert_eq!(
+            field("X", Value::Rational(Ratio::new(5, 2))),
+            parsed("X2.50")
+        );
+        assert_ne!(field("X", Value::Float(0.1)), parsed("X0.10001"));
+        assert_ne!(field("X", Value::Float(f64::NAN)), parsed("X0"));
+        assert_eq!(
+            field("P", Value::Integer(u64::MAX)),
+            parsed(&format!("P{}", u64::MAX))
+        );
+
+        assert_eq!(
+            field("P", Value::String("a\"\"b".into())),
+            parsed("P\"a\"\"b\"")
+        );
+        assert_ne!(field("P", Value::String("1".into())), parsed("P1"));
+        assert_ne!(field("P", Value::Integer(1)), parsed("P\"1\""));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert_eq!("".parse::<Value>(), Err(ValueParseError::Empty));
+        for invalid in &[".", "-", "+1", "1.2.3", "1e5", "0x10", " 1"] {
+            assert_eq!(
+                invalid.parse::<Value>(),
+                Err(ValueParseError::InvalidNumber(invalid.to_string()))
+            );
+        }
+        for invalid in &["\"", "\"abc", "\"a\"b\"", "\"§\""] {
+            assert_eq!(
+                invalid.parse::<Value>(),
+                Err(ValueParseError::InvalidString(invalid.to_string()))
+            );
+        }
+        assert_eq!(
+            "99999999999999999999999".parse::<Value>(),
+            Err(ValueParseError::OutOfRange(
+                "99999999999999999999999".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parsed_strings_are_converted_without_delimiters() {
+        let parsed = crate::parse::file_parser(r#"M587 S"MYROUTER" P"a""b""#).unwrap();
+        let fields = parsed.iter_fields().map(Field::from).collect::<Vec<_>>();
+        assert_eq!(fields[1].value, Value::String(Cow::Borrowed("MYROUTER")));
+        assert_eq!(fields[2].to_string(), r#"P"a""b""#);
+    }
+
+    #[test]
+    fn fields_are_split_at_their_value() {
+        assert_eq!(
+            "X12.5".parse::<Field>(),
+            Ok(Field {
+                letters: Cow::Borrowed("X"),
+                value: Value::Rational(Ratio::new(25, 2)),
+            })
+        );
+        assert_eq!(
+            "TOOL\"drill\"".parse::<Field>(),
+            Ok(Field {
+                letters: Cow::Borrowed("TOOL"),
+                value: Value::String(Cow::Borrowed("drill")),
+            })
+        );
+        assert_eq!(
+            "12".parse::<Field>(),
+            Err(ValueParseError::MissingLetters("12".to_string()))
+        );
+        assert_eq!(
+            "F".parse::<Field>(),
+            Err(ValueParseError::MissingValue("F".to_string()))
+        );
+        assert_eq!(
+            "F1x".parse::<Field>(),
+            Err(ValueParseError::InvalidNumber("1x".to_string()))
+        );
+    }
+
+    #[test]
+    fn spline_commands_keep_their_arguments() {
+        let args = || {
+            ["X10", "Y5", "I2", "J0", "P-2", "Q0", "Z1"]
+                .iter()
+                .map(|s| s.parse::<Field>().unwrap())
+        };
+        let spline = bezier_spline(args());
+        assert_eq!(
+            spline
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+            "G5 X10 Y5 I2 J0 P-2 Q0"
+        );
+        assert_eq!(quadratic_spline(args()).iter().count(), 5);
+        assert_eq!(QUADRATIC_SPLINE_FIELD.to_string(), "G5.1");
+        assert_eq!(NURBS_BLOCK_FIELD.to_string(), "G6.2");
+        assert_eq!("G5.1".parse::<Field>(), Ok(QUADRATIC_SPLINE_FIELD));
+        assert_eq!("G6.2".parse::<Field>(), Ok(NURBS_BLOCK_FIELD));
+        // Declared in lowest terms, as parsing gives them, so that they hash the same
+        for field in [QUADRATIC_SPLINE_FIELD, NURBS_BLOCK_FIELD].iter() {
+            match field.value {
+                Value::Rational(r) => assert_eq!((*r.numer(), *r.denom()), {
+                    let reduced = r.reduced();
+                    (*reduced.numer(), *reduced.denom())
+                }),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn fields(args: &[&str]) -> impl Iterator<Item = Field<'static>> {
+        args.iter()
+            .map(|s| s.parse::<Field>().unwrap())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn typed_accessors_convert_or_return_none() {
+        let mut command = linear_interpolation(fields(&["x1.5", "Y-2", "Z3", "F300", "E\"a\""]));
+        command.push(Field::new("A", Value::Float(0.1)).unwrap());
+
+        assert_eq!(command.get_f64("X"), Some(1.5));
+        assert_eq!(command.get_f64("x"), Some(1.5));
+        assert_eq!(command.get_f64("E"), None);
+        assert_eq!(command.get_f64("B"), None);
+
+        assert_eq!(command.get_decimal("X"), Some(Ratio::new(3, 2)));
+        assert_eq!(command.get_decimal("F"), Some(Ratio::from_integer(300)));
+        assert_eq!(command.get_decimal("A"), Some(Ratio::new(1, 10)));
+        assert_eq!(command.get_decimal("E"), None);
+
+        assert_eq!(command.get_int("Y"), Some(-2));
+        assert_eq!(command.get_int("F"), Some(300));
+        assert_eq!(command.get_int("X"), None);
+        assert_eq!(command.get_int("B"), None);
+
+        command.set_f64("z", 0.25);
+        assert_eq!(
+            command.get("Z").map(|f| &f.value),
+            Some(&Value::Float(0.25))
+        );
+        // Added, since G1 takes a B axis
+        command.set_f64("b", 1.);
+        assert_eq!(
+            command.get("B").map(|f| f.to_string()),
+            Some("B1".to_string())
+        );
+    }
+
+    #[test]
+    fn duplicate_arguments_follow_the_policy() {
+        let args = || fields(&["X1", "Y2", "x3"]);
+        let written = |command: &Command| {
+            command
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(written(&rapid_positioning(args())), "G0 X1 Y2 x3");
+        let cases = [
+            (DuplicatePolicy::KeepAll, Ok("G0 X1 Y2 x3")),
+            (DuplicatePolicy::KeepFirst, Ok("G0 X1 Y2")),
+            (DuplicatePolicy::KeepLast, Ok("G0 X3 Y2")),
+            (
+                DuplicatePolicy::Error,
+                Err(DuplicateArgument {
+                    letters: "x".to_string(),
+                }),
+            ),
+        ];
+        for (policy, expected) in cases.iter() {
+            let constructed = rapid_positioning_with(args(), *policy);
+            assert_eq!(
+                constructed.map(|command| written(&command)),
+                expected.clone().map(String::from)
+            );
+
+            let mut deduped = rapid_positioning(args());
+            let result = deduped.dedup(*policy);
+            assert_eq!(
+                result.map(|()| written(&deduped)),
+                expected.clone().map(String::from)
+            );
+        }
+
+        // Aliases count as the same argument, and a failed dedup leaves the command alone
+        let mut arc = clockwise_circular_interpolation(fields(&["X1", "E1", "A2"]));
+        assert_eq!(
+            arc.dedup(DuplicatePolicy::Error).unwrap_err().to_string(),
+            "argument A was given more than once"
+        );
+        assert_eq!(written(&arc), "G2 X1 E1 A2");
+        arc.push_with("e3".parse().unwrap(), DuplicatePolicy::KeepLast)
+            .unwrap();
+        assert_eq!(written(&arc), "G2 X1 E3 A2");
+    }
+
+    #[test]
+    fn set_adds_missing_arguments_the_command_takes() {
+        let mut command = rapid_positioning(fields(&["X1"]));
+        command.set_f64("y", 2.);
+        command.set_f64("X", 3.);
+        command.set_f64("Q", 4.);
+        let written = command
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(written, "G0 X3 Y2");
+    }
+
+    #[test]
+    fn aliases_are_interchangeable() {
+        let mut arc = clockwise_circular_interpolation(fields(&["X1", "Y1", "R1", "a2"]));
+        assert_eq!(arc.get_int("E"), Some(2));
+        assert_eq!(arc.get_int("A"), Some(2));
+        arc.set_f64("E", 3.);
+        assert_eq!(arc.get_f64("a"), Some(3.));
+
+        // G1 has a rotary A axis, so A is not an alias there
+        let line = linear_interpolation(fields(&["A2"]));
+        assert_eq!(line.get_int("A"), Some(2));
+        assert_eq!(line.get_int("E"), None);
+    }
+
+    #[test]
+    fn name_value_gives_letter_and_number() {
+        assert_eq!(
+            linear_interpolation(std::iter::empty()).name_value(),
+            Some(('G', 1))
+        );
+        assert_eq!(
+            filament_change(std::iter::empty()).name_value(),
+            Some(('M', 600))
+        );
+        assert_eq!(quadratic_spline(std::iter::empty()).name_value(), None);
+        match rapid_positioning(std::iter::empty()).name_value() {
+            Some(('G', 0)) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn bools_are_written_as_digits_or_words() {
+        let mut command = linear_interpolation(fields(&["X1", "S0"]));
+        command.set_bool("s", true);
+        assert_eq!(command.get("S").map(|f| &f.value), Some(&Value::Bool(true)));
+        assert_eq!(command.get_int("S"), Some(1));
+        command.set_bool("P", true);
+        assert_eq!(command.get("P"), None);
+
+        let tokens = command.into_token_vec();
+        let format = |bool_style| {
+            let mut acc = String::new();
+            let opts = FormatOptions {
+                bool_style,
+                ..Default::default()
+            };
+            format_gcode_fmt(&tokens, opts, &mut acc).unwrap();
+            acc
+        };
+        assert_eq!(format(BoolStyle::Digits), "G1 X1 S1\n");
+        assert_eq!(format(BoolStyle::Words), "G1 X1 STRUE\n");
+
+        // Parsing has no booleans, but the values still match
+        let parsed = crate::parse::snippet_parser("S1 S0").unwrap();
+        let parsed = parsed.iter_fields().collect::<Vec<_>>();
+        assert_eq!(Value::from(&parsed[0].value), Value::Integer(1));
+        assert_eq!(Field::new("S", Value::Bool(true)).unwrap(), *parsed[0]);
+        assert_eq!(Field::new("S", Value::Bool(false)).unwrap(), *parsed[1]);
+    }
+
+    #[test]
+    fn display_beep_and_led_commands_are_written_exactly() {
+        let format = |commands: Vec<Command>, opts: FormatOptions| {
+            let tokens = commands
+                .into_iter()
+                .flat_map(Command::into_token_vec)
+                .collect::<Vec<_>>();
+            let mut acc = String::new();
+            format_gcode_fmt(&tokens, opts, &mut acc).unwrap();
+            acc
+        };
+        let commands = || {
+            vec![
+                display_message("Heating (2/3)").unwrap(),
+                beep(440, 200),
+                set_led(255, 128, 0),
+            ]
+        };
+        assert_eq!(
+            format(commands(), FormatOptions::default()),
+            "M117 Heating (2/3)\nM300 S440 P200\nM150 R255 U128 B0\n"
+        );
+        let numbered = format(
+            commands(),
+            FormatOptions {
+                checksums: true,
+                line_numbers: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            numbered,
+            "N1 M117 Heating (2/3)*82\nN2 M300 S440 P200*35\nN3 M150 R255 U128 B0*120\n"
+        );
+
+        // Nothing but a comment follows text on its line
+        let mut tokens = display_message("Done").unwrap().into_token_vec();
+        tokens.push(Field::new("X", Value::Integer(1)).unwrap().into());
+        tokens.push(Token::Comment {
+            is_inline: false,
+            inner: Cow::Borrowed("end"),
+        });
+        let mut acc = String::new();
+        format_gcode_fmt(&tokens, FormatOptions::default(), &mut acc).unwrap();
+        assert_eq!(acc, "M117 Done\nX1 ;end\n");
+
+        for text in ["two\nlines", "50%; done", "checksum*", "caf\u{e9}"].iter() {
+            assert_eq!(
+                display_message(text),
+                Err(FieldError::InvalidText(text.to_string()))
+            );
+        }
+        assert!(Field::new("", Value::Integer(1)).is_err());
+    }
+
+    #[test]
+    fn registry_covers_every_constructor() {
+        type Constructor = fn(std::vec::IntoIter<Field<'static>>) -> Command<'static>;
+        let constructors: Vec<(&str, Constructor)> = vec![
+            ("rapid_positioning", rapid_positioning),
+            ("linear_interpolation", linear_interpolation),
+            (
+                "clockwise_circular_interpolation",
+                clockwise_circular_interpolation,
+            ),
+            (
+                "counterclockwise_circular_interpolation",
+                counterclockwise_circular_interpolation,
+            ),
+            ("dwell", dwell),
+            ("bezier_spline", bezier_spline),
+            ("quadratic_spline", quadratic_spline),
+            ("nurbs_block", nurbs_block),
+            ("units_inches", units_inches),
+            ("units_millimeters", units_millimeters),
+            ("absolute_distance_mode", absolute_distance_mode),
+            ("relative_distance_mode", relative_distance_mode),
+            ("feed_rate_units_per_minute", feed_rate_units_per_minute),
+            ("start_spindle_clockwise", start_spindle_clockwise),
+            (
+                "start_spindle_counterclockwise",
+                start_spindle_counterclockwise,
+            ),
+            ("stop_spindle", stop_spindle),
+            ("program_end", program_end),
+            ("unconditional_stop", unconditional_stop),
+            ("optional_stop", optional_stop),
+            ("filament_change", filament_change),
+            ("pause_print", pause_print),
+            ("resume_print", resume_print),
+            ("set_lcd_message", set_lcd_message),
+            ("play_tone", play_tone),
+            ("set_rgb_color", set_rgb_color),
+        ];
+        let registry = all_commands();
+        assert_eq!(registry.len(), constructors.len());
+        for (descriptor, (name, constructor)) in registry.iter().zip(constructors) {
+            assert_eq!(descriptor.name, name);
+            let command = constructor(vec![].into_iter());
+            assert_eq!(command.iter().next(), Some(&descriptor.field));
+            assert_eq!(command.allowed_letters(), descriptor.allowed_args);
+        }
+
+        let dwell = registry.iter().find(|c| c.name == "dwell").unwrap();
+        assert_eq!(
+            dwell.doc,
+            " This will keep the axes unmoving for the period of time in seconds specified by the P number\n"
+        );
+        let arc = registry
+            .iter()
+            .find(|c| c.name == "clockwise_circular_interpolation")
+            .unwrap();
+        assert_eq!(
+            arc.allowed_args,
+            ["X", "Y", "Z", "E", "A", "F", "I", "J", "K", "R", "P", "S"]
+        );
+    }
+
+    #[test]
+    fn push_agrees_with_allowed_letters() {
+        for descriptor in all_commands() {
+            let mut command = Command {
+                name: descriptor.field.clone(),
+                args: vec![],
+            };
+            for letter in b'A'..=b'Z' {
+                let letter = char::from(letter).to_string();
+                command.push(Field::new(letter.clone(), Value::Integer(1)).unwrap());
+                let accepted = command.args.iter().any(|arg| arg.letters == letter);
+                assert_eq!(
+                    accepted,
+                    descriptor.allowed_args.contains(&letter.as_str()),
+                    "{} {}",
+                    descriptor.name,
+                    letter
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn equal_fields_hash_the_same() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashSet;
+
+        let hash = |field: &Field| {
+            let mut hasher = DefaultHasher::new();
+            field.hash(&mut hasher);
+            hasher.finish()
+        };
+        let field = |letters, value| Field::new(letters, value).unwrap();
+        let pairs = [
+            (field("X", Value::Float(0.)), field("X", Value::Float(-0.))),
+            (
+                field("X", Value::Rational(Ratio::new(2, 4))),
+                field("X", Value::Rational(Ratio::new(1, 2))),
+            ),
+            (
+                field("M", Value::String(Cow::Borrowed("a"))),
+                field("M", Value::String(Cow::Owned("a".to_string()))),
+            ),
+        ];
+        for (a, b) in pairs.iter() {
+            assert_eq!(a, b);
+            assert_eq!(hash(a), hash(b), "{:?} and {:?}", a, b);
+        }
+
+        let mut set = fields(&["X1", "Y2", "X1", "x1", "X1.0"]).collect::<HashSet<_>>();
+        assert_eq!(set.len(), 4);
+        assert!(set.insert(field("Y", Value::Float(2.))));
+        // NaN is equal to itself, so it can be found again
+        let nan = field("Z", Value::Float(f64::NAN));
+        assert_eq!(nan, field("Z", Value::Float(-f64::NAN)));
+        assert!(set.insert(nan.clone()));
+        assert!(set.contains(&nan));
+        assert!(!set.insert(nan));
+        assert_ne!(
+            field("Z", Value::Float(1.)),
+            field("Z", Value::Rational(Ratio::from_integer(1)))
+        );
+    }
+
+    #[test]
+    fn sorting_arguments_is_stable_and_keeps_the_command_first() {
+        let written = |command: &Command| {
+            command
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let mut command = linear_interpolation(fields(&["F300", "E1", "z2", "A1", "Y3", "X4"]));
+        command.sort_args(&ArgOrder::default());
+        assert_eq!(written(&command), "G1 X4 Y3 z2 F300 E1 A1");
+        command.sort_args(&ArgOrder::default());
+        assert_eq!(written(&command), "G1 X4 Y3 z2 F300 E1 A1");
+
+        let order = ArgOrder {
+            letters: &["E", "F"],
+        };
+        command.sort_args(&order);
+        assert_eq!(written(&command), "G1 E1 F300 X4 Y3 z2 A1");
+
+        // Sorted commands are still written on one line, with a valid checksum
+        let mut formatted = String::new();
+        let opts = FormatOptions {
+            checksums: true,
+            line_numbers: true,
+            ..Default::default()
+        };
+        format_gcode_fmt(command.into_token_vec(), opts, &mut formatted).unwrap();
+        let reparsed = crate::parse::file_parser(&formatted).unwrap();
+        let lines = reparsed.iter().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].validate_checksum(), Some(Ok(())));
+    }
+}
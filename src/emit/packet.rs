@@ -0,0 +1,206 @@
+use std::io;
+
+/// A 16-bit cyclic redundancy check appended to every packet by [PacketWriter].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Crc16 {
+    /// Polynomial `0x1021`, initial value `0x0000`, as used by XMODEM.
+    #[default]
+    Xmodem,
+    /// Polynomial `0x1021`, initial value `0xFFFF` (CRC-16/CCITT-FALSE).
+    CcittFalse,
+    /// Reflected polynomial `0x8005`, initial value `0xFFFF` (CRC-16/MODBUS).
+    Modbus,
+}
+
+impl Crc16 {
+    /// Compute the CRC of a sequence of bytes.
+    pub fn checksum(self, bytes: &[u8]) -> u16 {
+        match self {
+            Self::Xmodem => crc16_msb_first(0x0000, bytes),
+            Self::CcittFalse => crc16_msb_first(0xFFFF, bytes),
+            Self::Modbus => bytes.iter().fold(0xFFFF, |mut crc: u16, byte| {
+                crc ^= u16::from(*byte);
+                for _ in 0..8 {
+                    crc = if crc & 1 != 0 {
+                        (crc >> 1) ^ 0xA001
+                    } else {
+                        crc >> 1
+                    };
+                }
+                crc
+            }),
+        }
+    }
+}
+
+/// CRC with polynomial `0x1021`, shifting out the most significant bit first.
+fn crc16_msb_first(init: u16, bytes: &[u8]) -> u16 {
+    bytes.iter().fold(init, |mut crc, byte| {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// An [io::Write] that splits everything written to it into fixed-size packets,
+/// each followed by its [Crc16] in big-endian byte order.
+///
+/// Bytes are held back until a full packet is available,
+/// so a partial packet at the end is only written by [PacketWriter::finish].
+/// [format_gcode_io](super::format_gcode_io) can write to it directly:
+///
+/// ```
+/// use g_code::emit::{format_gcode_io, packet::{Crc16, PacketWriter}, FormatOptions};
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("G28\nG1 X10").unwrap();
+/// let mut writer = PacketWriter::new(vec![], 4, Crc16::Xmodem);
+/// format_gcode_io(file.iter_emit_tokens(), FormatOptions::default(), &mut writer).unwrap();
+/// let packets = writer.finish().unwrap();
+/// // "G28\n", "G1 X", and "10\n", each with two bytes of CRC
+/// assert_eq!(packets.len(), 4 + 2 + 4 + 2 + 3 + 2);
+/// ```
+#[derive(Debug)]
+pub struct PacketWriter<W: io::Write> {
+    inner: W,
+    packet_size: usize,
+    crc: Crc16,
+    pending: Vec<u8>,
+}
+
+impl<W: io::Write> PacketWriter<W> {
+    /// Write packets of `packet_size` bytes, not counting the CRC, to `inner`.
+    ///
+    /// # Panics
+    ///
+    /// If `packet_size` is zero.
+    pub fn new(inner: W, packet_size: usize, crc: Crc16) -> Self {
+        assert!(packet_size > 0, "packets must hold at least one byte");
+        Self {
+            inner,
+            packet_size,
+            crc,
+            pending: Vec::with_capacity(packet_size),
+        }
+    }
+
+    /// Write the last packet, even if it is not full, and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            let last = std::mem::take(&mut self.pending);
+            self.write_packet(&last)?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.inner.write_all(packet)?;
+        self.inner
+            .write_all(&self.crc.checksum(packet).to_be_bytes())
+    }
+}
+
+impl<W: io::Write> io::Write for PacketWriter<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len();
+        if !self.pending.is_empty() {
+            let take = (self.packet_size - self.pending.len()).min(buf.len());
+            self.pending.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
+            if self.pending.len() < self.packet_size {
+                return Ok(written);
+            }
+            let full = std::mem::take(&mut self.pending);
+            self.write_packet(&full)?;
+        }
+        let mut packets = buf.chunks_exact(self.packet_size);
+        for packet in &mut packets {
+            self.write_packet(packet)?;
+        }
+        self.pending.extend_from_slice(packets.remainder());
+        Ok(written)
+    }
+
+    /// Flushes the inner writer, but holds back a partial packet until [PacketWriter::finish].
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::{format_gcode_io, FormatOptions};
+    use crate::parse::file_parser;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn crcs_match_their_check_values() {
+        assert_eq!(Crc16::Xmodem.checksum(b"123456789"), 0x31C3);
+        assert_eq!(Crc16::CcittFalse.checksum(b"123456789"), 0x29B1);
+        assert_eq!(Crc16::Modbus.checksum(b"123456789"), 0x4B37);
+    }
+
+    /// Reassemble the payload, checking every CRC along the way.
+    fn receive(stream: &[u8], packet_size: usize, crc: Crc16) -> Vec<u8> {
+        let mut payload = vec![];
+        for packet in stream.chunks(packet_size + 2) {
+            assert!(packet.len() > 2, "empty packet");
+            let (data, checksum) = packet.split_at(packet.len() - 2);
+            assert_eq!(
+                u16::from_be_bytes([checksum[0], checksum[1]]),
+                crc.checksum(data)
+            );
+            payload.extend_from_slice(data);
+        }
+        payload
+    }
+
+    #[test]
+    fn receiver_reassembles_formatter_output() {
+        let tokens = file_parser(include_str!("../../tests/vandy_commodores_logo.gcode"))
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let mut unchunked = vec![];
+        format_gcode_io(&tokens, FormatOptions::default(), &mut unchunked).unwrap();
+
+        for &crc in [Crc16::Xmodem, Crc16::CcittFalse, Crc16::Modbus].iter() {
+            for &packet_size in [1, 7, 64, 512, unchunked.len(), unchunked.len() + 1].iter() {
+                let mut writer = PacketWriter::new(vec![], packet_size, crc);
+                format_gcode_io(&tokens, FormatOptions::default(), &mut writer).unwrap();
+                let stream = writer.finish().unwrap();
+                assert_eq!(
+                    stream.len(),
+                    unchunked.len() + 2 * unchunked.len().div_ceil(packet_size)
+                );
+                assert_eq!(receive(&stream, packet_size, crc), unchunked);
+            }
+        }
+    }
+
+    #[test]
+    fn small_writes_are_held_until_a_packet_fills() {
+        use std::io::Write;
+
+        let mut writer = PacketWriter::new(vec![], 4, Crc16::Xmodem);
+        writer.write_all(b"G2").unwrap();
+        writer.flush().unwrap();
+        assert!(writer.inner.is_empty());
+        writer.write_all(b"8\nG").unwrap();
+        assert_eq!(writer.inner.len(), 6);
+        let stream = writer.finish().unwrap();
+        assert_eq!(receive(&stream, 4, Crc16::Xmodem), b"G28\nG");
+        assert!(PacketWriter::new(vec![], 4, Crc16::Xmodem)
+            .finish()
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,380 @@
+use std::borrow::Cow;
+
+use super::{Field, Token, Value};
+
+/// Settings for turning geometry into a toolpath with [from_polyline], or `from_path` with the `lyon` feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathToGcode {
+    /// Feed rate of cutting moves, including plunges to [PathToGcode::z_cut].
+    pub feed: f64,
+    /// Feed rate written on rapid `G0` moves, since some firmware like Marlin moves `G0` at the current feed rate.
+    pub rapid_feed: f64,
+    /// Height to cut at.
+    pub z_cut: f64,
+    /// Height to travel at between cuts.
+    pub z_safe: f64,
+    /// Largest distance allowed between a curve and the moves standing in for it.
+    ///
+    /// Curves that are circular arcs to within this distance become `G2` or `G3` arcs,
+    /// and any others are flattened into `G1` lines.
+    pub tolerance: f64,
+}
+
+/// Cut along a polyline, given as a sequence of XY points.
+///
+/// The toolpath starts by rising to [PathToGcode::z_safe] and moving above the first point,
+/// plunges to [PathToGcode::z_cut], cuts to each point in turn, and rises again at the end.
+/// Repeat the first point at the end to cut a closed shape.
+/// Numbers are rounded to six decimal places.
+///
+/// ```
+/// use g_code::emit::{format_gcode_fmt, from_polyline, FormatOptions, PathToGcode};
+///
+/// let opts = PathToGcode { feed: 300., rapid_feed: 3000., z_cut: -1., z_safe: 5., tolerance: 0.01 };
+/// let tokens = from_polyline(&[[0., 0.], [10., 0.], [10., 5.]], opts);
+/// let mut gcode = String::new();
+/// format_gcode_fmt(&tokens, FormatOptions::default(), &mut gcode).unwrap();
+/// assert_eq!(
+///     gcode,
+///     "G0 Z5 F3000\nG0 X0 Y0\nG1 Z-1 F300\nG1 X10 Y0\nG1 X10 Y5\nG0 Z5 F3000\n"
+/// );
+/// ```
+pub fn from_polyline(points: &[[f64; 2]], opts: PathToGcode) -> Vec<Token<'static>> {
+    let mut toolpath = Toolpath::new(opts);
+    if let Some((first, rest)) = points.split_first() {
+        toolpath.move_to(*first);
+        for point in rest {
+            toolpath.line_to(*point);
+        }
+    }
+    toolpath.finish()
+}
+
+/// Cut along each of the subpaths of a [lyon_path::Path], in order.
+///
+/// Subpaths are cut as in [from_polyline], without rising in between if one starts where the last ended.
+/// Quadratic and cubic curves become `G2` (clockwise) or `G3` (counterclockwise) arcs
+/// if they are circular arcs to within [PathToGcode::tolerance], and are flattened into `G1` lines otherwise.
+/// Arcs give the offset to their center as `I` and `J`, and are taken to be in the XY plane (`G17`)
+/// with Y pointing up, so paths from an SVG may need flipping first.
+#[cfg(feature = "lyon")]
+pub fn from_path(path: &lyon_path::Path, opts: PathToGcode) -> Vec<Token<'static>> {
+    use lyon_path::geom::{CubicBezierSegment, QuadraticBezierSegment};
+    use lyon_path::Event;
+
+    let mut toolpath = Toolpath::new(opts);
+    let tolerance = opts.tolerance as f32;
+    for event in path.iter() {
+        match event {
+            Event::Begin { at } => toolpath.move_to(point(at)),
+            Event::Line { to, .. } => toolpath.line_to(point(to)),
+            Event::Quadratic { from, ctrl, to } => {
+                let curve = QuadraticBezierSegment { from, ctrl, to };
+                match fit_arc(|t| point(curve.sample(t as f32)), opts.tolerance) {
+                    Some((center, clockwise)) => toolpath.arc_to(point(to), center, clockwise),
+                    None => curve.for_each_flattened(tolerance, &mut |line| {
+                        toolpath.line_to(point(line.to))
+                    }),
+                }
+            }
+            Event::Cubic {
+                from,
+                ctrl1,
+                ctrl2,
+                to,
+            } => {
+                let curve = CubicBezierSegment {
+                    from,
+                    ctrl1,
+                    ctrl2,
+                    to,
+                };
+                match fit_arc(|t| point(curve.sample(t as f32)), opts.tolerance) {
+                    Some((center, clockwise)) => toolpath.arc_to(point(to), center, clockwise),
+                    None => curve.for_each_flattened(tolerance, &mut |line| {
+                        toolpath.line_to(point(line.to))
+                    }),
+                }
+            }
+            Event::End { first, close, .. } => {
+                if close {
+                    toolpath.line_to(point(first));
+                }
+            }
+        }
+    }
+    toolpath.finish()
+}
+
+#[cfg(feature = "lyon")]
+fn point(p: lyon_path::math::Point) -> [f64; 2] {
+    [f64::from(p.x), f64::from(p.y)]
+}
+
+/// How far the middle of a curve can stray from its chord, as a fraction of the chord,
+/// for [fit_arc] to still take it as straight.
+#[cfg(feature = "lyon")]
+const STRAIGHT_ENOUGH: f64 = 1e-6;
+
+/// Find the circle that a curve follows to within the tolerance, given a way to sample it from `0` to `1`,
+/// returning its center and whether the curve goes around it clockwise.
+#[cfg(feature = "lyon")]
+fn fit_arc(sample: impl Fn(f64) -> [f64; 2], tolerance: f64) -> Option<([f64; 2], bool)> {
+    let [a, b, c] = [sample(0.), sample(0.5), sample(1.)];
+    // Relative to the start, which keeps the arithmetic precise far from the origin
+    let [u, v] = [[b[0] - a[0], b[1] - a[1]], [c[0] - a[0], c[1] - a[1]]];
+    let d = 2. * (u[0] * v[1] - u[1] * v[0]);
+    let chord = v[0].hypot(v[1]);
+    // The middle strays from the chord by |d| / (2 * chord). When that is a tiny fraction of the chord,
+    // the circle is so large that its center can't be written accurately, and a line does just as well.
+    if chord <= f64::EPSILON || d.abs() <= 2. * chord * chord * STRAIGHT_ENOUGH {
+        return None;
+    }
+    let squared = |p: [f64; 2]| p[0] * p[0] + p[1] * p[1];
+    let center = [
+        a[0] + (v[1] * squared(u) - u[1] * squared(v)) / d,
+        a[1] + (u[0] * squared(v) - v[0] * squared(u)) / d,
+    ];
+    let distance = |p: [f64; 2]| (p[0] - center[0]).hypot(p[1] - center[1]);
+    let radius = distance(a);
+    if (1..8).any(|i| (distance(sample(f64::from(i) / 8.)) - radius).abs() > tolerance) {
+        return None;
+    }
+    Some((center, d < 0.))
+}
+
+/// Builds up the tokens of a toolpath, keeping track of where the tool is.
+struct Toolpath {
+    opts: PathToGcode,
+    tokens: Vec<Token<'static>>,
+    position: Option<[f64; 2]>,
+    cutting: bool,
+}
+
+impl Toolpath {
+    fn new(opts: PathToGcode) -> Self {
+        Self {
+            opts,
+            tokens: vec![],
+            position: None,
+            cutting: false,
+        }
+    }
+
+    /// Travel to a point and plunge, unless already cutting there.
+    fn move_to(&mut self, to: [f64; 2]) {
+        if self.cutting && self.position == Some(to) {
+            return;
+        }
+        self.retract();
+        self.command(0, &[("X", to[0]), ("Y", to[1])]);
+        self.command(1, &[("Z", self.opts.z_cut), ("F", self.opts.feed)]);
+        self.position = Some(to);
+        self.cutting = true;
+    }
+
+    fn line_to(&mut self, to: [f64; 2]) {
+        if self.position == Some(to) {
+            return;
+        }
+        self.command(1, &[("X", to[0]), ("Y", to[1])]);
+        self.position = Some(to);
+    }
+
+    #[cfg(feature = "lyon")]
+    fn arc_to(&mut self, to: [f64; 2], center: [f64; 2], clockwise: bool) {
+        let from = self.position.unwrap_or(to);
+        self.command(
+            if clockwise { 2 } else { 3 },
+            &[
+                ("X", to[0]),
+                ("Y", to[1]),
+                ("I", center[0] - from[0]),
+                ("J", center[1] - from[1]),
+            ],
+        );
+        self.position = Some(to);
+    }
+
+    fn retract(&mut self) {
+        self.command(0, &[("Z", self.opts.z_safe), ("F", self.opts.rapid_feed)]);
+        self.cutting = false;
+    }
+
+    fn finish(mut self) -> Vec<Token<'static>> {
+        if self.cutting {
+            self.retract();
+        }
+        self.tokens
+    }
+
+    fn command(&mut self, g: u64, arguments: &[(&'static str, f64)]) {
+        self.tokens.push(
+            Field {
+                letters: Cow::Borrowed("G"),
+                value: Value::Integer(g),
+            }
+            .into(),
+        );
+        for (letters, value) in arguments {
+            self.tokens.push(
+                Field {
+                    letters: Cow::Borrowed(letters),
+                    value: Value::Float(round(*value)),
+                }
+                .into(),
+            );
+        }
+    }
+}
+
+/// Round to six decimal places, so that floating point noise isn't written out.
+fn round(value: f64) -> f64 {
+    // Adding zero turns -0 into 0
+    (value * 1e6).round() / 1e6 + 0.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpret::State;
+    use crate::parse::file_parser;
+    use crate::transform::format;
+    use num::ToPrimitive;
+    use pretty_assertions::assert_eq;
+
+    const OPTS: PathToGcode = PathToGcode {
+        feed: 600.,
+        rapid_feed: 3000.,
+        z_cut: -0.5,
+        z_safe: 2.,
+        tolerance: 0.01,
+    };
+
+    /// The bounds of the positions the interpreter goes through while cutting, as `[min, max]` for X and Y.
+    fn cut_bounds(gcode: &str) -> [[f64; 2]; 2] {
+        let file = file_parser(gcode).unwrap();
+        let mut state = State::default();
+        let mut bounds = [[f64::INFINITY, f64::NEG_INFINITY]; 2];
+        for line in file.iter() {
+            state.step(line);
+            let position = state.position.map(|p| p.to_f64().unwrap());
+            if position[2] == OPTS.z_cut {
+                for (axis, bound) in bounds.iter_mut().enumerate() {
+                    bound[0] = bound[0].min(position[axis]);
+                    bound[1] = bound[1].max(position[axis]);
+                }
+            }
+        }
+        bounds
+    }
+
+    #[test]
+    fn polyline_is_cut_between_rapid_moves() {
+        let gcode = format(&from_polyline(
+            &[[0., 0.], [10., 0.], [10., 10.], [0., 10.], [0., 0.]],
+            OPTS,
+        ));
+        assert_eq!(
+            gcode,
+            "G0 Z2 F3000\nG0 X0 Y0\nG1 Z-0.5 F600\nG1 X10 Y0\nG1 X10 Y10\nG1 X0 Y10\nG1 X0 Y0\nG0 Z2 F3000\n"
+        );
+        assert_eq!(cut_bounds(&gcode), [[0., 10.], [0., 10.]]);
+        assert_eq!(from_polyline(&[], OPTS), vec![]);
+    }
+
+    #[cfg(feature = "lyon")]
+    mod lyon {
+        use super::*;
+        use lyon_path::math::point;
+        use lyon_path::{Path, Winding};
+        use pretty_assertions::assert_eq;
+
+        #[test]
+        fn square_is_cut_with_lines() {
+            let mut builder = Path::builder();
+            builder.begin(point(0., 0.));
+            builder.line_to(point(10., 0.));
+            builder.line_to(point(10., 10.));
+            builder.line_to(point(0., 10.));
+            builder.close();
+            let gcode = format(&from_path(&builder.build(), OPTS));
+            assert_eq!(
+                gcode,
+                format(&from_polyline(
+                    &[[0., 0.], [10., 0.], [10., 10.], [0., 10.], [0., 0.]],
+                    OPTS
+                ))
+            );
+            assert_eq!(cut_bounds(&gcode), [[0., 10.], [0., 10.]]);
+        }
+
+        #[test]
+        fn circle_is_cut_with_arcs() {
+            let mut builder = Path::builder();
+            builder.add_circle(point(5., 5.), 5., Winding::Positive);
+            let tokens = from_path(&builder.build(), OPTS);
+            let gcode = format(&tokens);
+            let arcs = gcode
+                .lines()
+                .filter(|line| line.starts_with("G3"))
+                .collect::<Vec<_>>();
+            assert_eq!(arcs.len(), 4, "{}", gcode);
+            assert!(!gcode.contains("G2"));
+            assert_eq!(cut_bounds(&gcode), [[0., 10.], [0., 10.]]);
+            for arc in arcs {
+                let file = file_parser(arc).unwrap();
+                let offset = file
+                    .iter_fields()
+                    .filter(|f| f.letters == "I" || f.letters == "J")
+                    .map(|f| Field::from(f).value.as_f64().unwrap())
+                    .collect::<Vec<_>>();
+                assert!((offset[0].hypot(offset[1]) - 5.).abs() <= OPTS.tolerance);
+            }
+
+            // Clockwise, when drawn the other way around
+            let mut builder = Path::builder();
+            builder.add_circle(point(5., 5.), 5., Winding::Negative);
+            let gcode = format(&from_path(&builder.build(), OPTS));
+            assert_eq!(gcode.matches("G2").count(), 4);
+        }
+
+        #[test]
+        fn curves_are_flattened_when_not_circular_enough() {
+            let mut builder = Path::builder();
+            builder.add_circle(point(5., 5.), 5., Winding::Positive);
+            let opts = PathToGcode {
+                tolerance: 0.0001,
+                ..OPTS
+            };
+            let gcode = format(&from_path(&builder.build(), opts));
+            assert!(!gcode.contains("G3"));
+            assert!(gcode.matches("G1 X").count() > 16);
+            let [x, y] = cut_bounds(&gcode);
+            for (bound, expected) in [x, y].iter().zip([[0., 10.], [0., 10.]].iter()) {
+                assert!((bound[0] - expected[0]).abs() <= 0.001, "{:?}", bound);
+                assert!((bound[1] - expected[1]).abs() <= 0.001, "{:?}", bound);
+            }
+
+            let mut builder = Path::builder();
+            builder.begin(point(0., 0.));
+            builder.quadratic_bezier_to(point(5., 10.), point(10., 0.));
+            builder.end(false);
+            let gcode = format(&from_path(&builder.build(), OPTS));
+            assert!(!gcode.contains("G2") && !gcode.contains("G3"));
+            let top = cut_bounds(&gcode)[1][1];
+            assert!((5. - OPTS.tolerance..=5.).contains(&top), "{}", top);
+        }
+
+        #[test]
+        fn nearly_straight_curves_are_cut_with_lines() {
+            let mut builder = Path::builder();
+            builder.begin(point(0., 0.));
+            builder.cubic_bezier_to(point(33., 0.00001), point(66., 0.00001), point(100., 0.));
+            builder.end(false);
+            let gcode = format(&from_path(&builder.build(), OPTS));
+            assert!(!gcode.contains("G2") && !gcode.contains("G3"), "{}", gcode);
+            assert_eq!(cut_bounds(&gcode)[0], [0., 100.]);
+        }
+    }
+}
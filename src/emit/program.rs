@@ -0,0 +1,208 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+
+use super::{
+    format_gcode_fmt, format_gcode_io, Command, Field, FieldError, Flag, FormatOptions, Token,
+    Value,
+};
+use crate::parse::ast::Snippet;
+
+/// An owned sequence of [Token]s, built up fluently.
+///
+/// ```
+/// use g_code::emit::{linear_interpolation, units_millimeters, Field, FormatOptions, Program, Value};
+///
+/// let program = Program::new()
+///     .command(units_millimeters(std::iter::empty()))
+///     .comment("start of cut")?
+///     .command(linear_interpolation(
+///         vec![Field::new("X", Value::Float(1.5))?].into_iter(),
+///     ))
+///     .raw_field("F", Value::Integer(300))?;
+/// assert_eq!(
+///     program.format(FormatOptions::default()),
+///     "G21 ;start of cut\nG1 X1.5 F300\n"
+/// );
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Program(pub Vec<Token<'static>>);
+
+impl Program {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a [Command] and its arguments.
+    pub fn command(mut self, command: Command<'_>) -> Self {
+        self.0.extend(
+            command
+                .into_token_vec()
+                .into_iter()
+                .map(|token| token.into_owned()),
+        );
+        self
+    }
+
+    /// Append an end-of-line comment.
+    ///
+    /// The comment must be ASCII and must not contain a newline.
+    pub fn comment(mut self, comment: &str) -> Result<Self, ProgramError> {
+        if !comment.is_ascii() || comment.contains(['\n', '\r']) {
+            return Err(ProgramError::InvalidComment(comment.to_string()));
+        }
+        self.0.push(Token::Comment {
+            is_inline: false,
+            inner: Cow::Owned(comment.to_string()),
+        });
+        Ok(self)
+    }
+
+    /// Append a single field, validated by [Field::new].
+    ///
+    /// Prefer [Program::command] where a constructor exists for the command.
+    pub fn raw_field(mut self, letters: &str, value: Value<'_>) -> Result<Self, ProgramError> {
+        let field = Field::new(letters, value)?;
+        self.0.push(Token::Field(field.into_owned()));
+        Ok(self)
+    }
+
+    /// Append a [Flag], validated by [Flag::new].
+    pub fn flag(mut self, letters: &str) -> Result<Self, ProgramError> {
+        let flag = Flag::new(letters)?;
+        self.0.push(Token::Flag(flag.into_owned()));
+        Ok(self)
+    }
+
+    /// Append a [Token::BlankLine].
+    pub fn blank_line(mut self) -> Self {
+        self.0.push(Token::BlankLine);
+        self
+    }
+
+    /// Append the contents of a parsed [Snippet], such as a user-supplied tool sequence.
+    pub fn extend_snippet(mut self, snippet: &Snippet) -> Self {
+        self.0
+            .extend(snippet.iter_emit_tokens().map(|token| token.into_owned()));
+        self
+    }
+
+    /// Iterate over the tokens in the program.
+    pub fn iter(&self) -> impl Iterator<Item = &Token<'static>> {
+        self.0.iter()
+    }
+
+    /// Format the program as a [String] using [format_gcode_fmt].
+    pub fn format(&self, opts: FormatOptions) -> String {
+        let mut acc = String::new();
+        // Writing to a String cannot fail
+        let _ = format_gcode_fmt(&self.0, opts, &mut acc);
+        acc
+    }
+
+    /// Format the program to an [io::Write] using [format_gcode_io].
+    pub fn format_io<W: io::Write>(&self, opts: FormatOptions, w: W) -> io::Result<()> {
+        format_gcode_io(&self.0, opts, w)
+    }
+}
+
+impl<'a> IntoIterator for &'a Program {
+    type Item = &'a Token<'static>;
+    type IntoIter = std::slice::Iter<'a, Token<'static>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// Reasons that building a [Program] can fail.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ProgramError {
+    Field(FieldError),
+    /// A comment contained a newline or a non-ASCII character.
+    InvalidComment(String),
+}
+
+impl From<FieldError> for ProgramError {
+    fn from(err: FieldError) -> Self {
+        Self::Field(err)
+    }
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Field(err) => write!(f, "{}", err),
+            Self::InvalidComment(comment) => {
+                write!(f, "comments must be ASCII without newlines: {:?}", comment)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::{dwell, program_end};
+    use crate::parse::snippet_parser;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn builds_and_formats_a_program() {
+        let tool_on = snippet_parser("M3 S1000\nG4 P0.5").unwrap();
+        let program = Program::new()
+            .comment("generated")
+            .unwrap()
+            .extend_snippet(&tool_on)
+            .blank_line()
+            .command(dwell(
+                vec![Field::new("P", Value::Float(2.)).unwrap()].into_iter(),
+            ))
+            .command(program_end(std::iter::empty()));
+        assert_eq!(
+            program.format(FormatOptions {
+                preserve_blank_lines: true,
+                ..Default::default()
+            }),
+            ";generated\nM3 S1000\nG4 P0.5\n\nG4 P2\nM2\n"
+        );
+        let mut io_output = vec![];
+        program
+            .format_io(FormatOptions::default(), &mut io_output)
+            .unwrap();
+        assert_eq!(io_output, b";generated\nM3 S1000\nG4 P0.5\nG4 P2\nM2\n");
+    }
+
+    #[test]
+    fn rejects_invalid_fields_and_comments() {
+        assert_eq!(
+            Program::new().raw_field("X1", Value::Integer(1)),
+            Err(ProgramError::Field(FieldError::InvalidLetters(
+                "X1".to_string()
+            )))
+        );
+        assert_eq!(
+            Program::new().raw_field("", Value::Integer(1)),
+            Err(ProgramError::Field(FieldError::InvalidLetters(
+                String::new()
+            )))
+        );
+        assert_eq!(
+            Program::new().raw_field("P", Value::String("a\nb".into())),
+            Err(ProgramError::Field(FieldError::InvalidString(
+                "a\nb".to_string()
+            )))
+        );
+        assert_eq!(
+            Program::new().comment("two\nlines"),
+            Err(ProgramError::InvalidComment("two\nlines".to_string()))
+        );
+        assert_eq!(
+            Program::new().comment("§"),
+            Err(ProgramError::InvalidComment("§".to_string()))
+        );
+    }
+}
@@ -0,0 +1,373 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+
+use super::format::Layout;
+use super::{FormatOptions, Token};
+use crate::parse::ast::DuplicateLineNumber;
+
+/// The last lines written by [format_gcode_io_resendable], kept by line number
+/// so that a sender can write them again when firmware asks for a resend.
+///
+/// Lines are kept exactly as written, without their newline.
+/// Once the buffer holds `capacity` lines, each new one evicts the oldest,
+/// so the capacity should cover as many lines as the sender has in flight.
+///
+/// ```
+/// use g_code::emit::{format_gcode_io_resendable, FormatOptions, ResendBuffer};
+/// use g_code::parse::file_parser;
+/// use g_code::send::response::{parse, Response};
+///
+/// let file = file_parser("G28\nG1 X10\nG1 Y10\nG1 X0").unwrap();
+/// let opts = FormatOptions { line_numbers: true, checksums: true, ..Default::default() };
+/// let mut buffer = ResendBuffer::new(2);
+/// let mut output = vec![];
+/// format_gcode_io_resendable(file.iter_emit_tokens(), opts, &mut output, &mut buffer).unwrap();
+///
+/// if let Response::Resend(line_number) = parse("Resend: 3") {
+///     let lines = buffer.resend_from(line_number).unwrap().collect::<Vec<_>>();
+///     assert_eq!(lines, [&b"N3 G1 Y10*83"[..], b"N4 G1 X0*100"]);
+/// }
+/// assert!(buffer.resend_from(1).is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResendBuffer {
+    capacity: usize,
+    lines: VecDeque<(u64, Vec<u8>)>,
+    /// The highest line number evicted so far
+    evicted: Option<u64>,
+}
+
+impl ResendBuffer {
+    /// An empty buffer that keeps up to `capacity` lines.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lines: VecDeque::new(),
+            evicted: None,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of lines kept.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Keep a line that was written with this line number, evicting the oldest line if full.
+    pub fn push(&mut self, line_number: u64, line: impl Into<Vec<u8>>) {
+        if self.lines.len() == self.capacity {
+            match self.lines.pop_front() {
+                Some((evicted, _)) => {
+                    self.evicted = self.evicted.max(Some(evicted));
+                }
+                // Nothing is ever kept
+                None => {
+                    self.evicted = self.evicted.max(Some(line_number));
+                    return;
+                }
+            }
+        }
+        self.lines.push_back((line_number, line.into()));
+    }
+
+    /// The line written with this line number, if it is still kept.
+    pub fn get(&self, line_number: u64) -> Option<&[u8]> {
+        self.lines
+            .iter()
+            .rfind(|(number, _)| *number == line_number)
+            .map(|(_, line)| line.as_slice())
+    }
+
+    /// The line with this line number and every line written after it, in order,
+    /// which is what firmware expects to receive again after a `Resend: <n>`.
+    ///
+    /// Line numbers are expected to increase, as they do with [FormatOptions::line_numbers]:
+    /// a number that isn't kept is taken to have been evicted if it is no higher than one that was.
+    pub fn resend_from(
+        &self,
+        line_number: u64,
+    ) -> Result<impl Iterator<Item = &[u8]>, ResendError> {
+        match self
+            .lines
+            .iter()
+            .rposition(|(number, _)| *number == line_number)
+        {
+            Some(start) => Ok(self
+                .lines
+                .iter()
+                .skip(start)
+                .map(|(_, line)| line.as_slice())),
+            None if self.evicted >= Some(line_number) => Err(ResendError::Evicted {
+                line_number,
+                oldest: self.lines.front().map(|(number, _)| *number),
+            }),
+            None => Err(ResendError::NotWritten(line_number)),
+        }
+    }
+}
+
+/// Reasons that a [ResendBuffer] can't resend a line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResendError {
+    /// The line was written, but has since been evicted to make room for newer ones.
+    Evicted {
+        line_number: u64,
+        /// The oldest line number still kept, if any
+        oldest: Option<u64>,
+    },
+    /// No line with this number has been written.
+    NotWritten(u64),
+}
+
+impl fmt::Display for ResendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Evicted {
+                line_number,
+                oldest: Some(oldest),
+            } => write!(
+                f,
+                "line N{} is too old to resend, the oldest kept is N{}",
+                line_number, oldest
+            ),
+            Self::Evicted {
+                line_number,
+                oldest: None,
+            } => write!(f, "line N{} is too old to resend", line_number),
+            Self::NotWritten(line_number) => {
+                write!(f, "line N{} has not been written", line_number)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResendError {}
+
+/// Write a sequence of tokens as GCode to an [io::Write] like [format_gcode_io](super::format_gcode_io),
+/// keeping each line with a line number in a [ResendBuffer] as it is written.
+///
+/// Line numbers come from [FormatOptions::line_numbers] or from `N` fields in the token stream.
+/// Since a resend request would be ambiguous, two lines with the same number fail with
+/// [io::ErrorKind::InvalidData], wrapping a [DuplicateLineNumber] that counts lines of the output.
+/// Lines before the second one have been written by then.
+pub fn format_gcode_io_resendable<'b, W, I>(
+    tokens: I,
+    opts: FormatOptions,
+    w: W,
+    buffer: &mut ResendBuffer,
+) -> io::Result<()>
+where
+    W: io::Write,
+    I: IntoIterator,
+    I::Item: Borrow<Token<'b>>,
+{
+    let mut w = io::BufWriter::new(w);
+    let mut layout = Layout::new(opts, true);
+    let mut tokens = tokens.into_iter().peekable();
+    // Output that has been laid out, but not yet matched up with its line
+    let mut pending = String::new();
+    // Where each line number was first written, as an index into the lines of the output
+    let mut seen = HashMap::new();
+    let mut lines = 0;
+    let mut started = false;
+    let mut finished = false;
+    while !finished {
+        // Writing to a String cannot fail
+        let _ = if !started {
+            started = true;
+            layout.start(&mut pending)
+        } else if let Some(token) = tokens.next() {
+            layout.feed(token.borrow(), tokens.peek().is_some(), &mut pending)
+        } else {
+            finished = true;
+            layout.finish(&mut pending)
+        };
+        let mut consumed = 0;
+        if lines == 0 && pending.starts_with('\u{feff}') {
+            consumed = '\u{feff}'.len_utf8();
+        }
+        for line in layout
+            .written
+            .iter_mut()
+            .flat_map(|written| written.drain(..))
+        {
+            // The newline that ended the line before
+            if lines > 0 {
+                consumed += 1;
+            }
+            let text = &pending[consumed..consumed + line.stats.bytes];
+            consumed += line.stats.bytes;
+            if let Some(number) = line.number {
+                if let Some(first) = seen.insert(number, lines) {
+                    w.write_all(&pending.as_bytes()[..consumed - line.stats.bytes])?;
+                    w.flush()?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        DuplicateLineNumber {
+                            number,
+                            first,
+                            second: lines,
+                        },
+                    ));
+                }
+                buffer.push(number, text);
+            }
+            lines += 1;
+        }
+        if finished {
+            consumed = pending.len();
+        }
+        w.write_all(&pending.as_bytes()[..consumed])?;
+        pending.drain(..consumed);
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::format_gcode_fmt;
+    use crate::parse::file_parser;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn recent_lines_can_be_resent() {
+        let src = (1..=10)
+            .map(|i| format!("G1 X{} ;move {}", i, i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let tokens = file_parser(&src)
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let opts = FormatOptions {
+            line_numbers: true,
+            checksums: true,
+            ..Default::default()
+        };
+        let mut buffer = ResendBuffer::new(4);
+        let mut output = vec![];
+        format_gcode_io_resendable(&tokens, opts, &mut output, &mut buffer).unwrap();
+        let mut formatted = String::new();
+        format_gcode_fmt(&tokens, opts, &mut formatted).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), formatted);
+        assert_eq!(buffer.len(), 4);
+
+        // Firmware garbled N7 while N10 was the last line sent
+        let resent = buffer.resend_from(7).unwrap().collect::<Vec<_>>();
+        let expected = formatted.lines().skip(6).collect::<Vec<_>>();
+        assert_eq!(
+            resent,
+            expected
+                .iter()
+                .map(|line| line.as_bytes())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(buffer.get(7), Some(b"N7 G1 X7*96;move 7".as_ref()));
+
+        assert_eq!(buffer.get(6), None);
+        assert_eq!(
+            buffer.resend_from(6).err(),
+            Some(ResendError::Evicted {
+                line_number: 6,
+                oldest: Some(7),
+            })
+        );
+        assert_eq!(
+            buffer.resend_from(11).err(),
+            Some(ResendError::NotWritten(11))
+        );
+        assert_eq!(
+            buffer.resend_from(6).err().unwrap().to_string(),
+            "line N6 is too old to resend, the oldest kept is N7"
+        );
+    }
+
+    #[test]
+    fn buffered_lines_match_formatted_output() {
+        for src in [
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/blank_lines.gcode"),
+            include_str!("../../tests/bom_crlf.gcode"),
+            "%\nN1 G28*18;home\nG1 X1 (inline) ;eol\n%",
+            "",
+        ]
+        .iter()
+        {
+            let tokens = file_parser(src)
+                .unwrap()
+                .iter_emit_tokens()
+                .collect::<Vec<_>>();
+            for bits in 0..32u8 {
+                let opts = FormatOptions {
+                    line_numbers: true,
+                    checksums: bits & 1 != 0,
+                    delimit_with_percent: bits & 2 != 0,
+                    newline_before_comment: bits & 4 != 0,
+                    preserve_blank_lines: bits & 8 != 0,
+                    byte_order_mark: bits & 16 != 0,
+                    ..Default::default()
+                };
+                let mut buffer = ResendBuffer::new(10_000);
+                let mut output = vec![];
+                format_gcode_io_resendable(&tokens, opts, &mut output, &mut buffer).unwrap();
+                let mut formatted = String::new();
+                format_gcode_fmt(&tokens, opts, &mut formatted).unwrap();
+                assert_eq!(String::from_utf8(output).unwrap(), formatted, "{:?}", opts);
+
+                let numbered = formatted
+                    .trim_start_matches('\u{feff}')
+                    .lines()
+                    .filter(|line| line.starts_with('N'))
+                    .collect::<Vec<_>>();
+                assert_eq!(buffer.len(), numbered.len(), "{:?}", opts);
+                for (number, line) in (1..).zip(numbered) {
+                    assert_eq!(buffer.get(number), Some(line.as_bytes()), "{:?}", opts);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_line_numbers_are_refused() {
+        let file = file_parser("N1 G28 ;home\nN2 G1 X1\nN1 G1 X2\nN3 G1 X3").unwrap();
+        let mut buffer = ResendBuffer::new(8);
+        let mut output = vec![];
+        let err = format_gcode_io_resendable(
+            file.iter_emit_tokens(),
+            FormatOptions::default(),
+            &mut output,
+            &mut buffer,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<DuplicateLineNumber>(),
+            Some(&DuplicateLineNumber {
+                number: 1,
+                first: 0,
+                second: 2,
+            })
+        );
+        assert_eq!(output, b"N1 G28 ;home\nN2 G1 X1\n");
+
+        // Numbering the lines afresh replaces the repeated numbers
+        let mut output = vec![];
+        let opts = FormatOptions {
+            line_numbers: true,
+            ..Default::default()
+        };
+        format_gcode_io_resendable(file.iter_emit_tokens(), opts, &mut output, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer.get(4), Some(b"N4 G1 X3".as_ref()));
+    }
+}
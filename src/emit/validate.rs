@@ -0,0 +1,347 @@
+use std::fmt;
+
+use super::{Command, Value};
+
+/// The firmware that a program is written for, which decides some of the limits that
+/// [Command::validate] checks against.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Flavor {
+    /// No firmware-specific limits.
+    #[default]
+    Generic,
+    /// [GRBL](https://github.com/gnea/grbl), which rejects spindle speeds above its `$30` setting.
+    Grbl(MachineLimits),
+    /// [Marlin](https://marlinfw.org), with the limits of the machine it runs on.
+    Marlin(MachineLimits),
+}
+
+impl Flavor {
+    /// The limits of the machine, if the flavor knows them.
+    pub fn limits(&self) -> MachineLimits {
+        match self {
+            Self::Grbl(limits) | Self::Marlin(limits) => *limits,
+            Self::Generic => MachineLimits::default(),
+        }
+    }
+}
+
+/// Physical limits of a machine. Unset limits are not checked.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct MachineLimits {
+    pub max_spindle_speed: Option<f64>,
+    /// Maximum acceleration of the X, Y, and Z axes, in program units per second squared.
+    ///
+    /// When unset, [estimate_duration](crate::interpret::estimate_duration) takes moves to reach their feed rate at once.
+    pub max_acceleration: Option<[f64; 3]>,
+    /// How far the path may stray from a corner while taking it without stopping, in program units,
+    /// which decides how fast corners are taken as in GRBL's planner.
+    ///
+    /// When unset, [estimate_duration](crate::interpret::estimate_duration) takes corners without slowing down.
+    pub junction_deviation: Option<f64>,
+    /// The longest message the machine's display shows, in characters,
+    /// as checked for [display_message](super::display_message).
+    pub max_message_length: Option<usize>,
+}
+
+/// A constraint on the arguments of a [Command], declared alongside it in `impl_commands!`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgRule {
+    /// The argument must be zero or more.
+    NonNegative(&'static str),
+    /// The argument must be more than zero.
+    Positive(&'static str),
+    /// The argument must be zero or more, and no more than [MachineLimits::max_spindle_speed].
+    SpindleSpeed(&'static str),
+    /// An arc must have either a radius `R` or a center offset `I`/`J`/`K`, but not both.
+    RadiusXorCenter,
+    /// A message must be no longer than [MachineLimits::max_message_length].
+    MessageLength,
+}
+
+impl ArgRule {
+    /// The argument this rule constrains on its own, if any.
+    fn letters(&self) -> Option<&'static str> {
+        match self {
+            Self::NonNegative(letters) | Self::Positive(letters) | Self::SpindleSpeed(letters) => {
+                Some(letters)
+            }
+            Self::RadiusXorCenter | Self::MessageLength => None,
+        }
+    }
+
+    fn check(&self, command: &Command, flavor: Flavor, errors: &mut Vec<ArgError>) {
+        if let Some(letters) = self.letters() {
+            let value = match command.get(letters) {
+                Some(field) => &field.value,
+                None => return,
+            };
+            let number = match value {
+                Value::String(_) | Value::Text(_) => {
+                    errors.push(ArgError::NotANumber(letters.to_string()));
+                    return;
+                }
+                other => other.as_f64().unwrap_or(f64::NAN),
+            };
+            match self {
+                Self::NonNegative(_) | Self::SpindleSpeed(_) if number < 0. => {
+                    errors.push(ArgError::Negative(letters.to_string(), number))
+                }
+                Self::Positive(_) if number <= 0. => {
+                    errors.push(ArgError::NotPositive(letters.to_string(), number))
+                }
+                Self::SpindleSpeed(_) => {
+                    if let Some(max) = flavor.limits().max_spindle_speed {
+                        if number > max {
+                            errors.push(ArgError::SpindleSpeedTooHigh { speed: number, max });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        } else if let Self::RadiusXorCenter = self {
+            let has_radius = command.get("R").is_some();
+            let has_center = ["I", "J", "K"].iter().any(|l| command.get(l).is_some());
+            if has_radius == has_center {
+                errors.push(ArgError::RadiusXorCenter);
+            }
+        } else if let (Self::MessageLength, Some(max)) = (self, flavor.limits().max_message_length)
+        {
+            for arg in command.iter_args() {
+                if let Value::Text(text) = &arg.value {
+                    if text.len() > max {
+                        errors.push(ArgError::MessageTooLong {
+                            length: text.len(),
+                            max,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reasons that [Command::validate] can reject a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArgError {
+    /// The command does not take this argument.
+    Unexpected(String),
+    /// A numeric argument had a string value.
+    NotANumber(String),
+    Negative(String, f64),
+    NotPositive(String, f64),
+    SpindleSpeedTooHigh {
+        speed: f64,
+        max: f64,
+    },
+    /// An arc had both a radius and a center offset, or neither.
+    RadiusXorCenter,
+    /// A message was longer than the display shows.
+    MessageTooLong {
+        length: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unexpected(letters) => {
+                write!(f, "{} is not an argument of this command", letters)
+            }
+            Self::NotANumber(letters) => write!(f, "{} must be a number", letters),
+            Self::Negative(letters, value) => {
+                write!(f, "{} must not be negative, but was {}", letters, value)
+            }
+            Self::NotPositive(letters, value) => {
+                write!(f, "{} must be positive, but was {}", letters, value)
+            }
+            Self::SpindleSpeedTooHigh { speed, max } => write!(
+                f,
+                "spindle speed {} is above the machine's maximum of {}",
+                speed, max
+            ),
+            Self::RadiusXorCenter => write!(
+                f,
+                "arcs need either a radius (R) or a center offset (I, J, K), but not both"
+            ),
+            Self::MessageTooLong { length, max } => write!(
+                f,
+                "message is {} characters long, but the display shows at most {}",
+                length, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArgError {}
+
+impl<'a> Command<'a> {
+    /// Check the command's arguments against the rules declared for it.
+    ///
+    /// All broken rules are reported, not just the first.
+    pub fn validate(&self, flavor: Flavor) -> Result<(), Vec<ArgError>> {
+        let mut errors = vec![];
+        for rule in self.rules() {
+            rule.check(self, flavor, &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Like [Command::push], but rejects arguments that the command does not take
+    /// or that break a rule on their own.
+    ///
+    /// Rules spanning several arguments, like [ArgRule::RadiusXorCenter],
+    /// are left to [Command::validate] once the command is complete.
+    pub fn push_validated(
+        &mut self,
+        arg: super::Field<'a>,
+        flavor: Flavor,
+    ) -> Result<(), Vec<ArgError>> {
+        if !self.accepts(&arg.letters) {
+            return Err(vec![ArgError::Unexpected(arg.letters.to_string())]);
+        }
+        let letters = arg.letters.to_ascii_uppercase();
+        let mut single = Command {
+            name: self.name.clone(),
+            args: vec![arg],
+        };
+        let mut errors = vec![];
+        for rule in self.rules() {
+            if rule.letters() == Some(letters.as_str()) {
+                rule.check(&single, flavor, &mut errors);
+            }
+        }
+        if errors.is_empty() {
+            self.args.append(&mut single.args);
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::*;
+    use pretty_assertions::assert_eq;
+
+    fn fields(args: &[&str]) -> impl Iterator<Item = Field<'static>> {
+        args.iter()
+            .map(|s| s.parse::<Field>().unwrap())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn dwell_must_not_be_negative() {
+        assert_eq!(dwell(fields(&["P0.5"])).validate(Flavor::Generic), Ok(()));
+        assert_eq!(
+            dwell(fields(&["P-5"])).validate(Flavor::Generic),
+            Err(vec![ArgError::Negative("P".to_string(), -5.)])
+        );
+        assert_eq!(
+            dwell(fields(&["P\"x\""])).validate(Flavor::Generic),
+            Err(vec![ArgError::NotANumber("P".to_string())])
+        );
+    }
+
+    #[test]
+    fn feed_must_be_positive() {
+        assert_eq!(
+            linear_interpolation(fields(&["X1", "F300"]))
+                .validate(Flavor::Marlin(MachineLimits::default())),
+            Ok(())
+        );
+        assert_eq!(
+            linear_interpolation(fields(&["X1", "F0"]))
+                .validate(Flavor::Marlin(MachineLimits::default())),
+            Err(vec![ArgError::NotPositive("F".to_string(), 0.)])
+        );
+    }
+
+    #[test]
+    fn arcs_need_radius_xor_center() {
+        let arc = |args| clockwise_circular_interpolation(fields(args));
+        assert_eq!(arc(&["X1", "Y1", "R1"]).validate(Flavor::Generic), Ok(()));
+        assert_eq!(
+            arc(&["X1", "Y1", "I1", "J0"]).validate(Flavor::Generic),
+            Ok(())
+        );
+        assert_eq!(
+            arc(&["X1", "Y1"]).validate(Flavor::Generic),
+            Err(vec![ArgError::RadiusXorCenter])
+        );
+        assert_eq!(
+            counterclockwise_circular_interpolation(fields(&["X1", "R1", "K1", "F-1"]))
+                .validate(Flavor::Generic),
+            Err(vec![
+                ArgError::NotPositive("F".to_string(), -1.),
+                ArgError::RadiusXorCenter
+            ])
+        );
+    }
+
+    #[test]
+    fn message_length_is_limited_by_flavor() {
+        let marlin = Flavor::Marlin(MachineLimits {
+            max_message_length: Some(20),
+            ..Default::default()
+        });
+        let short = display_message("Printing").unwrap();
+        let long = display_message("Printing layer 120 of 240").unwrap();
+        assert_eq!(short.validate(marlin), Ok(()));
+        assert_eq!(long.validate(Flavor::Generic), Ok(()));
+        assert_eq!(
+            long.validate(marlin),
+            Err(vec![ArgError::MessageTooLong {
+                length: 25,
+                max: 20
+            }])
+        );
+        assert_eq!(set_led(255, 0, 0).validate(marlin), Ok(()));
+    }
+
+    #[test]
+    fn spindle_speed_is_limited_by_flavor() {
+        let grbl = Flavor::Grbl(MachineLimits {
+            max_spindle_speed: Some(1000.),
+            ..Default::default()
+        });
+        let spindle = start_spindle_clockwise(fields(&["P12000"]));
+        assert_eq!(spindle.validate(Flavor::Generic), Ok(()));
+        assert_eq!(
+            spindle.validate(grbl),
+            Err(vec![ArgError::SpindleSpeedTooHigh {
+                speed: 12000.,
+                max: 1000.
+            }])
+        );
+        assert_eq!(
+            start_spindle_counterclockwise(fields(&["P-1"])).validate(grbl),
+            Err(vec![ArgError::Negative("P".to_string(), -1.)])
+        );
+    }
+
+    #[test]
+    fn push_validated_checks_single_arguments() {
+        let mut arc = clockwise_circular_interpolation(std::iter::empty());
+        for arg in fields(&["X1", "Y1", "R2"]) {
+            arc.push_validated(arg, Flavor::Generic).unwrap();
+        }
+        assert_eq!(
+            arc.push_validated("F0".parse().unwrap(), Flavor::Generic),
+            Err(vec![ArgError::NotPositive("F".to_string(), 0.)])
+        );
+        assert_eq!(
+            arc.push_validated("Q1".parse().unwrap(), Flavor::Generic),
+            Err(vec![ArgError::Unexpected("Q".to_string())])
+        );
+        assert_eq!(arc.iter_args().count(), 3);
+        assert_eq!(arc.validate(Flavor::Generic), Ok(()));
+    }
+}
@@ -0,0 +1,149 @@
+//! Hashing and comparison of programs by what they do rather than how they are written.
+//!
+//! Programs are normalized before comparison:
+//!
+//! * comments, whitespace, checksums, and `N` line numbers are skipped
+//! * lines without any other fields are skipped
+//! * letters are uppercased
+//! * numbers are written as reduced fractions, so `X1`, `X1.`, and `X1.00` are all `X1`, and `X1.5` is `X3/2`
+//! * strings are kept as they are, including their delimiting quotes
+//! * extended commands are written as their uppercased name followed by `KEY=value` parameters,
+//!   with the keys uppercased and the values kept as they are
+//! * meta commands are written as their indentation and keyword followed by a space and their expression,
+//!   since indentation delimits their blocks
+//!
+//! Fields on a line are separated by a space and each line ends with a newline.
+//! These rules are part of the hash's stability guarantee and will not change between versions.
+use sha2::{Digest, Sha256};
+
+use crate::parse::ast::{File, Line};
+use crate::parse::token::{real_to_ratio, Field, Value};
+
+/// SHA-256 of the normalized program, suitable as a cache key.
+pub fn semantic_hash(file: &File) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for line in normalized_lines(file) {
+        hasher.update(line.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// Whether two programs are the same once normalized.
+pub fn semantic_eq(a: &File, b: &File) -> bool {
+    normalized_lines(a).eq(normalized_lines(b))
+}
+
+fn normalized_lines<'a>(file: &'a File) -> impl Iterator<Item = String> + 'a {
+    file.iter().filter_map(normalize_line)
+}
+
+fn normalize_line(line: &Line) -> Option<String> {
+    let mut acc = String::new();
+    if let Some(command) = line.extended_command() {
+        acc.push_str(command.name());
+        for (key, value) in command.iter_params() {
+            acc.push(' ');
+            acc.push_str(&key.to_ascii_uppercase());
+            acc.push('=');
+            acc.push_str(value);
+        }
+    }
+    if let Some(command) = line.meta_command() {
+        acc.push_str(command.indent());
+        acc.push_str(command.keyword());
+        if !command.expression_text().is_empty() {
+            acc.push(' ');
+            acc.push_str(command.expression_text());
+        }
+    }
+    for field in line
+        .iter_fields()
+        .filter(|f| !f.letters.eq_ignore_ascii_case("N"))
+    {
+        if !acc.is_empty() {
+            acc.push(' ');
+        }
+        normalize_field(field, &mut acc);
+    }
+    if acc.is_empty() {
+        None
+    } else {
+        acc.push('\n');
+        Some(acc)
+    }
+}
+
+fn normalize_field(field: &Field, acc: &mut String) {
+    acc.push_str(&field.letters.to_ascii_uppercase());
+    match &field.value {
+        Value::Integer(i) => acc.push_str(&i.to_string()),
+        // Ratio is always kept reduced, and its Display omits a denominator of 1.
+        // A float is hashed as a Ratio too, so that hashes do not depend on the number type.
+        Value::Rational(r) => match real_to_ratio(r) {
+            Some(r) => acc.push_str(&r.to_string()),
+            None => acc.push_str(&r.to_string()),
+        },
+        Value::String(s) | Value::Raw(s) => acc.push_str(s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use pretty_assertions::assert_eq;
+
+    fn hex(hash: [u8; 32]) -> String {
+        hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn normalization_rules() {
+        let lines = |gcode| {
+            normalized_lines(&file_parser(gcode).unwrap())
+                .collect::<Vec<_>>()
+                .concat()
+        };
+        assert_eq!(
+            lines("N10 g1 (move) x1.50 Y-0.25 Z1. E2.00 F300*99;comment\n\n;only a comment\nN20\nM117 P\"Hi\"\"\""),
+            "G1 X3/2 Y-1/4 Z1 E2 F300\nM117 P\"Hi\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn formatting_differences_are_equal() {
+        let a = file_parser("G1 X1.50 Y2 ;fast\nG4 P1").unwrap();
+        let b = file_parser("N1 G1 X1.5 Y2.0*54\n\n(pause)\nN2 g4 p1.").unwrap();
+        assert!(semantic_eq(&a, &b));
+        assert_eq!(semantic_hash(&a), semantic_hash(&b));
+
+        let moved_line_break = file_parser("G1 X1.5\nY2\nG4 P1").unwrap();
+        assert!(!semantic_eq(&a, &moved_line_break));
+        assert_ne!(semantic_hash(&a), semantic_hash(&moved_line_break));
+    }
+
+    #[test]
+    fn corpus_hashes_are_stable() {
+        let corpus = [
+            (
+                include_str!("../tests/vandy_commodores_logo.gcode"),
+                "8cfd481b802b4144cef6128b075723399b0b4f7793ac30cfc113e2d53068d66d",
+            ),
+            (
+                include_str!("../tests/ncviewer_sample.gcode"),
+                "5b44221de10a2838d18dc471e1568ec26a81fe04f93947d93a89a83867d56813",
+            ),
+            (
+                include_str!("../tests/blank_lines.gcode"),
+                "f212b3663b94cf39323d71ed66f953cd8cbfb6439d50a8e1fbad3e715e491554",
+            ),
+            (
+                include_str!("../tests/square.gcode"),
+                "6559b1657b2ed4201cb8237dcee1f14683d8c00f005e967949ab31b529941666",
+            ),
+        ];
+        for (gcode, expected) in corpus.iter() {
+            assert_eq!(hex(semantic_hash(&file_parser(gcode).unwrap())), *expected);
+        }
+    }
+}
@@ -0,0 +1,1545 @@
+use num::ToPrimitive;
+use num_rational::Ratio;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::emit::{
+    self, clockwise_circular_interpolation, counterclockwise_circular_interpolation,
+    linear_interpolation, rapid_positioning, Command, MachineLimits,
+};
+use crate::parse::ast::{File, Line, Span, Spanned};
+use crate::parse::token::{real_to_ratio, Field, Real, Value};
+#[cfg(feature = "uom")]
+use uom::si::{
+    f64::{Length, Time, Velocity},
+    length::{inch, millimeter},
+    time::minute,
+};
+
+/// How numbers for an axis are interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DistanceMode {
+    /// Numbers are positions in the current coordinate system.
+    #[default]
+    Absolute,
+    /// Numbers are increments from the current position.
+    Relative,
+}
+
+/// Units of length that numbers are given in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Units {
+    /// Set by `G21`.
+    #[default]
+    Millimeters,
+    /// Set by `G20`.
+    Inches,
+}
+
+/// How the `F` word is interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FeedRateMode {
+    /// Set by `G93`: each move takes one over the feed rate minutes.
+    InverseTime,
+    /// Set by `G94`: the feed rate is in units per minute.
+    #[default]
+    UnitsPerMinute,
+    /// Set by `G95`: the feed rate is in units per revolution of the spindle.
+    UnitsPerRevolution,
+}
+
+/// Positions of the X, Y, and Z axes, in that order.
+pub type Xyz = [Ratio<i64>; 3];
+
+/// Machine state tracked while walking through a program line by line.
+///
+/// Only the state needed for extrusion and position analysis is tracked so far:
+///
+/// * `G0`-`G3` and probing (`G38.2`-`G38.5`) move to their `X`/`Y`/`Z` end point,
+///   assuming that probes reach it
+/// * `G53` on a move takes its end point in machine coordinates, for that line only
+/// * `G54`-`G59` and `G59.1`-`G59.3` select one of nine work coordinate systems
+/// * `G10 L2 P<n>` sets the offset of work coordinate system `n` (`P0` is the active one),
+///   and `G10 L20 P<n>` sets it so that the current position has the given coordinates
+/// * `G90`/`G91` set the distance mode for every axis, including E
+/// * `M82`/`M83` override the distance mode for E alone
+/// * `G92` offsets the position of `X`, `Y`, `Z`, and `E` without moving, and `G92.1` clears the offset
+/// * `M206` sets the home offset of `X`, `Y`, and `Z`,
+///   and `M428` sets it so that the current position becomes zero, as Marlin does
+/// * `T` selects the active tool
+/// * `G20`/`G21` select the [Units] of later numbers
+/// * `G93`-`G95` select the [FeedRateMode], and `F` sets the feed rate
+///
+/// Program coordinates are in the active work coordinate system with the `G92` offset and home offset applied.
+/// Machine coordinates take all three offsets back out: see [State::machine_position].
+///
+/// Lengths are in the current [Units]: when `G20` or `G21` switches units, positions, offsets,
+/// and a feed rate that is a length are converted so that they stay the same distance.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct State {
+    /// Position in program coordinates.
+    pub position: Xyz,
+    pub mode: DistanceMode,
+    pub e_position: Ratio<i64>,
+    pub e_mode: DistanceMode,
+    pub active_tool: u64,
+    /// Offsets of the work coordinate systems `G54` through `G59.3`, relative to the machine.
+    pub work_offsets: [Xyz; 9],
+    /// Index into [State::work_offsets], where `0` is `G54`.
+    pub active_work_offset: usize,
+    /// Offset set by `G92`, relative to the active work coordinate system.
+    pub g92_offset: Xyz,
+    /// Offset set by `M206` or `M428`, which is added to machine coordinates rather than subtracted.
+    pub home_offset: Xyz,
+    pub units: Units,
+    pub feed_rate_mode: FeedRateMode,
+    /// The last `F` word given, if any.
+    pub feed_rate: Option<Ratio<i64>>,
+}
+
+impl State {
+    /// Position in program coordinates.
+    pub fn program_position(&self) -> Xyz {
+        self.position
+    }
+
+    /// Position in machine coordinates.
+    pub fn machine_position(&self) -> Xyz {
+        let mut machine = self.position;
+        for (axis, position) in machine.iter_mut().enumerate() {
+            *position += self.offset(axis);
+        }
+        machine
+    }
+
+    /// Total offset of program coordinates from machine coordinates for an axis.
+    fn offset(&self, axis: usize) -> Ratio<i64> {
+        self.work_offsets[self.active_work_offset][axis] + self.g92_offset[axis]
+            - self.home_offset[axis]
+    }
+
+    /// Recompute the program position after the offsets change, keeping the machine still.
+    fn set_offsets(&mut self, machine: Xyz, change: impl FnOnce(&mut Self)) {
+        change(self);
+        for (axis, machine) in machine.iter().enumerate() {
+            self.position[axis] = machine - self.offset(axis);
+        }
+    }
+
+    /// Switch to other units, converting every length already known so that it stays the same distance.
+    ///
+    /// The feed rate is converted if it was given in `feed_rate_mode`, which it is a length in.
+    fn set_units(&mut self, units: Units, feed_rate_mode: FeedRateMode) {
+        let factor = match (self.units, units) {
+            (Units::Millimeters, Units::Inches) => Ratio::new(5, 127),
+            (Units::Inches, Units::Millimeters) => Ratio::new(127, 5),
+            _ => return,
+        };
+        self.units = units;
+        // An inverse time feed rate is not a length
+        let is_length = feed_rate_mode != FeedRateMode::InverseTime;
+        let feed_rate = self.feed_rate.as_mut().filter(|_| is_length);
+        let lengths = self
+            .position
+            .iter_mut()
+            .chain(self.work_offsets.iter_mut().flatten())
+            .chain(self.g92_offset.iter_mut())
+            .chain(self.home_offset.iter_mut())
+            .chain(std::iter::once(&mut self.e_position))
+            .chain(feed_rate);
+        for length in lengths {
+            *length *= factor;
+        }
+    }
+
+    /// Apply a [Line] to the state, returning how far the extruder moved.
+    ///
+    /// E words are only treated as motion on `G0`-`G3` moves and `G5`/`G5.1` splines,
+    /// since other commands (e.g. `M203 E`) use E for settings.
+    pub fn step(&mut self, line: &Line) -> Ratio<i64> {
+        let mut e_delta = Ratio::from_integer(0);
+        let feed_rate_mode = self.feed_rate_mode;
+        let mut is_move = false;
+        let mut is_probe = false;
+        let mut in_machine_coordinates = false;
+        let mut is_set_position = false;
+        let mut is_reset_offset = false;
+        let mut is_set_work_offset = false;
+        let mut is_set_home_offset = false;
+        let mut is_home_offset_here = false;
+        let mut work_offset = None;
+        let mut units = None;
+        let mut feed_rate = None;
+        let (mut l, mut p) = (None, None);
+        let mut axes: [Option<Ratio<i64>>; 3] = [None; 3];
+        let mut e = None;
+        for field in line.iter_fields() {
+            match (field.letters.to_ascii_uppercase().as_str(), &field.value) {
+                ("G", Value::Integer(0..=3 | 5)) => is_move = true,
+                ("G", Value::Rational(r)) if tenths(r) == Some(51) => is_move = true,
+                ("G", Value::Rational(r)) if matches!(tenths(r), Some(382..=385)) => {
+                    is_probe = true
+                }
+                ("G", Value::Integer(10)) => is_set_work_offset = true,
+                ("G", Value::Integer(53)) => in_machine_coordinates = true,
+                ("G", Value::Integer(n @ 54..=59)) => work_offset = Some(*n as usize - 54),
+                ("G", Value::Rational(r)) if matches!(tenths(r), Some(591..=593)) => {
+                    work_offset = tenths(r).map(|n| n as usize - 585)
+                }
+                ("G", Value::Integer(90)) => {
+                    self.mode = DistanceMode::Absolute;
+                    self.e_mode = DistanceMode::Absolute;
+                }
+                ("G", Value::Integer(91)) => {
+                    self.mode = DistanceMode::Relative;
+                    self.e_mode = DistanceMode::Relative;
+                }
+                ("G", Value::Integer(92)) => is_set_position = true,
+                ("G", Value::Rational(r)) if tenths(r) == Some(921) => is_reset_offset = true,
+                ("G", Value::Integer(20)) => units = Some(Units::Inches),
+                ("G", Value::Integer(21)) => units = Some(Units::Millimeters),
+                ("G", Value::Integer(93)) => self.feed_rate_mode = FeedRateMode::InverseTime,
+                ("G", Value::Integer(94)) => self.feed_rate_mode = FeedRateMode::UnitsPerMinute,
+                ("G", Value::Integer(95)) => self.feed_rate_mode = FeedRateMode::UnitsPerRevolution,
+                ("F", _) => feed_rate = as_ratio(field).or(feed_rate),
+                ("M", Value::Integer(82)) => self.e_mode = DistanceMode::Absolute,
+                ("M", Value::Integer(83)) => self.e_mode = DistanceMode::Relative,
+                ("M", Value::Integer(206)) => is_set_home_offset = true,
+                ("M", Value::Integer(428)) => is_home_offset_here = true,
+                ("T", Value::Integer(tool)) => self.active_tool = *tool,
+                ("L", Value::Integer(n)) => l = Some(*n),
+                ("P", Value::Integer(n)) => p = Some(*n),
+                ("X", _) => axes[0] = as_ratio(field),
+                ("Y", _) => axes[1] = as_ratio(field),
+                ("Z", _) => axes[2] = as_ratio(field),
+                ("E", _) => e = as_ratio(field),
+                _ => {}
+            }
+        }
+
+        // Numbers on the line are already in the new units
+        if let Some(units) = units {
+            self.set_units(units, feed_rate_mode);
+        }
+        if feed_rate.is_some() {
+            self.feed_rate = feed_rate;
+        }
+        let machine = self.machine_position();
+        if let Some(index) = work_offset {
+            self.set_offsets(machine, |state| state.active_work_offset = index);
+        }
+        if is_reset_offset {
+            self.set_offsets(machine, |state| state.g92_offset = Xyz::default());
+        }
+        if is_set_home_offset {
+            self.set_offsets(machine, |state| {
+                for (axis, value) in axes.iter().enumerate() {
+                    if let Some(value) = value {
+                        state.home_offset[axis] = *value;
+                    }
+                }
+            });
+        } else if is_home_offset_here {
+            self.set_offsets(machine, |state| {
+                for (offset, position) in state.home_offset.iter_mut().zip(state.position.iter()) {
+                    *offset -= position;
+                }
+            });
+        }
+        // Firmware retraction is also G10, but without an L word
+        if let (true, Some(l @ (2 | 20)), Some(p @ 0..=9)) = (is_set_work_offset, l, p) {
+            let index = p
+                .checked_sub(1)
+                .map_or(self.active_work_offset, |p| p as usize);
+            self.set_offsets(machine, |state| {
+                for (axis, value) in axes.iter().enumerate() {
+                    if let Some(value) = value {
+                        state.work_offsets[index][axis] = if l == 2 {
+                            *value
+                        } else {
+                            // Chosen so that the current position is at the given coordinate
+                            machine[axis] - state.g92_offset[axis] - value
+                        };
+                    }
+                }
+            });
+        } else if is_set_position {
+            for (axis, value) in axes.iter().enumerate() {
+                if let Some(value) = value {
+                    self.g92_offset[axis] += self.position[axis] - value;
+                    self.position[axis] = *value;
+                }
+            }
+        } else if is_move || is_probe {
+            for (axis, value) in axes.iter().enumerate() {
+                if let Some(value) = value {
+                    if in_machine_coordinates {
+                        self.position[axis] = value - self.offset(axis);
+                    } else {
+                        match self.mode {
+                            DistanceMode::Absolute => self.position[axis] = *value,
+                            DistanceMode::Relative => self.position[axis] += value,
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(e) = e {
+            if is_set_position {
+                self.e_position = e;
+            } else if is_move {
+                e_delta = match self.e_mode {
+                    DistanceMode::Absolute => e - self.e_position,
+                    DistanceMode::Relative => e,
+                };
+                self.e_position += e_delta;
+            }
+        }
+        e_delta
+    }
+}
+
+#[cfg(feature = "uom")]
+impl Units {
+    /// A number given in these units as a [Length].
+    pub fn length(self, value: f64) -> Length {
+        match self {
+            Self::Millimeters => Length::new::<millimeter>(value),
+            Self::Inches => Length::new::<inch>(value),
+        }
+    }
+}
+
+/// The feed rate can't be given as a [Velocity] in this [FeedRateMode],
+/// as returned by [State::feed_velocity].
+#[cfg(feature = "uom")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedFeedRateMode(pub FeedRateMode);
+
+#[cfg(feature = "uom")]
+impl fmt::Display for UnsupportedFeedRateMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            FeedRateMode::InverseTime => write!(f, "inverse time feed rates are not a velocity"),
+            FeedRateMode::UnitsPerMinute => write!(f, "units per minute feed rates are supported"),
+            FeedRateMode::UnitsPerRevolution => write!(
+                f,
+                "units per revolution feed rates depend on the spindle speed"
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "uom")]
+impl std::error::Error for UnsupportedFeedRateMode {}
+
+#[cfg(feature = "uom")]
+impl State {
+    /// [State::program_position] as lengths in the current [Units].
+    pub fn position_length(&self) -> [Length; 3] {
+        self.position.map(|value| self.units.length(to_f64(value)))
+    }
+
+    /// The feed rate as a velocity in the current [Units] per minute, if one has been given.
+    ///
+    /// Only [FeedRateMode::UnitsPerMinute] feed rates are velocities.
+    pub fn feed_velocity(&self) -> Result<Option<Velocity>, UnsupportedFeedRateMode> {
+        if self.feed_rate_mode != FeedRateMode::UnitsPerMinute {
+            return Err(UnsupportedFeedRateMode(self.feed_rate_mode));
+        }
+        Ok(self
+            .feed_rate
+            .map(|feed_rate| self.units.length(to_f64(feed_rate)) / Time::new::<minute>(1.)))
+    }
+}
+
+#[cfg(feature = "uom")]
+fn to_f64(value: Ratio<i64>) -> f64 {
+    // Always representable, if not exactly
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+/// A G-code number with a decimal point (e.g. `38.2`) as a count of tenths (e.g. `382`).
+fn tenths(r: &Real) -> Option<i64> {
+    let tenths = real_to_ratio(r)? * 10;
+    tenths.is_integer().then(|| tenths.to_integer())
+}
+
+/// Whether the line waits on the user for an unknown length of time:
+/// a stop (`M0`/`M1`), a filament change (`M600`), or a pause (`M601`).
+///
+/// Time estimates should report these separately instead of adding them to the duration.
+pub fn is_pause(line: &Line) -> bool {
+    line.iter_fields().any(|field| {
+        field.letters.eq_ignore_ascii_case("M")
+            && matches!(field.value, Value::Integer(0 | 1 | 600 | 601))
+    })
+}
+
+/// Indices of the lines in a [File] that are pauses, as decided by [is_pause].
+pub fn pauses(file: &File) -> Vec<usize> {
+    file.iter()
+        .enumerate()
+        .filter(|(_, line)| is_pause(line))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// How long a [File] takes to run, as returned by [estimate_duration].
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct DurationEstimate {
+    /// Seconds taken by each line, in the order of [File::iter].
+    ///
+    /// Lines that neither move nor dwell take no time.
+    pub line_durations: Vec<f64>,
+}
+
+impl DurationEstimate {
+    /// Seconds taken by the whole file.
+    pub fn total(&self) -> f64 {
+        self.line_durations.iter().sum()
+    }
+}
+
+/// Estimate how long each line of a [File] takes to run.
+///
+/// Moves are planned as GRBL and Marlin plan them, in program units with feed rates per minute:
+/// each move speeds up and slows down at the [MachineLimits::max_acceleration] of its axes,
+/// and corners between moves are taken as fast as [MachineLimits::junction_deviation] allows.
+/// A backward and then a forward pass over the moves decide the speed each one starts at,
+/// and each move then takes the time of its trapezoidal speed profile.
+/// The machine comes to a stop at the end of the file and wherever it waits: at dwells, [pauses](is_pause),
+/// `M400`, and moves that can't be planned.
+///
+/// Arcs are taken to be in the XY plane, and are planned as a single move of their length
+/// in the direction of their chord. So are `G5` cubic and `G5.1` quadratic splines,
+/// whose control points are offset by `I`/`J` from the start and, for `G5`, by `P`/`Q` from the end.
+/// Moves that only extrude or retract are taken at their feed rate, without accelerating.
+/// `G4` dwells for `S` seconds or `P` milliseconds, as in Marlin.
+/// Pauses take no time, since they wait on the user, and moves without a feed rate
+/// or in [FeedRateMode::UnitsPerRevolution] are not counted.
+/// Moves in [FeedRateMode::InverseTime] take the time they are given.
+///
+/// ```
+/// use g_code::emit::MachineLimits;
+/// use g_code::interpret::estimate_duration;
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("G1 X100 F6000\nG4 S2").unwrap();
+/// assert_eq!(estimate_duration(&file, &MachineLimits::default()).line_durations, [1., 2.]);
+///
+/// // Speeding up to 100 mm/s and slowing back down each take a tenth of a second
+/// let limits = MachineLimits { max_acceleration: Some([1000.; 3]), ..Default::default() };
+/// assert!((estimate_duration(&file, &limits).total() - 3.1).abs() < 1e-9);
+/// ```
+pub fn estimate_duration(file: &File, limits: &MachineLimits) -> DurationEstimate {
+    let mut state = State::default();
+    let mut durations = vec![];
+    let mut planner = Planner {
+        limits,
+        blocks: vec![],
+        previous: None,
+    };
+    for (index, line) in file.iter().enumerate() {
+        durations.push(0.);
+        // In tenths of the G code, so that G5.1 is 51
+        let mut motion = None;
+        let mut waits = is_pause(line);
+        let (mut i, mut j, mut r) = (None, None, None);
+        let (mut p, mut q) = (None, None);
+        for field in line.iter_fields() {
+            let number = || as_ratio(field).and_then(|n| n.to_f64());
+            match (field.letters.to_ascii_uppercase().as_str(), &field.value) {
+                ("G", Value::Integer(n @ (0..=3 | 5))) => motion = Some(n * 10),
+                ("G", Value::Rational(g)) if tenths(g) == Some(51) => motion = Some(51),
+                ("G", Value::Integer(4)) => waits = true,
+                ("M", Value::Integer(400)) => waits = true,
+                ("I", _) => i = number(),
+                ("J", _) => j = number(),
+                ("R", _) => r = number(),
+                ("P", _) => p = number(),
+                ("Q", _) => q = number(),
+                _ => {}
+            }
+        }
+        if waits {
+            planner.flush(&mut durations);
+            durations[index] += dwell(line);
+        }
+
+        let start = state.machine_position().map(|n| n.to_f64().unwrap_or(0.));
+        let e_delta = state.step(line).to_f64().unwrap_or(0.).abs();
+        let end = state.machine_position().map(|n| n.to_f64().unwrap_or(0.));
+        let motion = match motion {
+            Some(motion) => motion,
+            None => continue,
+        };
+        let delta = [end[0] - start[0], end[1] - start[1], end[2] - start[2]];
+        let chord = delta.iter().map(|d| d * d).sum::<f64>().sqrt();
+        let offset = |from: [f64; 3], x: Option<f64>, y: Option<f64>| {
+            [from[0] + x.unwrap_or(0.), from[1] + y.unwrap_or(0.)]
+        };
+        let length = match motion {
+            20 | 30 => arc_length(start, end, motion == 30, i, j, r),
+            50 => bezier_length(start, end, &[offset(start, i, j), offset(end, p, q)]),
+            51 => bezier_length(start, end, &[offset(start, i, j)]),
+            _ => chord,
+        };
+        let feed_rate = state.feed_rate.and_then(|f| f.to_f64()).filter(|f| *f > 0.);
+        match (state.feed_rate_mode, feed_rate) {
+            (FeedRateMode::UnitsPerMinute, Some(feed_rate)) if length > 0. => {
+                let direction = if chord > 0. {
+                    delta.map(|d| d / chord)
+                } else {
+                    [0.; 3]
+                };
+                planner.push(index, length, direction, feed_rate / 60.);
+            }
+            (FeedRateMode::UnitsPerMinute, Some(feed_rate)) if e_delta > 0. => {
+                planner.flush(&mut durations);
+                durations[index] += e_delta / (feed_rate / 60.);
+            }
+            (FeedRateMode::InverseTime, Some(feed_rate)) => {
+                planner.flush(&mut durations);
+                durations[index] += 60. / feed_rate;
+            }
+            _ => planner.flush(&mut durations),
+        }
+    }
+    planner.flush(&mut durations);
+    DurationEstimate {
+        line_durations: durations,
+    }
+}
+
+/// Seconds that a `G4` on the line dwells for, or zero if it doesn't.
+fn dwell(line: &Line) -> f64 {
+    let mut is_dwell = false;
+    let mut seconds = 0.;
+    for field in line.iter_fields() {
+        let number = || as_ratio(field).and_then(|n| n.to_f64()).unwrap_or(0.);
+        match (field.letters.to_ascii_uppercase().as_str(), &field.value) {
+            ("G", Value::Integer(4)) => is_dwell = true,
+            ("S", _) => seconds += number(),
+            ("P", _) => seconds += number() / 1000.,
+            _ => {}
+        }
+    }
+    if is_dwell {
+        seconds.max(0.)
+    } else {
+        0.
+    }
+}
+
+/// Length of an arc in the XY plane, as a helix if it moves along Z too.
+///
+/// The center is offset from the start by `I` and `J`, or if neither is given,
+/// it is the one at distance `R` from both ends, which is further round the arc when `R` is negative.
+fn arc_length(
+    start: [f64; 3],
+    end: [f64; 3],
+    counterclockwise: bool,
+    i: Option<f64>,
+    j: Option<f64>,
+    r: Option<f64>,
+) -> f64 {
+    let planar = if i.is_some() || j.is_some() {
+        let (to_start_x, to_start_y) = (-i.unwrap_or(0.), -j.unwrap_or(0.));
+        let (to_end_x, to_end_y) = (
+            to_start_x + end[0] - start[0],
+            to_start_y + end[1] - start[1],
+        );
+        let angle = (to_start_x * to_end_y - to_start_y * to_end_x)
+            .atan2(to_start_x * to_end_x + to_start_y * to_end_y);
+        // An arc that ends where it started is a full circle
+        let sweep = match (counterclockwise, angle) {
+            (true, angle) if angle > 0. => angle,
+            (true, angle) => angle + 2. * PI,
+            (false, angle) if angle < 0. => -angle,
+            (false, angle) => 2. * PI - angle,
+        };
+        to_start_x.hypot(to_start_y) * sweep
+    } else if let Some(r) = r {
+        let chord = (end[0] - start[0]).hypot(end[1] - start[1]);
+        let short_sweep = 2. * (chord / (2. * r.abs())).min(1.).asin();
+        let sweep = if r < 0. {
+            2. * PI - short_sweep
+        } else {
+            short_sweep
+        };
+        r.abs() * sweep
+    } else {
+        0.
+    };
+    planar.hypot(end[2] - start[2])
+}
+
+/// Length of a Bézier curve in the XY plane from `start` to `end` with the given control points,
+/// as a helix if it moves along Z too.
+///
+/// The curve is measured along a polyline through points evenly spaced in its parameter,
+/// which is within a fraction of a percent for the gentle curves that splines in GCode describe.
+fn bezier_length(start: [f64; 3], end: [f64; 3], controls: &[[f64; 2]]) -> f64 {
+    const SEGMENTS: usize = 128;
+    let points = std::iter::once([start[0], start[1]])
+        .chain(controls.iter().copied())
+        .chain(std::iter::once([end[0], end[1]]))
+        .collect::<Vec<_>>();
+    // De Casteljau's algorithm
+    let at = |t: f64| {
+        let mut points = points.clone();
+        for len in (1..points.len()).rev() {
+            for k in 0..len {
+                points[k] = [
+                    points[k][0] + (points[k + 1][0] - points[k][0]) * t,
+                    points[k][1] + (points[k + 1][1] - points[k][1]) * t,
+                ];
+            }
+        }
+        points[0]
+    };
+    let mut planar = 0.;
+    let mut previous = at(0.);
+    for step in 1..=SEGMENTS {
+        let point = at(step as f64 / SEGMENTS as f64);
+        planar += (point[0] - previous[0]).hypot(point[1] - previous[1]);
+        previous = point;
+    }
+    planar.hypot(end[2] - start[2])
+}
+
+/// A move waiting to be planned.
+struct Block {
+    /// Index of its line in the file
+    line: usize,
+    length: f64,
+    /// Units per second
+    nominal_speed: f64,
+    /// Units per second squared, or infinite if unlimited
+    acceleration: f64,
+    /// Fastest it can start at, given the corner with the move before it
+    max_entry_speed: f64,
+}
+
+impl Block {
+    /// Seconds taken to start at one speed and end at the other, cruising at the nominal speed if it is reached.
+    fn duration(&self, entry_speed: f64, exit_speed: f64) -> f64 {
+        let (a, speed) = (self.acceleration, self.nominal_speed);
+        if a.is_infinite() {
+            return self.length / speed;
+        }
+        let accelerating = (speed * speed - entry_speed * entry_speed) / (2. * a);
+        let decelerating = (speed * speed - exit_speed * exit_speed) / (2. * a);
+        if accelerating + decelerating <= self.length {
+            (speed - entry_speed) / a
+                + (speed - exit_speed) / a
+                + (self.length - accelerating - decelerating) / speed
+        } else {
+            // The move is too short to reach its nominal speed
+            let peak =
+                ((2. * a * self.length + entry_speed * entry_speed + exit_speed * exit_speed) / 2.)
+                    .sqrt();
+            (peak - entry_speed) / a + (peak - exit_speed) / a
+        }
+    }
+}
+
+/// Plans consecutive moves, from a stop to a stop.
+struct Planner<'a> {
+    limits: &'a MachineLimits,
+    blocks: Vec<Block>,
+    /// Direction and nominal speed of the last move planned
+    previous: Option<([f64; 3], f64)>,
+}
+
+impl Planner<'_> {
+    fn push(&mut self, line: usize, length: f64, direction: [f64; 3], nominal_speed: f64) {
+        let acceleration = match self.limits.max_acceleration {
+            // Full circles have no direction, so take the slowest axis
+            Some(max) if direction == [0.; 3] => max.iter().copied().fold(f64::INFINITY, f64::min),
+            Some(max) => direction
+                .iter()
+                .zip(max.iter())
+                .filter(|(d, _)| **d != 0.)
+                .map(|(d, max)| max / d.abs())
+                .fold(f64::INFINITY, f64::min),
+            None => f64::INFINITY,
+        };
+        let max_entry_speed = match self.previous {
+            None => 0.,
+            Some((previous, previous_speed)) => {
+                let junction_speed = match self.limits.junction_deviation {
+                    None => f64::INFINITY,
+                    Some(deviation) => {
+                        let cos = -previous
+                            .iter()
+                            .zip(direction.iter())
+                            .map(|(a, b)| a * b)
+                            .sum::<f64>();
+                        if cos > 0.999_999 {
+                            // Reversing
+                            0.
+                        } else if cos < -0.999_999 {
+                            // Going straight on
+                            f64::INFINITY
+                        } else {
+                            let sin_half = (0.5 * (1. - cos)).sqrt();
+                            (acceleration * deviation * sin_half / (1. - sin_half)).sqrt()
+                        }
+                    }
+                };
+                junction_speed.min(previous_speed).min(nominal_speed)
+            }
+        };
+        self.blocks.push(Block {
+            line,
+            length,
+            nominal_speed,
+            acceleration,
+            max_entry_speed,
+        });
+        self.previous = Some((direction, nominal_speed));
+    }
+
+    /// Plan the moves so far, coming to a stop after the last one, and add up their durations.
+    fn flush(&mut self, durations: &mut [f64]) {
+        let blocks = std::mem::take(&mut self.blocks);
+        self.previous = None;
+        let mut speeds = blocks
+            .iter()
+            .map(|block| block.max_entry_speed)
+            .chain(Some(0.))
+            .collect::<Vec<_>>();
+        // Every move must be able to slow down to the speed that the next one starts at
+        for (i, block) in blocks.iter().enumerate().rev() {
+            let reachable = (speeds[i + 1].powi(2) + 2. * block.acceleration * block.length).sqrt();
+            speeds[i] = speeds[i].min(reachable);
+        }
+        // And speed up to it
+        for (i, block) in blocks.iter().enumerate() {
+            let reachable = (speeds[i].powi(2) + 2. * block.acceleration * block.length).sqrt();
+            speeds[i + 1] = speeds[i + 1].min(reachable);
+        }
+        for (i, block) in blocks.iter().enumerate() {
+            durations[block.line] += block.duration(speeds[i], speeds[i + 1]);
+        }
+    }
+}
+
+pub(crate) fn as_ratio(field: &Field) -> Option<Ratio<i64>> {
+    match &field.value {
+        Value::Rational(r) => real_to_ratio(r),
+        Value::Integer(i) => i64::try_from(*i).ok().map(Ratio::from_integer),
+        Value::String(_) | Value::Raw(_) => None,
+    }
+}
+
+/// Total length of filament extruded by each tool in a [File], in program units.
+///
+/// Retractions are not counted as usage: filament pushed back out after a retraction
+/// is only counted once it exceeds what was retracted.
+/// Tools are listed in ascending order, including any that were selected but never extruded.
+pub fn filament_usage(file: &File) -> Vec<(u64, Ratio<i64>)> {
+    let mut state = State::default();
+    // Per-tool (used, retracted)
+    let mut usage: BTreeMap<u64, (Ratio<i64>, Ratio<i64>)> = BTreeMap::new();
+    for line in file.iter() {
+        let e_delta = state.step(line);
+        let (used, retracted) = usage.entry(state.active_tool).or_default();
+        if e_delta < Ratio::from_integer(0) {
+            *retracted -= e_delta;
+        } else {
+            let recovered = e_delta.min(*retracted);
+            *retracted -= recovered;
+            *used += e_delta - recovered;
+        }
+    }
+    usage
+        .into_iter()
+        .map(|(tool, (used, _))| (tool, used))
+        .collect()
+}
+
+/// Motion commanded by a [MotionLine].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MotionMode {
+    /// `G0`
+    Rapid,
+    /// `G1`
+    Linear,
+    /// `G2`
+    ClockwiseArc,
+    /// `G3`
+    CounterclockwiseArc,
+    /// Axis words without a motion command, like `X10 Y5`,
+    /// which continue whichever motion an earlier line set.
+    Implicit,
+}
+
+/// A line that moves the machine, with the words of its move picked out.
+///
+/// This saves scanning fields for letters and numbers each time a move is looked at:
+///
+/// ```
+/// use g_code::interpret::{MotionLine, MotionMode};
+/// use g_code::parse::snippet_parser;
+/// use num_rational::Ratio;
+/// use std::convert::TryFrom;
+///
+/// let snippet = snippet_parser("G2 X10 Y0 I5 F1200").unwrap();
+/// let line = snippet.iter().next().unwrap();
+/// let motion = MotionLine::try_from(line).unwrap();
+/// assert_eq!(motion.mode, MotionMode::ClockwiseArc);
+/// assert_eq!(motion.i, Some(Ratio::from_integer(5)));
+/// assert_eq!(motion.j, None);
+/// ```
+///
+/// Numbers are kept exactly, as in [State].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MotionLine {
+    pub mode: MotionMode,
+    pub x: Option<Ratio<i64>>,
+    pub y: Option<Ratio<i64>>,
+    pub z: Option<Ratio<i64>>,
+    pub e: Option<Ratio<i64>>,
+    pub f: Option<Ratio<i64>>,
+    /// Offset of the center of an arc from its start
+    pub i: Option<Ratio<i64>>,
+    pub j: Option<Ratio<i64>>,
+    pub k: Option<Ratio<i64>>,
+    /// Radius of an arc, given instead of its center
+    pub r: Option<Ratio<i64>>,
+    /// Span of the line in the input
+    pub span: Span,
+}
+
+/// Reasons that a [Line] can't be read as a [MotionLine].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MotionLineError {
+    /// The line does something other than move, like `M104 S200` or `G92 X0`,
+    /// or has neither a motion command nor axis words.
+    NotAMotionLine,
+    /// The line has two different motion commands, like `G0 G1`.
+    ConflictingModes {
+        /// Span of the second motion command
+        span: Span,
+    },
+    /// A word is given twice, like `X1 X2`, and firmware would only take one of them.
+    RepeatedWord {
+        letter: char,
+        /// Span of the second occurrence
+        span: Span,
+    },
+    /// A word has a value that isn't a number, or is too big to hold exactly, like `X"1"`.
+    NotANumber { letter: char, span: Span },
+    /// The line gives both the radius `R` and the center `I`/`J`/`K` of an arc.
+    RadiusAndCenter {
+        /// Span of the `R` word
+        span: Span,
+    },
+}
+
+impl fmt::Display for MotionLineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAMotionLine => write!(f, "line is not a motion line"),
+            Self::ConflictingModes { .. } => {
+                write!(f, "line has more than one motion command")
+            }
+            Self::RepeatedWord { letter, .. } => {
+                write!(f, "line has more than one {} word", letter)
+            }
+            Self::NotANumber { letter, .. } => write!(f, "{} word is not a number", letter),
+            Self::RadiusAndCenter { .. } => write!(
+                f,
+                "arc has both a radius (R) and a center (I, J, or K), which is ambiguous"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MotionLineError {}
+
+/// Whether a `G` command only sets a mode that a move on the same line can use,
+/// like `G91 G1 X10`, rather than doing something with the axis words itself, like `G92 X0`.
+fn is_modal_setting(value: &Value) -> bool {
+    match value {
+        Value::Integer(n) => matches!(n, 17..=21 | 40 | 49 | 53..=59 | 61 | 64 | 90 | 91 | 93..=95),
+        Value::Rational(r) => matches!(tenths(r), Some(591..=593 | 611 | 901 | 911)),
+        Value::String(_) | Value::Raw(_) => false,
+    }
+}
+
+impl<'a, 'input> TryFrom<&'a Line<'input>> for MotionLine {
+    type Error = MotionLineError;
+
+    /// Picks out the words of a `G0`-`G3` move, or of a line with axis words and no command,
+    /// which continues the motion of an earlier line.
+    ///
+    /// Other `G` commands that only set a mode, like `G90` or `G21`, are allowed alongside.
+    /// A line with any other `G` command, or with an `M` command and no motion command,
+    /// uses its axis words for something else and is [MotionLineError::NotAMotionLine].
+    fn try_from(line: &'a Line<'input>) -> Result<Self, Self::Error> {
+        let mut mode = None;
+        let mut has_other_command = false;
+        let mut has_m = false;
+        let mut words: [Option<Ratio<i64>>; 9] = [None; 9];
+        let mut r_span = None;
+        for field in line.iter_fields() {
+            let letters = field.letters.to_ascii_uppercase();
+            match (letters.as_str(), &field.value) {
+                ("G", Value::Integer(n @ 0..=3)) => {
+                    let this = match n {
+                        0 => MotionMode::Rapid,
+                        1 => MotionMode::Linear,
+                        2 => MotionMode::ClockwiseArc,
+                        _ => MotionMode::CounterclockwiseArc,
+                    };
+                    match mode {
+                        Some(earlier) if earlier != this => {
+                            return Err(MotionLineError::ConflictingModes { span: field.span })
+                        }
+                        _ => mode = Some(this),
+                    }
+                }
+                ("G", value) if is_modal_setting(value) => {}
+                ("G", _) => has_other_command = true,
+                ("M", _) => has_m = true,
+                (word, _) => {
+                    let index = match "XYZEFIJKR".find(word).filter(|_| word.len() == 1) {
+                        Some(index) => index,
+                        None => continue,
+                    };
+                    let letter = char::from(word.as_bytes()[0]);
+                    if words[index].is_some() {
+                        return Err(MotionLineError::RepeatedWord {
+                            letter,
+                            span: field.span,
+                        });
+                    }
+                    words[index] = Some(as_ratio(field).ok_or(MotionLineError::NotANumber {
+                        letter,
+                        span: field.span,
+                    })?);
+                    if letter == 'R' {
+                        r_span = Some(field.span);
+                    }
+                }
+            }
+        }
+        let [x, y, z, e, f, i, j, k, r] = words;
+        let has_axes = [x, y, z, e, i, j, k, r].iter().any(Option::is_some);
+        let mode = match mode {
+            _ if has_other_command => return Err(MotionLineError::NotAMotionLine),
+            Some(mode) => mode,
+            None if has_axes && !has_m => MotionMode::Implicit,
+            None => return Err(MotionLineError::NotAMotionLine),
+        };
+        if let Some(span) = r_span.filter(|_| i.is_some() || j.is_some() || k.is_some()) {
+            return Err(MotionLineError::RadiusAndCenter { span });
+        }
+        Ok(Self {
+            mode,
+            x,
+            y,
+            z,
+            e,
+            f,
+            i,
+            j,
+            k,
+            r,
+            span: line.span(),
+        })
+    }
+}
+
+impl MotionLine {
+    /// The move as a [Command], to be emitted or changed.
+    ///
+    /// An [MotionMode::Implicit] line has no command of its own,
+    /// so it gives [None] until its mode is set to the motion it continues.
+    pub fn to_command(&self) -> Option<Command<'static>> {
+        let words = [
+            ("X", self.x),
+            ("Y", self.y),
+            ("Z", self.z),
+            ("I", self.i),
+            ("J", self.j),
+            ("K", self.k),
+            ("R", self.r),
+            ("E", self.e),
+            ("F", self.f),
+        ];
+        let args = words.iter().filter_map(|(letters, value)| {
+            value.map(|value| emit::Field {
+                letters: Cow::Borrowed(*letters),
+                value: emit::Value::Rational(value),
+            })
+        });
+        Some(match self.mode {
+            MotionMode::Rapid => rapid_positioning(args),
+            MotionMode::Linear => linear_interpolation(args),
+            MotionMode::ClockwiseArc => clockwise_circular_interpolation(args),
+            MotionMode::CounterclockwiseArc => counterclockwise_circular_interpolation(args),
+            MotionMode::Implicit => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use crate::parse::token::real;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tracks_extruder_mode_and_resets() {
+        let file = file_parser("G1 X1 E1\nM83\nG1 X2 E0.5\nG92 E0\nM82\nG1 X3 E2\nT1\nG91\nG1 E-1")
+            .unwrap();
+        let mut state = State::default();
+        let deltas = file.iter().map(|line| state.step(line)).collect::<Vec<_>>();
+        assert_eq!(
+            deltas,
+            [
+                Ratio::from_integer(1),
+                Ratio::from_integer(0),
+                Ratio::new(1, 2),
+                Ratio::from_integer(0),
+                Ratio::from_integer(0),
+                Ratio::from_integer(2),
+                Ratio::from_integer(0),
+                Ratio::from_integer(0),
+                Ratio::from_integer(-1),
+            ]
+        );
+        assert_eq!(
+            state,
+            State {
+                position: [3, 0, 0].map(Ratio::from_integer),
+                mode: DistanceMode::Relative,
+                e_position: Ratio::from_integer(1),
+                e_mode: DistanceMode::Relative,
+                active_tool: 1,
+                ..State::default()
+            }
+        );
+    }
+
+    #[test]
+    fn applies_work_offsets_to_machine_position() {
+        let file = file_parser(
+            "G38.2 Z-20\nG10 L20 P2 X0 Y0 Z0\nG0 Z5\nG10 L2 P2 X10 Y20 Z-12\nG55\nG0 X1 Y2 Z3\nG92 X0\nG1 X5\nG53 G0 Z-1",
+        )
+        .unwrap();
+        let mut state = State::default();
+        let positions = file
+            .iter()
+            .map(|line| {
+                state.step(line);
+                (state.program_position(), state.machine_position())
+            })
+            .collect::<Vec<_>>();
+        let xyz = |x, y, z| [x, y, z].map(Ratio::from_integer);
+        assert_eq!(
+            positions,
+            [
+                (xyz(0, 0, -20), xyz(0, 0, -20)),
+                // G55 is not active yet, so the program position is unchanged
+                (xyz(0, 0, -20), xyz(0, 0, -20)),
+                (xyz(0, 0, 5), xyz(0, 0, 5)),
+                (xyz(0, 0, 5), xyz(0, 0, 5)),
+                (xyz(-10, -20, 17), xyz(0, 0, 5)),
+                (xyz(1, 2, 3), xyz(11, 22, -9)),
+                (xyz(0, 2, 3), xyz(11, 22, -9)),
+                (xyz(5, 2, 3), xyz(16, 22, -9)),
+                (xyz(5, 2, 11), xyz(16, 22, -1)),
+            ]
+        );
+        assert_eq!(state.active_work_offset, 1);
+        assert_eq!(state.g92_offset, xyz(1, 0, 0));
+    }
+
+    #[test]
+    fn applies_home_offsets_to_machine_position() {
+        let file = file_parser("G0 X10 Y1\nM206 X-5\nG0 X0\nG0 X2\nM428\nM206 X0 Y0").unwrap();
+        let mut state = State::default();
+        let positions = file
+            .iter()
+            .map(|line| {
+                state.step(line);
+                (state.program_position(), state.machine_position())
+            })
+            .collect::<Vec<_>>();
+        let xyz = |x, y, z| [x, y, z].map(Ratio::from_integer);
+        assert_eq!(
+            positions,
+            [
+                (xyz(10, 1, 0), xyz(10, 1, 0)),
+                (xyz(5, 1, 0), xyz(10, 1, 0)),
+                (xyz(0, 1, 0), xyz(5, 1, 0)),
+                (xyz(2, 1, 0), xyz(7, 1, 0)),
+                (xyz(0, 0, 0), xyz(7, 1, 0)),
+                (xyz(7, 1, 0), xyz(7, 1, 0)),
+            ]
+        );
+        assert_eq!(state.home_offset, Xyz::default());
+    }
+
+    fn assert_durations(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-6,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn estimates_without_limits_from_feed_rates() {
+        let file = file_parser(
+            "G21\nG1 X10 F600\nG1 X10 Y10\nG0 X0 Y0 F1200\nG2 X10 Y0 I5 J0 F600\nG92 X0\nG1 E-5 F300\nG4 P500\nM0\nG93 G1 X5 F30",
+        )
+        .unwrap();
+        let estimate = estimate_duration(&file, &MachineLimits::default());
+        assert_durations(
+            &estimate.line_durations,
+            &[
+                0.,
+                1.,
+                1.,
+                200f64.sqrt() / 20.,
+                // Half of a circle of radius 5
+                5. * PI / 10.,
+                0.,
+                1.,
+                0.5,
+                0.,
+                2.,
+            ],
+        );
+    }
+
+    #[test]
+    fn estimates_splines_along_their_curve() {
+        let file = file_parser(
+            "G21\nG5 X1 Y0 I0 J1 P0 Q1 F60\nG1 X0 Y0\nG5.1 X2 Y0 I1 J2\nG5 X5 I1 P-1 E2",
+        )
+        .unwrap();
+        let estimate = estimate_duration(&file, &MachineLimits::default());
+        let durations = estimate.line_durations;
+        // An arch of length 2, and a parabola that peaks at Y1
+        assert!((durations[1] - 2.).abs() < 1e-3, "{}", durations[1]);
+        assert_eq!(durations[2], 1.);
+        assert!((durations[3] - 2.957_886).abs() < 1e-3, "{}", durations[3]);
+        // Control points on the chord make a straight line
+        assert!((durations[4] - 3.).abs() < 1e-9, "{}", durations[4]);
+
+        let mut state = State::default();
+        for line in file.iter() {
+            state.step(line);
+        }
+        assert_eq!(state.position, [5, 0, 0].map(Ratio::from_integer));
+        assert_eq!(state.e_position, Ratio::from_integer(2));
+    }
+
+    #[test]
+    fn moves_accelerate_and_slow_for_corners() {
+        let limits = MachineLimits {
+            max_acceleration: Some([1000., 1000., 100.]),
+            junction_deviation: Some(0.05),
+            ..Default::default()
+        };
+        let durations =
+            |gcode| estimate_duration(&file_parser(gcode).unwrap(), &limits).line_durations;
+
+        // 5 units to reach 100 units/s, and 5 more to stop
+        assert_durations(&durations("G1 X100 F6000"), &[0.2 + 0.9]);
+        // Too short to reach it
+        assert_durations(&durations("G1 X4 F6000"), &[2. * 4000f64.sqrt() / 1000.]);
+        // Going straight on doesn't slow down
+        assert_durations(&durations("G1 X50 F6000\nG1 X100"), &[0.55, 0.55]);
+        // Z accelerates slower, which limits a diagonal move
+        let diagonal = durations("G1 X30 Z40 F3000")[0];
+        let a = 100. / 0.8;
+        assert!((diagonal - (2. * 50. / a + (50. - 50. * 50. / a) / 50.)).abs() < 1e-6);
+
+        // A right angle is taken at the speed that keeps within the junction deviation
+        let sin_half = 0.5f64.sqrt();
+        let corner: f64 = (1000. * 0.05 * sin_half / (1. - sin_half)).sqrt();
+        let leg = 0.1
+            + (100. - corner) / 1000.
+            + (100. - 5. - (100f64.powi(2) - corner.powi(2)) / 2000.) / 100.;
+        assert_durations(&durations("G1 X100 F6000\nG1 Y100"), &[leg, leg]);
+        // Reversing stops, and so does a dwell
+        assert_durations(&durations("G1 X100 F6000\nG1 X0"), &[1.1, 1.1]);
+        assert_durations(&durations("G1 X50 F6000\nG4 S1\nG1 X100"), &[0.6, 1., 0.6]);
+    }
+
+    #[test]
+    fn short_segments_take_longer_than_their_feed_rate_suggests() {
+        let mut gcode = String::from("G1 X0 Y0 F6000\n");
+        for step in 1..=200 {
+            gcode += &format!("G1 X{} Y{}\n", step, (step % 2) as f64 * 0.5);
+        }
+        let file = file_parser(&gcode).unwrap();
+        let basic = estimate_duration(&file, &MachineLimits::default()).total();
+        let planned = estimate_duration(
+            &file,
+            &MachineLimits {
+                max_acceleration: Some([1000.; 3]),
+                junction_deviation: Some(0.02),
+                ..Default::default()
+            },
+        )
+        .total();
+        assert!(
+            planned > 2. * basic && planned < 4. * basic,
+            "{} vs {}",
+            planned,
+            basic
+        );
+    }
+
+    #[test]
+    fn corpus_estimate_is_close_to_a_reference() {
+        let file = file_parser(include_str!("../tests/vandy_commodores_logo.gcode")).unwrap();
+        let limits = MachineLimits {
+            max_acceleration: Some([10.; 3]),
+            junction_deviation: Some(0.01),
+            ..Default::default()
+        };
+        // Computed outside this crate by sampling the path every 0.001 mm and adding up
+        // distance over speed, where the speed at each sample is the lowest of the feed rate,
+        // the junction speed of Marlin's junction deviation formula at corners,
+        // and what speeding up from the start and slowing down to the end allow.
+        const REFERENCE: f64 = 244.96;
+        let planned = estimate_duration(&file, &limits).total();
+        assert!(
+            (planned - REFERENCE).abs() < 0.01 * REFERENCE,
+            "{} vs {}",
+            planned,
+            REFERENCE
+        );
+        // Without acceleration, the estimate falls outside of the band
+        let basic = estimate_duration(&file, &MachineLimits::default()).total();
+        assert!(basic < 0.99 * REFERENCE, "{}", basic);
+    }
+
+    #[test]
+    fn tracks_units_and_feed_rate() {
+        let file = file_parser("G20 G94\nG1 X1 F10\nG93 G1 X2 F0.5\nG21 G95 F0.1").unwrap();
+        let mut state = State::default();
+        let modes = file
+            .iter()
+            .map(|line| {
+                state.step(line);
+                (state.units, state.feed_rate_mode, state.feed_rate)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            modes,
+            [
+                (Units::Inches, FeedRateMode::UnitsPerMinute, None),
+                (
+                    Units::Inches,
+                    FeedRateMode::UnitsPerMinute,
+                    Some(Ratio::from_integer(10))
+                ),
+                (
+                    Units::Inches,
+                    FeedRateMode::InverseTime,
+                    Some(Ratio::new(1, 2))
+                ),
+                (
+                    Units::Millimeters,
+                    FeedRateMode::UnitsPerRevolution,
+                    Some(Ratio::new(1, 10))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn switching_units_converts_what_is_known() {
+        let file = file_parser("G21\nG1 X25.4 E2.54 F254\nG20\nG1 Y1\nG93 G1 X2 F4\nG21").unwrap();
+        let mut state = State::default();
+        let mut lines = file.iter();
+        for line in lines.by_ref().take(3) {
+            state.step(line);
+        }
+        assert_eq!(state.units, Units::Inches);
+        assert_eq!(
+            state.program_position(),
+            [
+                Ratio::from_integer(1),
+                Ratio::from_integer(0),
+                Ratio::from_integer(0)
+            ]
+        );
+        assert_eq!(state.e_position, Ratio::new(1, 10));
+        assert_eq!(state.feed_rate, Some(Ratio::from_integer(10)));
+
+        for line in lines {
+            state.step(line);
+        }
+        assert_eq!(
+            state.program_position(),
+            [
+                Ratio::new(254, 5),
+                Ratio::new(127, 5),
+                Ratio::from_integer(0)
+            ]
+        );
+        // An inverse time feed rate is not a length
+        assert_eq!(state.feed_rate, Some(Ratio::from_integer(4)));
+    }
+
+    #[cfg(feature = "uom")]
+    #[test]
+    fn switching_units_keeps_lengths() {
+        use uom::si::{length::millimeter, velocity::millimeter_per_minute};
+
+        let file = file_parser("G21\nG1 X25.4 F600\nG20").unwrap();
+        let mut state = State::default();
+        for line in file.iter() {
+            state.step(line);
+        }
+        let x = state.position_length()[0].get::<millimeter>();
+        assert!((x - 25.4).abs() < 1e-9, "{}", x);
+        let feed = state.feed_velocity().unwrap().unwrap();
+        assert!((feed.get::<millimeter_per_minute>() - 600.).abs() < 1e-9);
+    }
+
+    #[cfg(feature = "uom")]
+    #[test]
+    fn converts_inch_positions_and_feed_rates_to_millimeters() {
+        use uom::si::{length::millimeter, velocity::millimeter_per_minute};
+
+        let file = file_parser("G20\nG0 X1 Y2.5 Z-0.1\nG1 X3 F10\nG93 G1 X4 F2\nG95").unwrap();
+        let mut state = State::default();
+        let millimeters = |state: &State| {
+            state
+                .position_length()
+                .map(|length| length.get::<millimeter>())
+        };
+        let close =
+            |a: [f64; 3], b: [f64; 3]| a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-9);
+
+        state.step(file.iter().next().unwrap());
+        assert_eq!(state.feed_velocity(), Ok(None));
+        state.step(file.iter().nth(1).unwrap());
+        assert!(close(millimeters(&state), [25.4, 63.5, -2.54]));
+        state.step(file.iter().nth(2).unwrap());
+        assert!(close(millimeters(&state), [76.2, 63.5, -2.54]));
+        let feed = state.feed_velocity().unwrap().unwrap();
+        assert!((feed.get::<millimeter_per_minute>() - 254.).abs() < 1e-9);
+
+        state.step(file.iter().nth(3).unwrap());
+        assert_eq!(
+            state.feed_velocity(),
+            Err(UnsupportedFeedRateMode(FeedRateMode::InverseTime))
+        );
+        state.step(file.iter().nth(4).unwrap());
+        assert_eq!(
+            state.feed_velocity(),
+            Err(UnsupportedFeedRateMode(FeedRateMode::UnitsPerRevolution))
+        );
+
+        let field = file.iter().nth(1).unwrap().iter_fields().nth(2).unwrap();
+        let length = field.value_as_length(Units::Inches).unwrap();
+        assert!((length.get::<millimeter>() - 63.5).abs() < 1e-9);
+        assert_eq!(
+            field
+                .value_as_length(Units::Millimeters)
+                .unwrap()
+                .get::<millimeter>(),
+            2.5
+        );
+        let string = file_parser("M117 P\"hi\"").unwrap();
+        let string = string.iter_fields().nth(1).unwrap();
+        assert_eq!(string.value_as_length(Units::Millimeters), None);
+    }
+
+    #[test]
+    fn inserts_filament_change_at_layer() {
+        use crate::emit::{self, filament_change, format_gcode_fmt, FormatOptions};
+
+        let file =
+            file_parser("G1 Z0.2 F600\nG1 X10 E1\nG1 Z0.4\nG1 X0 E2\nG1 Z0.6\nM0 S5\nG1 X10 E3")
+                .unwrap();
+        let layer_z = Value::Rational(real(2, 5));
+
+        let mut tokens = vec![];
+        let mut inserted = false;
+        for line in file.iter() {
+            let is_move = line.iter_fields().any(|f| {
+                f.letters.eq_ignore_ascii_case("G") && matches!(f.value, Value::Integer(0 | 1))
+            });
+            let at_layer = line
+                .iter_fields()
+                .any(|f| f.letters.eq_ignore_ascii_case("Z") && f.value == layer_z);
+            if !inserted && is_move && at_layer {
+                let park = vec![
+                    emit::Field::new("X", emit::Value::Integer(0)).unwrap(),
+                    emit::Field::new("Y", emit::Value::Integer(0)).unwrap(),
+                ];
+                tokens.extend(filament_change(park.into_iter()).into_token_vec());
+                inserted = true;
+            }
+            tokens.extend(line.iter_emit_tokens());
+        }
+        let mut emitted = String::new();
+        format_gcode_fmt(&tokens, FormatOptions::default(), &mut emitted).unwrap();
+        assert_eq!(
+            emitted,
+            "G1 Z0.2 F600\nG1 X10 E1\nM600 X0 Y0\nG1 Z0.4\nG1 X0 E2\nG1 Z0.6\nM0 S5\nG1 X10 E3\n"
+        );
+
+        let reparsed = file_parser(&emitted).unwrap();
+        assert_eq!(pauses(&reparsed), [2, 6]);
+        // Pauses do not disturb extrusion tracking
+        assert_eq!(filament_usage(&reparsed), filament_usage(&file));
+    }
+
+    #[test]
+    fn ignores_e_outside_of_moves() {
+        let file = file_parser("M203 E25\nG1 E1").unwrap();
+        assert_eq!(filament_usage(&file), [(0, Ratio::from_integer(1))]);
+    }
+
+    #[test]
+    fn sums_usage_per_tool_without_counting_retractions() {
+        let file = file_parser(
+            "T0\nM83\nG1 X1 E2.5\nG1 E-0.8\nG0 X5\nG1 E0.8\nG1 X2 E1\nT1\nG92 E0\nG1 X3 E0.25\nT0\nG1 E-1\nG1 E1.5",
+        )
+        .unwrap();
+        assert_eq!(
+            filament_usage(&file),
+            [(0, Ratio::new(4, 1)), (1, Ratio::new(1, 4))]
+        );
+    }
+
+    fn motion_lines(src: &str) -> Vec<Result<MotionLine, MotionLineError>> {
+        file_parser(src)
+            .unwrap()
+            .iter()
+            .map(MotionLine::try_from)
+            .collect()
+    }
+
+    #[test]
+    fn motion_lines_pick_out_their_words() {
+        use crate::emit::{format_gcode_fmt, FormatOptions};
+
+        let src = "G90 G1 X10 y-2.5 F1200
+G3 X0 Y0 I-5 J0 E0.75
+Z5
+G0 R2";
+        let lines = motion_lines(src);
+        let ratio = Ratio::from_integer;
+        assert_eq!(
+            lines[0],
+            Ok(MotionLine {
+                mode: MotionMode::Linear,
+                x: Some(ratio(10)),
+                y: Some(Ratio::new(-5, 2)),
+                z: None,
+                e: None,
+                f: Some(ratio(1200)),
+                i: None,
+                j: None,
+                k: None,
+                r: None,
+                span: Span(0, 22),
+            })
+        );
+        let arc = lines[1].as_ref().unwrap();
+        assert_eq!(arc.mode, MotionMode::CounterclockwiseArc);
+        assert_eq!(
+            (arc.i, arc.j, arc.e),
+            (Some(ratio(-5)), Some(ratio(0)), Some(Ratio::new(3, 4)))
+        );
+        let continued = lines[2].as_ref().unwrap();
+        assert_eq!(
+            (continued.mode, continued.z),
+            (MotionMode::Implicit, Some(ratio(5)))
+        );
+        assert_eq!(continued.to_command(), None);
+
+        let mut emitted = String::new();
+        let tokens = lines
+            .iter()
+            .filter_map(|line| line.as_ref().unwrap().to_command())
+            .flat_map(|command| {
+                let mut tokens = command.into_token_vec();
+                tokens.push(emit::Token::Newline);
+                tokens
+            })
+            .collect::<Vec<_>>();
+        format_gcode_fmt(&tokens, FormatOptions::default(), &mut emitted).unwrap();
+        assert_eq!(
+            emitted,
+            "G1 X10 Y-2.5 F1200
+G3 X0 Y0 I-5 J0 E0.75
+G0 R2
+"
+        );
+    }
+
+    #[test]
+    fn other_lines_are_not_motion_lines() {
+        let src = "G92 X0 E0
+M206 X-5
+G28 G0 X0
+M104 S200
+F300
+G4 P100";
+        for line in motion_lines(src) {
+            assert_eq!(line, Err(MotionLineError::NotAMotionLine));
+        }
+        // A motion command on its own still sets the mode, and a feed rate with it
+        assert_eq!(
+            motion_lines("G1 F300")[0].as_ref().unwrap().f,
+            Some(Ratio::from_integer(300))
+        );
+    }
+
+    #[test]
+    fn conflicting_motion_words_are_refused() {
+        let src = "G2 X10 Y0 R5 I5
+G0 G1 X1
+G1 X1 x2
+G1 X\"1\"";
+        let lines = motion_lines(src);
+        assert_eq!(
+            lines,
+            [
+                Err(MotionLineError::RadiusAndCenter { span: Span(10, 12) }),
+                Err(MotionLineError::ConflictingModes { span: Span(19, 21) }),
+                Err(MotionLineError::RepeatedWord {
+                    letter: 'X',
+                    span: Span(31, 33),
+                }),
+                Err(MotionLineError::NotANumber {
+                    letter: 'X',
+                    span: Span(37, 41),
+                }),
+            ]
+        );
+        assert_eq!(&src[10..12], "R5");
+        assert_eq!(
+            lines[0].as_ref().unwrap_err().to_string(),
+            "arc has both a radius (R) and a center (I, J, or K), which is ambiguous"
+        );
+    }
+}
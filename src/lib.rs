@@ -1,17 +1,41 @@
+/// Inspecting how parsed programs are put together
+pub mod analyze;
 /// GCode emitter with a few basic commands and argument-checking
 pub mod emit;
+/// Semantic hashing and comparison of parsed programs
+pub mod hash;
+/// Tracking of machine state as a parsed program is executed
+pub mod interpret;
+/// Warnings about programs that probably don't do what was meant
+pub mod lint;
 /// GCode parser written with [peg]
 pub mod parse;
+/// Parsing firmware responses for programs that send GCode to a machine
+pub mod send;
+/// Passes that rewrite parsed programs
+pub mod transform;
+
+// Analysis pipelines share parsed programs and emitted tokens across threads,
+// so a field that loses Send or Sync (like an Rc) must fail to compile here first
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<parse::ast::File<'static>>();
+    assert_send_sync::<parse::ast::Snippet<'static>>();
+    assert_send_sync::<parse::ast::Line<'static>>();
+    assert_send_sync::<emit::Token<'static>>();
+    assert_send_sync::<emit::FormatOptions>();
+};
 
 #[cfg(test)]
 mod test {
     #[test]
     fn parsed_gcode_is_functionally_equivalent_to_emitted_gcode_reparsed() {
-        let parsed_file = super::parse::file_parser(include_str!("../tests/vandy_commodores_logo.gcode"))
-            .unwrap();
+        let parsed_file =
+            super::parse::file_parser(include_str!("../tests/vandy_commodores_logo.gcode"))
+                .unwrap();
         let emission_tokens = parsed_file
             .iter_fields()
-            .map(|f| super::emit::Token::from(f))
+            .map(super::emit::Token::from)
             .collect::<Vec<_>>();
         let emitted_gcode = emission_tokens
             .iter()
@@ -19,10 +43,251 @@ mod test {
             .collect::<Vec<_>>()
             .join(" ");
         let reparsed_file = super::parse::file_parser(&emitted_gcode).unwrap();
-        parsed_file.iter_fields()
+        parsed_file
+            .iter_fields()
             .zip(reparsed_file.iter_fields())
             .for_each(|(expected, actual)| {
                 assert_eq!(expected.raw_value, actual.raw_value);
             })
     }
+
+    #[test]
+    fn formatted_corpus_reparses_to_the_same_fields() {
+        use super::emit::{format_gcode_fmt, FormatOptions, Token};
+        use super::parse::{
+            ast::{File, Line},
+            diagnostic_for_checksum_mismatch, file_parser, Diagnostic,
+        };
+        use codespan_reporting::files::SimpleFile;
+        use codespan_reporting::term::{self, termcolor::NoColor};
+
+        let corpus = [
+            include_str!("../tests/vandy_commodores_logo.gcode"),
+            include_str!("../tests/ncviewer_sample.gcode"),
+            include_str!("../tests/blank_lines.gcode"),
+            include_str!("../tests/input/everything_at_once.gcode"),
+            "N0 M106*36\nN1 G28*18;home\nN2 M107*39",
+        ];
+        /// The fields, flags, and comments of a line, interleaved in the order they were written.
+        /// Numbers are compared by value, since whole numbers are formatted without a decimal point.
+        fn in_order(line: &Line, skip_line_numbers: bool, keep_eol_comment: bool) -> Vec<String> {
+            line.iter_emit_tokens()
+                .filter_map(|token| match token {
+                    Token::Field(f) if skip_line_numbers && f.letters == "N" => None,
+                    Token::Field(f) => Some(match f.value.as_f64() {
+                        Some(number) => format!("{}{}", f.letters, number),
+                        None => f.to_string(),
+                    }),
+                    Token::Flag(flag) => Some(flag.to_string()),
+                    Token::Comment {
+                        is_inline: false, ..
+                    } if !keep_eol_comment => None,
+                    Token::Comment { .. } => Some(token.to_string()),
+                    _ => None,
+                })
+                .collect()
+        }
+        /// Each line with a checksum, in order.
+        /// Other lines may be split or joined by formatting, but a checksum closes its line.
+        /// The end-of-line comment only stays on the line if the formatter keeps it there.
+        fn checksummed_lines(file: &File, keep_eol_comment: bool) -> Vec<Vec<String>> {
+            file.iter()
+                .filter(|line| line.checksum.is_some())
+                .map(|line| in_order(line, true, keep_eol_comment))
+                .collect()
+        }
+        fn render(diagnostic: &Diagnostic, gcode: &str) -> String {
+            let mut acc = NoColor::new(vec![]);
+            term::emit(
+                &mut acc,
+                &term::Config::default(),
+                &SimpleFile::new("formatted.gcode", gcode),
+                diagnostic,
+            )
+            .unwrap();
+            String::from_utf8(acc.into_inner()).unwrap()
+        }
+        for gcode in corpus.iter() {
+            let parsed_file = file_parser(gcode).unwrap();
+            for bits in 0..32u8 {
+                let opts = FormatOptions {
+                    checksums: bits & 1 != 0,
+                    line_numbers: bits & 2 != 0,
+                    delimit_with_percent: bits & 4 != 0,
+                    newline_before_comment: bits & 8 != 0,
+                    preserve_blank_lines: bits & 16 != 0,
+                    ..Default::default()
+                };
+                let mut emitted_gcode = String::new();
+                // Formatted straight from the parsed file, without collecting its tokens
+                format_gcode_fmt(parsed_file.iter_emit_tokens(), opts, &mut emitted_gcode).unwrap();
+                let reparsed_file = file_parser(&emitted_gcode).unwrap();
+
+                let all_in_order = |file: &File| {
+                    file.iter()
+                        .flat_map(|line| in_order(line, opts.line_numbers, true))
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(
+                    all_in_order(&reparsed_file),
+                    all_in_order(&parsed_file),
+                    "{:?}\n{}",
+                    opts,
+                    emitted_gcode
+                );
+                if opts.checksums {
+                    assert!(
+                        reparsed_file
+                            .iter()
+                            .all(|line| line.iter_fields().next().is_none()
+                                || line.checksum.is_some())
+                    );
+                } else {
+                    let keep_eol_comment = !opts.newline_before_comment;
+                    assert_eq!(
+                        checksummed_lines(&reparsed_file, keep_eol_comment),
+                        checksummed_lines(&parsed_file, keep_eol_comment),
+                        "{:?}\n{}",
+                        opts,
+                        emitted_gcode
+                    );
+                }
+                for line in reparsed_file.iter() {
+                    if let Some(Err(computed)) = line.validate_checksum() {
+                        panic!(
+                            "{:?}\n{}",
+                            opts,
+                            render(
+                                &diagnostic_for_checksum_mismatch(line, computed),
+                                &emitted_gcode
+                            )
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzed_inputs_reparse_to_the_same_fields() {
+        use super::emit::{format_gcode_fmt, Field, FormatOptions};
+        use super::parse::file_parser;
+
+        // Inputs found by the targets in fuzz/, kept here so that they stay fixed
+        let inputs = [include_str!("../tests/input/long_decimal.gcode")];
+        for gcode in inputs.iter() {
+            let parsed_file = file_parser(gcode).unwrap();
+            let mut emitted_gcode = String::new();
+            format_gcode_fmt(
+                parsed_file.iter_emit_tokens(),
+                FormatOptions::default(),
+                &mut emitted_gcode,
+            )
+            .unwrap();
+            let reparsed_file = file_parser(&emitted_gcode).unwrap();
+            assert_eq!(
+                parsed_file.iter_fields().count(),
+                reparsed_file.iter_fields().count()
+            );
+            for (expected, actual) in parsed_file.iter_fields().zip(reparsed_file.iter_fields()) {
+                assert_eq!(actual, &Field::from(expected), "{}", emitted_gcode);
+            }
+        }
+    }
+
+    #[test]
+    fn formatted_checksums_are_kept_and_recomputed() {
+        use super::emit::{format_gcode_fmt, FormatOptions};
+        use super::parse::file_parser;
+
+        let gcode = "N0 M106*36\nN1 G28*18;home\nN2 M107*39\nG1 X1 Y1";
+        let tokens = file_parser(gcode)
+            .unwrap()
+            .iter_emit_tokens()
+            .collect::<Vec<_>>();
+        let mut emitted_gcode = String::new();
+        format_gcode_fmt(&tokens, FormatOptions::default(), &mut emitted_gcode).unwrap();
+        assert_eq!(
+            emitted_gcode,
+            "N0 M106*36\nN1 G28*18;home\nN2 M107*39\nG1 X1 Y1\n"
+        );
+
+        let mut renumbered_gcode = String::new();
+        format_gcode_fmt(
+            &tokens,
+            FormatOptions {
+                line_numbers: true,
+                ..Default::default()
+            },
+            &mut renumbered_gcode,
+        )
+        .unwrap();
+        assert_eq!(
+            renumbered_gcode,
+            "N1 M106*37\nN2 G28*17;home\nN3 M107*38\nN4 G1 X1 Y1\n"
+        );
+    }
+
+    #[test]
+    fn files_can_be_parsed_and_analyzed_in_parallel() {
+        use super::emit::Field;
+        use super::parse::{ast::Line, file_parser};
+        use rayon::prelude::*;
+
+        /// No bounds at all, which any other bounds replace when merged
+        const EMPTY: [f64; 4] = [
+            f64::INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        /// The smallest and largest X and Y given on a line, as `[min x, min y, max x, max y]`
+        fn bounds(line: &Line) -> [f64; 4] {
+            let mut bounds = EMPTY;
+            for field in line.iter_fields() {
+                let field = Field::from(field);
+                let axis = match field.letters.to_ascii_uppercase().as_str() {
+                    "X" => 0,
+                    "Y" => 1,
+                    _ => continue,
+                };
+                let value = field.value.as_f64().unwrap();
+                bounds[axis] = bounds[axis].min(value);
+                bounds[axis + 2] = bounds[axis + 2].max(value);
+            }
+            bounds
+        }
+        fn merge(a: [f64; 4], b: [f64; 4]) -> [f64; 4] {
+            [
+                a[0].min(b[0]),
+                a[1].min(b[1]),
+                a[2].max(b[2]),
+                a[3].max(b[3]),
+            ]
+        }
+        let empty = || EMPTY;
+
+        let gcode = include_str!("../tests/vandy_commodores_logo.gcode");
+        let file = file_parser(gcode).unwrap();
+        let sequential = file.iter().map(bounds).fold(EMPTY, merge);
+        assert!(sequential.iter().all(|bound| bound.is_finite()));
+
+        // One file shared by every thread
+        let lines = file.iter().collect::<Vec<_>>();
+        let shared = lines
+            .par_iter()
+            .map(|line| bounds(line))
+            .reduce(empty, merge);
+        assert_eq!(shared, sequential);
+
+        // Each line parsed on whichever thread picks it up
+        let parsed = gcode
+            .par_lines()
+            .map(|line| {
+                let file = file_parser(line).unwrap();
+                file.iter().map(bounds).fold(EMPTY, merge)
+            })
+            .reduce(empty, merge);
+        assert_eq!(parsed, sequential);
+    }
 }
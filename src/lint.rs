@@ -0,0 +1,546 @@
+//! Warnings about programs that parse, but probably don't do what was meant.
+use codespan_reporting::diagnostic::{Label, Severity};
+use num::ToPrimitive;
+use num_rational::Ratio;
+
+use crate::analyze::end_code;
+use crate::interpret::{as_ratio, DistanceMode, FeedRateMode, State, Units, Xyz};
+use crate::parse::ast::{File, Snippet, Span, Spanned};
+use crate::parse::token::Value;
+use crate::parse::{diagnostic_at, Diagnostic};
+
+/// The ways a program can offset its coordinates from the machine's, as tracked by [State].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Offset {
+    Home,
+    G92,
+    Work,
+}
+
+impl Offset {
+    const ALL: [Self; 3] = [Self::Home, Self::G92, Self::Work];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Home => "home offset (M206/M428)",
+            Self::G92 => "G92 offset",
+            Self::Work => "work offset (G10 L2/L20)",
+        }
+    }
+
+    fn value(self, state: &State) -> Xyz {
+        match self {
+            Self::Home => state.home_offset,
+            Self::G92 => state.g92_offset,
+            Self::Work => state.work_offsets[state.active_work_offset],
+        }
+    }
+}
+
+/// Warn where a program offsets its coordinates in more than one way at once.
+///
+/// Printer profiles sometimes set a home offset with `M206` in their start g-code and then also use `G92`,
+/// which offsets every later position twice. Offsets are tracked through the file with [State].
+/// The first time that any two of home offsets, `G92` offsets, and work offsets set with `G10 L2` or `G10 L20`
+/// are in effect with non-zero values, a warning labels the lines that set them.
+///
+/// Only `X`, `Y`, and `Z` are offset this way, so resetting the extruder with `G92 E0` never conflicts.
+///
+/// ```
+/// use g_code::lint::conflicting_offsets;
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("M206 X-5\nG28\nG92 X0 E0\nG1 X10 E1").unwrap();
+/// let warnings = conflicting_offsets(&file);
+/// assert_eq!(warnings.len(), 1);
+/// assert_eq!(warnings[0].labels[0].range, 13..22);
+/// assert_eq!(warnings[0].labels[1].range, 0..8);
+/// ```
+pub fn conflicting_offsets(file: &File) -> Vec<Diagnostic> {
+    let mut state = State::default();
+    // The line that last set each offset, while it is non-zero
+    let mut set_by: [Option<Span>; 3] = [None; 3];
+    let mut reported = vec![];
+    let mut diagnostics = vec![];
+    for line in file.iter() {
+        let before = Offset::ALL.map(|offset| offset.value(&state));
+        state.step(line);
+        for (i, offset) in Offset::ALL.iter().enumerate() {
+            let value = offset.value(&state);
+            if value == before[i] {
+                continue;
+            }
+            if value.iter().all(|v| *v == Ratio::from_integer(0)) {
+                set_by[i] = None;
+                continue;
+            }
+            set_by[i] = Some(line.span());
+            for (j, other) in Offset::ALL.iter().enumerate() {
+                let other_span = match set_by[j] {
+                    Some(span) if j != i && !reported.contains(&(i.min(j), i.max(j))) => span,
+                    _ => continue,
+                };
+                reported.push((i.min(j), i.max(j)));
+                diagnostics.push(
+                    diagnostic_at(
+                        Severity::Warning,
+                        format!(
+                            "both a {} and a {} are in effect",
+                            other.name(),
+                            offset.name()
+                        ),
+                        line.span(),
+                        format!("{} set here", offset.name()),
+                    )
+                    .with_labels(vec![Label::secondary((), other_span.0..other_span.1)
+                        .with_message(format!("{} set here", other.name()))])
+                    .with_notes(vec![String::from(
+                        "positions are offset by both, which is rarely intended",
+                    )]),
+                );
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Warn about `G2`/`G3` arcs whose end point isn't on their circle, which GRBL rejects at runtime with error 33.
+///
+/// For an arc given by its center (`I` and `J`), the radius from the start point to the center
+/// is compared with the radius from the center to the end point. For one given by its radius (`R`),
+/// the radius must reach at least halfway across the chord from the start point to the end point.
+/// A warning is given wherever either is off by more than `tolerance`, in program units,
+/// with the computed lengths in its label.
+///
+/// Start and end points come from [State]. Only arcs in the XY plane with a `G2` or `G3` on their line are checked:
+/// arcs are skipped after a `G18` or `G19` until the next `G17`.
+///
+/// ```
+/// use g_code::lint::arc_consistency;
+/// use g_code::parse::file_parser;
+///
+/// let file = file_parser("G0 X0 Y0\nG2 X10 Y0 I5 J0\nG3 X0 Y0 I-4 J0").unwrap();
+/// let warnings = arc_consistency(&file, 0.002);
+/// assert_eq!(warnings.len(), 1);
+/// assert_eq!(
+///     warnings[0].labels[0].message,
+///     "radius is 4.000 from the start but 6.000 to the end"
+/// );
+/// ```
+pub fn arc_consistency(file: &File, tolerance: f64) -> Vec<Diagnostic> {
+    let mut state = State::default();
+    let mut in_xy_plane = true;
+    let mut diagnostics = vec![];
+    for line in file.iter() {
+        let mut is_arc = false;
+        let (mut i, mut j, mut r) = (None, None, None);
+        for field in line.iter_fields() {
+            match (field.letters.to_ascii_uppercase().as_str(), &field.value) {
+                ("G", Value::Integer(2 | 3)) => is_arc = true,
+                ("G", Value::Integer(17)) => in_xy_plane = true,
+                ("G", Value::Integer(18 | 19)) => in_xy_plane = false,
+                ("I", _) => i = as_ratio(field),
+                ("J", _) => j = as_ratio(field),
+                ("R", _) => r = as_ratio(field),
+                _ => {}
+            }
+        }
+        let start = state.program_position();
+        state.step(line);
+        if !is_arc || !in_xy_plane {
+            continue;
+        }
+        let end = state.program_position();
+        let to_f64 = |ratio: Ratio<i64>| ratio.to_f64().unwrap_or(f64::NAN);
+        let (start_x, start_y) = (to_f64(start[0]), to_f64(start[1]));
+        let (end_x, end_y) = (to_f64(end[0]), to_f64(end[1]));
+        let message = if let Some(r) = r {
+            let radius = to_f64(r).abs();
+            let chord = (end_x - start_x).hypot(end_y - start_y);
+            (chord / 2. - radius > tolerance).then(|| {
+                format!(
+                    "radius {:.3} is less than half of the {:.3} chord",
+                    radius, chord
+                )
+            })
+        } else if i.is_some() || j.is_some() {
+            let center_x = start_x + i.map_or(0., to_f64);
+            let center_y = start_y + j.map_or(0., to_f64);
+            let start_radius = (start_x - center_x).hypot(start_y - center_y);
+            let end_radius = (end_x - center_x).hypot(end_y - center_y);
+            ((start_radius - end_radius).abs() > tolerance).then(|| {
+                format!(
+                    "radius is {:.3} from the start but {:.3} to the end",
+                    start_radius, end_radius
+                )
+            })
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            diagnostics.push(
+                diagnostic_at(
+                    Severity::Warning,
+                    "arc does not end on its circle",
+                    line.span(),
+                    message,
+                )
+                    .with_notes(vec![format!(
+                        "GRBL rejects arcs like this with error 33, and other firmware may draw a different arc; \
+                         the tolerance is {}",
+                        tolerance
+                    )]),
+            );
+        }
+    }
+    diagnostics
+}
+
+/// Checks made by [Snippet::validate] of a user-supplied snippet,
+/// like the sequence that turns a tool on or off in a generated program.
+///
+/// By default, a snippet may neither end the program nor number its lines,
+/// while changes to modal state are allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnippetRules {
+    /// Refuse `M2` and `M30`, which would end the program the snippet is put into.
+    pub forbid_program_end: bool,
+    /// Refuse `N` line numbers, which would clash with the numbering of the program.
+    pub forbid_line_numbers: bool,
+    /// Require the units (`G20`/`G21`) to be left as they were.
+    pub restore_units: bool,
+    /// Require the distance mode (`G90`/`G91`, and `M82`/`M83` for E) to be left as it was.
+    pub restore_distance_mode: bool,
+    /// Require the feed rate mode (`G93`-`G95`) to be left as it was.
+    pub restore_feed_rate_mode: bool,
+    /// Require the work coordinate system (`G54`-`G59.3`) to be left as it was.
+    pub restore_work_offset: bool,
+}
+
+impl Default for SnippetRules {
+    fn default() -> Self {
+        Self {
+            forbid_program_end: true,
+            forbid_line_numbers: true,
+            restore_units: false,
+            restore_distance_mode: false,
+            restore_feed_rate_mode: false,
+            restore_work_offset: false,
+        }
+    }
+}
+
+/// The modal state that [SnippetRules] can require to be restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Units,
+    Distance,
+    FeedRate,
+    WorkOffset,
+}
+
+impl Mode {
+    const ALL: [Self; 4] = [
+        Self::Units,
+        Self::Distance,
+        Self::FeedRate,
+        Self::WorkOffset,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Units => "units",
+            Self::Distance => "distance mode",
+            Self::FeedRate => "feed rate mode",
+            Self::WorkOffset => "work coordinate system",
+        }
+    }
+
+    fn is_checked(self, rules: &SnippetRules) -> bool {
+        match self {
+            Self::Units => rules.restore_units,
+            Self::Distance => rules.restore_distance_mode,
+            Self::FeedRate => rules.restore_feed_rate_mode,
+            Self::WorkOffset => rules.restore_work_offset,
+        }
+    }
+
+    /// The mode in effect, as the commands that set it.
+    fn describe(self, state: &State) -> String {
+        let distance = match state.mode {
+            DistanceMode::Absolute => "absolute (G90)",
+            DistanceMode::Relative => "relative (G91)",
+        };
+        match self {
+            Self::Units => match state.units {
+                Units::Millimeters => "millimeters (G21)".to_string(),
+                Units::Inches => "inches (G20)".to_string(),
+            },
+            Self::Distance if state.mode == state.e_mode => distance.to_string(),
+            Self::Distance => match state.e_mode {
+                DistanceMode::Absolute => format!("{} with absolute E (M82)", distance),
+                DistanceMode::Relative => format!("{} with relative E (M83)", distance),
+            },
+            Self::FeedRate => match state.feed_rate_mode {
+                FeedRateMode::InverseTime => "inverse time (G93)".to_string(),
+                FeedRateMode::UnitsPerMinute => "units per minute (G94)".to_string(),
+                FeedRateMode::UnitsPerRevolution => "units per revolution (G95)".to_string(),
+            },
+            Self::WorkOffset => [
+                "G54", "G55", "G56", "G57", "G58", "G59", "G59.1", "G59.2", "G59.3",
+            ][state.active_work_offset]
+                .to_string(),
+        }
+    }
+}
+
+impl Snippet<'_> {
+    /// Check a snippet against the [SnippetRules], with an error for each rule that it breaks.
+    ///
+    /// Snippets can't hold `%` delimiters at all, since [Snippet::parse] refuses them.
+    ///
+    /// Modal state is tracked with [State], starting from its default as the program around
+    /// the snippet is assumed to be in, so a snippet that switches to `G91` for a move
+    /// and back to `G90` after it restores the distance mode.
+    /// An error for a mode that isn't restored labels the line that last changed it.
+    ///
+    /// ```
+    /// use g_code::lint::SnippetRules;
+    /// use g_code::parse::ast::Snippet;
+    ///
+    /// let rules = SnippetRules { restore_distance_mode: true, ..SnippetRules::default() };
+    /// let tool_off = Snippet::parse("M5\nG91\nG0 Z5\nG90").unwrap();
+    /// assert!(tool_off.validate(&rules).is_empty());
+    ///
+    /// let tool_off = Snippet::parse("M5\nG91\nG0 Z5\nM2").unwrap();
+    /// let errors = tool_off.validate(&rules);
+    /// assert_eq!(errors[0].message, "the snippet ends the program");
+    /// assert_eq!(errors[1].message, "the snippet leaves the distance mode changed");
+    /// ```
+    pub fn validate(&self, rules: &SnippetRules) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+        for field in self.iter_fields() {
+            if rules.forbid_program_end && end_code(field).is_some() {
+                diagnostics.push(
+                    diagnostic_at(
+                        Severity::Error,
+                        "the snippet ends the program",
+                        field.span(),
+                        "program ends here",
+                    )
+                    .with_notes(vec![String::from(
+                        "the rest of the program after the snippet would never run",
+                    )]),
+                );
+            } else if rules.forbid_line_numbers && field.letters.eq_ignore_ascii_case("N") {
+                diagnostics.push(
+                    diagnostic_at(
+                        Severity::Error,
+                        "the snippet has a line number",
+                        field.span(),
+                        "line number",
+                    )
+                    .with_notes(vec![String::from(
+                        "it would clash with the numbering of the program it is put into",
+                    )]),
+                );
+            }
+        }
+
+        let start = State::default();
+        let mut state = start.clone();
+        // The line that last changed each mode
+        let mut changed_by = [None; 4];
+        for line in self.iter() {
+            let before = state.clone();
+            state.step(line);
+            for (i, mode) in Mode::ALL.iter().enumerate() {
+                if mode.describe(&state) != mode.describe(&before) {
+                    changed_by[i] = Some(line.span());
+                }
+            }
+        }
+        for (mode, changed_by) in Mode::ALL.iter().zip(changed_by.iter()) {
+            let (was, now) = (mode.describe(&start), mode.describe(&state));
+            if let Some(span) = changed_by.filter(|_| mode.is_checked(rules) && was != now) {
+                diagnostics.push(
+                    diagnostic_at(
+                        Severity::Error,
+                        format!("the snippet leaves the {} changed", mode.name()),
+                        span,
+                        format!("{} set to {} here", mode.name(), now),
+                    )
+                    .with_notes(vec![format!(
+                        "the program is assumed to be in {}, so set it back before the snippet ends",
+                        was
+                    )]),
+                );
+            }
+        }
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use codespan_reporting::diagnostic::Severity;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn home_offset_and_g92_conflict() {
+        let gcode = include_str!("../tests/double_offset_start.gcode");
+        let file = file_parser(gcode).unwrap();
+        let warnings = conflicting_offsets(&file);
+        assert_eq!(warnings.len(), 1);
+        let warning = &warnings[0];
+        assert_eq!(warning.severity, Severity::Warning);
+        let labelled = warning
+            .labels
+            .iter()
+            .map(|label| &gcode[label.range.clone()])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labelled,
+            [
+                "G92 X0 Y0 ; make the nozzle position the origin",
+                "M206 X-5 Y-3 ; bed is mounted off center"
+            ]
+        );
+    }
+
+    #[test]
+    fn extruder_resets_do_not_conflict() {
+        let file =
+            file_parser("M206 X-5 Y-3\nG28\nG92 E0\nG1 X60 E9 F1500\nG92 E0\nG10 L2 P1 X0 Y0")
+                .unwrap();
+        assert_eq!(conflicting_offsets(&file).len(), 0);
+    }
+
+    #[test]
+    fn cleared_offsets_no_longer_conflict() {
+        let file = file_parser(
+            "G0 X1\nG92 X0\nG92.1\nM206 X-5\nG10 L2 P1 X3\nM206 X0\nG92 X0\nG10 L2 P1 X4",
+        )
+        .unwrap();
+        let warnings = conflicting_offsets(&file);
+        let primary_lines = warnings
+            .iter()
+            .map(|warning| warning.labels[0].range.start)
+            .collect::<Vec<_>>();
+        // The same pair is only reported once, and a cleared offset is not reported against
+        assert_eq!(primary_lines, [28, 49]);
+    }
+
+    #[test]
+    fn inconsistent_arcs_are_reported_with_their_radii() {
+        let file = file_parser(
+            "G21 G90\nG0 X0 Y0\nG2 X10 Y0 I4 J0\nG0 X0 Y0\nG2 X10 Y0 R4.9\nG3 X0 Y0 R5\nG18\nG2 X10 Y0 I4",
+        )
+        .unwrap();
+        let warnings = arc_consistency(&file, 0.002);
+        let labels = warnings
+            .iter()
+            .map(|warning| {
+                let label = &warning.labels[0];
+                (label.range.clone(), label.message.as_str())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [
+                (
+                    17..32,
+                    "radius is 4.000 from the start but 6.000 to the end"
+                ),
+                (42..56, "radius 4.900 is less than half of the 10.000 chord"),
+            ]
+        );
+    }
+
+    #[test]
+    fn arcs_within_tolerance_are_not_reported() {
+        let arcs = |end_x| {
+            let gcode = format!("G0 X0 Y0\nG2 X{} Y0 I5 J0", end_x);
+            arc_consistency(&file_parser(&gcode).unwrap(), 0.002).len()
+        };
+        assert_eq!(arcs("10.0019"), 0);
+        assert_eq!(arcs("10.0021"), 1);
+
+        // Relative moves end relative to where the arc starts
+        let file = file_parser("G0 X5 Y5\nG91\nG2 X10 Y0 I5 J0\nG3 X-10 Y0 R-5").unwrap();
+        assert_eq!(arc_consistency(&file, 0.002).len(), 0);
+    }
+
+    fn labelled<'a>(snippet: &'a str, diagnostic: &Diagnostic) -> Vec<&'a str> {
+        diagnostic
+            .labels
+            .iter()
+            .map(|label| &snippet[label.range.clone()])
+            .collect()
+    }
+
+    const STRICT: SnippetRules = SnippetRules {
+        forbid_program_end: true,
+        forbid_line_numbers: true,
+        restore_units: true,
+        restore_distance_mode: true,
+        restore_feed_rate_mode: true,
+        restore_work_offset: true,
+    };
+
+    #[test]
+    fn tool_sequences_that_restore_their_modes_are_valid() {
+        let src =
+            "M3 S1000 ; spindle on\nG4 P0.5\nG91\nG1 Z-1 F100\nG90\nG20\nG0 X1\nG21\nG55\nG54";
+        let snippet = Snippet::parse(src).unwrap();
+        assert_eq!(snippet.validate(&STRICT).len(), 0);
+    }
+
+    #[test]
+    fn ending_the_program_and_numbering_lines_are_refused() {
+        let src = "M5\nN10 G0 Z5\nm2";
+        let snippet = Snippet::parse(src).unwrap();
+        let errors = snippet.validate(&SnippetRules::default());
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.severity, error.message.as_str(), labelled(src, error)))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    Severity::Error,
+                    "the snippet has a line number",
+                    vec!["N10"]
+                ),
+                (Severity::Error, "the snippet ends the program", vec!["m2"]),
+            ]
+        );
+        let allowed = SnippetRules {
+            forbid_program_end: false,
+            forbid_line_numbers: false,
+            ..SnippetRules::default()
+        };
+        assert_eq!(snippet.validate(&allowed).len(), 0);
+    }
+
+    #[test]
+    fn units_left_changed_are_refused() {
+        let src = "G20\nG0 X1\nG21\nG0 X2\nG20 ; back to inches";
+        let snippet = Snippet::parse(src).unwrap();
+        assert_eq!(snippet.validate(&SnippetRules::default()).len(), 0);
+        let errors = snippet.validate(&STRICT);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "the snippet leaves the units changed");
+        assert_eq!(labelled(src, &errors[0]), ["G20 ; back to inches"]);
+        assert_eq!(
+            errors[0].labels[0].message,
+            "units set to inches (G20) here"
+        );
+        assert_eq!(
+            errors[0].notes,
+            ["the program is assumed to be in millimeters (G21), so set it back before the snippet ends"]
+        );
+    }
+}
@@ -1,5 +1,9 @@
 use super::token::*;
-use std::fmt::Debug;
+use crate::emit::{ChecksumStyle, Command, Token};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
 /// A range of [u8] in the raw text of the program.
@@ -13,6 +17,62 @@ pub trait Spanned {
     fn span(&self) -> Span;
 }
 
+impl Span {
+    /// The union of no spans, which leaves any span it is added to unchanged.
+    ///
+    /// Unlike a zero-length span such as `Span(0, 0)`, it has no position,
+    /// so it does not stretch a union to cover the start of the text.
+    /// It is not a valid range of any text, so check [Span::is_empty] before slicing with a union.
+    pub const EMPTY: Self = Self(usize::MAX, 0);
+
+    /// The smallest span covering all of the spans, or [Span::EMPTY] if there are none.
+    pub fn union_all<I: IntoIterator<Item = Span>>(spans: I) -> Self {
+        spans.into_iter().fold(Self::EMPTY, std::ops::Add::add)
+    }
+
+    /// Length of the span in bytes.
+    pub fn len(&self) -> usize {
+        self.1.saturating_sub(self.0)
+    }
+
+    /// True if the span covers no bytes, as for [Span::EMPTY] or a zero-length span.
+    pub fn is_empty(&self) -> bool {
+        self.0 >= self.1
+    }
+
+    /// True if the byte at `offset` is in the span.
+    pub fn contains(&self, offset: usize) -> bool {
+        self.0 <= offset && offset < self.1
+    }
+
+    /// True if there is a byte in both spans.
+    ///
+    /// Empty spans intersect nothing, not even a span they are positioned within.
+    pub fn intersects(&self, other: Span) -> bool {
+        self.0 < other.1 && other.0 < self.1 && !self.is_empty() && !other.is_empty()
+    }
+
+    /// Move the span by `offset` bytes, which is negative to move it towards the start.
+    /// [Span::EMPTY] stays where it is.
+    ///
+    /// Panics if the span would move before the start of the text or past [usize::MAX].
+    pub fn shift(self, offset: isize) -> Self {
+        if self == Self::EMPTY {
+            return self;
+        }
+        let shift = |pos: usize| {
+            if offset < 0 {
+                pos.checked_sub(offset.unsigned_abs())
+            } else {
+                pos.checked_add(offset as usize)
+            }
+            .expect("span shifted out of range")
+        };
+        Self(shift(self.0), shift(self.1))
+    }
+}
+
+/// The union of two spans, which also covers any bytes between them.
 impl std::ops::Add for Span {
     type Output = Self;
     fn add(self, rhs: Self) -> Self {
@@ -21,10 +81,19 @@ impl std::ops::Add for Span {
 }
 impl std::ops::AddAssign for Span {
     fn add_assign(&mut self, rhs: Self) {
-        *self = Self {
-            0: self.0.min(rhs.0),
-            1: self.1.max(rhs.1),
-        }
+        *self = Self(self.0.min(rhs.0), self.1.max(rhs.1))
+    }
+}
+
+impl std::iter::FromIterator<Span> for Span {
+    fn from_iter<I: IntoIterator<Item = Span>>(spans: I) -> Self {
+        Self::union_all(spans)
+    }
+}
+
+impl Extend<Span> for Span {
+    fn extend<I: IntoIterator<Item = Span>>(&mut self, spans: I) {
+        *self += Self::union_all(spans);
     }
 }
 
@@ -34,17 +103,38 @@ impl From<Span> for std::ops::Range<usize> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 /// Representation of a sequence of GCode logically organized as a file.
 /// This may also be referred to as a program.
+///
+/// A file borrows text from its input and owns everything else outright,
+/// so it is [Send] and [Sync]: threads can share one file, or each parse part of an input.
 pub struct File<'input> {
+    /// The input started with a UTF-8 byte order mark, which is not part of any line
+    pub(crate) byte_order_mark: bool,
     pub(crate) start_percent: bool,
     pub(crate) lines: Vec<(Line<'input>, Newline)>,
     pub(crate) last_line: Option<Line<'input>>,
     pub(crate) end_percent: bool,
     pub(crate) span: Span,
+    /// The input the file was parsed from, if it is still around
+    pub(crate) source: Option<&'input str>,
+}
+
+/// Files are compared by their contents, not by whether they kept their source.
+impl PartialEq for File<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.byte_order_mark == other.byte_order_mark
+            && self.start_percent == other.start_percent
+            && self.lines == other.lines
+            && self.last_line == other.last_line
+            && self.end_percent == other.end_percent
+            && self.span == other.span
+    }
 }
 
+impl Eq for File<'_> {}
+
 impl<'input> File<'input> {
     /// Iterate by [Line].
     /// The last [Line] may or may not be followed by a [Newline].
@@ -58,12 +148,237 @@ impl<'input> File<'input> {
     /// Iterating by [Line] may be too verbose, so this method is offered as
     /// an alternative for directly examining each [`Field`].
     pub fn iter_fields(&self) -> impl Iterator<Item = &Field<'input>> {
-        self.iter().map(|line| line.iter_fields()).flatten()
+        self.iter().flat_map(|line| line.iter_fields())
     }
 
     /// Iterate by [u8] in the file.
     pub fn iter_bytes(&self) -> impl Iterator<Item = &u8> {
-        self.iter().map(|line| line.iter_bytes()).flatten()
+        self.iter().flat_map(|line| line.iter_bytes())
+    }
+
+    /// Iterate by [Line], skipping lines that are not [executable](Line::is_executable),
+    /// which is what a sender needs to transmit.
+    ///
+    /// ```
+    /// use g_code::parse::file_parser;
+    ///
+    /// let file = file_parser("; start\nG28\n\n(wipe)\nG1 X1 ;move").unwrap();
+    /// assert_eq!(file.iter_executable().count(), 2);
+    /// ```
+    pub fn iter_executable(&self) -> impl Iterator<Item = &Line<'input>> {
+        self.iter().filter(|line| line.is_executable())
+    }
+
+    /// The input the file was parsed from.
+    ///
+    /// This is [None] for files that were not parsed from text, such as those from JSON.
+    pub fn source(&self) -> Option<&'input str> {
+        self.source
+    }
+
+    /// True if the input started with a UTF-8 byte order mark, as written by some Windows editors.
+    ///
+    /// The mark is skipped by the parser, but spans still count its three bytes
+    /// so that they match offsets in the input.
+    /// Set [FormatOptions::byte_order_mark](crate::emit::FormatOptions::byte_order_mark)
+    /// to keep it when re-formatting the file.
+    pub fn has_byte_order_mark(&self) -> bool {
+        self.byte_order_mark
+    }
+
+    /// True if the file is wrapped in `%` delimiters.
+    pub fn has_percent_delimiters(&self) -> bool {
+        self.start_percent && self.end_percent
+    }
+
+    /// Guess which [ChecksumStyle] the checksums of the file were computed with,
+    /// as the one under which the most checksummed lines are valid.
+    ///
+    /// Styles often agree, such as on lines without inline comments or line numbers.
+    /// Ties go to the style declared first, so such a file is taken to be [ChecksumStyle::Classic].
+    /// This is [None] if no checksum is valid under any style.
+    ///
+    /// ```
+    /// use g_code::emit::ChecksumStyle;
+    /// use g_code::parse::file_parser;
+    ///
+    /// // Checksummed by RepRapFirmware, which leaves out inline comments
+    /// let file = file_parser("N1 G28 (home)*50\nN2 M107*39").unwrap();
+    /// assert_eq!(file.detect_checksum_style(), Some(ChecksumStyle::ExcludeInlineComments));
+    /// ```
+    pub fn detect_checksum_style(&self) -> Option<ChecksumStyle> {
+        use ChecksumStyle::*;
+        let (style, valid) = [
+            Classic,
+            ExcludeSpaceAfterLineNumber,
+            IncludeAsterisk,
+            ExcludeInlineComments,
+        ]
+        .iter()
+        .rev()
+        .map(|style| {
+            let valid = self
+                .iter()
+                .filter(|line| line.validate_checksum_with(*style) == Some(Ok(())))
+                .count();
+            (*style, valid)
+        })
+        .max_by_key(|(_, valid)| *valid)?;
+        Some(style).filter(|_| valid > 0)
+    }
+
+    /// Iterate by emission [Token], suitable for re-formatting the file.
+    ///
+    /// Lines with nothing but whitespace on them become [Token::BlankLine].
+    /// A file wrapped in `%` delimiters starts and ends with a [Token::Percent],
+    /// and the empty remainder of the line with the opening percent sign is skipped.
+    /// Lines come in order, each as described for [Line::iter_emit_tokens], whatever their spans,
+    /// and are separated by a [Token::Newline] so that a comment-only line stays on a line of its own.
+    pub fn iter_emit_tokens(&self) -> impl Iterator<Item = Token<'input>> + '_ {
+        let start_percent = Some(Token::Percent).filter(|_| self.start_percent);
+        let end_percent = Some(Token::Percent).filter(|_| self.end_percent);
+        let skipped =
+            usize::from(self.start_percent && self.iter().next().is_some_and(Line::is_empty));
+        start_percent
+            .into_iter()
+            .chain(self.iter().skip(skipped).enumerate().flat_map(|(i, line)| {
+                Some(Token::Newline)
+                    .filter(|_| i > 0)
+                    .into_iter()
+                    .chain(line.iter_emit_tokens_or_blank())
+            }))
+            .chain(end_percent)
+    }
+
+    /// Iterate by emission [Token], keeping whitespace and newlines.
+    ///
+    /// Each line is as described for [Line::iter_verbatim_tokens] and followed by a [Token::Newline],
+    /// apart from a last line without one. Writing out the tokens one after another gives back the text
+    /// of the file, less any byte order mark, system commands, and realtime commands.
+    ///
+    /// ```
+    /// use g_code::parse::file_parser;
+    ///
+    /// let text = "%\nG0 X1 (rapid)  Y2*42;move\n\n  M2\n%";
+    /// let file = file_parser(text).unwrap();
+    /// let written = file.iter_verbatim_tokens().map(|token| token.to_string()).collect::<String>();
+    /// assert_eq!(written, text);
+    /// ```
+    pub fn iter_verbatim_tokens(&self) -> impl Iterator<Item = Token<'input>> + '_ {
+        let start_percent = Some(Token::Percent).filter(|_| self.start_percent);
+        let end_percent = Some(Token::Percent).filter(|_| self.end_percent);
+        start_percent
+            .into_iter()
+            .chain(self.lines.iter().flat_map(|(line, newline)| {
+                line.iter_verbatim_tokens()
+                    .chain(std::iter::once(Token::from(newline)))
+            }))
+            .chain(self.last_line.iter().flat_map(Line::iter_verbatim_tokens))
+            .chain(end_percent)
+    }
+}
+
+impl<'input> File<'input> {
+    /// Split the file into the programs it holds, each ending with an `M2` or `M30`.
+    ///
+    /// Some pendants concatenate programs into one file. Programs keep the spans of their lines,
+    /// so they still refer to the source of the file. Anything after the last end of program
+    /// is a program of its own, unless it is nothing but blank lines.
+    /// The remainder of the line with an opening percent sign is skipped, as in [File::iter_emit_tokens].
+    /// Only fields end a program, so an `M30` in a comment doesn't.
+    ///
+    /// ```
+    /// use g_code::parse::{ast::Spanned, file_parser};
+    ///
+    /// let text = "G0 X1 (M30 next)\nM30\nG0 X2\nM2\n\n";
+    /// let programs = file_parser(text).unwrap().split_programs();
+    /// let texts = programs.iter().map(|p| &text[p.span().0..p.span().1]).collect::<Vec<_>>();
+    /// assert_eq!(texts, ["G0 X1 (M30 next)\nM30\n", "G0 X2\nM2\n"]);
+    /// ```
+    pub fn split_programs(&self) -> Vec<Snippet<'input>> {
+        let ends_program = |line: &Line| {
+            line.iter_fields().any(|field| {
+                field.letters.eq_ignore_ascii_case("M")
+                    && matches!(field.value, Value::Integer(2 | 30))
+            })
+        };
+        let mut programs = vec![];
+        let mut lines = vec![];
+        for (i, (line, newline)) in self.lines.iter().enumerate() {
+            if self.start_percent && i == 0 && line.is_empty() {
+                continue;
+            }
+            lines.push((line.clone(), newline.clone()));
+            if ends_program(line) {
+                programs.push(Snippet::from_lines(std::mem::take(&mut lines), None));
+            }
+        }
+        if self.last_line.is_some() || lines.iter().any(|(line, _)| !line.is_empty()) {
+            programs.push(Snippet::from_lines(lines, self.last_line.clone()));
+        }
+        programs
+    }
+
+    /// Normalize the whitespace of every line as in [Line::normalize_whitespace],
+    /// moving the spans of everything after each line to follow it.
+    ///
+    /// Spans then match what parsing the normalized text would give,
+    /// so they no longer refer to the [source](File::source), which is dropped.
+    pub fn normalize_whitespace(&mut self) {
+        let mut removed = 0;
+        for (line, newline) in self.lines.iter_mut() {
+            removed += line.normalize_whitespace_at(line.span.0 - removed);
+            newline.pos -= removed;
+        }
+        if let Some(line) = self.last_line.as_mut() {
+            removed += line.normalize_whitespace_at(line.span.0 - removed);
+        }
+        self.span.1 -= removed;
+        self.source = None;
+    }
+
+    /// Map each `N` line number to the index of its [Line] in [File::iter].
+    ///
+    /// Fails on the first line number that appears more than once,
+    /// since a jump to it would be ambiguous.
+    pub fn line_number_index(&self) -> Result<BTreeMap<u64, usize>, DuplicateLineNumber> {
+        let mut index = BTreeMap::new();
+        for (i, line) in self.iter().enumerate() {
+            if let Some(number) = line.line_number() {
+                if let Some(first) = index.insert(number, i) {
+                    return Err(DuplicateLineNumber {
+                        number,
+                        first,
+                        second: i,
+                    });
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    /// Find the index of the [Line] that a jump like `GOTO 120` or `M99 P120` refers to,
+    /// given the `field` with the jump and the `line` it is on.
+    ///
+    /// Only `GOTO` fields and the `P` of an `M98` or `M99` on `line` are jumps,
+    /// so the `P` of a dwell like `G4 P500` is not. If the line number appears more than once,
+    /// the first line with it is the target.
+    pub fn resolve_jump(&self, line: &Line, field: &Field) -> Option<usize> {
+        let is_jump = field.letters.eq_ignore_ascii_case("GOTO")
+            || field.letters.eq_ignore_ascii_case("P")
+                && line.iter_fields().any(|f| {
+                    f.letters.eq_ignore_ascii_case("M")
+                        && matches!(f.value, Value::Integer(98 | 99))
+                });
+        if !is_jump {
+            return None;
+        }
+        let target = match field.value {
+            Value::Integer(target) => target,
+            _ => return None,
+        };
+        self.iter()
+            .position(|line| line.line_number() == Some(target))
     }
 }
 
@@ -73,7 +388,8 @@ impl<'input> Spanned for File<'input> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "float-values"), derive(Eq))]
 /// A sequence of GCode that may be inserted into a file.
 ///
 /// This might be used when verifying user-supplied tool
@@ -85,6 +401,40 @@ pub struct Snippet<'input> {
 }
 
 impl<'input> Snippet<'input> {
+    /// Parse a snippet, as done by [snippet_parser](crate::parse::snippet_parser).
+    ///
+    /// Unlike a [File], a snippet can't be wrapped in `%` delimiters, so any `%` is a parse error.
+    ///
+    /// ```
+    /// use g_code::parse::ast::Snippet;
+    ///
+    /// let tool_on = Snippet::parse("M3 S1000\nG4 P0.5").unwrap();
+    /// assert_eq!(tool_on.iter().count(), 2);
+    /// assert!(Snippet::parse("%\nM3 S1000\n%").is_err());
+    /// ```
+    pub fn parse(input: &'input str) -> Result<Self, crate::parse::ParseError> {
+        crate::parse::snippet_parser(input)
+    }
+
+    /// A snippet of lines that come one after another, spanning from the first to the last.
+    fn from_lines(lines: Vec<(Line<'input>, Newline)>, last_line: Option<Line<'input>>) -> Self {
+        let start = lines
+            .first()
+            .map(|(line, _)| line.span.0)
+            .or_else(|| last_line.as_ref().map(|line| line.span.0))
+            .unwrap_or(0);
+        let end = match (&last_line, lines.last()) {
+            (Some(line), _) => line.span.1,
+            (None, Some((_, newline))) => newline.span().1,
+            (None, None) => start,
+        };
+        Self {
+            lines,
+            last_line,
+            span: Span(start, end),
+        }
+    }
+
     /// Iterate by [Line].
     /// The last [Line] may or may not be followed by a [Newline].
     pub fn iter(&self) -> impl Iterator<Item = &Line<'input>> {
@@ -97,12 +447,39 @@ impl<'input> Snippet<'input> {
     /// Iterating by [Line] may be too verbose, so this method is offered as
     /// an alternative for directly examining each [Field].
     pub fn iter_fields(&self) -> impl Iterator<Item = &Field<'input>> {
-        self.iter().map(|line| line.iter_fields()).flatten()
+        self.iter().flat_map(|line| line.iter_fields())
     }
 
     /// Iterate by [u8] in the snippet.
     pub fn iter_bytes(&self) -> impl Iterator<Item = &u8> {
-        self.iter().map(|line| line.iter_bytes()).flatten()
+        self.iter().flat_map(|line| line.iter_bytes())
+    }
+
+    /// Iterate by emission [Token].
+    ///
+    /// Lines with nothing but whitespace on them become [Token::BlankLine],
+    /// and lines are separated by a [Token::Newline] as in [File::iter_emit_tokens].
+    pub fn iter_emit_tokens(&self) -> impl Iterator<Item = Token<'input>> + '_ {
+        self.iter().enumerate().flat_map(|(i, line)| {
+            Some(Token::Newline)
+                .filter(|_| i > 0)
+                .into_iter()
+                .chain(line.iter_emit_tokens_or_blank())
+        })
+    }
+
+    /// True if a line of the snippet has the command and all of its arguments.
+    ///
+    /// Fields are compared as described for [crate::emit::Field]'s `PartialEq` with parsed fields,
+    /// so `M3 P1000.0` contains [start_spindle_clockwise](crate::emit::start_spindle_clockwise) with `P1000`.
+    /// The line may have other fields too, so `M5` is contained in `N10 M5 P1`,
+    /// but arguments of the command must be on the same line as its name.
+    pub fn contains_command(&self, command: &Command) -> bool {
+        self.iter().any(|line| {
+            command
+                .iter()
+                .all(|expected| line.iter_fields().any(|field| field == expected))
+        })
     }
 }
 
@@ -111,12 +488,30 @@ impl<'input> Spanned for Snippet<'input> {
         self.span
     }
 }
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// How a [Line] is to be emitted, as returned by [Line::emit_plan].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineEmitPlan<'input> {
+    /// The tokens of the line, including a [Token::Checksum] only if its checksum was valid.
+    pub tokens: Vec<Token<'input>>,
+    /// The checksum the line had and whether it was valid.
+    ///
+    /// A checksum too large to be a byte is never valid and is given as 255.
+    pub original_checksum: Option<(u8, bool)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "float-values"), derive(Eq))]
 /// A sequence of GCode that is either followed by a [Newline] or at the end of a file.
 pub struct Line<'input> {
     pub(crate) line_components: Vec<LineComponent<'input>>,
     pub(crate) checksum: Option<Checksum>,
     pub(crate) comment: Option<Comment<'input>>,
+    /// Set instead of everything else for a GRBL system command line
+    pub(crate) system_command: Option<SystemCommand<'input>>,
+    /// Set for a Klipper extended command line, which can only be followed by whitespace and a comment
+    pub(crate) extended_command: Option<ExtendedCommand<'input>>,
+    /// Set for a RepRapFirmware meta command line, which can only be followed by whitespace and a comment
+    pub(crate) meta_command: Option<MetaCommand<'input>>,
     pub(crate) span: Span,
 }
 
@@ -126,6 +521,85 @@ impl<'input> Spanned for Line<'input> {
     }
 }
 
+/// Iterator over the bytes of a [Line], as returned by [Line::iter_bytes].
+#[derive(Debug, Clone)]
+pub struct LineBytes<'a> {
+    pieces: BytePieces<'a>,
+    current: std::slice::Iter<'a, u8>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for LineBytes<'a> {
+    type Item = &'a u8;
+
+    fn next(&mut self) -> Option<&'a u8> {
+        loop {
+            if let Some(byte) = self.current.next() {
+                self.remaining -= 1;
+                return Some(byte);
+            }
+            self.current = self.pieces.next()?.iter();
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for LineBytes<'_> {}
+
+impl std::iter::FusedIterator for LineBytes<'_> {}
+
+/// The bytes of a [Line] as the slices of text it holds, in order.
+#[derive(Debug, Clone)]
+struct BytePieces<'a> {
+    line: &'a Line<'a>,
+    /// The extended command, the meta command, each line component, and then the system command
+    part: usize,
+    /// Which slice of the part is next
+    index: usize,
+}
+
+impl<'a> Iterator for BytePieces<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let line = self.line;
+        let components = line.line_components.len();
+        loop {
+            let piece = match self.part {
+                0 => line
+                    .extended_command
+                    .as_ref()
+                    .and_then(|e| e.byte_piece(self.index)),
+                1 => line
+                    .meta_command
+                    .as_ref()
+                    .and_then(|m| m.byte_piece(self.index)),
+                part if part < components + 2 => {
+                    line.line_components[part - 2].byte_piece(self.index)
+                }
+                part if part == components + 2 => line
+                    .system_command
+                    .as_ref()
+                    .and_then(|s| s.byte_piece(self.index)),
+                _ => return None,
+            };
+            match piece {
+                Some(piece) => {
+                    self.index += 1;
+                    return Some(piece);
+                }
+                None => {
+                    self.part += 1;
+                    self.index = 0;
+                }
+            }
+        }
+    }
+}
+
 impl<'input> Line<'input> {
     /// Iterate by [Field] in a line of GCode.
     pub fn iter_fields(&self) -> impl Iterator<Item = &Field<'input>> {
@@ -137,38 +611,789 @@ impl<'input> Line<'input> {
     ///
     /// If the line does have a checksum, this will return an empty [Result::Ok]
     /// or an [Result::Err] containing the computed checksum that differs from the actual.
+    /// Checksums too large to be a byte never match.
     pub fn validate_checksum(&self) -> Option<Result<(), u8>> {
-        if let Some(Checksum {
-            inner: checksum, ..
-        }) = self.checksum.as_ref()
-        {
-            let computed_checksum = self.compute_checksum();
-            if computed_checksum != *checksum {
-                return Some(Err(computed_checksum));
-            } else {
-                return Some(Ok(()));
-            }
+        self.validate_checksum_with(ChecksumStyle::Classic)
+    }
+
+    /// The exact text of the line in the input of its [File], without the newline.
+    ///
+    /// This is [None] if the file has no [source](File::source),
+    /// or if edits have moved the line's span outside of it.
+    /// The text is always that of the input, so it does not reflect edits to the line.
+    pub fn raw_text(&self, file: &File<'input>) -> Option<&'input str> {
+        self.raw_text_in(file.source?)
+    }
+
+    fn raw_text_in<'s>(&self, source: &'s str) -> Option<&'s str> {
+        source.get(std::ops::Range::from(self.span))
+    }
+
+    /// The exact text of the line in the source it was parsed from, as a [Token::Raw]
+    /// that formatters write untouched, checksum and all.
+    ///
+    /// This is [None] if the line's span is not in the source, as for [Line::raw_text].
+    ///
+    /// ```
+    /// use g_code::emit::{format_gcode_fmt, FormatOptions};
+    /// use g_code::parse::file_parser;
+    ///
+    /// let source = "n1 g1 x1.000*12;kept as is";
+    /// let file = file_parser(source).unwrap();
+    /// let tokens = file.iter().map(|line| line.as_raw_token(source).unwrap()).collect::<Vec<_>>();
+    /// let mut written = String::new();
+    /// format_gcode_fmt(&tokens, FormatOptions { line_numbers: true, ..Default::default() }, &mut written)?;
+    /// assert_eq!(written, "n1 g1 x1.000*12;kept as is\n");
+    /// # Ok::<(), std::fmt::Error>(())
+    /// ```
+    pub fn as_raw_token<'s>(&self, source: &'s str) -> Option<Token<'s>> {
+        self.raw_text_in(source)
+            .map(|text| Token::Raw(Cow::Borrowed(text)))
+    }
+
+    /// The value of the `N` field that starts the line, if any.
+    pub fn line_number(&self) -> Option<u64> {
+        match self.iter_fields().next()? {
+            Field {
+                letters,
+                value: Value::Integer(number),
+                ..
+            } if letters.eq_ignore_ascii_case("N") => Some(*number),
+            _ => None,
         }
-        None
     }
 
-    /// Iterate over [u8] in a [Line].
-    pub fn iter_bytes(&self) -> impl Iterator<Item = &u8> {
+    /// The GRBL system command making up the line, if it is one.
+    pub fn system_command(&self) -> Option<&SystemCommand<'input>> {
+        self.system_command.as_ref()
+    }
+
+    /// The Klipper extended command at the start of the line, if it is one.
+    pub fn extended_command(&self) -> Option<&ExtendedCommand<'input>> {
+        self.extended_command.as_ref()
+    }
+
+    /// The RepRapFirmware meta command making up the line, if it is one.
+    pub fn meta_command(&self) -> Option<&MetaCommand<'input>> {
+        self.meta_command.as_ref()
+    }
+
+    /// Iterate over the GRBL realtime commands interleaved with the rest of the line.
+    pub fn iter_realtime_commands(&self) -> impl Iterator<Item = &RealtimeCommand> {
         self.line_components
             .iter()
-            .map(|c| c.iter_bytes())
-            .flatten()
+            .filter_map(|c| c.realtime_command.as_ref())
+    }
+
+    /// Iterate over the value-less letters following a command, like the `X` and `Y` in `G28 X Y`.
+    pub fn iter_flags(&self) -> impl Iterator<Item = &Flag<'input>> {
+        self.line_components.iter().filter_map(|c| c.flag.as_ref())
+    }
+
+    /// Iterate over [u8] in a [Line].
+    ///
+    /// The iterator knows how many bytes are left, so collecting it allocates once.
+    pub fn iter_bytes(&self) -> LineBytes<'_> {
+        LineBytes {
+            pieces: self.byte_pieces(),
+            current: [].iter(),
+            remaining: self.byte_len(),
+        }
+    }
+
+    /// The number of bytes in [iter_bytes](Self::iter_bytes).
+    ///
+    /// This is counted from the text the line holds rather than from its spans,
+    /// so it stays right after the line is edited.
+    pub fn byte_len(&self) -> usize {
+        self.byte_pieces().map(<[u8]>::len).sum()
+    }
+
+    /// Append the bytes of [iter_bytes](Self::iter_bytes) to `out`,
+    /// a slice at a time rather than a byte at a time.
+    pub fn write_bytes(&self, out: &mut Vec<u8>) {
+        out.reserve(self.byte_len());
+        for piece in self.byte_pieces() {
+            out.extend_from_slice(piece);
+        }
+    }
+
+    fn byte_pieces(&self) -> BytePieces<'_> {
+        BytePieces {
+            line: self,
+            part: 0,
+            index: 0,
+        }
+    }
+
+    /// Iterate by emission [Token] in a [Line].
+    ///
+    /// Whitespace is omitted since formatters decide on their own spacing.
+    /// GRBL system and realtime commands have no emission token, so they are omitted too.
+    /// A checksum is kept as a [Token::Checksum], which formatters take as a request to
+    /// checksum the line they write, since the original value will not match a reformatted line.
+    /// A checksum too large to be written is replaced by the one computed for the line.
+    ///
+    /// Tokens are in the order the line holds its parts: an extended or meta command,
+    /// the fields and inline comments in the order they were parsed or inserted, the checksum,
+    /// and then the end-of-line comment. Spans are never consulted, so edits that leave
+    /// several tokens with the same start, or with an empty span, don't reorder anything.
+    pub fn iter_emit_tokens(&self) -> impl Iterator<Item = Token<'input>> + '_ {
+        self.extended_command
+            .iter()
+            .map(|e| Token::ExtendedCommand(e.into()))
+            .chain(
+                self.meta_command
+                    .iter()
+                    .map(|m| Token::MetaCommand(m.into())),
+            )
+            .chain(self.line_components.iter().filter_map(|c| {
+                c.field
+                    .as_ref()
+                    .map(Token::from)
+                    .or_else(|| c.flag.as_ref().map(Token::from))
+                    .or_else(|| c.inline_comment.as_ref().map(Token::from))
+            }))
+            .chain(self.checksum_token())
+            .chain(self.comment.iter().map(Token::from))
+    }
+
+    /// Iterate by emission [Token], keeping whitespace as [Token::Whitespace].
+    ///
+    /// Tokens come in the same order as [Line::iter_emit_tokens],
+    /// with the whitespace between fields and inline comments where it was parsed.
+    pub fn iter_verbatim_tokens(&self) -> impl Iterator<Item = Token<'input>> + '_ {
+        self.extended_command
+            .iter()
+            .map(|e| Token::ExtendedCommand(e.into()))
+            .chain(
+                self.meta_command
+                    .iter()
+                    .map(|m| Token::MetaCommand(m.into())),
+            )
+            .chain(self.line_components.iter().filter_map(|c| {
+                c.field
+                    .as_ref()
+                    .map(Token::from)
+                    .or_else(|| c.flag.as_ref().map(Token::from))
+                    .or_else(|| c.whitespace.as_ref().map(Token::from))
+                    .or_else(|| c.inline_comment.as_ref().map(Token::from))
+            }))
+            .chain(self.checksum_token())
+            .chain(self.comment.iter().map(Token::from))
+    }
+
+    /// The emission tokens of the line, along with what became of its checksum.
+    ///
+    /// Unlike [Line::iter_emit_tokens], a checksum only becomes a [Token::Checksum] if it is valid,
+    /// so that formatting with [ChecksumPolicy::PreserveValid](crate::emit::ChecksumPolicy::PreserveValid)
+    /// keeps valid checksums as they were and recomputes the rest.
+    pub fn emit_plan(&self) -> LineEmitPlan<'input> {
+        let valid = self.validate_checksum();
+        LineEmitPlan {
+            tokens: self
+                .iter_emit_tokens()
+                .filter(|token| !matches!(token, Token::Checksum(_)) || valid == Some(Ok(())))
+                .collect(),
+            original_checksum: self.checksum.as_ref().map(|checksum| {
+                (
+                    u8::try_from(checksum.inner).unwrap_or(u8::MAX),
+                    valid == Some(Ok(())),
+                )
+            }),
+        }
+    }
+
+    pub(crate) fn checksum_token(&self) -> Option<Token<'input>> {
+        self.checksum.as_ref().map(|c| {
+            Token::try_from(c).unwrap_or_else(|_| Token::Checksum(self.compute_checksum()))
+        })
+    }
+
+    /// True if the line has at least one [Field], even if it is only an `N` line number.
+    pub fn has_fields(&self) -> bool {
+        self.iter_fields().next().is_some()
+    }
+
+    /// True if firmware has something to do with the line: a field,
+    /// or a system, extended, or meta command when the parser allows them.
+    ///
+    /// Lines without any, like blank or comment-only lines, aren't worth sending,
+    /// and are not counted by the line numbering of [FormatOptions::line_numbers](crate::emit::FormatOptions::line_numbers).
+    /// Realtime commands don't count either, since GRBL acts on them as soon as they arrive, line or not.
+    pub fn is_executable(&self) -> bool {
+        self.has_fields()
+            || self.system_command.is_some()
+            || self.extended_command.is_some()
+            || self.meta_command.is_some()
+    }
+
+    /// True if the line holds nothing but whitespace.
+    pub fn is_empty(&self) -> bool {
+        self.checksum.is_none()
+            && self.comment.is_none()
+            && self.system_command.is_none()
+            && self.extended_command.is_none()
+            && self.meta_command.is_none()
+            && self.line_components.iter().all(|c| {
+                c.field.is_none()
+                    && c.flag.is_none()
+                    && c.inline_comment.is_none()
+                    && c.realtime_command.is_none()
+            })
+    }
+
+    pub(crate) fn iter_emit_tokens_or_blank(&self) -> impl Iterator<Item = Token<'input>> + '_ {
+        let blank = if self.is_empty() {
+            Some(Token::BlankLine)
+        } else {
+            None
+        };
+        blank.into_iter().chain(self.iter_emit_tokens())
     }
 
     /// XORs bytes in a [Line] leading up to the asterisk of a [`Checksum`].
+    ///
+    /// Like firmware, this never includes the end-of-line comment.
     pub fn compute_checksum(&self) -> u8 {
-        let take = if let Some(checksum) = &self.checksum {
-            checksum.span.0
-        } else if let Some(comment) = &self.comment {
-            comment.pos
+        // Line components always come before the checksum and comment,
+        // so there is no need to rely on spans, which may be synthetic after editing
+        self.byte_pieces()
+            .fold(0u8, |acc, piece| piece.iter().fold(acc, |acc, b| acc ^ b))
+    }
+
+    /// Replace the end-of-line comment, or remove it with [None].
+    ///
+    /// The text is given without the leading semicolon.
+    /// A new comment is given a synthetic span at the end of the line.
+    /// If the text can't be a comment, the line is left as it was.
+    pub fn set_eol_comment(&mut self, text: Option<&str>) -> Result<(), InvalidComment> {
+        if let Some(text) = text.filter(|text| !text.bytes().all(is_comment_byte)) {
+            return Err(InvalidComment(text.to_string()));
+        }
+        let pos = self
+            .comment
+            .take()
+            .map(|comment| comment.pos)
+            .unwrap_or(self.span.1);
+        self.span.1 = pos;
+        if let Some(text) = text {
+            let comment = Comment {
+                inner: Cow::Owned(format!(";{}", text)),
+                pos,
+            };
+            self.span.1 = comment.span().1;
+            self.comment = Some(comment);
+        }
+        Ok(())
+    }
+
+    /// Insert an inline comment before the field at `position`, or after all fields
+    /// if `position` is the number of fields in the line.
+    ///
+    /// The text is given without parentheses.
+    /// The comment is given a synthetic span starting where the displaced field did,
+    /// so the spans of later tokens are left as they were.
+    /// If the text can't be an inline comment or `position` is greater than the number of fields,
+    /// the line is left as it was.
+    pub fn push_inline_comment(
+        &mut self,
+        text: &str,
+        position: usize,
+    ) -> Result<(), PushInlineCommentError> {
+        if !text.bytes().all(|b| is_comment_byte(b) && b != b')') {
+            return Err(InvalidComment(text.to_string()).into());
+        }
+        let index = self
+            .line_components
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.field.is_some())
+            .map(|(i, _)| i)
+            .nth(position);
+        let (index, pos) = match index {
+            Some(i) => (i, self.line_components[i].field.as_ref().unwrap().span.0),
+            None => {
+                let fields = self.iter_fields().count();
+                if position != fields {
+                    return Err(PushInlineCommentError::OutOfRange { position, fields });
+                }
+                let end = self
+                    .line_components
+                    .iter()
+                    .rev()
+                    .find_map(|c| {
+                        c.field
+                            .as_ref()
+                            .map(Spanned::span)
+                            .or_else(|| c.flag.as_ref().map(Spanned::span))
+                            .or_else(|| c.whitespace.as_ref().map(Spanned::span))
+                            .or_else(|| c.inline_comment.as_ref().map(Spanned::span))
+                    })
+                    .map_or(self.span.0, |span| span.1);
+                (self.line_components.len(), end)
+            }
+        };
+        self.line_components.insert(
+            index,
+            LineComponent {
+                inline_comment: Some(InlineComment {
+                    inner: Cow::Owned(format!("({})", text)),
+                    pos,
+                }),
+                ..Default::default()
+            },
+        );
+        Ok(())
+    }
+
+    /// Collapse runs of whitespace between the parts of the line to a single space,
+    /// and remove whitespace at the end of the line or before a checksum.
+    /// Whitespace before an end-of-line comment becomes a single space.
+    ///
+    /// Leading whitespace is kept as it is, since RepRapFirmware uses indentation to delimit blocks.
+    /// Fields are untouched, so spaces inside a string value or after a `GOTO` stay as they were.
+    ///
+    /// Spans within the line follow the normalized text, but the spans of later lines are left as they were;
+    /// see [File::normalize_whitespace] for moving those too.
+    /// Like any edit, this changes [Line::compute_checksum], but the line's own checksum is kept.
+    pub fn normalize_whitespace(&mut self) {
+        self.normalize_whitespace_at(self.span.0);
+    }
+
+    /// Normalize the whitespace and move the line to `start`, returning how much shorter it got.
+    fn normalize_whitespace_at(&mut self, start: usize) -> usize {
+        let len = self.span.1 - self.span.0;
+        let mut components = Vec::with_capacity(self.line_components.len());
+        let mut run: Option<Whitespace<'input>> = None;
+        let mut leading = self.extended_command.is_none() && self.meta_command.is_none();
+        for component in self.line_components.drain(..) {
+            if let Some(whitespace) = component.whitespace {
+                run = Some(match run {
+                    Some(mut run) => {
+                        run.inner.to_mut().push_str(&whitespace.inner);
+                        run
+                    }
+                    None => whitespace,
+                });
+                continue;
+            }
+            if let Some(mut whitespace) = run.take() {
+                if !leading {
+                    whitespace.inner = Cow::Borrowed(" ");
+                }
+                components.push(LineComponent {
+                    whitespace: Some(whitespace),
+                    ..Default::default()
+                });
+            }
+            leading = false;
+            components.push(component);
+        }
+        if let Some(mut whitespace) = run {
+            let before_comment = self.comment.is_some() && self.checksum.is_none();
+            if before_comment || leading && self.checksum.is_some() {
+                if !leading {
+                    whitespace.inner = Cow::Borrowed(" ");
+                }
+                components.push(LineComponent {
+                    whitespace: Some(whitespace),
+                    ..Default::default()
+                });
+            }
+        }
+        self.line_components = components;
+        self.relayout(start);
+        len - (self.span.1 - self.span.0)
+    }
+
+    /// Give everything in the line a span following the one before it, starting from `start`.
+    fn relayout(&mut self, start: usize) {
+        /// Move a span to `pos`, then move `pos` past it
+        fn place(pos: &mut usize, span: &mut Span) {
+            let len = span.1 - span.0;
+            *span = Span(*pos, *pos + len);
+            *pos += len;
+        }
+        /// Move a position to `pos`, then move `pos` past the `len` bytes starting there
+        fn place_at(pos: &mut usize, at: &mut usize, len: usize) {
+            *at = *pos;
+            *pos += len;
+        }
+        let mut pos = start;
+        if let Some(command) = self.extended_command.as_mut() {
+            place(&mut pos, &mut command.span);
+        }
+        if let Some(command) = self.meta_command.as_mut() {
+            place(&mut pos, &mut command.span);
+        }
+        if let Some(command) = self.system_command.as_mut() {
+            place_at(&mut pos, &mut command.pos, command.inner.len());
+        }
+        for component in self.line_components.iter_mut() {
+            if let Some(field) = component.field.as_mut() {
+                place(&mut pos, &mut field.span);
+            }
+            if let Some(whitespace) = component.whitespace.as_mut() {
+                place_at(&mut pos, &mut whitespace.pos, whitespace.inner.len());
+            }
+            if let Some(comment) = component.inline_comment.as_mut() {
+                place_at(&mut pos, &mut comment.pos, comment.inner.len());
+            }
+            if let Some(command) = component.realtime_command.as_mut() {
+                place_at(&mut pos, &mut command.pos, 1);
+            }
+            if let Some(flag) = component.flag.as_mut() {
+                place_at(&mut pos, &mut flag.pos, flag.letters.len());
+            }
+        }
+        if let Some(checksum) = self.checksum.as_mut() {
+            place(&mut pos, &mut checksum.span);
+        }
+        if let Some(comment) = self.comment.as_mut() {
+            place_at(&mut pos, &mut comment.pos, comment.inner.len());
+        }
+        self.span = Span(start, pos);
+    }
+
+    /// Like [Line::compute_checksum], but with the bytes chosen according to a [ChecksumStyle].
+    pub fn compute_checksum_with(&self, style: ChecksumStyle) -> u8 {
+        let classic = self.compute_checksum();
+        // XOR is its own inverse, so excluding bytes is the same as XORing them in again
+        match style {
+            ChecksumStyle::Classic => classic,
+            ChecksumStyle::ExcludeSpaceAfterLineNumber => self
+                .whitespace_after_line_number()
+                .map(|w| w.iter_bytes().fold(classic, |acc, b| acc ^ b))
+                .unwrap_or(classic),
+            ChecksumStyle::IncludeAsterisk => classic ^ b'*',
+            ChecksumStyle::ExcludeInlineComments => self
+                .line_components
+                .iter()
+                .filter_map(|c| c.inline_comment.as_ref())
+                .flat_map(|c| c.iter_bytes())
+                .fold(classic, |acc, b| acc ^ b),
+        }
+    }
+
+    /// Like [Line::validate_checksum], but with the checksum computed according to a [ChecksumStyle].
+    pub fn validate_checksum_with(&self, style: ChecksumStyle) -> Option<Result<(), u8>> {
+        let checksum = self.checksum.as_ref()?.inner;
+        let computed = self.compute_checksum_with(style);
+        if u16::from(computed) == checksum {
+            Some(Ok(()))
         } else {
-            self.span.1
-        } - self.span.0;
-        self.iter_bytes().take(take).fold(0u8, |acc, b| acc ^ b)
+            Some(Err(computed))
+        }
+    }
+
+    /// The whitespace immediately following a leading `N` field, if any.
+    fn whitespace_after_line_number(&self) -> Option<&Whitespace<'input>> {
+        let mut components = self
+            .line_components
+            .iter()
+            .skip_while(|c| c.field.is_none() && c.inline_comment.is_none());
+        match components.next()?.field.as_ref() {
+            Some(field) if field.letters.eq_ignore_ascii_case("N") => {
+                components.next()?.whitespace.as_ref()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Line<'static> {
+    /// Build a line from emission [Token]s, laid out the way the formatters would write it:
+    /// fields, flags, and inline comments separated by a space, then the checksum, then the end-of-line comment.
+    ///
+    /// Spans are synthetic, starting at `start_offset` and following the rendered text,
+    /// so the result matches what parsing that text at that offset would produce.
+    /// [Token::BlankLine]s and [Token::Whitespace] are ignored, and [Token::Checksum] keeps its value rather than being recomputed.
+    ///
+    /// # Errors
+    ///
+    /// If the tokens do not fit on one line or would not parse back the same way: see [FromTokensError].
+    pub fn from_tokens(
+        tokens: &[Token],
+        start_offset: usize,
+    ) -> Result<Line<'static>, FromTokensError> {
+        let mut line = Line {
+            line_components: vec![],
+            checksum: None,
+            comment: None,
+            system_command: None,
+            extended_command: None,
+            meta_command: None,
+            span: Span(start_offset, start_offset),
+        };
+        let mut pos = start_offset;
+        for (index, token) in tokens.iter().enumerate() {
+            if line.comment.is_some() {
+                return Err(FromTokensError::AfterComment { index });
+            }
+            let is_eol_comment = matches!(
+                token,
+                Token::Comment {
+                    is_inline: false,
+                    ..
+                }
+            );
+            if line.checksum.is_some() && !is_eol_comment {
+                return Err(FromTokensError::AfterChecksum { index });
+            }
+            match token {
+                Token::Field(_)
+                | Token::Flag(_)
+                | Token::Comment {
+                    is_inline: true, ..
+                } => {
+                    if !line.line_components.is_empty() {
+                        line.line_components.push(LineComponent {
+                            whitespace: Some(Whitespace {
+                                inner: Cow::Borrowed(" "),
+                                pos,
+                            }),
+                            ..Default::default()
+                        });
+                        pos += 1;
+                    }
+                    let (component, len) = match token {
+                        Token::Field(field) => {
+                            let text = field.to_string();
+                            let parsed = super::field(&text)
+                                .map_err(|_| FromTokensError::InvalidField { index })?;
+                            let component = LineComponent {
+                                field: Some(Field {
+                                    letters: Cow::Owned(parsed.letters.into_owned()),
+                                    value: match parsed.value {
+                                        Value::String(s) => {
+                                            Value::String(Cow::Owned(s.into_owned()))
+                                        }
+                                        Value::Rational(r) => Value::Rational(r),
+                                        Value::Integer(i) => Value::Integer(i),
+                                        Value::Raw(s) => Value::Raw(Cow::Owned(s.into_owned())),
+                                    },
+                                    raw_value: parsed
+                                        .raw_value
+                                        .into_iter()
+                                        .map(|raw| Cow::Owned(raw.into_owned()))
+                                        .collect(),
+                                    span: Span(pos, pos + text.len()),
+                                }),
+                                ..Default::default()
+                            };
+                            (component, text.len())
+                        }
+                        Token::Flag(flag) => {
+                            let follows_command = line.iter_fields().any(|field| {
+                                ["G", "M"]
+                                    .iter()
+                                    .any(|l| field.letters.eq_ignore_ascii_case(l))
+                            });
+                            if crate::emit::Flag::new(flag.letters.as_ref()).is_err()
+                                || !follows_command
+                            {
+                                return Err(FromTokensError::InvalidFlag { index });
+                            }
+                            let len = flag.letters.len();
+                            let component = LineComponent {
+                                flag: Some(Flag {
+                                    letters: Cow::Owned(flag.letters.to_string()),
+                                    pos,
+                                }),
+                                ..Default::default()
+                            };
+                            (component, len)
+                        }
+                        _ => {
+                            let text = token.to_string();
+                            if !text[1..text.len() - 1]
+                                .bytes()
+                                .all(|b| is_comment_byte(b) && b != b')')
+                            {
+                                return Err(FromTokensError::InvalidComment { index });
+                            }
+                            let len = text.len();
+                            let component = LineComponent {
+                                inline_comment: Some(InlineComment {
+                                    inner: Cow::Owned(text),
+                                    pos,
+                                }),
+                                ..Default::default()
+                            };
+                            (component, len)
+                        }
+                    };
+                    pos += len;
+                    line.line_components.push(component);
+                }
+                Token::Comment { inner, .. } => {
+                    if line.checksum.is_none() && !line.line_components.is_empty() {
+                        line.line_components.push(LineComponent {
+                            whitespace: Some(Whitespace {
+                                inner: Cow::Borrowed(" "),
+                                pos,
+                            }),
+                            ..Default::default()
+                        });
+                        pos += 1;
+                    }
+                    if !inner.bytes().all(is_comment_byte) {
+                        return Err(FromTokensError::InvalidComment { index });
+                    }
+                    let comment = Comment {
+                        inner: Cow::Owned(format!(";{}", inner)),
+                        pos,
+                    };
+                    pos = comment.span().1;
+                    line.comment = Some(comment);
+                }
+                Token::Checksum(checksum) => {
+                    let end = pos + token.to_string().len();
+                    line.checksum = Some(Checksum {
+                        inner: u16::from(*checksum),
+                        span: Span(pos, end),
+                    });
+                    pos = end;
+                }
+                Token::BlankLine | Token::Whitespace(_) => {}
+                Token::Percent | Token::Newline | Token::Raw(_) => {
+                    return Err(FromTokensError::NotOnOneLine { index })
+                }
+                Token::ExtendedCommand(_) | Token::MetaCommand(_) => {
+                    return Err(FromTokensError::NotParsedByDefault { index })
+                }
+            }
+        }
+        line.span.1 = pos;
+        Ok(line)
+    }
+}
+
+/// Reasons that [Line::from_tokens] can reject its tokens.
+///
+/// Each variant holds the index of the offending token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FromTokensError {
+    /// A [Token::Percent], [Token::Newline], or [Token::Raw], which cannot be part of a line.
+    NotOnOneLine { index: usize },
+    /// A token after an end-of-line comment, which runs to the end of the line.
+    AfterComment { index: usize },
+    /// A token other than an end-of-line comment after a checksum.
+    AfterChecksum { index: usize },
+    /// A [Token::ExtendedCommand] or [Token::MetaCommand], which the parser does not accept by default.
+    NotParsedByDefault { index: usize },
+    /// A field that would not parse back as the same single field.
+    InvalidField { index: usize },
+    /// A flag with invalid letters, or one that does not follow a `G` or `M` field,
+    /// which the parser would not take as a flag.
+    InvalidFlag { index: usize },
+    /// A comment that would not parse back as a single comment.
+    InvalidComment { index: usize },
+}
+
+impl fmt::Display for FromTokensError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotOnOneLine { index } => {
+                write!(f, "token {} cannot be part of a line", index)
+            }
+            Self::AfterComment { index } => {
+                write!(f, "token {} follows an end-of-line comment", index)
+            }
+            Self::AfterChecksum { index } => write!(
+                f,
+                "token {} follows a checksum but is not an end-of-line comment",
+                index
+            ),
+            Self::NotParsedByDefault { index } => {
+                write!(f, "token {} is not parsed by default", index)
+            }
+            Self::InvalidField { index } => {
+                write!(f, "field at token {} does not parse back", index)
+            }
+            Self::InvalidFlag { index } => write!(
+                f,
+                "flag at token {} is invalid or does not follow a command",
+                index
+            ),
+            Self::InvalidComment { index } => {
+                write!(f, "comment at token {} does not parse back", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FromTokensError {}
+
+/// Whether the parser accepts this byte in the body of a comment.
+fn is_comment_byte(b: u8) -> bool {
+    b == b'\t' || (b' '..=b'~').contains(&b)
+}
+
+/// A comment that would not parse back as a single comment,
+/// because it contains a newline, a closing parenthesis (for inline comments),
+/// or a non-ASCII character.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidComment(pub String);
+
+impl fmt::Display for InvalidComment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid comment text: {:?}", self.0)
     }
 }
+
+impl std::error::Error for InvalidComment {}
+
+/// Why [Line::push_inline_comment] could not insert a comment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushInlineCommentError {
+    /// The text would not parse back as a single inline comment.
+    InvalidComment(InvalidComment),
+    /// The position is past the end of the fields of the line.
+    OutOfRange { position: usize, fields: usize },
+}
+
+impl From<InvalidComment> for PushInlineCommentError {
+    fn from(err: InvalidComment) -> Self {
+        Self::InvalidComment(err)
+    }
+}
+
+impl fmt::Display for PushInlineCommentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidComment(err) => write!(f, "{}", err),
+            Self::OutOfRange { position, fields } => write!(
+                f,
+                "inline comment position {} is past the {} fields of the line",
+                position, fields
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PushInlineCommentError {}
+
+/// A line number used by more than one line in a [File],
+/// or in the output of [format_gcode_io_resendable](crate::emit::format_gcode_io_resendable).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateLineNumber {
+    pub number: u64,
+    /// Index of the first line with the number
+    pub first: usize,
+    /// Index of the second line with the number
+    pub second: usize,
+}
+
+impl fmt::Display for DuplicateLineNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line number N{} is used by lines {} and {}",
+            self.number, self.first, self.second
+        )
+    }
+}
+
+impl std::error::Error for DuplicateLineNumber {}
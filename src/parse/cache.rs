@@ -0,0 +1,731 @@
+//! A compact binary encoding of a parsed [File], for skipping the parser on files that haven't changed.
+//!
+//! The cache doesn't hold the text of the file: anything that can be found in the source
+//! is stored as a length and borrowed from the source again when loading.
+//! A hash of the source is stored alongside, so a cache is only ever loaded with the source it was made from:
+//!
+//! ```
+//! use g_code::parse::{cache::CacheError, ast::File, file_parser};
+//!
+//! let source = "G28\nG1 X10 ;move";
+//! let bytes = file_parser(source).unwrap().to_cache_bytes();
+//! let file = File::from_cache_bytes(&bytes, source).unwrap();
+//! assert_eq!(file, file_parser(source).unwrap());
+//! assert_eq!(File::from_cache_bytes(&bytes, "G28\nG1 X20 ;move"), Err(CacheError::StaleSource));
+//! ```
+//!
+//! The encoding is only meant to be read back by the same version of this crate built with the same number type
+//! (see [Real](super::token::Real)), and anything else is rejected as [CacheError::Incompatible].
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::ast::{File, Line, Span};
+use super::token::{
+    Checksum, Comment, ExtendedCommand, ExtendedParam, Field, Flag, InlineComment, LineComponent,
+    MetaCommand, Newline, RealtimeCommand, SystemCommand, Value, Whitespace,
+};
+
+const MAGIC: &[u8; 4] = b"GCAC";
+const VERSION: u8 = 1;
+#[cfg(not(feature = "float-values"))]
+const NUMBER_TYPE: u8 = 0;
+#[cfg(feature = "float-values")]
+const NUMBER_TYPE: u8 = 1;
+const HASH_LEN: usize = 32;
+/// Magic, version, number type, whether there is a source, and the hashes of the source and payload
+const HEADER_LEN: usize = MAGIC.len() + 3 + 2 * HASH_LEN;
+
+/// Reasons that [File::from_cache_bytes] can reject a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheError {
+    /// The bytes don't start like a cache.
+    NotACache,
+    /// The cache was written by another version of this crate, or with another number type.
+    Incompatible,
+    /// The cache was made from a different source.
+    StaleSource,
+    /// The cache is damaged or truncated.
+    Corrupted,
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotACache => write!(f, "not a parse cache"),
+            Self::Incompatible => write!(f, "parse cache was written by an incompatible version"),
+            Self::StaleSource => write!(f, "parse cache was made from a different source"),
+            Self::Corrupted => write!(f, "parse cache is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl<'input> File<'input> {
+    /// Encode the file for [File::from_cache_bytes].
+    ///
+    /// Text that isn't where its span says it is in the [source](File::source), like an edited comment,
+    /// is stored in the cache. A file without a source, like one from JSON, has all of its text stored,
+    /// and can be loaded with any source.
+    pub fn to_cache_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer {
+            out: vec![],
+            source: self.source,
+            cursor: 0,
+        };
+        writer.file(self);
+        let payload = writer.out;
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(NUMBER_TYPE);
+        match self.source {
+            Some(source) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&Sha256::digest(source.as_bytes()));
+            }
+            None => {
+                bytes.push(0);
+                bytes.extend_from_slice(&[0; HASH_LEN]);
+            }
+        }
+        bytes.extend_from_slice(&Sha256::digest(&payload));
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    /// Load a file encoded by [File::to_cache_bytes], borrowing its text from the source it was parsed from.
+    ///
+    /// The result is the same as parsing the source again, without running the parser.
+    pub fn from_cache_bytes(bytes: &[u8], source: &'input str) -> Result<Self, CacheError> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(CacheError::NotACache);
+        }
+        let (header, payload) = bytes.split_at(HEADER_LEN);
+        if header[4] != VERSION || header[5] != NUMBER_TYPE {
+            return Err(CacheError::Incompatible);
+        }
+        let (source_hash, payload_hash) = header[7..].split_at(HASH_LEN);
+        if Sha256::digest(payload).as_slice() != payload_hash {
+            return Err(CacheError::Corrupted);
+        }
+        let source = match header[6] {
+            0 => None,
+            1 if Sha256::digest(source.as_bytes()).as_slice() == source_hash => Some(source),
+            1 => return Err(CacheError::StaleSource),
+            _ => return Err(CacheError::Corrupted),
+        };
+        let mut reader = Reader {
+            bytes: payload,
+            source: source.unwrap_or_default(),
+            cursor: 0,
+        };
+        let mut file = reader.file().ok_or(CacheError::Corrupted)?;
+        if !reader.bytes.is_empty() {
+            return Err(CacheError::Corrupted);
+        }
+        file.source = source;
+        Ok(file)
+    }
+}
+
+const HAS_CHECKSUM: u8 = 1;
+const HAS_COMMENT: u8 = 1 << 1;
+const HAS_SYSTEM_COMMAND: u8 = 1 << 2;
+const HAS_EXTENDED_COMMAND: u8 = 1 << 3;
+const HAS_META_COMMAND: u8 = 1 << 4;
+
+const HAS_FIELD: u8 = 1;
+const HAS_WHITESPACE: u8 = 1 << 1;
+const HAS_INLINE_COMMENT: u8 = 1 << 2;
+const HAS_REALTIME_COMMAND: u8 = 1 << 3;
+const HAS_FLAG: u8 = 1 << 4;
+
+const RATIONAL: u8 = 0;
+const INTEGER: u8 = 1;
+const STRING: u8 = 2;
+const RAW: u8 = 3;
+
+/// Writes the payload of a cache.
+///
+/// Positions are written relative to a cursor that follows along the source,
+/// so that the spans of a file parsed from it mostly take a single byte.
+struct Writer<'a> {
+    out: Vec<u8>,
+    source: Option<&'a str>,
+    cursor: usize,
+}
+
+impl Writer<'_> {
+    fn file(&mut self, file: &File) {
+        self.byte(
+            u8::from(file.byte_order_mark)
+                | u8::from(file.start_percent) << 1
+                | u8::from(file.end_percent) << 2,
+        );
+        self.span(file.span);
+        self.uint(file.lines.len() as u64);
+        for (line, newline) in file.lines.iter() {
+            self.line(line);
+            self.pos(newline.pos);
+        }
+        match &file.last_line {
+            Some(line) => {
+                self.byte(1);
+                self.line(line);
+            }
+            None => self.byte(0),
+        }
+    }
+
+    fn line(&mut self, line: &Line) {
+        self.span(line.span);
+        self.byte(
+            flag(line.checksum.is_some(), HAS_CHECKSUM)
+                | flag(line.comment.is_some(), HAS_COMMENT)
+                | flag(line.system_command.is_some(), HAS_SYSTEM_COMMAND)
+                | flag(line.extended_command.is_some(), HAS_EXTENDED_COMMAND)
+                | flag(line.meta_command.is_some(), HAS_META_COMMAND),
+        );
+        self.uint(line.line_components.len() as u64);
+        for component in line.line_components.iter() {
+            self.byte(
+                flag(component.field.is_some(), HAS_FIELD)
+                    | flag(component.whitespace.is_some(), HAS_WHITESPACE)
+                    | flag(component.inline_comment.is_some(), HAS_INLINE_COMMENT)
+                    | flag(component.realtime_command.is_some(), HAS_REALTIME_COMMAND)
+                    | flag(component.flag.is_some(), HAS_FLAG),
+            );
+            if let Some(field) = &component.field {
+                self.field(field);
+            }
+            if let Some(whitespace) = &component.whitespace {
+                self.positioned(whitespace.pos, &whitespace.inner);
+            }
+            if let Some(comment) = &component.inline_comment {
+                self.positioned(comment.pos, &comment.inner);
+            }
+            if let Some(command) = &component.realtime_command {
+                self.pos(command.pos);
+                self.byte(command.inner);
+            }
+            if let Some(flag) = &component.flag {
+                self.positioned(flag.pos, &flag.letters);
+            }
+        }
+        if let Some(checksum) = &line.checksum {
+            self.uint(u64::from(checksum.inner));
+            self.span(checksum.span);
+        }
+        if let Some(comment) = &line.comment {
+            self.positioned(comment.pos, &comment.inner);
+        }
+        if let Some(command) = &line.system_command {
+            self.positioned(command.pos, &command.inner);
+        }
+        if let Some(command) = &line.extended_command {
+            self.span(command.span);
+            self.text(&command.name);
+            self.uint(command.params.len() as u64);
+            for param in command.params.iter() {
+                self.text(&param.whitespace);
+                self.text(&param.key);
+                // The equals sign
+                self.cursor = self.cursor.wrapping_add(1);
+                self.text(&param.value);
+            }
+        }
+        if let Some(command) = &line.meta_command {
+            self.span(command.span);
+            self.text(&command.indent);
+            self.text(&command.keyword);
+            self.text(&command.expression);
+        }
+    }
+
+    fn field(&mut self, field: &Field) {
+        self.span(field.span);
+        self.text(&field.letters);
+        let value_at = self.cursor;
+        self.uint(field.raw_value.len() as u64);
+        for segment in field.raw_value.iter() {
+            self.text(segment);
+        }
+        match &field.value {
+            Value::Rational(real) => {
+                self.byte(RATIONAL);
+                self.real(real);
+            }
+            Value::Integer(integer) => {
+                self.byte(INTEGER);
+                self.uint(*integer);
+            }
+            Value::String(text) | Value::Raw(text) => {
+                self.byte(if matches!(field.value, Value::Raw(_)) {
+                    RAW
+                } else {
+                    STRING
+                });
+                let end = self.cursor;
+                self.cursor = value_at;
+                self.text(text);
+                self.cursor = end;
+            }
+        }
+    }
+
+    #[cfg(not(feature = "float-values"))]
+    fn real(&mut self, real: &super::token::Real) {
+        self.int(*real.numer());
+        self.int(*real.denom());
+    }
+
+    #[cfg(feature = "float-values")]
+    fn real(&mut self, real: &super::token::Real) {
+        self.out.extend_from_slice(&real.to_bits().to_le_bytes());
+    }
+
+    fn positioned(&mut self, pos: usize, text: &str) {
+        self.pos(pos);
+        self.text(text);
+    }
+
+    /// Write text expected at the cursor, moving the cursor past it.
+    ///
+    /// The text is only stored if the source doesn't have it there.
+    fn text(&mut self, text: &str) {
+        let len = text.len() as u64;
+        let end = self.cursor.wrapping_add(text.len());
+        match self.source.and_then(|source| source.get(self.cursor..end)) {
+            Some(found) if found == text => self.uint(len << 1),
+            _ => {
+                self.uint(len << 1 | 1);
+                self.out.extend_from_slice(text.as_bytes());
+            }
+        }
+        self.cursor = end;
+    }
+
+    /// Write a span, moving the cursor to its start.
+    fn span(&mut self, span: Span) {
+        self.pos(span.0);
+        self.int(span.1.wrapping_sub(span.0) as i64);
+    }
+
+    /// Write a position, moving the cursor to it.
+    fn pos(&mut self, pos: usize) {
+        self.int(pos.wrapping_sub(self.cursor) as i64);
+        self.cursor = pos;
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.out.push(byte);
+    }
+
+    /// Write a LEB128 varint.
+    fn uint(&mut self, mut n: u64) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.out.push(byte);
+                return;
+            }
+            self.out.push(byte | 0x80);
+        }
+    }
+
+    /// Write a zigzag-encoded varint.
+    fn int(&mut self, n: i64) {
+        self.uint(((n << 1) ^ (n >> 63)) as u64);
+    }
+}
+
+fn flag(present: bool, flag: u8) -> u8 {
+    if present {
+        flag
+    } else {
+        0
+    }
+}
+
+/// Reads what [Writer] wrote, returning [None] at the first sign of corruption.
+struct Reader<'a, 'input> {
+    bytes: &'a [u8],
+    source: &'input str,
+    cursor: usize,
+}
+
+impl<'input> Reader<'_, 'input> {
+    fn file(&mut self) -> Option<File<'input>> {
+        let flags = self.byte()?;
+        let span = self.span()?;
+        let count = self.count()?;
+        let mut lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let line = self.line()?;
+            lines.push((line, Newline { pos: self.pos()? }));
+        }
+        let last_line = match self.byte()? {
+            0 => None,
+            1 => Some(self.line()?),
+            _ => return None,
+        };
+        Some(File {
+            byte_order_mark: flags & 1 != 0,
+            start_percent: flags & 1 << 1 != 0,
+            lines,
+            last_line,
+            end_percent: flags & 1 << 2 != 0,
+            span,
+            source: None,
+        })
+    }
+
+    fn line(&mut self) -> Option<Line<'input>> {
+        let span = self.span()?;
+        let flags = self.byte()?;
+        let count = self.count()?;
+        let mut line_components = Vec::with_capacity(count);
+        for _ in 0..count {
+            let parts = self.byte()?;
+            let mut component = LineComponent::default();
+            if parts & HAS_FIELD != 0 {
+                component.field = Some(self.field()?);
+            }
+            if parts & HAS_WHITESPACE != 0 {
+                let (inner, pos) = self.positioned()?;
+                component.whitespace = Some(Whitespace { inner, pos });
+            }
+            if parts & HAS_INLINE_COMMENT != 0 {
+                let (inner, pos) = self.positioned()?;
+                component.inline_comment = Some(InlineComment { inner, pos });
+            }
+            if parts & HAS_REALTIME_COMMAND != 0 {
+                let pos = self.pos()?;
+                component.realtime_command = Some(RealtimeCommand {
+                    pos,
+                    inner: self.byte()?,
+                });
+            }
+            if parts & HAS_FLAG != 0 {
+                let (letters, pos) = self.positioned()?;
+                component.flag = Some(Flag { letters, pos });
+            }
+            line_components.push(component);
+        }
+        let checksum = if flags & HAS_CHECKSUM != 0 {
+            Some(Checksum {
+                inner: u16::try_from(self.uint()?).ok()?,
+                span: self.span()?,
+            })
+        } else {
+            None
+        };
+        let comment = if flags & HAS_COMMENT != 0 {
+            let (inner, pos) = self.positioned()?;
+            Some(Comment { inner, pos })
+        } else {
+            None
+        };
+        let system_command = if flags & HAS_SYSTEM_COMMAND != 0 {
+            let (inner, pos) = self.positioned()?;
+            Some(SystemCommand { inner, pos })
+        } else {
+            None
+        };
+        let extended_command = if flags & HAS_EXTENDED_COMMAND != 0 {
+            let span = self.span()?;
+            let name = self.text()?;
+            let count = self.count()?;
+            let mut params = Vec::with_capacity(count);
+            for _ in 0..count {
+                let whitespace = self.text()?;
+                let key = self.text()?;
+                self.cursor = self.cursor.wrapping_add(1);
+                let value = self.text()?;
+                params.push(ExtendedParam {
+                    whitespace,
+                    key,
+                    value,
+                });
+            }
+            Some(ExtendedCommand { name, params, span })
+        } else {
+            None
+        };
+        let meta_command = if flags & HAS_META_COMMAND != 0 {
+            let span = self.span()?;
+            Some(MetaCommand {
+                indent: self.text()?,
+                keyword: self.text()?,
+                expression: self.text()?,
+                span,
+            })
+        } else {
+            None
+        };
+        Some(Line {
+            line_components,
+            checksum,
+            comment,
+            system_command,
+            extended_command,
+            meta_command,
+            span,
+        })
+    }
+
+    fn field(&mut self) -> Option<Field<'input>> {
+        let span = self.span()?;
+        let letters = self.text()?;
+        let value_at = self.cursor;
+        let count = self.count()?;
+        let mut raw_value = Vec::with_capacity(count);
+        for _ in 0..count {
+            raw_value.push(self.text()?);
+        }
+        let value = match self.byte()? {
+            RATIONAL => Value::Rational(self.real()?),
+            INTEGER => Value::Integer(self.uint()?),
+            tag @ (STRING | RAW) => {
+                let end = self.cursor;
+                self.cursor = value_at;
+                let text = self.text()?;
+                self.cursor = end;
+                if tag == RAW {
+                    Value::Raw(text)
+                } else {
+                    Value::String(text)
+                }
+            }
+            _ => return None,
+        };
+        Some(Field {
+            letters,
+            value,
+            raw_value,
+            span,
+        })
+    }
+
+    #[cfg(not(feature = "float-values"))]
+    fn real(&mut self) -> Option<super::token::Real> {
+        let (numer, denom) = (self.int()?, self.int()?);
+        if denom == 0 {
+            return None;
+        }
+        Some(num_rational::Ratio::new_raw(numer, denom))
+    }
+
+    #[cfg(feature = "float-values")]
+    fn real(&mut self) -> Option<super::token::Real> {
+        let bits = <[u8; 8]>::try_from(self.take(8)?).ok()?;
+        Some(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn positioned(&mut self) -> Option<(Cow<'input, str>, usize)> {
+        let pos = self.pos()?;
+        Some((self.text()?, pos))
+    }
+
+    /// Read text written by [Writer::text], moving the cursor past it.
+    fn text(&mut self) -> Option<Cow<'input, str>> {
+        let tag = self.uint()?;
+        let len = usize::try_from(tag >> 1).ok()?;
+        let end = self.cursor.checked_add(len)?;
+        let text = if tag & 1 == 0 {
+            Cow::Borrowed(self.source.get(self.cursor..end)?)
+        } else {
+            Cow::Owned(std::str::from_utf8(self.take(len)?).ok()?.to_string())
+        };
+        self.cursor = end;
+        Some(text)
+    }
+
+    fn span(&mut self) -> Option<Span> {
+        let start = self.pos()?;
+        Some(Span(start, start.wrapping_add(self.int()? as usize)))
+    }
+
+    fn pos(&mut self) -> Option<usize> {
+        self.cursor = self.cursor.wrapping_add(self.int()? as usize);
+        Some(self.cursor)
+    }
+
+    /// Read the number of items that follow, each of which takes at least a byte.
+    fn count(&mut self) -> Option<usize> {
+        usize::try_from(self.uint()?)
+            .ok()
+            .filter(|count| *count <= self.bytes.len())
+    }
+
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(byte)
+    }
+
+    fn uint(&mut self) -> Option<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    fn int(&mut self) -> Option<i64> {
+        let n = self.uint()?;
+        Some((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{file_parser, file_parser_with_options, ParseOptions};
+    use pretty_assertions::assert_eq;
+
+    fn corpus() -> Vec<File<'static>> {
+        let mut files = [
+            include_str!("../../tests/square.gcode"),
+            include_str!("../../tests/vandy_commodores_logo.gcode"),
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/bom_crlf.gcode"),
+            include_str!("../../tests/blank_lines.gcode"),
+            "%\nG1 X-0.5 (cut) P\"a b\"*12;go\n%",
+            include_str!("../../tests/input/everything_at_once.gcode"),
+        ]
+        .iter()
+        .map(|gcode| file_parser(gcode).unwrap())
+        .collect::<Vec<_>>();
+        let options = [
+            (
+                include_str!("../../tests/grbl_jog_session.gcode"),
+                ParseOptions {
+                    allow_grbl_system_commands: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                include_str!("../../tests/klipper_macros.gcode"),
+                ParseOptions {
+                    allow_extended_commands: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                include_str!("../../tests/daemon.g"),
+                ParseOptions {
+                    allow_rrf_meta: true,
+                    ..Default::default()
+                },
+            ),
+        ];
+        for (gcode, options) in options.iter() {
+            files.push(file_parser_with_options(gcode, options).unwrap());
+        }
+        files
+    }
+
+    #[test]
+    fn round_trips_through_the_cache() {
+        for file in corpus() {
+            let source = file.source().unwrap();
+            let loaded = File::from_cache_bytes(&file.to_cache_bytes(), source).unwrap();
+            assert_eq!(loaded, file);
+            assert_eq!(loaded.source(), Some(source));
+            // Text is borrowed from the source rather than stored
+            let longest = source.lines().max_by_key(|line| line.len()).unwrap();
+            assert!(!file
+                .to_cache_bytes()
+                .windows(longest.len())
+                .any(|window| window == longest.as_bytes()));
+        }
+        let source = "N18446744073709551616 G1 X-99999999999999999999.5";
+        let file = file_parser(source).unwrap();
+        assert_eq!(
+            File::from_cache_bytes(&file.to_cache_bytes(), source),
+            Ok(file)
+        );
+    }
+
+    #[test]
+    fn edited_text_is_stored_in_the_cache() {
+        let source = "G1 X1 ;fast\nG1 X2";
+        let mut file = file_parser(source).unwrap();
+        file.lines[0].0.set_eol_comment(Some("slow")).unwrap();
+        let loaded = File::from_cache_bytes(&file.to_cache_bytes(), source).unwrap();
+        assert_eq!(loaded, file);
+
+        // Without a source, everything is stored
+        file.source = None;
+        let loaded = File::from_cache_bytes(&file.to_cache_bytes(), "").unwrap();
+        assert_eq!(loaded, file);
+        assert_eq!(loaded.source(), None);
+    }
+
+    #[test]
+    fn stale_and_foreign_caches_are_rejected() {
+        let source = include_str!("../../tests/square.gcode");
+        let bytes = file_parser(source).unwrap().to_cache_bytes();
+        assert_eq!(
+            File::from_cache_bytes(&bytes, &source.replace("G1", "G0")),
+            Err(CacheError::StaleSource)
+        );
+        assert_eq!(
+            File::from_cache_bytes(source.as_bytes(), source),
+            Err(CacheError::NotACache)
+        );
+        let mut newer = bytes.clone();
+        newer[4] += 1;
+        assert_eq!(
+            File::from_cache_bytes(&newer, source),
+            Err(CacheError::Incompatible)
+        );
+    }
+
+    #[test]
+    fn corrupted_caches_fail_safely() {
+        let source = "%\nG1 X-0.5 (cut) P\"a b\"*12;go\n%";
+        let bytes = file_parser(source).unwrap().to_cache_bytes();
+        for i in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x55;
+            assert!(File::from_cache_bytes(&corrupted, source).is_err(), "{}", i);
+            assert!(
+                File::from_cache_bytes(&bytes[..i], source).is_err(),
+                "{}",
+                i
+            );
+        }
+
+        // Even with a matching hash, a payload that doesn't decode is rejected
+        let mut forged = bytes[..HEADER_LEN].to_vec();
+        let payload = [
+            0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ];
+        forged[HEADER_LEN - HASH_LEN..].copy_from_slice(&Sha256::digest(payload));
+        forged.extend_from_slice(&payload);
+        assert_eq!(
+            File::from_cache_bytes(&forged, source),
+            Err(CacheError::Corrupted)
+        );
+    }
+}
@@ -0,0 +1,441 @@
+//! A hand-written scanner for the common subset of GCode, for files too large to parse
+//! comfortably with the [peg] grammar.
+//!
+//! Lines made of letters, numbers, whitespace, inline comments, a checksum, and an end-of-line comment
+//! are scanned directly. Anything else on a line, like a string value, falls back to the grammar,
+//! so the resulting [File] is always identical to the one from [super::file_parser].
+use memchr::memchr2_iter;
+use std::borrow::Cow;
+
+use super::ast::{File, Line, Span};
+use super::parser::g_code;
+use super::token::*;
+use super::ParseError;
+
+/// Parse a GCode file, producing the same [File] as [super::file_parser], only faster.
+pub fn file_parser(src: &str) -> Result<File<'_>, ParseError> {
+    file_parser_with_progress(src, |_| {})
+}
+
+/// Parse a GCode file like [file_parser], calling `progress` with the number of bytes consumed so far
+/// after each line, so that a user interface can show how far along parsing is.
+///
+/// Since the scanner works a line at a time, progress is reported as each [Newline] is reached,
+/// and finally with the length of the input.
+/// The reported counts always increase, and the last one is the length of the input
+/// even if parsing fails.
+///
+/// The grammar parses a file all at once, so whenever the scanner falls back to it
+/// (for an invalid line to report an error for),
+/// progress through the rest of the file is reported once the grammar is done.
+///
+/// ```
+/// use g_code::parse::file_parser_with_progress;
+///
+/// let src = "G28\nG1 X10\nG1 Y10";
+/// let mut consumed = vec![];
+/// file_parser_with_progress(src, |bytes| consumed.push(bytes)).unwrap();
+/// assert_eq!(consumed, [4, 11, 17]);
+/// ```
+pub fn file_parser_with_progress<F: FnMut(usize)>(
+    src: &str,
+    progress: F,
+) -> Result<File<'_>, ParseError> {
+    let mut progress = Progress {
+        callback: progress,
+        consumed: None,
+    };
+    let result = scan_file(src, &mut progress);
+    progress.report(src.len());
+    result
+}
+
+/// Reports progress to a callback, skipping anything that isn't past what was already reported.
+struct Progress<F> {
+    callback: F,
+    consumed: Option<usize>,
+}
+
+impl<F: FnMut(usize)> Progress<F> {
+    fn report(&mut self, consumed: usize) {
+        if self.consumed < Some(consumed) {
+            self.consumed = Some(consumed);
+            (self.callback)(consumed);
+        }
+    }
+
+    /// Parse the whole file with the grammar, reporting progress through the rest of it afterwards.
+    fn fall_back<'input>(&mut self, src: &'input str) -> Result<File<'input>, ParseError> {
+        let file = super::file_parser(src)?;
+        for (_, newline) in file.lines.iter() {
+            let len = if src[newline.pos..].starts_with("\r\n") {
+                2
+            } else {
+                1
+            };
+            self.report(newline.pos + len);
+        }
+        Ok(file)
+    }
+}
+
+fn scan_file<'input, F: FnMut(usize)>(
+    src: &'input str,
+    progress: &mut Progress<F>,
+) -> Result<File<'input>, ParseError> {
+    let byte_order_mark = src.starts_with('\u{feff}');
+    let start = if byte_order_mark {
+        '\u{feff}'.len_utf8()
+    } else {
+        0
+    };
+    let percent = src.len() >= start + 2 && src[start..].starts_with('%') && src.ends_with('%');
+    let (body_start, body_end) = if percent {
+        (start + 1, src.len() - 1)
+    } else {
+        (start, src.len())
+    };
+
+    let mut lines = vec![];
+    let mut start = body_start;
+    let body = &src.as_bytes()[body_start..body_end];
+    // Like the grammar, a newline is `\r\n`, `\r`, or `\n`
+    for pos in memchr2_iter(b'\r', b'\n', body) {
+        let end = body_start + pos;
+        // The `\n` of a `\r\n`, already taken with the `\r` before it
+        if end < start {
+            continue;
+        }
+        let newline_len = if body[pos] == b'\r' && body.get(pos + 1) == Some(&b'\n') {
+            2
+        } else {
+            1
+        };
+        match scan_line(src, start, end) {
+            Some(line) => lines.push((line, Newline { pos: end })),
+            None => return progress.fall_back(src),
+        }
+        start = end + newline_len;
+        progress.report(start);
+    }
+    let last_line = match scan_line(src, start, body_end) {
+        Some(line) => line,
+        None => return progress.fall_back(src),
+    };
+    Ok(File {
+        byte_order_mark,
+        start_percent: percent,
+        lines,
+        last_line: if last_line.line_components.is_empty()
+            && last_line.checksum.is_none()
+            && last_line.comment.is_none()
+        {
+            None
+        } else {
+            Some(last_line)
+        },
+        end_percent: percent,
+        span: Span(0, src.len()),
+        source: Some(src),
+    })
+}
+
+/// Scan the line in `src[start..end]`, falling back to the grammar if the scanner can't handle it.
+///
+/// Returns [None] if the line is not valid GCode.
+fn scan_line(src: &str, start: usize, end: usize) -> Option<Line<'_>> {
+    scan_simple_line(src, start, end).or_else(|| {
+        let mut line = g_code::line(&src[start..end]).ok()?;
+        shift_line(&mut line, start);
+        Some(line)
+    })
+}
+
+fn scan_simple_line(src: &str, start: usize, end: usize) -> Option<Line<'_>> {
+    let bytes = src.as_bytes();
+    let mut line = Line {
+        line_components: vec![],
+        checksum: None,
+        comment: None,
+        system_command: None,
+        extended_command: None,
+        meta_command: None,
+        span: Span(start, end),
+    };
+    let mut i = start;
+    while i < end {
+        match bytes[i] {
+            b' ' | b'\t' => {
+                let run = i + count(&bytes[i..end], |b| b == b' ' || b == b'\t');
+                line.line_components.push(LineComponent {
+                    whitespace: Some(Whitespace {
+                        inner: Cow::Borrowed(&src[i..run]),
+                        pos: i,
+                    }),
+                    ..Default::default()
+                });
+                i = run;
+            }
+            b'(' => {
+                let close = i + 1 + count(&bytes[i + 1..end], |b| is_comment_byte(b) && b != b')');
+                if close >= end || bytes[close] != b')' {
+                    return None;
+                }
+                line.line_components.push(LineComponent {
+                    inline_comment: Some(InlineComment {
+                        inner: Cow::Borrowed(&src[i..=close]),
+                        pos: i,
+                    }),
+                    ..Default::default()
+                });
+                i = close + 1;
+            }
+            b if b.is_ascii_alphabetic() => {
+                let field = scan_field(src, i, end)?;
+                i = field.span.1;
+                line.line_components.push(LineComponent {
+                    field: Some(field),
+                    ..Default::default()
+                });
+            }
+            _ => break,
+        }
+    }
+    if i < end && bytes[i] == b'*' {
+        let digits = count(&bytes[i + 1..end], |b| b.is_ascii_digit());
+        if digits == 0 {
+            return None;
+        }
+        let checksum_end = i + 1 + digits;
+        line.checksum = Some(Checksum {
+            inner: saturating_checksum(&src[i + 1..checksum_end]),
+            span: Span(i, checksum_end),
+        });
+        i = checksum_end;
+    }
+    if i < end && bytes[i] == b';' {
+        if !bytes[i + 1..end].iter().all(|&b| is_comment_byte(b)) {
+            return None;
+        }
+        line.comment = Some(Comment {
+            inner: Cow::Borrowed(&src[i..end]),
+            pos: i,
+        });
+        i = end;
+    }
+    if i == end {
+        Some(line)
+    } else {
+        None
+    }
+}
+
+/// Scan a field with a numeric value, mirroring the alternatives of the grammar's `value` rule.
+fn scan_field(src: &str, start: usize, end: usize) -> Option<Field<'_>> {
+    let bytes = src.as_bytes();
+    let letters_end = start + count(&bytes[start..end], |b| b.is_ascii_alphabetic());
+    let letters = &src[start..letters_end];
+    // Strings and `GOTO 120` are left to the grammar
+    if letters.eq_ignore_ascii_case("GOTO") {
+        return None;
+    }
+    let mut i = letters_end;
+    let neg = i < end && bytes[i] == b'-';
+    if neg {
+        i += 1;
+    }
+    let lhs_end = i + count(&bytes[i..end], |b| b.is_ascii_digit());
+    let lhs = &src[i..lhs_end];
+    i = lhs_end;
+    let dot = i < end && bytes[i] == b'.';
+    if dot {
+        let rhs_end = i + 1 + count(&bytes[i + 1..end], |b| b.is_ascii_digit());
+        if lhs.is_empty() && rhs_end == i + 1 {
+            return None;
+        }
+        i = rhs_end;
+    } else if lhs.is_empty() {
+        return None;
+    }
+    let text = &src[letters_end..i];
+    // Integers too large for a u64 are a Rational, as in the grammar
+    let (value, raw_value) = match lhs.parse::<u64>() {
+        Ok(integer) if !dot && !neg => (Value::Integer(integer), vec![Cow::Borrowed(lhs)]),
+        _ => (
+            Value::Rational(parse_real(text).ok()?),
+            number_segments(text),
+        ),
+    };
+    Some(Field {
+        letters: Cow::Borrowed(letters),
+        value,
+        raw_value,
+        span: Span(start, i),
+    })
+}
+
+fn count(bytes: &[u8], pred: impl Fn(u8) -> bool) -> usize {
+    bytes.iter().take_while(|&&b| pred(b)).count()
+}
+
+fn is_comment_byte(b: u8) -> bool {
+    b == b'\t' || (b' '..=b'~').contains(&b)
+}
+
+/// Move a line parsed on its own to where it is in the file.
+fn shift_line(line: &mut Line, offset: usize) {
+    let shift = |span: &mut Span| {
+        span.0 += offset;
+        span.1 += offset;
+    };
+    shift(&mut line.span);
+    for component in line.line_components.iter_mut() {
+        if let Some(field) = component.field.as_mut() {
+            shift(&mut field.span);
+        }
+        if let Some(whitespace) = component.whitespace.as_mut() {
+            whitespace.pos += offset;
+        }
+        if let Some(inline_comment) = component.inline_comment.as_mut() {
+            inline_comment.pos += offset;
+        }
+        if let Some(flag) = component.flag.as_mut() {
+            flag.pos += offset;
+        }
+    }
+    if let Some(checksum) = line.checksum.as_mut() {
+        shift(&mut checksum.span);
+    }
+    if let Some(comment) = line.comment.as_mut() {
+        comment.pos += offset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn assert_same(src: &str) {
+        assert_eq!(
+            file_parser(src),
+            super::super::file_parser(src),
+            "{:?}",
+            src
+        );
+    }
+
+    #[test]
+    fn corpus_is_identical_to_the_grammar() {
+        for src in [
+            include_str!("../../tests/vandy_commodores_logo.gcode"),
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/blank_lines.gcode"),
+            include_str!("../../tests/square.gcode"),
+        ]
+        .iter()
+        {
+            assert!(file_parser(src).is_ok());
+            assert_same(src);
+        }
+    }
+
+    #[test]
+    fn progress_increases_to_the_end_of_the_input() {
+        for src in [
+            include_str!("../../tests/vandy_commodores_logo.gcode"),
+            include_str!("../../tests/ncviewer_sample.gcode"),
+            include_str!("../../tests/bom_crlf.gcode"),
+            "",
+            "\n\n",
+            "%\nG1 X1\n%",
+            "G1\nM117 \"string\"\nG2\nG3",
+            "G1\n(unterminated\nG2",
+            "G1\r\nG2\rG3",
+        ]
+        .iter()
+        {
+            let mut consumed = vec![];
+            let file = file_parser_with_progress(src, |bytes| consumed.push(bytes));
+            assert_eq!(file, super::super::file_parser(src));
+            assert!(consumed.windows(2).all(|w| w[0] < w[1]), "{:?}", consumed);
+            assert_eq!(consumed.last(), Some(&src.len()));
+
+            if let Ok(file) = file {
+                // Every line but the last ends where progress was reported
+                let line_ends = file
+                    .lines
+                    .iter()
+                    .map(|(_, newline)| newline.pos)
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    consumed.len(),
+                    line_ends.len() + 1 - usize::from(src.ends_with('\n'))
+                );
+                for (end, consumed) in line_ends.iter().zip(consumed.iter()) {
+                    assert!(*consumed > *end && src[*end..*consumed].trim().is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn progress_is_reported_for_each_line_with_carriage_returns() {
+        let src = "G1\r\nG2\rG3\r\n(unterminated";
+        let mut consumed = vec![];
+        assert!(file_parser_with_progress(src, |bytes| consumed.push(bytes)).is_err());
+        // The invalid last line is left to the grammar, after the lines before it were scanned
+        assert_eq!(consumed, [4, 7, 11, src.len()]);
+    }
+
+    #[test]
+    fn edge_cases_are_identical_to_the_grammar() {
+        for src in [
+            "",
+            "\n",
+            "%",
+            "%%",
+            "%\n%",
+            "%\nG1 X1\n%",
+            "G1 X1\n%",
+            "G0X1Y-2Z.5E-.25F3.A-4.\n",
+            "G1 X1.5.3",
+            "G1 X- Y1",
+            "G1 X",
+            "G1 X99999999999999999999",
+            "N4294967296 G1 X18446744073709551615\nN18446744073709551616",
+            "G1 X0.99999999999999999999",
+            "X-99999999999999999999",
+            " \t G1 (a (b) X1 ()\n",
+            "(unterminated\nG1",
+            "N1 G28*18;home\nN2 M107*39 \n*0\n*256",
+            "G1 ;§",
+            "M587 S\"MYROUTER\" P\"ABCxyz;\"\" 123\"\nG1 X1",
+            "GOTO 10\nGOTO10\ngoto 10",
+            "G1\r\nG2\rG3",
+            "\r",
+            "\r\n",
+            "\n\r",
+            "G1\r",
+            "G1\r\r\nG2\n\rG3",
+            "%\r\nG1 X1\r\n%",
+            "G1 (a\r) X1\r\nG2",
+            "G1 ;end\r\nM117 \"string\"\r\nG2",
+            "G1 X1*",
+            "G1 X1*300\nG1*0070",
+            "G1*70000",
+            "G1*99999999999999999999999",
+            "G1 =",
+            "\u{feff}",
+            "\u{feff}G1 X1\nG2",
+            "\u{feff}%\nG1\n%",
+            "\u{feff}\u{feff}G1",
+            "G1\nG28 X Y*76\nM6 T",
+            include_str!("../../tests/input/everything_at_once.gcode"),
+        ]
+        .iter()
+        {
+            assert_same(src);
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Resolution of subprogram calls (`M98`) into a single flattened program.
+//!
+//! Both the RepRapFirmware form (`M98 P"subprogram.g"`) and the Fanuc form (`M98 P1234 L2`)
+//! are recognized. Called programs end at `M99` or at their last line, whichever comes first.
+
+use std::fmt;
+
+use super::ast::{File, Line, Span, Spanned};
+use super::token::{Field, Value};
+use super::{file_parser, ParseError};
+use crate::emit::Token;
+
+/// Identifies one of the programs in a [ResolvedProgram].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SourceId(pub usize);
+
+impl SourceId {
+    /// The file passed to [resolve_includes].
+    pub const ROOT: Self = Self(0);
+}
+
+/// A program loaded while resolving includes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    /// The name the program was referenced by, as passed to the loader.
+    ///
+    /// This is empty for [SourceId::ROOT].
+    pub name: String,
+    /// The text returned by the loader.
+    ///
+    /// This is empty for [SourceId::ROOT], whose text the caller already has.
+    pub text: String,
+}
+
+/// A call from one program to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    pub caller: SourceId,
+    pub callee: SourceId,
+    /// Span of the calling `M98` line in the caller.
+    pub span: Span,
+}
+
+/// A [Token] annotated with where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcedToken {
+    pub token: Token<'static>,
+    pub source: SourceId,
+    pub span: Span,
+}
+
+/// The result of [resolve_includes]: every call expanded in place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedProgram {
+    sources: Vec<Source>,
+    calls: Vec<Call>,
+    tokens: Vec<SourcedToken>,
+}
+
+impl ResolvedProgram {
+    /// Iterate over the flattened program.
+    pub fn iter(&self) -> impl Iterator<Item = &SourcedToken> {
+        self.tokens.iter()
+    }
+
+    /// Iterate over the flattened program without source information,
+    /// for instance to pass it to [crate::emit::format_gcode_fmt].
+    pub fn iter_emit_tokens(&self) -> impl Iterator<Item = &Token<'static>> {
+        self.tokens.iter().map(|t| &t.token)
+    }
+
+    /// Look up a program by its [SourceId].
+    pub fn source(&self, id: SourceId) -> Option<&Source> {
+        self.sources.get(id.0)
+    }
+
+    /// Every call that was expanded, forming the call graph of the program.
+    ///
+    /// A program called more than once appears once per call.
+    pub fn calls(&self) -> &[Call] {
+        &self.calls
+    }
+}
+
+/// Reasons that [resolve_includes] can fail.
+#[derive(Debug)]
+pub enum ResolveError<E> {
+    /// The loader could not provide a program.
+    Load {
+        name: String,
+        error: E,
+        /// Where the failing call was made.
+        source: SourceId,
+        span: Span,
+    },
+    /// A loaded program is not valid GCode.
+    Parse { name: String, error: ParseError },
+    /// A program ends up calling itself.
+    Cycle {
+        /// Names of the programs involved, starting and ending with the repeated one.
+        chain: Vec<String>,
+        /// Where the call closing the cycle was made.
+        source: SourceId,
+        span: Span,
+    },
+}
+
+impl<E: fmt::Display> fmt::Display for ResolveError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Load { name, error, .. } => write!(f, "could not load {}: {}", name, error),
+            Self::Parse { name, error } => write!(f, "could not parse {}: {}", name, error),
+            Self::Cycle { chain, .. } => write!(f, "cyclic call: {}", chain.join(" -> ")),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for ResolveError<E> {}
+
+/// Expand subprogram calls in `file`, loading called programs by name through `loader`.
+///
+/// Each program is only loaded once, no matter how many times it is called.
+/// Calls are recognized on lines containing an `M98` field with a `P` argument; the `M98`, `P`, and `L`
+/// fields are removed and the called program is inserted after the rest of the line `L` times (default 1).
+/// Quoted names are unescaped before being passed to the loader; numbered programs are passed as their number.
+pub fn resolve_includes<E, L>(file: &File, loader: L) -> Result<ResolvedProgram, ResolveError<E>>
+where
+    L: FnMut(&str) -> Result<String, E>,
+{
+    let mut resolver = Resolver {
+        loader,
+        program: ResolvedProgram {
+            sources: vec![Source {
+                name: String::new(),
+                text: String::new(),
+            }],
+            calls: vec![],
+            tokens: vec![],
+        },
+        stack: vec![],
+    };
+    resolver.expand(SourceId::ROOT, file.iter(), false)?;
+    Ok(resolver.program)
+}
+
+struct Resolver<L> {
+    loader: L,
+    program: ResolvedProgram,
+    /// Programs currently being expanded, used for cycle detection
+    stack: Vec<SourceId>,
+}
+
+impl<E, L> Resolver<L>
+where
+    L: FnMut(&str) -> Result<String, E>,
+{
+    fn expand<'a, 'input: 'a>(
+        &mut self,
+        source: SourceId,
+        lines: impl Iterator<Item = &'a Line<'input>>,
+        is_subprogram: bool,
+    ) -> Result<(), ResolveError<E>> {
+        self.stack.push(source);
+        for line in lines {
+            let fields = line.iter_fields().collect::<Vec<_>>();
+            if is_subprogram && fields.iter().any(|f| is_command(f, 99)) {
+                break;
+            }
+            match call_of(&fields) {
+                Some((name, repeat)) => {
+                    self.push_line(source, line, |f| {
+                        !is_command(f, 98) && !matches!(f.letters.as_ref(), "P" | "p" | "L" | "l")
+                    });
+                    let callee = self.load(&name, source, line.span())?;
+                    self.program.calls.push(Call {
+                        caller: source,
+                        callee,
+                        span: line.span(),
+                    });
+                    let text = std::mem::take(&mut self.program.sources[callee.0].text);
+                    let result = file_parser(&text)
+                        .map_err(|error| ResolveError::Parse {
+                            name: name.clone(),
+                            error,
+                        })
+                        .and_then(|called| {
+                            (0..repeat).try_for_each(|_| self.expand(callee, called.iter(), true))
+                        });
+                    self.program.sources[callee.0].text = text;
+                    result?;
+                }
+                None => self.push_line(source, line, |_| true),
+            }
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn push_line(&mut self, source: SourceId, line: &Line, keep_field: impl Fn(&Field) -> bool) {
+        if line.is_empty() {
+            self.program.tokens.push(SourcedToken {
+                token: Token::BlankLine,
+                source,
+                span: line.span(),
+            });
+            return;
+        }
+        for component in &line.line_components {
+            let (token, span) = if let Some(field) = component.field.as_ref() {
+                if !keep_field(field) {
+                    continue;
+                }
+                (Token::from(field), field.span())
+            } else if let Some(flag) = component.flag.as_ref() {
+                (Token::from(flag), flag.span())
+            } else if let Some(comment) = component.inline_comment.as_ref() {
+                (Token::from(comment), comment.span())
+            } else {
+                continue;
+            };
+            self.program.tokens.push(SourcedToken {
+                token: token.into_owned(),
+                source,
+                span,
+            });
+        }
+        if let (Some(checksum), Some(token)) = (line.checksum.as_ref(), line.checksum_token()) {
+            self.program.tokens.push(SourcedToken {
+                token: token.into_owned(),
+                source,
+                span: checksum.span(),
+            });
+        }
+        if let Some(comment) = line.comment.as_ref() {
+            self.program.tokens.push(SourcedToken {
+                token: Token::from(comment).into_owned(),
+                source,
+                span: comment.span(),
+            });
+        }
+    }
+
+    /// Find or load the program called `name`, checking that calling it would not form a cycle.
+    fn load(
+        &mut self,
+        name: &str,
+        caller: SourceId,
+        span: Span,
+    ) -> Result<SourceId, ResolveError<E>> {
+        let existing = self
+            .program
+            .sources
+            .iter()
+            .skip(1)
+            .position(|s| s.name == name)
+            .map(|i| SourceId(i + 1));
+        if let Some(id) = existing {
+            if let Some(start) = self.stack.iter().position(|s| *s == id) {
+                let chain = self.stack[start..]
+                    .iter()
+                    .chain(std::iter::once(&id))
+                    .map(|s| self.program.sources[s.0].name.clone())
+                    .collect();
+                return Err(ResolveError::Cycle {
+                    chain,
+                    source: caller,
+                    span,
+                });
+            }
+            return Ok(id);
+        }
+        let text = (self.loader)(name).map_err(|error| ResolveError::Load {
+            name: name.to_string(),
+            error,
+            source: caller,
+            span,
+        })?;
+        self.program.sources.push(Source {
+            name: name.to_string(),
+            text,
+        });
+        Ok(SourceId(self.program.sources.len() - 1))
+    }
+}
+
+fn is_command(field: &Field, number: u64) -> bool {
+    field.letters.eq_ignore_ascii_case("M") && field.value == Value::Integer(number)
+}
+
+/// The name and repeat count of the program called on a line, if any.
+fn call_of(fields: &[&Field]) -> Option<(String, u64)> {
+    if !fields.iter().any(|f| is_command(f, 98)) {
+        return None;
+    }
+    let argument = |letter: &str| {
+        fields
+            .iter()
+            .find(|f| f.letters.eq_ignore_ascii_case(letter))
+    };
+    let name = match &argument("P")?.value {
+        Value::String(quoted) => quoted[1..quoted.len() - 1].replace("\"\"", "\""),
+        Value::Integer(number) => number.to_string(),
+        Value::Rational(_) | Value::Raw(_) => return None,
+    };
+    let repeat = match argument("L").map(|f| &f.value) {
+        Some(Value::Integer(repeat)) => *repeat,
+        _ => 1,
+    };
+    Some((name, repeat))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::ops::Range;
+
+    fn loader<'a>(
+        programs: &'a HashMap<&str, &str>,
+    ) -> impl FnMut(&str) -> Result<String, String> + 'a {
+        move |name| {
+            programs
+                .get(name)
+                .map(|text| text.to_string())
+                .ok_or_else(|| format!("{} not found", name))
+        }
+    }
+
+    fn fields_of(program: &ResolvedProgram) -> Vec<String> {
+        program
+            .iter_emit_tokens()
+            .filter(|t| matches!(t, Token::Field(_)))
+            .map(|t| t.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn resolves_two_levels_of_includes() {
+        let programs = [
+            ("outer.g", "G0 X1\nM98 P\"inner.g\"\nG0 X2"),
+            ("inner.g", "G1 Y1\nM99\nG1 Y2"),
+        ]
+        .iter()
+        .copied()
+        .collect::<HashMap<_, _>>();
+        let root = file_parser("G28\nN10 M98 P\"outer.g\"\nM2").unwrap();
+        let program = resolve_includes(&root, loader(&programs)).unwrap();
+        assert_eq!(
+            fields_of(&program),
+            ["G28", "N10", "G0", "X1", "G1", "Y1", "G0", "X2", "M2"]
+        );
+        assert_eq!(program.calls().len(), 2);
+        assert_eq!(program.calls()[0].caller, SourceId::ROOT);
+        let outer = program.calls()[0].callee;
+        assert_eq!(program.source(outer).unwrap().name, "outer.g");
+        assert_eq!(program.calls()[1].caller, outer);
+
+        let inner_field = program
+            .iter()
+            .find(|t| t.token.to_string() == "Y1")
+            .unwrap();
+        let inner = program.source(inner_field.source).unwrap();
+        assert_eq!(inner.name, "inner.g");
+        assert_eq!(&inner.text[Range::from(inner_field.span)], "Y1");
+    }
+
+    #[test]
+    fn numbered_program_is_repeated() {
+        let programs = [("1234", "G91 G0 X1\nM99")]
+            .iter()
+            .copied()
+            .collect::<HashMap<_, _>>();
+        let root = file_parser("M98 P1234 L2").unwrap();
+        let program = resolve_includes(&root, loader(&programs)).unwrap();
+        assert_eq!(fields_of(&program), ["G91", "G0", "X1", "G91", "G0", "X1"]);
+        assert_eq!(program.source(SourceId(1)).unwrap().name, "1234");
+    }
+
+    #[test]
+    fn cyclic_include_is_an_error() {
+        let programs = [("a.g", "M98 P\"b.g\""), ("b.g", "G0 X1\nM98 P\"a.g\"")]
+            .iter()
+            .copied()
+            .collect::<HashMap<_, _>>();
+        let root = file_parser("M98 P\"a.g\"").unwrap();
+        match resolve_includes(&root, loader(&programs)) {
+            Err(ResolveError::Cycle {
+                chain,
+                source,
+                span,
+            }) => {
+                assert_eq!(chain, ["a.g", "b.g", "a.g"]);
+                assert_eq!(source, SourceId(2));
+                assert_eq!(span, Span(6, 16));
+            }
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn loader_errors_are_reported_with_the_call_site() {
+        let root = file_parser("G0\nM98 P\"missing.g\"").unwrap();
+        match resolve_includes(&root, |name: &str| Err::<String, _>(name.len())) {
+            Err(ResolveError::Load {
+                name,
+                error,
+                source,
+                span,
+            }) => {
+                assert_eq!(name, "missing.g");
+                assert_eq!(error, 9);
+                assert_eq!(source, SourceId::ROOT);
+                assert_eq!(span, Span(3, 19));
+            }
+            other => panic!("expected a load error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! A stable JSON schema for a parsed [File], for interop with tools outside of Rust.
+//!
+//! ```json
+//! {
+//!   "start_percent": false,
+//!   "end_percent": false,
+//!   "span": [0, 12],
+//!   "lines": [
+//!     {
+//!       "span": [0, 11],
+//!       "components": [
+//!         { "field": { "letters": "G", "value": { "type": "integer", "repr": "1" }, "raw": ["1"], "span": [0, 2] } },
+//!         { "whitespace": { "text": " ", "span": [2, 3] } },
+//!         { "inline_comment": { "text": "(cut)", "span": [3, 8] } }
+//!       ],
+//!       "checksum": null,
+//!       "comment": { "text": ";go", "span": [8, 11] },
+//!       "newline": 11
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Values are always serialized as strings so that no precision is lost:
+//! rationals are written as `numerator/denominator` (or just the numerator when it is whole),
+//! strings keep their delimiting quotes just like [Value::String],
+//! and numbers too large for either are `"raw"` with the text of [Value::Raw].
+//! Only the last line may have a `null` newline.
+//!
+//! Optional syntax only appears when present: a file that started with a byte order mark has
+//! `"byte_order_mark": true`, a GRBL system command line has a `"system_command"` text instead of components,
+//! GRBL realtime commands are `"realtime_command"` components,
+//! value-less letters after a command, like the `X` in `G28 X`, are `"flag"` components,
+//! a Klipper extended command line has an `"extended_command"` with its name and parameters,
+//! and a RepRapFirmware meta command line has a `"meta_command"` with its indent, keyword, and expression.
+use num_rational::Ratio;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use super::ast::{File, Line, Span};
+use super::token::{
+    parse_real, real_from_ratio, real_to_ratio, Checksum, Comment, ExtendedCommand, ExtendedParam,
+    Field, Flag, InlineComment, LineComponent, MetaCommand, Newline, RealtimeCommand,
+    SystemCommand, Value, Whitespace,
+};
+
+#[derive(Serialize, Deserialize)]
+struct JsonFile {
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    byte_order_mark: bool,
+    start_percent: bool,
+    end_percent: bool,
+    span: [usize; 2],
+    lines: Vec<JsonLine>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonLine {
+    span: [usize; 2],
+    components: Vec<JsonComponent>,
+    checksum: Option<JsonChecksum>,
+    comment: Option<JsonText>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system_command: Option<JsonText>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extended_command: Option<JsonExtendedCommand>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta_command: Option<JsonMetaCommand>,
+    newline: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonComponent {
+    Field(JsonField),
+    Whitespace(JsonText),
+    InlineComment(JsonText),
+    RealtimeCommand(JsonText),
+    Flag(JsonText),
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonField {
+    letters: String,
+    value: JsonValue,
+    raw: Vec<String>,
+    span: [usize; 2],
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "repr", rename_all = "snake_case")]
+enum JsonValue {
+    Rational(String),
+    Integer(String),
+    String(String),
+    Raw(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonChecksum {
+    value: u16,
+    span: [usize; 2],
+    /// Ignored when deserializing, since it is recomputed from the fields.
+    #[serde(default)]
+    valid: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonExtendedCommand {
+    name: String,
+    params: Vec<JsonExtendedParam>,
+    span: [usize; 2],
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonExtendedParam {
+    whitespace: String,
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonMetaCommand {
+    indent: String,
+    keyword: String,
+    /// Everything after the keyword, including the whitespace separating them
+    expression: String,
+    span: [usize; 2],
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonText {
+    text: String,
+    span: [usize; 2],
+}
+
+impl<'input> File<'input> {
+    /// Serialize the file to JSON using the schema described in [the module docs](self).
+    pub fn to_json(&self) -> String {
+        // Serializing these plain structs to a String cannot fail
+        serde_json::to_string_pretty(&JsonFile::from(self)).unwrap()
+    }
+}
+
+impl File<'static> {
+    /// Deserialize a file produced by [File::to_json].
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let file: JsonFile = serde_json::from_str(json)?;
+        file.into_file().map_err(serde::de::Error::custom)
+    }
+}
+
+fn to_span(span: Span) -> [usize; 2] {
+    [span.0, span.1]
+}
+
+fn from_span([start, end]: [usize; 2]) -> Span {
+    Span(start, end)
+}
+
+impl From<&File<'_>> for JsonFile {
+    fn from(file: &File<'_>) -> Self {
+        Self {
+            byte_order_mark: file.byte_order_mark,
+            start_percent: file.start_percent,
+            end_percent: file.end_percent,
+            span: to_span(file.span),
+            lines: file
+                .lines
+                .iter()
+                .map(|(line, newline)| JsonLine::new(line, Some(newline)))
+                .chain(file.last_line.iter().map(|line| JsonLine::new(line, None)))
+                .collect(),
+        }
+    }
+}
+
+impl JsonLine {
+    fn new(line: &Line<'_>, newline: Option<&Newline>) -> Self {
+        Self {
+            span: to_span(line.span),
+            components: line
+                .line_components
+                .iter()
+                .flat_map(|c| {
+                    c.field
+                        .iter()
+                        .map(|f| JsonComponent::Field(JsonField::from(f)))
+                        .chain(c.whitespace.iter().map(|w| {
+                            JsonComponent::Whitespace(JsonText {
+                                text: w.inner.to_string(),
+                                span: [w.pos, w.pos + w.inner.len()],
+                            })
+                        }))
+                        .chain(c.inline_comment.iter().map(|i| {
+                            JsonComponent::InlineComment(JsonText {
+                                text: i.inner.to_string(),
+                                span: [i.pos, i.pos + i.inner.len()],
+                            })
+                        }))
+                        .chain(c.realtime_command.iter().map(|r| {
+                            JsonComponent::RealtimeCommand(JsonText {
+                                text: r.as_char().to_string(),
+                                span: [r.pos, r.pos + 1],
+                            })
+                        }))
+                        .chain(c.flag.iter().map(|f| {
+                            JsonComponent::Flag(JsonText {
+                                text: f.letters.to_string(),
+                                span: [f.pos, f.pos + f.letters.len()],
+                            })
+                        }))
+                })
+                .collect(),
+            checksum: line.checksum.as_ref().map(|c| JsonChecksum {
+                value: c.inner,
+                span: to_span(c.span),
+                valid: line.validate_checksum() == Some(Ok(())),
+            }),
+            comment: line.comment.as_ref().map(|c| JsonText {
+                text: c.inner.to_string(),
+                span: [c.pos, c.pos + c.inner.len()],
+            }),
+            system_command: line.system_command.as_ref().map(|s| JsonText {
+                text: s.inner.to_string(),
+                span: [s.pos, s.pos + s.inner.len()],
+            }),
+            extended_command: line.extended_command.as_ref().map(|e| JsonExtendedCommand {
+                name: e.name.to_string(),
+                params: e
+                    .params
+                    .iter()
+                    .map(|p| JsonExtendedParam {
+                        whitespace: p.whitespace.to_string(),
+                        key: p.key.to_string(),
+                        value: p.value.to_string(),
+                    })
+                    .collect(),
+                span: to_span(e.span),
+            }),
+            meta_command: line.meta_command.as_ref().map(|m| JsonMetaCommand {
+                indent: m.indent.to_string(),
+                keyword: m.keyword.to_string(),
+                expression: m.expression.to_string(),
+                span: to_span(m.span),
+            }),
+            newline: newline.map(|n| n.pos),
+        }
+    }
+}
+
+impl From<&Field<'_>> for JsonField {
+    fn from(field: &Field<'_>) -> Self {
+        Self {
+            letters: field.letters.to_string(),
+            value: match &field.value {
+                // Written as a fraction whatever the number type, so the format doesn't depend on it
+                Value::Rational(r) => JsonValue::Rational(match real_to_ratio(r) {
+                    Some(ratio) => ratio.to_string(),
+                    None => r.to_string(),
+                }),
+                Value::Integer(i) => JsonValue::Integer(i.to_string()),
+                Value::String(s) => JsonValue::String(s.to_string()),
+                Value::Raw(s) => JsonValue::Raw(s.to_string()),
+            },
+            raw: field.raw_value.iter().map(|s| s.to_string()).collect(),
+            span: to_span(field.span),
+        }
+    }
+}
+
+impl JsonFile {
+    fn into_file(self) -> Result<File<'static>, String> {
+        let mut lines = vec![];
+        let mut last_line = None;
+        for line in self.lines {
+            if last_line.is_some() {
+                return Err("only the last line may omit its newline".to_string());
+            }
+            match line.newline {
+                Some(pos) => lines.push((line.into_line()?, Newline { pos })),
+                None => last_line = Some(line.into_line()?),
+            }
+        }
+        Ok(File {
+            source: None,
+            byte_order_mark: self.byte_order_mark,
+            start_percent: self.start_percent,
+            lines,
+            last_line,
+            end_percent: self.end_percent,
+            span: from_span(self.span),
+        })
+    }
+}
+
+impl JsonLine {
+    fn into_line(self) -> Result<Line<'static>, String> {
+        Ok(Line {
+            line_components: self
+                .components
+                .into_iter()
+                .map(JsonComponent::into_line_component)
+                .collect::<Result<_, _>>()?,
+            checksum: self.checksum.map(|c| Checksum {
+                inner: c.value,
+                span: from_span(c.span),
+            }),
+            comment: self.comment.map(|c| Comment {
+                inner: Cow::Owned(c.text),
+                pos: c.span[0],
+            }),
+            system_command: self.system_command.map(|s| SystemCommand {
+                inner: Cow::Owned(s.text),
+                pos: s.span[0],
+            }),
+            extended_command: self.extended_command.map(|e| ExtendedCommand {
+                name: Cow::Owned(e.name),
+                params: e
+                    .params
+                    .into_iter()
+                    .map(|p| ExtendedParam {
+                        whitespace: Cow::Owned(p.whitespace),
+                        key: Cow::Owned(p.key),
+                        value: Cow::Owned(p.value),
+                    })
+                    .collect(),
+                span: from_span(e.span),
+            }),
+            meta_command: self.meta_command.map(|m| MetaCommand {
+                indent: Cow::Owned(m.indent),
+                keyword: Cow::Owned(m.keyword),
+                expression: Cow::Owned(m.expression),
+                span: from_span(m.span),
+            }),
+            span: from_span(self.span),
+        })
+    }
+}
+
+impl JsonComponent {
+    fn into_line_component(self) -> Result<LineComponent<'static>, String> {
+        Ok(match self {
+            Self::Field(field) => LineComponent {
+                field: Some(field.into_field()?),
+                ..Default::default()
+            },
+            Self::Whitespace(whitespace) => LineComponent {
+                whitespace: Some(Whitespace {
+                    inner: Cow::Owned(whitespace.text),
+                    pos: whitespace.span[0],
+                }),
+                ..Default::default()
+            },
+            Self::InlineComment(comment) => LineComponent {
+                inline_comment: Some(InlineComment {
+                    inner: Cow::Owned(comment.text),
+                    pos: comment.span[0],
+                }),
+                ..Default::default()
+            },
+            Self::RealtimeCommand(command) => LineComponent {
+                realtime_command: Some(match command.text.as_bytes() {
+                    [inner @ (b'?' | b'!' | b'~')] => RealtimeCommand {
+                        inner: *inner,
+                        pos: command.span[0],
+                    },
+                    _ => return Err(format!("not a realtime command: {:?}", command.text)),
+                }),
+                ..Default::default()
+            },
+            Self::Flag(flag) => LineComponent {
+                flag: Some(
+                    if !flag.text.is_empty() && flag.text.bytes().all(|b| b.is_ascii_alphabetic()) {
+                        Flag {
+                            pos: flag.span[0],
+                            letters: Cow::Owned(flag.text),
+                        }
+                    } else {
+                        return Err(format!("not a flag: {:?}", flag.text));
+                    },
+                ),
+                ..Default::default()
+            },
+        })
+    }
+}
+
+impl JsonField {
+    fn into_field(self) -> Result<Field<'static>, String> {
+        let value = match self.value {
+            JsonValue::Rational(repr) => Value::Rational(
+                repr.parse::<Ratio<i64>>()
+                    .ok()
+                    .and_then(real_from_ratio)
+                    // Floats too large for a fraction are written as they are
+                    .or_else(|| parse_real(&repr).ok())
+                    .ok_or_else(|| format!("invalid rational value: {:?}", repr))?,
+            ),
+            JsonValue::Integer(repr) => Value::Integer(
+                repr.parse::<u64>()
+                    .map_err(|_| format!("invalid integer value: {:?}", repr))?,
+            ),
+            JsonValue::String(repr) => Value::String(Cow::Owned(repr)),
+            JsonValue::Raw(repr) => Value::Raw(Cow::Owned(repr)),
+        };
+        Ok(Field {
+            letters: Cow::Owned(self.letters),
+            value,
+            raw_value: self.raw.into_iter().map(Cow::Owned).collect(),
+            span: from_span(self.span),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn square_matches_golden_file() {
+        let file = file_parser(include_str!("../../tests/square.gcode")).unwrap();
+        let actual: serde_json::Value = serde_json::from_str(&file.to_json()).unwrap();
+        let expected: serde_json::Value =
+            serde_json::from_str(include_str!("../../tests/square.json")).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        for gcode in [
+            include_str!("../../tests/square.gcode"),
+            include_str!("../../tests/vandy_commodores_logo.gcode"),
+            include_str!("../../tests/bom_crlf.gcode"),
+            "%\nG1 X-0.5 P\"a\"\"b\"\n%",
+            "N18446744073709551616 G1 X-99999999999999999999.5",
+            include_str!("../../tests/input/everything_at_once.gcode"),
+        ]
+        .iter()
+        {
+            let file = file_parser(gcode).unwrap();
+            assert_eq!(File::from_json(&file.to_json()).unwrap(), file);
+        }
+        let grbl = crate::parse::file_parser_with_options(
+            include_str!("../../tests/grbl_jog_session.gcode"),
+            &crate::parse::ParseOptions {
+                allow_grbl_system_commands: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(File::from_json(&grbl.to_json()).unwrap(), grbl);
+        let klipper = crate::parse::file_parser_with_options(
+            include_str!("../../tests/klipper_macros.gcode"),
+            &crate::parse::ParseOptions {
+                allow_extended_commands: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(File::from_json(&klipper.to_json()).unwrap(), klipper);
+        let rrf = crate::parse::file_parser_with_options(
+            include_str!("../../tests/daemon.g"),
+            &crate::parse::ParseOptions {
+                allow_rrf_meta: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(File::from_json(&rrf.to_json()).unwrap(), rrf);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let file = file_parser("G1 X1.5").unwrap();
+        let json = file.to_json().replace("3/2", "three halves");
+        assert!(File::from_json(&json)
+            .unwrap_err()
+            .to_string()
+            .contains("invalid rational value"));
+    }
+}
@@ -0,0 +1,277 @@
+//! Reading machine data that firmware and slicers leave in comments and logs.
+//!
+//! Marlin reports its bed leveling mesh in reply to `G29 T` and `M420 V`,
+//! and the report is often kept in captured logs or pasted into files as comments:
+//!
+//! ```text
+//! Bed Topography Report:
+//!
+//!     ( 10,220)                      (220,220)
+//!         0       1       2
+//!  2 | +0.062  +0.087 [+0.050]
+//!    |
+//!  1 | -0.012  +0.037  +0.025
+//!    |
+//!  0 | -0.125  -0.087  -0.062
+//!         0       1       2
+//!     ( 10, 10)                      (220, 10)
+//! ```
+//!
+//! [BedMesh::parse_report] reads reports from logs, and [bed_mesh] reads them from the comments of a file.
+use std::ops::RangeInclusive;
+
+use super::ast::File;
+
+/// Lines that start a mesh report.
+const REPORT_HEADERS: [&str; 4] = [
+    "Bed Topography Report",
+    "Bilinear Leveling Grid",
+    "Mesh Bed Level data",
+    "MESH:",
+];
+
+/// Prefixes that senders and firmware add to the lines of a report in a log.
+const LOG_PREFIXES: [&str; 3] = ["Recv:", "echo:", "//"];
+
+/// A bed leveling mesh: the height of the bed measured at each point of a grid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BedMesh {
+    /// Heights in millimeters, one row for each Y index starting from the front of the bed,
+    /// with a height for each X index starting from the left.
+    ///
+    /// Points that weren't probed, or failed to probe, are [f32::NAN].
+    pub rows: Vec<Vec<f32>>,
+    /// The X coordinates of the first and last columns, if the report labels its corners.
+    pub x_range: Option<RangeInclusive<f32>>,
+    /// The Y coordinates of the first and last rows, if the report labels its corners.
+    pub y_range: Option<RangeInclusive<f32>>,
+}
+
+impl BedMesh {
+    /// Read the first mesh report in some text, like a log captured from the serial port.
+    ///
+    /// Reports from unified bed leveling, bilinear leveling, and mesh bed leveling are recognized,
+    /// along with the axis labels they are drawn with. Senders' `Recv:` and Marlin's `echo:` prefixes are ignored.
+    /// This is [None] if there is no report, or if its rows are missing or don't all have the same number of points.
+    ///
+    /// ```
+    /// use g_code::parse::metadata::BedMesh;
+    ///
+    /// let log = "Recv: Bilinear Leveling Grid:\nRecv:       0      1\nRecv:  0 +0.107 +0.077\nRecv:  1 -0.025 nan\nRecv: ok";
+    /// let mesh = BedMesh::parse_report(log).unwrap();
+    /// assert_eq!(mesh.rows[0], vec![0.107, 0.077]);
+    /// assert!(mesh.rows[1][1].is_nan());
+    /// assert_eq!(mesh.x_range, None);
+    /// ```
+    pub fn parse_report(text: &str) -> Option<Self> {
+        Self::from_lines(text.lines().map(Some))
+    }
+
+    /// Read a report from lines, where [None] is a line that can't be part of one.
+    fn from_lines<'a>(lines: impl Iterator<Item = Option<&'a str>>) -> Option<Self> {
+        let mut lines = lines.map(|line| line.map(strip_log_prefixes));
+        lines.find(|line| {
+            line.is_some_and(|line| REPORT_HEADERS.iter().any(|header| line.starts_with(header)))
+        })?;
+
+        let mut rows = vec![];
+        let mut corners = vec![];
+        for line in lines {
+            let line = match line {
+                Some(line) => line,
+                None => break,
+            };
+            if let Some(row) = mesh_row(line) {
+                rows.push(row);
+            } else if let Some(mut labelled) = corner_labels(line) {
+                corners.append(&mut labelled);
+            } else if !is_decoration(line) {
+                break;
+            }
+        }
+
+        // Unified bed leveling draws the back of the bed first
+        rows.sort_by_key(|(index, _)| *index);
+        let indices_are_contiguous = rows.iter().enumerate().all(|(i, (index, _))| i == *index);
+        let rows = rows.into_iter().map(|(_, row)| row).collect::<Vec<_>>();
+        let columns = rows.first()?.len();
+        if !indices_are_contiguous || rows.iter().any(|row| row.len() != columns) {
+            return None;
+        }
+
+        let range = |coordinates: Vec<f32>| {
+            let min = coordinates.iter().copied().reduce(f32::min)?;
+            let max = coordinates.iter().copied().reduce(f32::max)?;
+            Some(min..=max)
+        };
+        Some(Self {
+            rows,
+            x_range: range(corners.iter().map(|(x, _)| *x).collect()),
+            y_range: range(corners.iter().map(|(_, y)| *y).collect()),
+        })
+    }
+}
+
+/// Read the first mesh report in the end-of-line comments of a file.
+///
+/// The report must be on consecutive lines that have nothing but a comment.
+/// See [BedMesh::parse_report] for the reports that are recognized.
+///
+/// ```
+/// use g_code::parse::{file_parser, metadata::bed_mesh};
+///
+/// let file = file_parser(";Bilinear Leveling Grid:\n;      0      1\n; 0 +0.107 +0.077\n; 1 -0.025 +0.010\nM420 S1").unwrap();
+/// assert_eq!(bed_mesh(&file).unwrap().rows, vec![vec![0.107, 0.077], vec![-0.025, 0.010]]);
+/// ```
+pub fn bed_mesh(file: &File) -> Option<BedMesh> {
+    BedMesh::from_lines(file.iter().map(|line| {
+        if line.line_components.is_empty() && line.checksum.is_none() {
+            line.comment
+                .as_ref()
+                .map(|comment| comment.inner.trim_start_matches(';'))
+        } else {
+            None
+        }
+    }))
+}
+
+fn strip_log_prefixes(mut line: &str) -> &str {
+    line = line.trim();
+    while let Some(rest) = LOG_PREFIXES
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix))
+    {
+        line = rest.trim_start();
+    }
+    line
+}
+
+/// A line of heights, which starts with its Y index: ` 3 | +0.012  -0.025 [+0.037]  .  `
+fn mesh_row(line: &str) -> Option<(usize, Vec<f32>)> {
+    let mut words = line.split_whitespace();
+    let index = words.next()?.trim_end_matches('|').parse().ok()?;
+    let row = words
+        .filter(|word| *word != "|")
+        .map(height)
+        .collect::<Option<Vec<_>>>()?;
+    if row.is_empty() {
+        None
+    } else {
+        Some((index, row))
+    }
+}
+
+/// A height in a mesh row.
+///
+/// Marlin brackets the point the nozzle is over, prints `.` for points that weren't probed, and `nan` or `=====`
+/// for points that failed. Heights always have a decimal point, which tells rows apart from X axis labels.
+fn height(word: &str) -> Option<f32> {
+    let word = word.trim_start_matches('[').trim_end_matches(']');
+    if word == "."
+        || word.eq_ignore_ascii_case("nan")
+        || (!word.is_empty() && word.bytes().all(|b| b == b'='))
+    {
+        Some(f32::NAN)
+    } else if word.contains('.') {
+        word.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Coordinates labelling the corners of a mesh: `( 10,220)    (220,220)`
+fn corner_labels(line: &str) -> Option<Vec<(f32, f32)>> {
+    let mut corners = vec![];
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let inner = rest.strip_prefix('(')?;
+        let close = inner.find(')')?;
+        let (x, y) = inner[..close].split_once(',')?;
+        corners.push((x.trim().parse().ok()?, y.trim().parse().ok()?));
+        rest = inner[close + 1..].trim_start();
+    }
+    if corners.is_empty() {
+        None
+    } else {
+        Some(corners)
+    }
+}
+
+/// Blank lines, the `|` between rows, and the X axis labels.
+fn is_decoration(line: &str) -> bool {
+    line.split_whitespace()
+        .all(|word| word == "|" || word.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use pretty_assertions::assert_eq;
+
+    /// The mesh in `tests/ubl_mesh_report.log`, with the failed point in the second row from the back.
+    fn assert_fixture_mesh(mesh: &BedMesh) {
+        let expected = [
+            [-0.125, -0.087, -0.062, -0.075, -0.100],
+            [-0.087, -0.050, -0.025, -0.037, -0.062],
+            [-0.050, 0.000, 0.012, -0.012, -0.037],
+            [-0.012, 0.037, 0.025, f32::NAN, -0.025],
+            [0.062, 0.087, 0.050, 0.025, 0.012],
+        ];
+        assert_eq!(mesh.rows.len(), expected.len());
+        for (row, expected) in mesh.rows.iter().zip(expected.iter()) {
+            assert_eq!(row.len(), expected.len());
+            for (height, expected) in row.iter().zip(expected.iter()) {
+                assert!(
+                    height == expected || (height.is_nan() && expected.is_nan()),
+                    "{:?} != {:?}",
+                    row,
+                    expected
+                );
+            }
+        }
+        assert_eq!(mesh.x_range, Some(10.0..=220.0));
+        assert_eq!(mesh.y_range, Some(10.0..=220.0));
+    }
+
+    #[test]
+    fn reads_a_captured_ubl_report() {
+        let mesh = BedMesh::parse_report(include_str!("../../tests/ubl_mesh_report.log")).unwrap();
+        assert_fixture_mesh(&mesh);
+    }
+
+    #[test]
+    fn reads_a_report_kept_in_comments() {
+        let log = include_str!("../../tests/ubl_mesh_report.log");
+        let mut gcode = String::from("G28\n");
+        for line in log.lines().filter_map(|line| line.strip_prefix("Recv: ")) {
+            gcode += ";";
+            gcode += line;
+            gcode += "\n";
+        }
+        gcode += "M420 S1\nG1 X0 Y0 ;done";
+        let file = file_parser(&gcode).unwrap();
+        assert_fixture_mesh(&bed_mesh(&file).unwrap());
+    }
+
+    #[test]
+    fn incomplete_reports_are_not_meshes() {
+        assert_eq!(
+            BedMesh::parse_report("Recv: ok\nRecv: echo:busy: processing"),
+            None
+        );
+        assert_eq!(
+            BedMesh::parse_report(
+                "Bilinear Leveling Grid:\n      0      1\n 0 +0.107 +0.077\n 1 -0.025"
+            ),
+            None
+        );
+        assert_eq!(
+            BedMesh::parse_report("Bilinear Leveling Grid:\n 0 +0.107 +0.077\n 2 -0.025 +0.010"),
+            None
+        );
+        // The block ends at the first line that isn't part of it
+        let file = file_parser(";Bilinear Leveling Grid:\nG28\n; 0 +0.107 +0.077").unwrap();
+        assert_eq!(bed_mesh(&file), None);
+    }
+}
@@ -1,50 +1,291 @@
-use codespan_reporting::diagnostic::{Diagnostic as CodespanDiagnostic, Label};
+use codespan_reporting::diagnostic::{Diagnostic as CodespanDiagnostic, Label, Severity};
 
 mod parser;
-pub use parser::g_code::{file_parser, snippet_parser};
+pub use parser::g_code::snippet_parser;
 pub mod ast;
+pub mod cache;
+pub mod fast;
+pub mod include;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod metadata;
 pub mod token;
 
+pub use fast::file_parser_with_progress;
+pub use include::resolve_includes;
+
 pub type ParseError = peg::error::ParseError<peg::str::LineCol>;
 pub type Diagnostic = CodespanDiagnostic<()>;
 
+/// Parse a GCode file.
+///
+/// The file keeps a reference to the input, so the original text of its lines
+/// is available through [Line::raw_text](ast::Line::raw_text).
+pub fn file_parser(input: &str) -> Result<ast::File<'_>, ParseError> {
+    let mut file = parser::g_code::file_parser(input)?;
+    file.source = Some(input);
+    Ok(file)
+}
+
+/// Parse a GCode file that holds several programs, each wrapped in `%` delimiters,
+/// as some pendants save them.
+///
+/// Each program becomes a [File](ast::File) of its own, with spans into the whole input.
+/// Programs may be separated by blank lines. Input without delimiters is a single program, as parsed by [file_parser].
+///
+/// ```
+/// use g_code::parse::file_parser_multi;
+///
+/// let programs = file_parser_multi("%\nO1\nG0 X1\nM30\n%\n\n%\nO2\nM30\n%\n").unwrap();
+/// assert_eq!(programs.len(), 2);
+/// assert_eq!(programs[1].iter().nth(1).unwrap().raw_text(&programs[1]), Some("O2"));
+/// ```
+pub fn file_parser_multi(input: &str) -> Result<Vec<ast::File<'_>>, ParseError> {
+    let mut files = parser::g_code::multi_file_parser(input)?;
+    for file in files.iter_mut() {
+        file.source = Some(input);
+    }
+    Ok(files)
+}
+
+/// Optional syntax that is rejected by [file_parser] unless enabled here, and limits on the size of the input.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ParseOptions {
+    /// Accept GRBL system commands like `$H` and `$J=G91X10F500` as [SystemCommand](token::SystemCommand) lines,
+    /// and the realtime characters `?`, `!`, and `~` as [RealtimeCommand](token::RealtimeCommand)s.
+    ///
+    /// This is meant for reading captured serial logs.
+    pub allow_grbl_system_commands: bool,
+    /// Accept Klipper extended commands like `SET_PRESSURE_ADVANCE ADVANCE=0.05`
+    /// as [ExtendedCommand](token::ExtendedCommand) lines.
+    pub allow_extended_commands: bool,
+    /// Accept RepRapFirmware meta commands like `if move.axes[0].homed` and `set var.x = 3`
+    /// as [MetaCommand](token::MetaCommand) lines.
+    pub allow_rrf_meta: bool,
+    /// Accept a comma between the digits of a field's value as a decimal point, so `X12,5` is `X12.5`,
+    /// as written by some European CAM software.
+    ///
+    /// The comma is kept in the [raw segments](token::Field::raw_segments) of the field,
+    /// so checksums are computed over the text as it was sent.
+    /// See [normalize_decimal_separators](crate::transform::decimal::normalize_decimal_separators)
+    /// for rewriting such a file with decimal points.
+    pub accept_comma_decimal: bool,
+    /// Reject inline comments with more than this many opening parentheses.
+    ///
+    /// Inline comments don't nest, so `(a (b)` is a single comment, but each
+    /// opening parenthesis in it counts as a level.
+    pub max_inline_comment_depth: Option<usize>,
+    /// Reject lines longer than this many bytes, not counting the line ending.
+    pub max_line_length: Option<usize>,
+    /// Reject files with more than this many lines.
+    ///
+    /// The limits are meant for parsing untrusted input, like a file given to a web viewer,
+    /// and there are none by default.
+    pub max_lines: Option<usize>,
+}
+
+impl ParseOptions {
+    pub(crate) fn check_inline_comment_depth(&self, comment: &str) -> Result<(), &'static str> {
+        match self.max_inline_comment_depth {
+            Some(max) if comment.matches('(').count() > max => {
+                Err("inline comment nested no deeper than max_inline_comment_depth")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_line_count(&self, lines: usize) -> Result<(), &'static str> {
+        match self.max_lines {
+            Some(max) if lines > max => Err("file with no more lines than max_lines"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Like [file_parser], but with optional syntax enabled by [ParseOptions].
+pub fn file_parser_with_options<'input>(
+    input: &'input str,
+    opts: &ParseOptions,
+) -> Result<ast::File<'input>, ParseError> {
+    let mut file = parser::g_code::file_with_options(input, opts)?;
+    file.source = Some(input);
+    Ok(file)
+}
+
+/// Parse a single [Field](token::Field), such as one given in a command line flag or a config file.
+///
+/// The whole input must be the field: surrounding whitespace or anything after the value is an error.
+///
+/// Convert the result with [`crate::emit::Field::from`] to use it for emission.
+///
+/// ```
+/// use g_code::{emit, parse};
+///
+/// let travel_speed = emit::Field::from(&parse::field("F3000")?);
+/// assert_eq!(travel_speed.to_string(), "F3000");
+/// assert!(parse::field("F3000 X").is_err());
+/// # Ok::<(), g_code::parse::ParseError>(())
+/// ```
+pub fn field(input: &str) -> Result<token::Field<'_>, ParseError> {
+    parser::g_code::field(input)
+}
+
+/// Parse a single [Value](token::Value), like `12.5`, `-3`, or `"MYROUTER"`.
+///
+/// The whole input must be the value: surrounding whitespace or anything after it is an error.
+///
+/// Convert the result with [`crate::emit::Value::from`] to use it for emission.
+/// Note that, as with the parser, strings keep their delimiting quotes until converted.
+pub fn value(input: &str) -> Result<token::Value<'_>, ParseError> {
+    parser::g_code::value(input)
+}
+
 /// Convenience function for converting a parsing error
 /// into a [codespan_reporting::diagnostic::Diagnostic] for displaying to a user.
 pub fn into_diagnostic(err: &ParseError) -> Diagnostic {
-    let expected_count = err.expected.tokens().count();
-    let label_msg = if expected_count == 0 {
-        "unclear cause".to_string()
-    } else if expected_count == 1 {
-        format!("expected {}", err.expected.tokens().next().unwrap())
-    } else {
-        let mut acc = "expected one of ".to_string();
-        for token in err.expected.tokens().take(expected_count - 1) {
-            acc += token;
-            acc += ", ";
-        }
-        acc += "or ";
-        acc += err.expected.tokens().last().unwrap();
-        acc
-    };
     Diagnostic::error()
         .with_message("could not parse gcode")
         .with_labels(vec![Label::primary(
             (),
             err.location.offset..err.location.offset,
         )
-        .with_message(label_msg)])
+        .with_message(expected_label(err))])
+}
+
+/// Like [into_diagnostic], but the source is examined to suggest a fix
+/// for common mistakes, which is rendered as a note.
+pub fn into_diagnostic_with_source(err: &ParseError, src: &str) -> Diagnostic {
+    let details = ErrorDetails::new(err, src);
+    let diagnostic = into_diagnostic(err);
+    match details.suggestion {
+        Some(suggestion) => diagnostic.with_notes(vec![suggestion]),
+        None => diagnostic,
+    }
+}
+
+/// A [Diagnostic] with a primary label on a span of the input, for reporting problems
+/// found after parsing the same way as parse errors.
+///
+/// Further labels can be added with [Diagnostic::with_labels], which appends to the primary one.
+pub fn diagnostic_at(
+    severity: Severity,
+    message: impl Into<String>,
+    span: ast::Span,
+    label: impl Into<String>,
+) -> Diagnostic {
+    Diagnostic::new(severity)
+        .with_message(message)
+        .with_labels(vec![
+            Label::primary((), std::ops::Range::from(span)).with_message(label)
+        ])
+}
+
+/// Convenience function for reporting a line whose checksum differs from the `computed` one,
+/// as found by [Line::validate_checksum](ast::Line::validate_checksum), as an error.
+///
+/// The checksum is labeled with the one expected, and the text it covers with a secondary label.
+/// A line without a checksum is labeled where the checksum would go, before any end-of-line comment.
+pub fn diagnostic_for_checksum_mismatch(line: &ast::Line, computed: u8) -> Diagnostic {
+    use ast::Spanned;
+    let line_span = line.span();
+    let checksum_span = line.checksum.as_ref().map_or_else(
+        || {
+            let end = line.comment.as_ref().map_or(line_span.1, |c| c.span().0);
+            ast::Span(end, end)
+        },
+        Spanned::span,
+    );
+    diagnostic_at(
+        Severity::Error,
+        "checksum does not match",
+        checksum_span,
+        format!("expected *{}", computed),
+    )
+    .with_labels(vec![
+        Label::secondary((), line_span.0..checksum_span.0).with_message("checksum of this text")
+    ])
+}
+
+/// Structured information about a [ParseError], for tooling that presents errors on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorDetails {
+    /// Byte offset of the error in the source
+    pub offset: usize,
+    /// Describes what the parser expected to find
+    pub label: String,
+    /// How to fix the error, if it is a recognizable mistake
+    pub suggestion: Option<String>,
+}
+
+impl ErrorDetails {
+    pub fn new(err: &ParseError, src: &str) -> Self {
+        Self {
+            offset: err.location.offset,
+            label: expected_label(err),
+            suggestion: suggest_fix(err, src),
+        }
+    }
+}
+
+fn expected_label(err: &ParseError) -> String {
+    // The expected set is unordered, so sort it to keep messages stable
+    let mut expected = err.expected.tokens().collect::<Vec<_>>();
+    expected.sort_unstable();
+    match expected.as_slice() {
+        [] => "unclear cause".to_string(),
+        [token] => format!("expected {}", token),
+        [init @ .., last] => format!("expected one of {}, or {}", init.join(", "), last),
+    }
+}
+
+fn suggest_fix(err: &ParseError, src: &str) -> Option<String> {
+    let offset = err.location.offset;
+    let before = src.get(..offset)?;
+    let found = src.get(offset..).and_then(|rest| rest.chars().next());
+    let expects = |token: &str| err.expected.tokens().any(|t| t == token);
+
+    let suggestion = match found {
+        Some(c) if !c.is_ascii() => {
+            "g-code is ASCII-only; remove or transliterate this character"
+        }
+        Some('=') | Some('#') => {
+            "parameters and assignments (like `#1=2`) are not supported; remove them or move them into a comment"
+        }
+        Some(' ') | Some('\t')
+            if expects("semicolon")
+                && before
+                    .trim_end_matches(|c: char| c.is_ascii_digit())
+                    .ends_with('*') =>
+        {
+            "remove the whitespace between the checksum and the comment"
+        }
+        _ if expects("closing parenthesis") => {
+            "add a closing parenthesis; inline comments cannot span multiple lines"
+        }
+        _ if err.expected.tokens().eq(std::iter::once("quotation mark")) => {
+            "add the closing quotation mark; strings cannot span multiple lines"
+        }
+        _ if expects("integer") && before.ends_with(|c: char| c.is_ascii_alphabetic()) => {
+            "add a value after the field's letters, like `X0`"
+        }
+        _ => return None,
+    };
+    Some(suggestion.to_string())
 }
 
 #[cfg(test)]
 mod tests {
     use super::file_parser;
-    use crate::parse::ast::{Line, Span};
+    use crate::parse::ast::{
+        DuplicateLineNumber, FromTokensError, InvalidComment, Line, PushInlineCommentError, Span,
+        Spanned,
+    };
     use crate::parse::token::*;
     use pretty_assertions::assert_eq;
 
     mod parser {
         use super::super::parser::g_code::*;
-        use super::{assert_eq, *};
+        use super::{assert_eq, file_parser, *};
 
         #[test]
         fn parses_svg2gcode_output() {
@@ -79,6 +320,28 @@ mod tests {
             file_parser(gcode).unwrap();
         }
 
+        #[test]
+        fn integers_past_u32_are_kept_exactly() {
+            let file = file_parser("N4294967296 G1 X1\nN18446744073709551615 M110").unwrap();
+            assert_eq!(
+                file.iter().map(Line::line_number).collect::<Vec<_>>(),
+                vec![Some(1 << 32), Some(u64::MAX)]
+            );
+        }
+
+        #[test]
+        fn integers_past_u64_are_parsed_as_rationals_if_they_fit() {
+            let parsed = file_parser("N18446744073709551616 G1").unwrap();
+            let number = parsed.iter_fields().next().unwrap();
+            #[cfg(feature = "float-values")]
+            assert_eq!(number.value, Value::Rational(18446744073709551616.));
+            // A Ratio<i64> is even smaller than a u64, so the number is kept as text
+            #[cfg(not(feature = "float-values"))]
+            assert_eq!(number.value, Value::Raw("18446744073709551616".into()));
+            assert_eq!(number.raw_text(), "18446744073709551616");
+            assert_eq!(parsed.iter().next().unwrap().line_number(), None);
+        }
+
         #[test]
         fn parses_fields_without_whitespace() {
             let gcode = "G0X1Y0";
@@ -115,6 +378,610 @@ N2 M107*39"#;
             }
         }
 
+        #[test]
+        fn out_of_range_checksum_fails_validation_not_parsing() {
+            use crate::emit::{ChecksumOutOfRange, Token};
+            use std::convert::TryFrom;
+
+            let parsed = file_parser(include_str!("../../tests/bad_checksum.gcode")).unwrap();
+            let validations = parsed
+                .iter()
+                .map(|line| line.validate_checksum())
+                .collect::<Vec<_>>();
+            assert_eq!(validations, vec![Some(Ok(())), Some(Err(83)), Some(Ok(()))]);
+
+            let bad_line = parsed.iter().nth(1).unwrap();
+            assert_eq!(
+                Token::try_from(bad_line.checksum.as_ref().unwrap()),
+                Err(ChecksumOutOfRange(300))
+            );
+            assert_eq!(
+                bad_line.iter_emit_tokens().last(),
+                Some(Token::Checksum(83))
+            );
+        }
+
+        #[test]
+        fn line_from_tokens_matches_its_formatted_text() {
+            use crate::emit::{self, format_gcode_fmt, FormatOptions, Token};
+            use num_rational::Ratio;
+
+            let field = |letters, value| Token::Field(emit::Field::new(letters, value).unwrap());
+            let without_checksum = vec![
+                field("N", emit::Value::Integer(10)),
+                field("G", emit::Value::Integer(1)),
+                field("X", emit::Value::Float(1.5)),
+                Token::Comment {
+                    is_inline: true,
+                    inner: "fast".into(),
+                },
+                field("Y", emit::Value::Rational(Ratio::new(-1, 4))),
+                field("P", emit::Value::String("a\"\"b".into())),
+                Token::Comment {
+                    is_inline: false,
+                    inner: " move".into(),
+                },
+            ];
+            let mut formatted = String::new();
+            format_gcode_fmt(&without_checksum, FormatOptions::default(), &mut formatted).unwrap();
+            let fresh = line(formatted.trim_end()).unwrap();
+            let constructed = Line::from_tokens(&without_checksum, 0).unwrap();
+            assert_eq!(constructed, fresh);
+            assert_eq!(constructed.compute_checksum(), fresh.compute_checksum());
+
+            let mut with_checksum = without_checksum.clone();
+            with_checksum.insert(6, Token::Checksum(0));
+            let checksum = Line::from_tokens(&with_checksum, 0)
+                .unwrap()
+                .compute_checksum();
+            with_checksum[6] = Token::Checksum(checksum);
+            let mut formatted = String::new();
+            format_gcode_fmt(&with_checksum, FormatOptions::default(), &mut formatted).unwrap();
+            let fresh = line(formatted.trim_end()).unwrap();
+            assert_eq!(Line::from_tokens(&with_checksum, 0).unwrap(), fresh);
+            assert_eq!(fresh.validate_checksum(), Some(Ok(())));
+
+            let shifted = Line::from_tokens(&with_checksum, 100).unwrap();
+            assert_eq!(shifted.span(), Span(100, 100 + formatted.trim_end().len()));
+            assert_eq!(shifted.compute_checksum(), fresh.compute_checksum());
+
+            let g28 = field("G", emit::Value::Integer(28));
+            let x = Token::Flag(emit::Flag::new("X").unwrap());
+            let comment = Token::Comment {
+                is_inline: false,
+                inner: "home".into(),
+            };
+            assert_eq!(
+                Line::from_tokens(&[g28.clone(), x.clone()], 0).unwrap(),
+                line("G28 X").unwrap()
+            );
+            let cases = [
+                (
+                    vec![g28.clone(), Token::Percent],
+                    FromTokensError::NotOnOneLine { index: 1 },
+                ),
+                (
+                    vec![g28.clone(), Token::Newline, x.clone()],
+                    FromTokensError::NotOnOneLine { index: 1 },
+                ),
+                (
+                    vec![g28.clone(), comment.clone(), x.clone()],
+                    FromTokensError::AfterComment { index: 2 },
+                ),
+                (
+                    vec![g28.clone(), Token::Checksum(0), x.clone()],
+                    FromTokensError::AfterChecksum { index: 2 },
+                ),
+                (
+                    vec![x.clone(), g28.clone()],
+                    FromTokensError::InvalidFlag { index: 0 },
+                ),
+                (
+                    vec![Token::Comment {
+                        is_inline: true,
+                        inner: "a)b".into(),
+                    }],
+                    FromTokensError::InvalidComment { index: 0 },
+                ),
+            ];
+            for (tokens, err) in cases.iter() {
+                assert_eq!(Line::from_tokens(tokens, 0), Err(*err));
+            }
+        }
+
+        #[test]
+        fn snippets_contain_commands_with_their_arguments() {
+            use crate::emit::{
+                linear_interpolation, start_spindle_clockwise, stop_spindle, Field, Value,
+            };
+
+            let snippet =
+                snippet_parser("G21\nN10 m3 P1000.0 ; spin up\nG10 L2 P1 X0\nG1 X1.5 F300")
+                    .unwrap();
+            let spindle = |speed| {
+                start_spindle_clockwise(std::iter::once(
+                    Field::new("P", Value::Float(speed)).unwrap(),
+                ))
+            };
+            assert!(!snippet.contains_command(&stop_spindle(std::iter::empty())));
+            assert!(snippet.contains_command(&start_spindle_clockwise(std::iter::empty())));
+            assert!(snippet.contains_command(&spindle(1000.)));
+            assert!(!snippet.contains_command(&spindle(100.)));
+
+            let feed = Field::new("F", Value::Integer(300)).unwrap();
+            let x = |x| Field::new("X", Value::Float(x)).unwrap();
+            assert!(snippet.contains_command(&linear_interpolation(
+                vec![x(1.5), feed.clone()].into_iter()
+            )));
+            // Arguments must be on the same line as the command
+            assert!(!snippet.contains_command(&linear_interpolation(vec![x(0.)].into_iter())));
+            assert!(!snippet.contains_command(&linear_interpolation(vec![x(1.)].into_iter())));
+        }
+
+        #[test]
+        fn comment_only_and_blank_lines_are_not_executable() {
+            let opts = super::super::ParseOptions {
+                allow_extended_commands: true,
+                ..Default::default()
+            };
+            let src = "; start\n  \nN5\n(wipe) *0\nSET_FAN_SPEED FAN=part SPEED=0.5\nG28 ;home";
+            let file = super::super::file_parser_with_options(src, &opts).unwrap();
+            let lines = file
+                .iter()
+                .map(|line| (line.is_empty(), line.has_fields(), line.is_executable()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                lines,
+                [
+                    (false, false, false),
+                    (true, false, false),
+                    (false, true, true),
+                    (false, false, false),
+                    (false, false, true),
+                    (false, true, true),
+                ]
+            );
+            assert_eq!(file.iter_executable().count(), 3);
+        }
+
+        #[test]
+        fn klipper_extended_commands_round_trip_when_allowed() {
+            use crate::emit::{format_gcode_fmt, FormatOptions};
+
+            let src = include_str!("../../tests/klipper_macros.gcode");
+            assert_eq!(file_parser(src).unwrap_err().location.line, 2);
+
+            let opts = super::super::ParseOptions {
+                allow_extended_commands: true,
+                ..Default::default()
+            };
+            let file = super::super::file_parser_with_options(src, &opts).unwrap();
+            let commands = file
+                .iter()
+                .filter_map(|line| line.extended_command())
+                .collect::<Vec<_>>();
+            assert_eq!(commands.len(), 10);
+            assert_eq!(commands[0].name(), "SET_PRESSURE_ADVANCE");
+            assert_eq!(
+                commands[0].iter_params().collect::<Vec<_>>(),
+                [("ADVANCE", "0.05"), ("EXTRUDER", "extruder")]
+            );
+            assert_eq!(commands[5].get("msg"), Some("\"Layer 2\""));
+            assert_eq!(commands[3].get("MOVE"), Some("1"));
+            for line in file.iter() {
+                let text = line.raw_text(&file).unwrap();
+                let code = text.split(';').next().unwrap();
+                assert_eq!(
+                    line.iter_bytes().copied().collect::<Vec<_>>(),
+                    code.as_bytes()
+                );
+                if let Some(command) = line.extended_command() {
+                    assert_eq!(&src[std::ops::Range::from(command.span())], code.trim_end());
+                }
+            }
+
+            let tokens = file.iter_emit_tokens().collect::<Vec<_>>();
+            let mut emitted = String::new();
+            format_gcode_fmt(&tokens, FormatOptions::default(), &mut emitted).unwrap();
+            assert_eq!(emitted, src);
+
+            let mut numbered = String::new();
+            format_gcode_fmt(
+                &tokens,
+                FormatOptions {
+                    line_numbers: true,
+                    checksums: true,
+                    ..Default::default()
+                },
+                &mut numbered,
+            )
+            .unwrap();
+            let reparsed = super::super::file_parser_with_options(&numbered, &opts).unwrap();
+            assert!(crate::hash::semantic_eq(&file, &reparsed));
+            for line in reparsed.iter() {
+                assert_eq!(
+                    line.validate_checksum().is_some(),
+                    line.iter_fields().next().is_some()
+                );
+            }
+        }
+
+        #[test]
+        fn rrf_meta_commands_round_trip_when_allowed() {
+            use crate::emit::{format_gcode_fmt, FormatOptions, MetaCommand, Token};
+
+            let src = include_str!("../../tests/daemon.g");
+            assert_eq!(file_parser(src).unwrap_err().location.line, 2);
+
+            let opts = super::super::ParseOptions {
+                allow_rrf_meta: true,
+                ..Default::default()
+            };
+            let file = super::super::file_parser_with_options(src, &opts).unwrap();
+            let commands = file
+                .iter()
+                .filter_map(|line| line.meta_command())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                commands
+                    .iter()
+                    .map(|command| (command.indent(), command.keyword()))
+                    .collect::<Vec<_>>(),
+                [
+                    ("", "if"),
+                    ("  ", "global"),
+                    ("", "if"),
+                    ("  ", "echo"),
+                    ("", "elif"),
+                    ("  ", "set"),
+                    ("", "else"),
+                    ("", "var"),
+                    ("", "while"),
+                    ("\t", "if"),
+                    ("\t\t", "break"),
+                    ("\t", "set"),
+                    ("", "if"),
+                    ("  ", "abort"),
+                ]
+            );
+            assert_eq!(commands[0].expression_text(), "!exists(global.lastLayer)");
+            assert_eq!(
+                commands[3].expression_text(),
+                "\"Hotend over temperature; turned off\""
+            );
+            assert_eq!(commands[6].expression_text(), "");
+            assert_eq!(commands[11].expression_text(), "var.tries = var.tries + 1");
+            for line in file.iter() {
+                let text = line.raw_text(&file).unwrap();
+                let code = match &line.comment {
+                    Some(comment) => &text[..comment.pos - line.span.0],
+                    None => text,
+                };
+                assert_eq!(
+                    line.iter_bytes().copied().collect::<Vec<_>>(),
+                    code.as_bytes()
+                );
+                if let Some(command) = line.meta_command() {
+                    assert_eq!(&src[std::ops::Range::from(command.span())], code.trim_end());
+                }
+            }
+
+            // Meta commands are written verbatim, but other lines lose their indentation
+            let mut emitted = String::new();
+            format_gcode_fmt(
+                file.iter_emit_tokens(),
+                FormatOptions::default(),
+                &mut emitted,
+            )
+            .unwrap();
+            for command in commands.iter() {
+                let text = &src[std::ops::Range::from(command.span())];
+                assert!(
+                    emitted.lines().any(|line| line.starts_with(text)),
+                    "{}",
+                    text
+                );
+            }
+            assert!(emitted.contains("\nM568 P0 A0 ; hotend off\n"));
+            let reparsed = super::super::file_parser_with_options(&emitted, &opts).unwrap();
+            assert!(crate::hash::semantic_eq(&file, &reparsed));
+
+            let command = MetaCommand::new("set", "var.x = 3").unwrap().indented(2);
+            let mut emitted = String::new();
+            format_gcode_fmt(
+                [
+                    Token::from(command),
+                    Token::from(MetaCommand::new("else", "").unwrap()),
+                ],
+                FormatOptions {
+                    line_numbers: true,
+                    checksums: true,
+                    ..Default::default()
+                },
+                &mut emitted,
+            )
+            .unwrap();
+            assert_eq!(emitted, "  set var.x = 3\nelse\n");
+            assert!(MetaCommand::new("echo", "\"a;b\"").is_ok());
+            for &(keyword, expression) in [
+                ("goto", "x"),
+                ("If", "x"),
+                ("echo", "a;b"),
+                ("echo", "\"a"),
+                ("echo", " a"),
+                ("echo", "a\nb"),
+            ]
+            .iter()
+            {
+                assert!(
+                    MetaCommand::new(keyword, expression).is_err(),
+                    "{} {}",
+                    keyword,
+                    expression
+                );
+            }
+        }
+
+        #[test]
+        fn value_less_letters_after_a_command_are_flags() {
+            let parsed = file_parser("G28 X Y*76\nM6 T;tool\n").unwrap();
+            let lines = parsed.iter().collect::<Vec<_>>();
+            assert_eq!(
+                lines[0]
+                    .iter_flags()
+                    .map(|f| f.letters())
+                    .collect::<Vec<_>>(),
+                vec!["X", "Y"]
+            );
+            assert_eq!(lines[0].validate_checksum(), Some(Ok(())));
+            assert_eq!(lines[1].iter_flags().next().unwrap().span(), Span(14, 15));
+
+            for gcode in ["X Y1", "G1 N", "G1 X-", "G1 X."].iter() {
+                assert!(file_parser(gcode).is_err(), "{}", gcode);
+            }
+        }
+
+        #[test]
+        fn grbl_system_and_realtime_commands_are_parsed_when_allowed() {
+            let src = include_str!("../../tests/grbl_jog_session.gcode");
+            assert_eq!(file_parser(src).unwrap_err().location.offset, 0);
+
+            let opts = super::super::ParseOptions {
+                allow_grbl_system_commands: true,
+                ..Default::default()
+            };
+            let file = super::super::file_parser_with_options(src, &opts).unwrap();
+            let commands = file
+                .iter()
+                .filter_map(|line| line.system_command())
+                .map(|command| command.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                commands,
+                [
+                    "$X",
+                    "$H",
+                    "$J=G91X10F500",
+                    "$J=G91 Y-5.5 F500",
+                    "$J=G90X0Y0F1000",
+                    "$$"
+                ]
+            );
+            let realtime = file
+                .iter()
+                .flat_map(|line| line.iter_realtime_commands())
+                .map(|command| (command.as_char(), command.span()))
+                .collect::<Vec<_>>();
+            assert_eq!(realtime[0], ('?', Span(6, 7)));
+            assert_eq!(
+                realtime.iter().map(|(c, _)| *c).collect::<String>(),
+                "?!~??"
+            );
+            for line in file.iter() {
+                let text = line.raw_text(&file).unwrap();
+                assert_eq!(
+                    line.iter_bytes().copied().collect::<Vec<_>>(),
+                    text.split(';').next().unwrap().as_bytes()
+                );
+                if let Some(command) = line.system_command() {
+                    assert_eq!(command.span(), line.span());
+                    assert_eq!(line.iter_emit_tokens().count(), 0);
+                }
+            }
+
+            let jog = file
+                .iter()
+                .filter_map(|line| line.system_command()?.jog())
+                .map(|snippet| {
+                    snippet
+                        .unwrap()
+                        .iter_fields()
+                        .map(|f| format!("{}{}", f.letters, f.raw_text()))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(jog, ["G91 X10 F500", "G91 Y-5.5 F500", "G90 X0 Y0 F1000"]);
+            assert_eq!(
+                file.iter_fields()
+                    .map(|f| f.letters.as_ref())
+                    .collect::<String>(),
+                "GGGZGX"
+            );
+        }
+
+        #[test]
+        fn byte_order_mark_is_skipped_and_counted_in_spans() {
+            let src = include_str!("../../tests/bom_crlf.gcode");
+            for file in [file_parser(src), crate::parse::fast::file_parser(src)].iter() {
+                let file = file.as_ref().unwrap();
+                assert!(file.has_byte_order_mark());
+                assert_eq!(file.span(), Span(0, src.len()));
+                let first = file.iter().next().unwrap();
+                assert_eq!(first.span().0, 3);
+                assert_eq!(first.raw_text(file), Some("; saved on Windows"));
+                assert_eq!(file.iter().count(), 5);
+            }
+            assert!(!file_parser("G28").unwrap().has_byte_order_mark());
+
+            let err = file_parser("\u{feff}G1 X=1").unwrap_err();
+            assert_eq!(err.location.offset, 7);
+            let err = file_parser("G28\n\u{feff}G1").unwrap_err();
+            assert_eq!(err.location.offset, 4);
+        }
+
+        #[test]
+        fn byte_order_mark_and_crlf_round_trip() {
+            use crate::emit::{format_gcode_fmt, FormatOptions};
+
+            let src = include_str!("../../tests/bom_crlf.gcode");
+            let file = file_parser(src).unwrap();
+            let tokens = file.iter_emit_tokens().collect::<Vec<_>>();
+            let mut emitted = String::new();
+            format_gcode_fmt(
+                &tokens,
+                FormatOptions {
+                    byte_order_mark: file.has_byte_order_mark(),
+                    preserve_blank_lines: true,
+                    ..Default::default()
+                },
+                &mut emitted,
+            )
+            .unwrap();
+            assert_eq!(emitted, src.replace("\r\n", "\n"));
+            let reparsed = file_parser(&emitted).unwrap();
+            assert!(reparsed.has_byte_order_mark());
+            // Spans differ once carriage returns are dropped, so compare the text of each field
+            let text = |f: &Field| format!("{}{}", f.letters, f.raw_text());
+            assert!(file
+                .iter_fields()
+                .map(text)
+                .eq(reparsed.iter_fields().map(text)));
+        }
+
+        #[test]
+        fn raw_field_text_tells_apart_spellings_of_zero() {
+            let src = "G1 X0 Y-0 Z0.0 E-.0 F0. GOTO 10";
+            for file in [file_parser(src), crate::parse::fast::file_parser(src)].iter() {
+                let fields = file.as_ref().unwrap().iter_fields().collect::<Vec<_>>();
+                assert_eq!(
+                    fields.iter().map(|f| f.raw_text()).collect::<Vec<_>>(),
+                    ["1", "0", "-0", "0.0", "-.0", "0.", " 10"]
+                );
+                assert_eq!(
+                    fields[3]
+                        .raw_segments()
+                        .iter()
+                        .map(|s| s.as_ref())
+                        .collect::<Vec<_>>(),
+                    ["0", ".", "0"]
+                );
+                for field in fields.iter().skip(1).take(5) {
+                    assert_eq!(crate::emit::Value::from(&field.value).as_f64(), Some(0.));
+                }
+                for field in fields.iter() {
+                    let bytes = field.iter_bytes().copied().collect::<Vec<_>>();
+                    assert_eq!(
+                        String::from_utf8(bytes).unwrap(),
+                        format!("{}{}", field.letters, field.raw_text())
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn raw_text_is_the_line_in_the_input() {
+            for src in [
+                include_str!("../../tests/vandy_commodores_logo.gcode"),
+                include_str!("../../tests/ncviewer_sample.gcode"),
+                include_str!("../../tests/blank_lines.gcode"),
+                include_str!("../../tests/square.gcode"),
+            ]
+            .iter()
+            {
+                for file in [file_parser(src), crate::parse::fast::file_parser(src)].iter() {
+                    let file = file.as_ref().unwrap();
+                    assert_eq!(file.source(), Some(*src));
+                    for line in file.iter() {
+                        assert_eq!(
+                            line.raw_text(file),
+                            Some(&src[std::ops::Range::from(line.span())])
+                        );
+                        assert!(!line.raw_text(file).unwrap().contains('\n'));
+                    }
+                }
+            }
+            assert_eq!(
+                super::super::parser::g_code::file_parser("G1")
+                    .unwrap()
+                    .source(),
+                None
+            );
+        }
+
+        #[test]
+        fn computes_checksums_in_each_style() {
+            use crate::emit::ChecksumStyle::*;
+            let xor = |text: &str| text.bytes().fold(0u8, |acc, b| acc ^ b);
+            // The Marlin host handshake and the examples of the RepRap wiki, checksums as sent
+            let known_good = [
+                ("N0 M110 N0", 125),
+                ("N3 T0", 57),
+                ("N4 G92 E0", 67),
+                ("N5 G28", 22),
+                ("N6 G1 F1500.0", 82),
+                ("N7 G1 X2.0 Y2.0 F3000.0", 85),
+            ];
+            for (body, checksum) in known_good.iter() {
+                let text = format!("{}*{}", body, checksum);
+                let parsed = line(&text).unwrap();
+                assert_eq!(xor(body), *checksum);
+                assert_eq!(parsed.compute_checksum_with(Classic), *checksum);
+                assert_eq!(parsed.validate_checksum_with(Classic), Some(Ok(())));
+                // The other styles change which bytes are XORed, so they are checked
+                // against the text with those bytes taken out or put in
+                assert_eq!(
+                    parsed.compute_checksum_with(ExcludeSpaceAfterLineNumber),
+                    xor(&body.replacen(' ', "", 1)),
+                    "{}",
+                    body
+                );
+                assert_eq!(
+                    parsed.compute_checksum_with(IncludeAsterisk),
+                    xor(&format!("{}*", body))
+                );
+                assert_eq!(
+                    parsed.compute_checksum_with(ExcludeInlineComments),
+                    *checksum
+                );
+            }
+        }
+
+        #[test]
+        fn inline_comments_can_be_left_out_of_checksums() {
+            use crate::emit::ChecksumStyle::*;
+            let xor = |text: &str| text.bytes().fold(0u8, |acc, b| acc ^ b);
+            assert_eq!((xor("N1 G28 (home)"), xor("N1 G28 ")), (60, 50));
+            let parsed = file_parser("N1 G28 (home)*50\nN2 M107*39").unwrap();
+            let line = parsed.iter().next().unwrap();
+            assert_eq!(line.compute_checksum_with(Classic), 60);
+            assert_eq!(line.compute_checksum_with(ExcludeInlineComments), 50);
+            assert_eq!(line.validate_checksum_with(Classic), Some(Err(60)));
+            assert_eq!(
+                line.validate_checksum_with(ExcludeInlineComments),
+                Some(Ok(()))
+            );
+            assert_eq!(parsed.detect_checksum_style(), Some(ExcludeInlineComments));
+
+            let classic = file_parser("N1 G28 (home)*60\nN2 M107*39").unwrap();
+            assert_eq!(classic.detect_checksum_style(), Some(Classic));
+            assert_eq!(file_parser("N1 G28").unwrap().detect_checksum_style(), None);
+            assert_eq!(
+                file_parser("N1 G28*0").unwrap().detect_checksum_style(),
+                None
+            );
+        }
+
         #[test]
         fn checksum_of_empty_line_is_zero() {
             let gcode = "*0";
@@ -142,6 +1009,63 @@ N2 M107*39"#;
             );
         }
 
+        #[test]
+        fn bytes_of_line_with_decimal_fields_are_preserved() {
+            let gcode = "G1 X1.5 Y-2.25 Z.5 E3.";
+            let parsed = file_parser(gcode).unwrap();
+            assert_eq!(
+                parsed.iter_bytes().copied().collect::<Vec<u8>>(),
+                gcode.as_bytes()
+            );
+        }
+
+        #[test]
+        fn line_bytes_agree_with_the_bytes_of_each_part() {
+            use super::super::{file_parser_with_options, ParseOptions};
+            let everything = ParseOptions {
+                allow_grbl_system_commands: true,
+                allow_extended_commands: true,
+                allow_rrf_meta: true,
+                accept_comma_decimal: true,
+                ..Default::default()
+            };
+            let corpus = [
+                include_str!("../../tests/vandy_commodores_logo.gcode"),
+                include_str!("../../tests/cura_sample.gcode"),
+                include_str!("../../tests/klipper_macros.gcode"),
+                include_str!("../../tests/daemon.g"),
+                include_str!("../../tests/grbl_jog_session.gcode"),
+                "G1 X1,5 (a)? Y2*7;b",
+            ];
+            for gcode in corpus.iter() {
+                for line in file_parser_with_options(gcode, &everything).unwrap().iter() {
+                    let expected = line
+                        .extended_command
+                        .iter()
+                        .flat_map(|e| e.iter_bytes())
+                        .chain(line.meta_command.iter().flat_map(|m| m.iter_bytes()))
+                        .chain(line.line_components.iter().flat_map(|c| c.iter_bytes()))
+                        .chain(line.system_command.iter().flat_map(|s| s.iter_bytes()))
+                        .copied()
+                        .collect::<Vec<u8>>();
+                    let mut iter = line.iter_bytes();
+                    for remaining in (0..=expected.len()).rev() {
+                        assert_eq!(iter.len(), remaining);
+                        iter.next();
+                    }
+                    assert_eq!(line.iter_bytes().copied().collect::<Vec<_>>(), expected);
+                    assert_eq!(line.byte_len(), expected.len());
+                    let mut written = b"prefix".to_vec();
+                    line.write_bytes(&mut written);
+                    assert_eq!(&written[6..], expected.as_slice());
+                    assert_eq!(
+                        line.compute_checksum(),
+                        expected.iter().fold(0u8, |acc, b| acc ^ b)
+                    );
+                }
+            }
+        }
+
         #[test]
         fn checksum_of_line_with_comment_is_correct() {
             let gcode = "(inline)G0 X0 (inline) (inline) Y0(inline);eolcomment";
@@ -158,6 +1082,15 @@ N2 M107*39"#;
             );
         }
 
+        #[test]
+        fn checksums_too_large_for_a_byte_are_invalid() {
+            for gcode in ["G1*256", "G1*70000", "G1*99999999999999999999999"].iter() {
+                let parsed = file_parser(gcode).unwrap();
+                let line = parsed.iter().next().unwrap();
+                assert_eq!(line.validate_checksum(), Some(Err(line.compute_checksum())));
+            }
+        }
+
         #[test]
         fn checksum_of_line_with_checkum_and_comment_is_correct() {
             let gcode = "(inline)G0 X0 (inline) (inline) Y0(inline)*118;eolcomment";
@@ -178,6 +1111,364 @@ N2 M107*39"#;
             );
         }
 
+        #[test]
+        fn asterisks_in_comments_and_strings_are_not_checksums() {
+            // The real checksum of each line, and the text it covers
+            let cases = [
+                ("G1 X0 ;speed *2 for roughing", None, "G1 X0 "),
+                ("G1 X0 *12;note *2", Some(12), "G1 X0 "),
+                ("G1 X0*3;*4", Some(3), "G1 X0"),
+                ("M117 P\"a*b\"*99", Some(99), "M117 P\"a*b\""),
+                (
+                    "M117 P\"*\"\"*\"\"\" *7;*",
+                    Some(7),
+                    "M117 P\"*\"\"*\"\"\" ",
+                ),
+                ("(a*b) G1 (*)*5", Some(5), "(a*b) G1 (*)"),
+            ];
+            for &(gcode, checksum, covered) in cases.iter() {
+                let parsed = file_parser(gcode).unwrap();
+                assert_eq!(super::super::fast::file_parser(gcode).unwrap(), parsed);
+                let line = parsed.iter().next().unwrap();
+                assert_eq!(
+                    line.checksum.as_ref().map(|c| c.inner),
+                    checksum,
+                    "{}",
+                    gcode
+                );
+                assert_eq!(
+                    line.iter_bytes().copied().collect::<Vec<u8>>(),
+                    covered.as_bytes(),
+                    "{}",
+                    gcode
+                );
+                assert_eq!(
+                    line.compute_checksum(),
+                    covered.bytes().fold(0u8, |acc, x| acc ^ x)
+                );
+                let comment = gcode[covered.len()..]
+                    .find(';')
+                    .map(|i| &gcode[covered.len() + i..]);
+                assert_eq!(line.comment.as_ref().map(|c| c.inner.as_ref()), comment);
+            }
+            // Whitespace is not allowed after a checksum, so neither of these can be misread
+            assert_eq!(
+                file_parser("G1 X0 *12 ;note *2")
+                    .unwrap_err()
+                    .location
+                    .offset,
+                9
+            );
+            assert!(file_parser("G1 X0 *12 *13").is_err());
+        }
+
+        /// Write a file back out from its parts, for comparing against a reparse.
+        fn render(file: &crate::parse::ast::File) -> String {
+            let line_text = |line: &Line| {
+                let mut text = String::from_utf8(line.iter_bytes().copied().collect()).unwrap();
+                if let Some(checksum) = &line.checksum {
+                    text += &format!("*{}", checksum.inner);
+                }
+                if let Some(comment) = &line.comment {
+                    text += &comment.inner;
+                }
+                text
+            };
+            let mut text = String::new();
+            if file.byte_order_mark {
+                text.push('\u{feff}');
+            }
+            if file.start_percent {
+                text.push('%');
+            }
+            for (line, _) in file.lines.iter() {
+                text += &line_text(line);
+                text.push('\n');
+            }
+            if let Some(line) = &file.last_line {
+                text += &line_text(line);
+            }
+            if file.end_percent {
+                text.push('%');
+            }
+            text
+        }
+
+        #[test]
+        fn whitespace_is_normalized_with_consistent_spans() {
+            let cases = [
+                ("G1   X1\t\tY2   ;note", "G1 X1 Y2 ;note"),
+                ("G1 X1  *12", "G1 X1*12"),
+                ("G1 X1 \t", "G1 X1"),
+                ("\t\tG1  X1", "\t\tG1 X1"),
+                ("  ;only a comment", "  ;only a comment"),
+                ("G0 ;a  \t", "G0 ;a  \t"),
+                ("M117 P\"a   b\"  S1", "M117 P\"a   b\" S1"),
+                ("GOTO   120  (jump)  ", "GOTO   120 (jump)"),
+                ("(a)  (b)\tG0", "(a) (b) G0"),
+            ];
+            for &(gcode, normalized) in cases.iter() {
+                let mut file = file_parser(gcode).unwrap();
+                let mut line = file.iter().next().cloned();
+                file.normalize_whitespace();
+                assert_eq!(render(&file), normalized);
+                assert_eq!(file, file_parser(normalized).unwrap(), "{:?}", gcode);
+
+                if let Some(line) = line.as_mut() {
+                    line.normalize_whitespace();
+                    assert_eq!(Some(&*line), file.iter().next());
+                }
+            }
+
+            let gcode = "%\nN1  G28*18\n\n   \nG1\tX1   Y\"a  b\" ;c\n\tG0  X0  \n%";
+            let mut file = file_parser(gcode).unwrap();
+            file.normalize_whitespace();
+            let normalized = render(&file);
+            assert_eq!(
+                normalized,
+                "%\nN1 G28*18\n\n\nG1 X1 Y\"a  b\" ;c\n\tG0 X0\n%"
+            );
+            assert_eq!(file, file_parser(&normalized).unwrap());
+            assert_eq!(file.source(), None);
+            let once = file.clone();
+            file.normalize_whitespace();
+            assert_eq!(file, once);
+
+            // A parsed line is laid out exactly as parsed, so only changed lines move
+            let mut file = file_parser(include_str!("../../tests/ncviewer_sample.gcode")).unwrap();
+            let before = file.clone();
+            file.normalize_whitespace();
+            assert_eq!(file, before);
+        }
+
+        #[test]
+        fn edited_field_checksum_matches_reparse() {
+            let mut line = file_parser("N3 G1 X1.50 Y2*0")
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .clone();
+            for component in line.line_components.iter_mut() {
+                match component.field.as_mut() {
+                    Some(f) if f.letters == "X" => f.set_value(Value::Rational(real(-9, 4))),
+                    Some(f) if f.letters == "Y" => f.set_value(Value::String("\"a\"".into())),
+                    _ => {}
+                }
+            }
+            let edited = String::from_utf8(line.iter_bytes().copied().collect()).unwrap();
+            assert_eq!(edited, "N3 G1 X-2.25 Y\"a\"");
+            let x = line.iter_fields().nth(2).unwrap();
+            assert_eq!(x.span(), Span(6, 12));
+
+            let reparsed = file_parser(&edited).unwrap();
+            assert_eq!(
+                line.compute_checksum(),
+                reparsed.iter().next().unwrap().compute_checksum()
+            );
+        }
+
+        #[test]
+        fn resolves_jumps_to_line_numbers() {
+            let gcode = "%\nN10 G0 X0\nN20 G1 X1\nGOTO 10\nN30 M99 P20\n%";
+            let parsed = file_parser(gcode).unwrap();
+            assert_eq!(
+                parsed.line_number_index(),
+                Ok([(10, 1), (20, 2), (30, 4)].iter().copied().collect())
+            );
+            let jumps = parsed
+                .iter()
+                .flat_map(|line| line.iter_fields().map(move |f| (line, f)))
+                .filter_map(|(line, f)| parsed.resolve_jump(line, f))
+                .collect::<Vec<_>>();
+            assert_eq!(jumps, [1, 2]);
+            assert_eq!(
+                parsed
+                    .iter()
+                    .nth(3)
+                    .unwrap()
+                    .iter_bytes()
+                    .copied()
+                    .collect::<Vec<u8>>(),
+                b"GOTO 10"
+            );
+
+            // Other commands take P for something else
+            let parsed = file_parser("N500 G0 X0\nG4 P500\nM3 P500\nM98 P500").unwrap();
+            let jumps = parsed
+                .iter()
+                .flat_map(|line| line.iter_fields().map(move |f| (line, f)))
+                .filter_map(|(line, f)| parsed.resolve_jump(line, f))
+                .collect::<Vec<_>>();
+            assert_eq!(jumps, [0]);
+
+            // The line given with the field decides, not another line that shares its span
+            let dwell = file_parser("G04 P500\nN500 G0 X0").unwrap();
+            let dwell_line = dwell.iter().next().unwrap();
+            let dwell_p = dwell_line.iter_fields().nth(1).unwrap();
+            let call = file_parser("M98 P500\nN500 G0 X0").unwrap();
+            assert_eq!(call.iter_fields().nth(1).unwrap().span, dwell_p.span);
+            assert_eq!(call.resolve_jump(dwell_line, dwell_p), None);
+        }
+
+        #[test]
+        fn duplicate_line_numbers_are_reported() {
+            let gcode = "N1 G0 X0\nN2 G1 X1\nN1 G1 X2\nGOTO1";
+            let parsed = file_parser(gcode).unwrap();
+            assert_eq!(
+                parsed.line_number_index(),
+                Err(DuplicateLineNumber {
+                    number: 1,
+                    first: 0,
+                    second: 2
+                })
+            );
+            let last = parsed.iter().last().unwrap();
+            let goto = last.iter_fields().next().unwrap();
+            assert_eq!(parsed.resolve_jump(last, goto), Some(0));
+        }
+
+        #[test]
+        fn edited_eol_comment_is_excluded_from_checksum() {
+            let mut line = file_parser("N1 G28*18;home")
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .clone();
+            line.set_eol_comment(Some(" +postprocessed")).unwrap();
+            assert_eq!(line.validate_checksum(), Some(Ok(())));
+            assert_eq!(line.span(), Span(0, 25));
+            assert_eq!(
+                line.iter_emit_tokens().last(),
+                Some(crate::emit::Token::Comment {
+                    is_inline: false,
+                    inner: " +postprocessed".into()
+                })
+            );
+
+            line.set_eol_comment(None).unwrap();
+            assert_eq!(line.validate_checksum(), Some(Ok(())));
+            assert_eq!(line.span(), Span(0, 9));
+            assert_eq!(line.iter_emit_tokens().count(), 3);
+
+            let mut uncommented = file_parser("G1 X1").unwrap().iter().next().unwrap().clone();
+            uncommented.set_eol_comment(Some("tagged")).unwrap();
+            assert_eq!(uncommented.comment.as_ref().unwrap().span(), Span(5, 12));
+            assert_eq!(
+                uncommented.set_eol_comment(Some("two\nlines")),
+                Err(InvalidComment("two\nlines".to_string()))
+            );
+            // The comment it had survives the error
+            assert_eq!(
+                uncommented.comment.as_ref().map(|comment| &*comment.inner),
+                Some(";tagged")
+            );
+            assert_eq!(uncommented.span(), Span(0, 12));
+        }
+
+        #[test]
+        fn pushed_inline_comments_are_part_of_the_line() {
+            let mut line = file_parser("G1 X1 Y2;eol")
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .clone();
+            line.push_inline_comment("probe", 1).unwrap();
+            line.push_inline_comment("end", 3).unwrap();
+            line.push_inline_comment("start", 0).unwrap();
+            let expected = "(start)G1 (probe)X1 Y2(end)";
+            assert_eq!(
+                line.iter_bytes().copied().collect::<Vec<u8>>(),
+                expected.as_bytes()
+            );
+            assert_eq!(
+                line.compute_checksum(),
+                expected.bytes().fold(0u8, |acc, x| acc ^ x)
+            );
+            assert_eq!(
+                line.push_inline_comment("(nested)", 0),
+                Err(PushInlineCommentError::InvalidComment(InvalidComment(
+                    "(nested)".to_string()
+                )))
+            );
+            assert_eq!(
+                line.push_inline_comment("late", 4),
+                Err(PushInlineCommentError::OutOfRange {
+                    position: 4,
+                    fields: 3
+                })
+            );
+            assert_eq!(
+                line.iter_bytes().copied().collect::<Vec<u8>>(),
+                expected.as_bytes()
+            );
+        }
+
+        #[test]
+        fn emit_tokens_follow_the_line_not_spans() {
+            use crate::emit::Token;
+            let comment = |inner: &str| Token::Comment {
+                is_inline: true,
+                inner: inner.to_string().into(),
+            };
+
+            let file = file_parser("G1 X1*99;eol").unwrap();
+            let mut line = file.iter().next().unwrap().clone();
+            // Both comments take the start of X1 as their span
+            line.push_inline_comment("a", 1).unwrap();
+            line.push_inline_comment("b", 1).unwrap();
+            let starts = line
+                .line_components
+                .iter()
+                .map(|c| {
+                    c.inline_comment
+                        .as_ref()
+                        .map(|i| i.pos)
+                        .or_else(|| c.field.as_ref().map(|f| f.span.0))
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(starts, [Some(0), None, Some(3), Some(3), Some(3)]);
+            let tokens = line.iter_emit_tokens().collect::<Vec<_>>();
+            assert_eq!(tokens[1], comment("a"));
+            assert_eq!(tokens[2], comment("b"));
+            assert!(matches!(tokens[3], Token::Field(_)));
+            assert!(matches!(tokens[4], Token::Checksum(_)));
+            assert!(matches!(
+                tokens[5],
+                Token::Comment {
+                    is_inline: false,
+                    ..
+                }
+            ));
+
+            // Collapsing every span onto the start of the file changes nothing
+            let mut collapsed = file_parser("%\nG1 X1 (c);eol\n%").unwrap();
+            let expected = collapsed.iter_emit_tokens().collect::<Vec<_>>();
+            for (line, newline) in collapsed.lines.iter_mut() {
+                line.span = Span(0, 0);
+                newline.pos = 0;
+                for component in line.line_components.iter_mut() {
+                    if let Some(field) = component.field.as_mut() {
+                        field.span = Span(0, 0);
+                    }
+                    if let Some(whitespace) = component.whitespace.as_mut() {
+                        whitespace.pos = 0;
+                    }
+                    if let Some(inline_comment) = component.inline_comment.as_mut() {
+                        inline_comment.pos = 0;
+                    }
+                }
+                if let Some(comment) = line.comment.as_mut() {
+                    comment.pos = 0;
+                }
+            }
+            assert_eq!(collapsed.iter_emit_tokens().collect::<Vec<_>>(), expected);
+            assert_eq!(expected.first(), Some(&Token::Percent));
+            assert_eq!(expected.last(), Some(&Token::Percent));
+        }
+
         #[test]
         fn inline_comment_is_parsed() {
             let gcode = "(comment)";
@@ -187,19 +1478,439 @@ N2 M107*39"#;
                 Line {
                     line_components: vec![LineComponent {
                         inline_comment: Some(InlineComment {
-                            inner: "(comment)",
+                            inner: "(comment)".into(),
                             pos: 0
                         }),
                         ..Default::default()
                     }],
                     checksum: None,
                     comment: None,
+                    system_command: None,
+                    extended_command: None,
+                    meta_command: None,
                     span: Span(0, gcode.len())
                 }
             );
         }
     }
 
+    mod diagnostics {
+        use super::super::{
+            diagnostic_for_checksum_mismatch, into_diagnostic_with_source, Diagnostic, ErrorDetails,
+        };
+        use super::{assert_eq, *};
+        use codespan_reporting::files::SimpleFile;
+        use codespan_reporting::term::{self, termcolor::NoColor};
+
+        fn render(gcode: &str) -> String {
+            let err = file_parser(gcode).unwrap_err();
+            emit(&into_diagnostic_with_source(&err, gcode), gcode)
+        }
+
+        fn emit(diagnostic: &Diagnostic, gcode: &str) -> String {
+            let mut acc = NoColor::new(vec![]);
+            term::emit(
+                &mut acc,
+                &term::Config::default(),
+                &SimpleFile::new("test.gcode", gcode),
+                diagnostic,
+            )
+            .unwrap();
+            String::from_utf8(acc.into_inner()).unwrap()
+        }
+
+        #[test]
+        fn renders_suggestion_as_note() {
+            assert_eq!(
+                render("G1 X§"),
+                r#"error: could not parse gcode
+  ┌─ test.gcode:1:5
+  │
+1 │ G1 X§
+  │     ^ expected one of EOF, checksum asterisk, decimal point, integer, letters, minus sign, newline, opening parenthesis, quotation mark, semicolon, or whitespace
+  │
+  = g-code is ASCII-only; remove or transliterate this character
+
+"#
+            );
+            assert_eq!(
+                render("N1 G28*18 ;home"),
+                r#"error: could not parse gcode
+  ┌─ test.gcode:1:10
+  │
+1 │ N1 G28*18 ;home
+  │          ^ expected one of EOF, newline, or semicolon
+  │
+  = remove the whitespace between the checksum and the comment
+
+"#
+            );
+        }
+
+        #[test]
+        fn suggests_fixes_for_common_mistakes() {
+            let cases = [
+                ("G1 X§", "g-code is ASCII-only; remove or transliterate this character"),
+                ("G1 P\"abc\nG1", "add the closing quotation mark; strings cannot span multiple lines"),
+                ("G1 (abc\nG2", "add a closing parenthesis; inline comments cannot span multiple lines"),
+                ("#1=2", "parameters and assignments (like `#1=2`) are not supported; remove them or move them into a comment"),
+                ("G1 X1 =", "parameters and assignments (like `#1=2`) are not supported; remove them or move them into a comment"),
+                ("N1 G28*18 ;home", "remove the whitespace between the checksum and the comment"),
+                ("X Y1", "add a value after the field's letters, like `X0`"),
+            ];
+            for (gcode, suggestion) in cases.iter() {
+                let err = file_parser(gcode).unwrap_err();
+                assert_eq!(
+                    ErrorDetails::new(&err, gcode).suggestion.as_deref(),
+                    Some(*suggestion),
+                    "{}",
+                    gcode
+                );
+            }
+            let err = file_parser("G1 X1 Y2 )").unwrap_err();
+            assert_eq!(ErrorDetails::new(&err, "G1 X1 Y2 )").suggestion, None);
+        }
+
+        #[test]
+        fn unterminated_strings_and_comments_point_at_where_they_end() {
+            // Newlines and the end of the input both end the line that the string or comment was on
+            for (gcode, offset) in [
+                ("G1 P\"abc\nG1", 8),
+                ("G1 P\"abc", 8),
+                ("G1 P\"a\"\"bc\r\nG1", 10),
+                ("G1 (abc\nG2", 7),
+                ("G1 (abc", 7),
+                ("G1 X1\n(a (b\r\n", 11),
+            ]
+            .iter()
+            {
+                let err = file_parser(gcode).unwrap_err();
+                assert_eq!(
+                    ErrorDetails::new(&err, gcode).offset,
+                    *offset,
+                    "{:?}",
+                    gcode
+                );
+                let diagnostic = into_diagnostic_with_source(&err, gcode);
+                assert_eq!(diagnostic.labels[0].range, *offset..*offset, "{:?}", gcode);
+            }
+        }
+
+        #[test]
+        fn checksum_mismatches_render_like_parse_errors() {
+            let gcode = "N1 G28*18\nN2 G1 X1 ;move";
+            let file = file_parser(gcode).unwrap();
+            // Trailing whitespace is left on label connectors, so it is trimmed to keep the snapshots readable
+            let rendered = file
+                .iter()
+                .map(|line| {
+                    let diagnostic = diagnostic_for_checksum_mismatch(line, 71);
+                    let text = emit(&diagnostic, gcode);
+                    text.lines()
+                        .map(str::trim_end)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                rendered[0],
+                r#"error: checksum does not match
+  ┌─ test.gcode:1:7
+  │
+1 │ N1 G28*18
+  │ ------^^^ expected *71
+  │ │
+  │ checksum of this text
+"#
+            );
+            assert_eq!(
+                rendered[1],
+                r#"error: checksum does not match
+  ┌─ test.gcode:2:10
+  │
+2 │ N2 G1 X1 ;move
+  │ ---------^ expected *71
+  │ │
+  │ checksum of this text
+"#
+            );
+        }
+    }
+
+    mod standalone {
+        use super::super::{field, value};
+        use super::{assert_eq, *};
+
+        #[test]
+        fn parses_single_fields_and_values() {
+            let parsed = field("F3000").unwrap();
+            assert_eq!(parsed.letters, "F");
+            assert_eq!(parsed.value, Value::Integer(3000));
+            assert_eq!(parsed.span(), Span(0, 5));
+            assert_eq!(field("GOTO 10").unwrap().value, Value::Integer(10));
+
+            assert_eq!(value("12.5"), Ok(Value::Rational(real(25, 2))));
+            assert_eq!(value("-.5"), Ok(Value::Rational(real(-1, 2))));
+            assert_eq!(value("-3"), Ok(Value::Rational(real(-3, 1))));
+            assert_eq!(value("\"a\"\"b\""), Ok(Value::String("\"a\"\"b\"".into())));
+        }
+
+        #[test]
+        fn numbers_keep_their_raw_segments() {
+            for (text, value, segments) in [
+                ("X1.", real(1, 1), &["1", ".", ""][..]),
+                ("X.5", real(1, 2), &[".", "5"][..]),
+                ("X-.5", real(-1, 2), &["-", ".", "5"][..]),
+                ("X-1.25", real(-5, 4), &["-", "1", ".", "25"][..]),
+                ("X-3", real(-3, 1), &["-", "3"][..]),
+                ("X-0.0", real(0, 1), &["-", "0", ".", "0"][..]),
+            ]
+            .iter()
+            {
+                let parsed = field(text).unwrap();
+                assert_eq!(parsed.value, Value::Rational(*value), "{:?}", text);
+                assert_eq!(parsed.raw_segments(), *segments);
+                let fast = crate::parse::fast::file_parser(text).unwrap();
+                assert_eq!(fast.iter_fields().next(), Some(&parsed));
+            }
+            // Too many digits for a Ratio<i64>, but fine as an f64
+            let huge = field("X9223372036854775807.5").unwrap();
+            assert_eq!(
+                matches!(huge.value, Value::Raw(_)),
+                cfg!(not(feature = "float-values"))
+            );
+            assert_eq!(huge.raw_text(), "9223372036854775807.5");
+        }
+
+        #[test]
+        fn trailing_garbage_is_an_error() {
+            for input in ["F3000 ", " F3000", "F3000X1", "F3000;feed", "F"].iter() {
+                assert!(field(input).is_err(), "{:?}", input);
+            }
+            for input in ["12.5mm", "1.2.3", "", "X1"].iter() {
+                assert!(value(input).is_err(), "{:?}", input);
+            }
+        }
+    }
+
+    mod programs {
+        use super::super::{file_parser, file_parser_multi};
+        use super::{assert_eq, *};
+
+        #[test]
+        fn percent_delimited_programs_are_parsed_one_by_one() {
+            let gcode = include_str!("../../tests/two_programs.gcode");
+            assert!(file_parser(gcode).is_err());
+            let programs = file_parser_multi(gcode).unwrap();
+            let texts = programs
+                .iter()
+                .map(|program| &gcode[program.span().0..program.span().1])
+                .collect::<Vec<_>>();
+            assert_eq!(
+                texts,
+                [
+                    "%\nO1001 (FACE)\nG0 X0 Y0\nG1 X10 F100 (M30 IS BELOW)\nM30\n%",
+                    "%\nO1002 (DRILL)\nG81 X5 Y5 Z-2 R1 F50\nG80\nM30\n%"
+                ]
+            );
+            for (program, text) in programs.iter().zip(texts.iter()) {
+                assert!(program.has_percent_delimiters());
+                let alone = file_parser(text).unwrap();
+                assert!(program.iter_emit_tokens().eq(alone.iter_emit_tokens()));
+            }
+
+            // A single program parses the same either way
+            let square = include_str!("../../tests/square.gcode");
+            assert_eq!(
+                file_parser_multi(square).unwrap(),
+                [file_parser(square).unwrap()]
+            );
+            assert_eq!(file_parser_multi("G0 X1\nM2").unwrap().len(), 1);
+        }
+
+        #[test]
+        fn programs_split_at_their_end_codes() {
+            let gcode = "G0 X1 (M30 is next)\nM30\nG0 X2 ;M2\nm2\n(tail)";
+            let file = file_parser(gcode).unwrap();
+            let programs = file.split_programs();
+            let texts = programs
+                .iter()
+                .map(|program| &gcode[program.span().0..program.span().1])
+                .collect::<Vec<_>>();
+            assert_eq!(
+                texts,
+                ["G0 X1 (M30 is next)\nM30\n", "G0 X2 ;M2\nm2\n", "(tail)"]
+            );
+            assert_eq!(programs[1].iter().count(), 2);
+
+            let gcode = include_str!("../../tests/two_programs.gcode");
+            for file in file_parser_multi(gcode).unwrap() {
+                let programs = file.split_programs();
+                assert_eq!(programs.len(), 1);
+                let lines = programs[0]
+                    .iter()
+                    .map(|line| line.raw_text(&file).unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(lines.first().map(|line| &line[..1]), Some("O"));
+                assert_eq!(lines.last(), Some(&"M30"));
+            }
+        }
+    }
+
+    mod limits {
+        use super::super::{file_parser_with_options, ParseOptions};
+        use super::{assert_eq, *};
+        use std::time::{Duration, Instant};
+
+        const UNTRUSTED: ParseOptions = ParseOptions {
+            allow_grbl_system_commands: false,
+            allow_extended_commands: false,
+            allow_rrf_meta: false,
+            accept_comma_decimal: false,
+            max_inline_comment_depth: Some(16),
+            max_line_length: Some(4096),
+            max_lines: Some(1000),
+        };
+
+        fn expected(input: &str) -> Vec<&'static str> {
+            let err = file_parser_with_options(input, &UNTRUSTED).unwrap_err();
+            err.expected.tokens().collect()
+        }
+
+        #[test]
+        fn fuzzed_inputs_fail_quickly() {
+            let long_line = "G1 X1 ".repeat(5000);
+            let many_lines = "G0\n".repeat(5000);
+            for (input, limit) in [
+                (
+                    include_str!("../../tests/input/unclosed_parentheses.gcode"),
+                    "line no longer than max_line_length",
+                ),
+                (
+                    include_str!("../../tests/input/nested_inline_comments.gcode"),
+                    "inline comment nested no deeper than max_inline_comment_depth",
+                ),
+                (long_line.as_str(), "line no longer than max_line_length"),
+                (
+                    many_lines.as_str(),
+                    "file with no more lines than max_lines",
+                ),
+            ]
+            .iter()
+            {
+                let start = Instant::now();
+                assert!(expected(input).contains(limit), "{:?}", limit);
+                assert!(start.elapsed() < Duration::from_secs(5));
+            }
+        }
+
+        #[test]
+        fn inputs_within_the_limits_parse() {
+            let gcode = "G1 (a (b) X1\n".repeat(1000);
+            let file = file_parser_with_options(&gcode, &UNTRUSTED).unwrap();
+            assert_eq!(file.iter().count(), 1000);
+            assert!(expected(&"G1 X1\n".repeat(1001))
+                .contains(&"file with no more lines than max_lines"));
+            assert!(expected(&format!("{}G1 X1", "G1 X1\n".repeat(1000)))
+                .contains(&"file with no more lines than max_lines"));
+            let err = file_parser_with_options(&"G0\n".repeat(5000), &UNTRUSTED).unwrap_err();
+            assert_eq!(err.location.line, 1001);
+            let percent = format!("%{}\n%", "\nG0".repeat(999));
+            assert!(file_parser_with_options(&percent, &UNTRUSTED).is_ok());
+            assert!(
+                file_parser_with_options(&format!("%\nG0{}", &percent[1..]), &UNTRUSTED).is_err()
+            );
+            let line = format!("G1 ({})", "a".repeat(4091));
+            assert_eq!(line.len(), 4096);
+            assert!(file_parser_with_options(&line, &UNTRUSTED).is_ok());
+            assert!(file_parser_with_options(&format!("G0\n{} ", line), &UNTRUSTED).is_err());
+            // No limits by default
+            assert!(file_parser(&"G1 X1 ".repeat(5000)).is_ok());
+        }
+    }
+
+    mod span {
+        use super::{assert_eq, *};
+
+        #[test]
+        fn unions_ignore_empty() {
+            assert_eq!(Span::EMPTY + Span(5, 8), Span(5, 8));
+            assert_eq!(Span(5, 8) + Span::EMPTY, Span(5, 8));
+            assert_eq!(Span::EMPTY + Span::EMPTY, Span::EMPTY);
+            assert_eq!(Span::union_all(vec![]), Span::EMPTY);
+            assert_eq!(
+                Span::union_all(vec![Span(12, 15), Span(5, 8), Span(9, 10)]),
+                Span(5, 15)
+            );
+            // A zero-length span still has a position
+            assert_eq!(Span(0, 0) + Span(5, 8), Span(0, 8));
+
+            let file = file_parser("G0 X1\nG1 Y2 ;go").unwrap();
+            assert_eq!(
+                file.iter().map(Spanned::span).collect::<Span>(),
+                file.span()
+            );
+            let mut span = Span::EMPTY;
+            span.extend(file.iter().skip(1).map(Spanned::span));
+            assert_eq!(span, Span(6, 15));
+            span.extend(vec![]);
+            assert_eq!(span, Span(6, 15));
+        }
+
+        #[test]
+        fn lengths_and_emptiness() {
+            assert_eq!(Span(3, 7).len(), 4);
+            assert!(!Span(3, 7).is_empty());
+            assert_eq!(Span(3, 3).len(), 0);
+            assert!(Span(3, 3).is_empty());
+            assert_eq!(Span::EMPTY.len(), 0);
+            assert!(Span::EMPTY.is_empty());
+        }
+
+        #[test]
+        fn contains_offsets_before_the_end() {
+            let span = Span(3, 7);
+            assert!(!span.contains(2));
+            assert!(span.contains(3));
+            assert!(span.contains(6));
+            assert!(!span.contains(7));
+            assert!(!Span(3, 3).contains(3));
+            assert!(!Span::EMPTY.contains(0));
+            assert!(!Span::EMPTY.contains(usize::MAX));
+        }
+
+        #[test]
+        fn intersects_when_a_byte_is_shared() {
+            let span = Span(3, 7);
+            assert!(span.intersects(Span(6, 10)));
+            assert!(span.intersects(Span(0, 4)));
+            assert!(span.intersects(Span(4, 5)));
+            assert!(Span(4, 5).intersects(span));
+            assert!(!span.intersects(Span(7, 10)));
+            assert!(!span.intersects(Span(0, 3)));
+            assert!(!span.intersects(Span(5, 5)));
+            assert!(!Span(5, 5).intersects(span));
+            assert!(!span.intersects(Span::EMPTY));
+            assert!(!Span::EMPTY.intersects(Span(0, usize::MAX)));
+        }
+
+        #[test]
+        fn shifts_both_ends() {
+            assert_eq!(Span(3, 7).shift(5), Span(8, 12));
+            assert_eq!(Span(3, 7).shift(-3), Span(0, 4));
+            assert_eq!(Span(3, 7).shift(0), Span(3, 7));
+            assert_eq!(Span(3, 3).shift(-1), Span(2, 2));
+            assert_eq!(Span::EMPTY.shift(-5), Span::EMPTY);
+            assert_eq!(Span::EMPTY.shift(5), Span::EMPTY);
+        }
+
+        #[test]
+        #[should_panic(expected = "span shifted out of range")]
+        fn shifting_before_the_start_panics() {
+            let _ = Span(3, 7).shift(-4);
+        }
+    }
+
     mod lexer {
         use super::super::parser::g_code::*;
         use super::{assert_eq, *};
@@ -216,7 +1927,7 @@ N2 M107*39"#;
             assert_eq!(
                 comment(gcode),
                 Ok(Comment {
-                    inner: gcode,
+                    inner: gcode.into(),
                     pos: 0
                 })
             );
@@ -271,7 +1982,7 @@ N2 M107*39"#;
                 inline_comment(gcode),
                 Ok(InlineComment {
                     pos: 0,
-                    inner: gcode
+                    inner: gcode.into()
                 }),
             )
         }
@@ -2,7 +2,8 @@ peg::parser! {
     pub grammar g_code() for str{
         use super::super::token::*;
         use super::super::ast::*;
-        use num_rational::Ratio;
+        use super::super::ParseOptions;
+        use std::borrow::Cow;
         pub rule newline() -> Newline = pos:position!() inner:(quiet!{ $("\r\n" / "\r" / "\n") } / expected!("newline")) {
             Newline {
                 pos
@@ -20,7 +21,7 @@ peg::parser! {
         rule closing_parenthesis() -> &'input str = quiet! { $(")") } / expected!("closing parenthesis");
         pub rule inline_comment() -> InlineComment<'input> = pos:position!() inner:$(opening_parenthesis() ascii_except_closing_parenthesis_or_newline() closing_parenthesis()) {
             InlineComment {
-                inner,
+                inner: Cow::Borrowed(inner),
                 pos,
             }
         };
@@ -28,7 +29,7 @@ peg::parser! {
         rule semicolon() -> &'input str = quiet! { $(";") } / expected!("semicolon");
         pub rule comment() -> Comment<'input> = pos:position!() inner:$(semicolon() ascii_character_except_newline()) {
             Comment {
-                inner,
+                inner: Cow::Borrowed(inner),
                 pos,
             }
         };
@@ -36,91 +37,244 @@ peg::parser! {
         pub rule letters() -> &'input str = quiet! { $(['a'..='z' | 'A'..='Z']+) } / expected!("letters");
         pub rule whitespace() -> Whitespace<'input> = pos:position!() inner:(quiet! { $([' ' | '\t' ]+) } / expected!("whitespace")) {
             Whitespace {
-                inner,
+                inner: Cow::Borrowed(inner),
                 pos,
             }
         };
 
-        pub rule checksum() -> Checksum = left:position!() star:star() checksum:integer() right:position!() {?
-            Ok(Checksum {
-                inner: checksum.parse::<u8>().map_err(|e| "checksum is not byte-sized")?,
+        pub rule checksum() -> Checksum = left:position!() star:star() checksum:integer() right:position!() {
+            Checksum {
+                inner: saturating_checksum(checksum),
                 span: Span(left, right)
-            })
+            }
         };
 
+        rule goto() -> &'input str = quiet! { $(['G' | 'g'] ['O' | 'o'] ['T' | 't'] ['O' | 'o']) };
+
         pub rule field() -> Field<'input>
-            = left:position!() letters:letters() neg:minus()? lhs:integer()  dot:dot() rhs:integer()? right:position!() {?
-                let lhs_start = left + letters.len() + neg.as_ref().map(|_| 1).unwrap_or(0);
-                let lhs_end = lhs_start + lhs.len();
-                let rhs_start = lhs_end + 1;
-                let rhs_end = rhs_start + rhs.map(|x| x.len()).unwrap_or(0);
+            // Jumps are commonly written as `GOTO 120`, so whitespace is allowed before the target
+            = left:position!() letters:goto() space:$([' ' | '\t']+) value:integer() right:position!() {?
                 Ok(Field {
-                    letters,
-                    value: Value::Rational(lhs.parse::<Ratio<i64>>()
-                        .map_err(|e| "integer part does not fit in an i64")
-                        .and_then(|lhs| if let Some(rhs_str) = rhs {
-                            rhs_str.parse::<i64>()
-                                .map(|rhs| Ratio::new(rhs, 10i64.pow(rhs_str.len() as u32)))
-                                .map(|rhs| lhs + rhs)
-                                .map(|value| if neg.is_some() { -value } else { value })
-                                .map_err(|e| "fractional part does not fit in an i64")
-                        } else {
-                            Ok(lhs)
-                        })?),
-                    raw_value: if neg.is_some() { vec!["-", lhs, ".", rhs.unwrap_or("")] } else { vec![".", rhs.unwrap_or("")] },
+                    letters: Cow::Borrowed(letters),
+                    value: Value::Integer(value.parse::<u64>().map_err(|e| "integer does not fit in u64")?),
+                    raw_value: vec![Cow::Borrowed(space), Cow::Borrowed(value)],
                     span: Span(left, right)
                 })
             }
-            / left:position!() letters:letters() neg:minus()? dot:dot() rhs_str:integer() right:position!() {?
-                let rhs_start = left + letters.len() + neg.as_ref().map(|_| 1).unwrap_or(0) + 1;
-                let rhs_end = right;
-                Ok(Field {
-                    letters,
-                    value: Value::Rational(rhs_str.parse::<i64>()
-                        .map(|rhs| Ratio::new(rhs, 10i64.pow(rhs_str.len() as u32)))
-                        .map(|rhs| if neg.is_some() { -rhs } else { rhs })
-                        .map_err(|e| "fractional part does not fit in an i64")?),
-                    raw_value: if neg.is_some() { vec!["-", ".", rhs_str] } else { vec![".", rhs_str] },
+            / left:position!() letters:letters() value:value_and_raw() right:position!() {
+                let (value, raw_value) = value;
+                Field {
+                    letters: Cow::Borrowed(letters),
+                    value,
+                    raw_value,
                     span: Span(left, right)
-                })
+                }
+            };
+
+        /// Parse a standalone [Value]
+        pub rule value() -> Value<'input> = value:value_and_raw() { value.0 };
+
+        rule value_and_raw() -> (Value<'input>, Vec<Cow<'input, str>>)
+            = text:$(minus()? integer() dot() integer()?) {
+                (real_or_raw(Cow::Borrowed(text)), number_segments(text))
             }
-            / left:position!() letters:letters() value:integer() right:position!() {?
-                Ok(Field {
-                    letters,
-                    value: Value::Integer(value.parse::<usize>().map_err(|e| "integer does not fit in usize")?),
-                    raw_value: vec![value],
-                    span: Span(left, right)
-                })
+            / text:$(minus()? dot() integer()) {
+                (real_or_raw(Cow::Borrowed(text)), number_segments(text))
+            }
+            / value:integer() {
+                match value.parse::<u64>() {
+                    Ok(integer) => (Value::Integer(integer), vec![Cow::Borrowed(value)]),
+                    // Too large for an integer, but it may still fit in a Real
+                    Err(_) => (real_or_raw(Cow::Borrowed(value)), number_segments(value)),
+                }
+            }
+            / text:$(minus() integer()) {
+                (real_or_raw(Cow::Borrowed(text)), number_segments(text))
             }
+            / string:string() {
+                (Value::String(Cow::Borrowed(string)), vec![Cow::Borrowed(string)])
+            };
 
-            / left:position!() letters:letters() minus:minus() value:integer() right:position!() {?
-                let value_start = left + letters.len() + 1;
-                let value_end = right;
-                Ok(Field {
-                    letters,
-                    value: Value::Rational(-value.parse::<Ratio<i64>>().map_err(|e| "integer does not fit in i64")?),
-                    raw_value: vec!["-", value],
-                    span: Span(left, right)
-                })
+        /// Succeeds without consuming anything if `enabled`, so that optional syntax can be switched off
+        rule enabled(enabled: bool) = {? if enabled { Ok(()) } else { Err("disabled syntax") } };
+
+        pub rule system_command() -> SystemCommand<'input> = pos:position!() inner:$("$" ascii_character_except_newline()) {
+            SystemCommand {
+                inner: Cow::Borrowed(inner),
+                pos,
+            }
+        };
+
+        pub rule realtime_command() -> RealtimeCommand = pos:position!() inner:$(['?' | '!' | '~']) {
+            RealtimeCommand {
+                inner: inner.as_bytes()[0],
+                pos,
             }
-            / left:position!() letters:letters() string:string() right:position!() {
+        };
+
+        rule extended_name() -> &'input str = name:$(['A'..='Z'] ['A'..='Z' | '0'..='9' | '_']*) {?
+            if name.contains('_') { Ok(name) } else { Err("extended command name") }
+        };
+
+        rule extended_param() -> ExtendedParam<'input>
+            = whitespace:$([' ' | '\t']+) key:$(['A'..='Z' | 'a'..='z' | '0'..='9' | '_']+) "=" value:$(string() / ['!'..=':' | '<'..='~']*) {
+            ExtendedParam {
+                whitespace: Cow::Borrowed(whitespace),
+                key: Cow::Borrowed(key),
+                value: Cow::Borrowed(value),
+            }
+        };
+
+        pub rule extended_command() -> ExtendedCommand<'input> = left:position!() name:extended_name() params:extended_param()* right:position!() {
+            ExtendedCommand {
+                name: Cow::Borrowed(name),
+                params,
+                span: Span(left, right),
+            }
+        };
+
+        rule meta_keyword() -> &'input str
+            = keyword:$(['a'..='z']+) !['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.'] {?
+                if MetaCommand::KEYWORDS.contains(&keyword) { Ok(keyword) } else { Err("meta command keyword") }
+            };
+
+        /// Printable ASCII except quotes, which start a string, semicolons, which start a comment, and spaces
+        rule meta_expression_character() = ['!' | '#'..=':' | '<'..='~'];
+
+        pub rule meta_command() -> MetaCommand<'input>
+            = left:position!()
+                indent:$([' ' | '\t']*)
+                keyword:meta_keyword()
+                // Trailing whitespace is left out, so it can go before a comment
+                expression:$(([' ' | '\t']+ (string() / meta_expression_character())+)*)
+                right:position!() {
+            MetaCommand {
+                indent: Cow::Borrowed(indent),
+                keyword: Cow::Borrowed(keyword),
+                expression: Cow::Borrowed(expression),
+                span: Span(left, right),
+            }
+        };
+
+        /// A field whose value has a comma in place of the decimal point, like `X12,5`
+        rule comma_decimal_field() -> Field<'input>
+            = left:position!() letters:letters() sign:minus()? lhs:integer() comma:$(",") rhs:integer() right:position!() {
+                let text = format!("{}{}.{}", sign.unwrap_or_default(), lhs, rhs);
                 Field {
-                    letters,
-                    value: Value::String(string),
-                    raw_value: vec![string],
+                    letters: Cow::Borrowed(letters),
+                    value: real_or_raw(Cow::Owned(text)),
+                    raw_value: sign
+                        .into_iter()
+                        .chain([lhs, comma, rhs])
+                        .map(Cow::Borrowed)
+                        .collect(),
                     span: Span(left, right)
                 }
             };
 
-        rule line_component() -> LineComponent<'input>
-            = field:field() { LineComponent { field: Some(field), ..Default::default() } }
+        /// Letters not followed by anything that could start a value, so `X-` and `X.` are still errors
+        rule flag() -> Flag<'input> = pos:position!() letters:letters() !['0'..='9' | '.' | '-' | '"' | ','] {?
+            if ["G", "M", "N"].iter().any(|l| letters.eq_ignore_ascii_case(l)) {
+                Err("a value for a command or line number")
+            } else {
+                Ok(Flag {
+                    letters: Cow::Borrowed(letters),
+                    pos,
+                })
+            }
+        };
+
+        /// A `G` or `M` field, after which value-less letters are taken as [Flag]s
+        rule command_component(opts: &ParseOptions) -> LineComponent<'input> = component:line_component(opts, false) {?
+            match &component.field {
+                Some(field) if ["G", "M"].iter().any(|l| field.letters.eq_ignore_ascii_case(l)) => Ok(component),
+                _ => Err("command"),
+            }
+        };
+
+        rule line_components(opts: &ParseOptions) -> Vec<LineComponent<'input>>
+            = before:(!command_component(opts) c:line_component(opts, false) { c })*
+                after:(command:quiet!{ command_component(opts) } rest:line_component(opts, true)* { (command, rest) })? {
+                let mut components = before;
+                if let Some((command, rest)) = after {
+                    components.push(command);
+                    components.extend(rest);
+                }
+                components
+            };
+
+        rule line_component(opts: &ParseOptions, allow_flags: bool) -> LineComponent<'input>
+            // Tried before plain fields, which would stop short at the comma
+            = field:quiet!{ enabled((opts.accept_comma_decimal)) f:comma_decimal_field() { f } } {
+                LineComponent { field: Some(field), ..Default::default() }
+            }
+            / field:field() { LineComponent { field: Some(field), ..Default::default() } }
+            / flag:quiet!{ enabled(allow_flags) f:flag() { f } } { LineComponent { flag: Some(flag), ..Default::default() } }
             / whitespace:whitespace() { LineComponent { whitespace: Some(whitespace), ..Default::default() } }
-            / inline_comment:inline_comment() { LineComponent { inline_comment: Some(inline_comment), ..Default::default() } };
+            / inline_comment:inline_comment() {?
+                opts.check_inline_comment_depth(&inline_comment.inner)?;
+                Ok(LineComponent { inline_comment: Some(inline_comment), ..Default::default() })
+            }
+            // Quiet so that disabled syntax never shows up in the expected tokens of an error
+            / realtime_command:quiet!{ enabled((opts.allow_grbl_system_commands)) r:realtime_command() { r } } {
+                LineComponent { realtime_command: Some(realtime_command), ..Default::default() }
+            };
 
-        pub rule line() -> Line<'input> =
+        pub rule line() -> Line<'input> = line_with_options((&ParseOptions::default()));
+
+        rule line_with_options(opts: &ParseOptions) -> Line<'input> =
                 left:position!()
+                    system_command:quiet!{ enabled((opts.allow_grbl_system_commands)) s:system_command() { s } }
+                right:position!() {
+            Line {
+                line_components: vec![],
+                checksum: None,
+                comment: None,
+                system_command: Some(system_command),
+                extended_command: None,
+                meta_command: None,
+                span: Span(left, right)
+            }
+        }
+            / left:position!()
+                    extended_command:quiet!{ enabled((opts.allow_extended_commands)) e:extended_command() { e } }
+                    whitespace:whitespace()?
+                    comment:comment()?
+                right:position!() {
+            Line {
+                line_components: whitespace
+                    .map(|whitespace| LineComponent { whitespace: Some(whitespace), ..Default::default() })
+                    .into_iter()
+                    .collect(),
+                checksum: None,
+                comment,
+                system_command: None,
+                extended_command: Some(extended_command),
+                meta_command: None,
+                span: Span(left, right)
+            }
+        }
+            / left:position!()
+                    meta_command:quiet!{ enabled((opts.allow_rrf_meta)) m:meta_command() { m } }
+                    whitespace:whitespace()?
+                    comment:comment()?
+                right:position!() {
+            Line {
+                line_components: whitespace
+                    .map(|whitespace| LineComponent { whitespace: Some(whitespace), ..Default::default() })
+                    .into_iter()
+                    .collect(),
+                checksum: None,
+                comment,
+                system_command: None,
+                extended_command: None,
+                meta_command: Some(meta_command),
+                span: Span(left, right)
+            }
+        }
+            / left:position!()
                      // Hacky way of imitating lalrpop following https://github.com/kevinmehall/rust-peg/blob/master/peg-macros/grammar.rustpeg#L90
-                    line_components:line_component()*
+                    line_components:line_components(opts)
                     checksum:checksum()?
                     comment:comment()?
                 right:position!() {
@@ -128,38 +282,95 @@ peg::parser! {
                 line_components,
                 checksum,
                 comment,
+                system_command: None,
+                extended_command: None,
+                meta_command: None,
                 span: Span(left, right)
             }
         };
 
+        /// Succeeds without consuming anything if the line starting here is no longer than `max` bytes
+        rule line_length_within(max: Option<usize>)
+            = quiet!{ enabled((max.is_none())) }
+            / quiet!{ &(text:$([^ '\r' | '\n']*) {? if text.len() <= max.unwrap_or(usize::MAX) { Ok(()) } else { Err("") } }) }
+            / expected!("line no longer than max_line_length");
+
+        /// A line, checked against the limits in [ParseOptions]
+        rule limited_line(opts: &ParseOptions) -> Line<'input>
+            = line_length_within((opts.max_line_length)) line:line_with_options(opts) { line };
+
+        /// Succeeds without consuming anything if another line may follow the `parsed` ones
+        /// without going over `max`, so a long file fails at the first line past the limit
+        rule line_count_within(max: Option<usize>, parsed: usize)
+            = quiet!{ enabled((parsed < max.unwrap_or(usize::MAX))) }
+            / quiet!{ !([^ '\r' | '\n']* newline()) }
+            / expected!("file with no more lines than max_lines");
+
+        rule byte_order_mark() -> &'input str = $("\u{feff}");
+
         /// Parse a GCode file
-        pub rule file_parser() -> File<'input>
-            = left:position!() start_percent:percent() lines:(a:line() b:newline() { (a, b) })* last_line:line() end_percent:percent() right:position!() {
-                File {
-                    start_percent: true,
+        pub rule file_parser() -> File<'input> = file_with_options((&ParseOptions::default()));
+
+        /// Parse a GCode file, with optional syntax enabled by [ParseOptions]
+        pub rule file_with_options(opts: &ParseOptions) -> File<'input>
+            = left:position!() bom:byte_order_mark()? file:percent_section(opts) {?
+                let file = File {
+                    byte_order_mark: bom.is_some(),
+                    span: Span(left, file.span.1),
+                    ..file
+                };
+                opts.check_line_count(file.iter().count())?;
+                Ok(file)
+            }
+            / left:position!() bom:byte_order_mark()? lines:(a:limited_line(opts) b:newline() { (a, b) })*<,{opts.max_lines.unwrap_or(usize::MAX)}> line_count_within((opts.max_lines), (lines.len())) last_line:limited_line(opts) right:position!() {?
+                let file = File {
+                    byte_order_mark: bom.is_some(),
+                    start_percent: false,
                     lines,
-                    last_line: if last_line.line_components.is_empty() && last_line.checksum.is_none() && last_line.comment.is_none() {
+                    last_line: if last_line.line_components.is_empty() && last_line.checksum.is_none() && last_line.comment.is_none() && last_line.system_command.is_none() && last_line.extended_command.is_none() && last_line.meta_command.is_none() {
                         None
                     } else {
                         Some(last_line)
                     },
-                    end_percent: true,
-                    span: Span(left, right)
-                }
-            }
-            / left:position!() lines:(a:line() b:newline() { (a, b) })* last_line:line() right:position!() {
+                    end_percent: false,
+                    span: Span(left, right),
+                    source: None,
+                };
+                opts.check_line_count(file.iter().count())?;
+                Ok(file)
+            };
+
+        /// A program wrapped in `%` delimiters
+        rule percent_section(opts: &ParseOptions) -> File<'input>
+            = left:position!() percent() lines:(a:limited_line(opts) b:newline() { (a, b) })*<,{opts.max_lines.unwrap_or(usize::MAX)}> line_count_within((opts.max_lines), (lines.len())) last_line:limited_line(opts) percent() right:position!() {
                 File {
-                    start_percent: false,
+                    byte_order_mark: false,
+                    start_percent: true,
                     lines,
-                    last_line: if last_line.line_components.is_empty() && last_line.checksum.is_none() && last_line.comment.is_none() {
+                    last_line: if last_line.line_components.is_empty() && last_line.checksum.is_none() && last_line.comment.is_none() && last_line.system_command.is_none() && last_line.extended_command.is_none() && last_line.meta_command.is_none() {
                         None
                     } else {
                         Some(last_line)
                     },
-                    end_percent: false,
-                    span: Span(left, right)
+                    end_percent: true,
+                    span: Span(left, right),
+                    source: None,
                 }
-            };
+            }
+
+        /// Blank lines between programs
+        rule section_gap() = quiet!{ ([' ' | '\t']* ("\r\n" / "\r" / "\n"))+ [' ' | '\t']* }
+
+        /// Parse a GCode file holding `%` delimited programs one after another,
+        /// or a single program without delimiters
+        pub rule multi_file_parser() -> Vec<File<'input>>
+            = left:position!() bom:byte_order_mark()? files:percent_section((&ParseOptions::default())) ++ section_gap() section_gap()? {
+                let mut files = files;
+                files[0].byte_order_mark = bom.is_some();
+                files[0].span.0 = left;
+                files
+            }
+            / file:file_parser() { vec![file] }
 
         /// The snippet parser is identical to the [file_parser], but it does not allow a leading and trailing percent symbol
         pub rule snippet_parser() -> Snippet<'input> = left:position!() lines:(a:line() b:newline() { (a, b) })* last_line:line() right:position!() {
@@ -1,23 +1,73 @@
 use super::ast::{Span, Spanned};
 use num_rational::Ratio;
+use std::borrow::Cow;
 use std::cmp::PartialEq;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "float-values"), derive(Eq))]
 /// ASCII letter(s) followed by a [Value]
+///
+/// The [Value] is the semantic form of the field, so `X0`, `X-0`, and `X0.0` all have a value of zero.
+/// The syntactic form is kept as well: see [raw_text](Self::raw_text) and [raw_segments](Self::raw_segments).
 pub struct Field<'input> {
-    pub(crate) letters: &'input str,
+    pub(crate) letters: Cow<'input, str>,
     pub(crate) value: Value<'input>,
-    pub(crate) raw_value: Vec<&'input str>,
+    pub(crate) raw_value: Vec<Cow<'input, str>>,
     pub(crate) span: Span,
 }
 
 impl<'input> Field<'input> {
+    /// Replace the value of the field.
+    ///
+    /// The raw text of the value is regenerated the same way the emitter would write it,
+    /// so [iter_bytes](Self::iter_bytes) and checksums reflect the edit.
+    /// The end of the field's span moves with the new length,
+    /// but the spans of any tokens after it are left as they were.
+    pub fn set_value(&mut self, value: Value<'input>) {
+        let raw = crate::emit::Value::from(&value).to_string();
+        self.span.1 = self.span.0 + self.letters.len() + raw.len();
+        self.raw_value = vec![Cow::Owned(raw)];
+        self.value = value;
+    }
+
+    /// The value of the field exactly as it was written, without the letters.
+    ///
+    /// Unlike [Value], this tells apart spellings of the same number like `-0`, `0`, and `0.0`.
+    pub fn raw_text(&self) -> String {
+        self.raw_value.concat()
+    }
+
+    /// The pieces that make up [raw_text](Self::raw_text), in the order they were written.
+    ///
+    /// For a number, these are the sign, integer part, decimal point, and fractional part
+    /// that are present in the input.
+    /// After [set_value](Self::set_value), this is a single segment as the emitter would write it.
+    pub fn raw_segments(&self) -> &[Cow<'input, str>] {
+        &self.raw_value
+    }
+
+    /// The value of the field as a length in the given units, if it is a number.
+    #[cfg(feature = "uom")]
+    pub fn value_as_length(&self, units: crate::interpret::Units) -> Option<uom::si::f64::Length> {
+        crate::emit::Value::from(&self.value)
+            .as_f64()
+            .map(|value| units.length(value))
+    }
+
     /// Iterate over [u8] in a [Field].
     pub fn iter_bytes(&'input self) -> impl Iterator<Item = &'input u8> {
         self.letters
             .as_bytes()
             .iter()
-            .chain(self.raw_value.iter().map(|s| s.as_bytes().iter()).flatten())
+            .chain(self.raw_value.iter().flat_map(|s| s.as_bytes().iter()))
+    }
+
+    /// The bytes of [iter_bytes](Self::iter_bytes) as slices: the letters, then each raw segment.
+    pub(crate) fn byte_piece(&self, index: usize) -> Option<&[u8]> {
+        match index {
+            0 => Some(self.letters.as_bytes()),
+            _ => self.raw_value.get(index - 1).map(|s| s.as_bytes()),
+        }
     }
 }
 
@@ -27,7 +77,141 @@ impl<'input> Spanned for Field<'input> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// The number type of a parsed [Value::Rational].
+///
+/// This is a [`Ratio<i64>`] by default, and also when neither `decimal-values` nor `float-values`
+/// is enabled. With the `float-values` feature, it is an [f64] instead, which is faster to parse but inexact.
+#[cfg(not(feature = "float-values"))]
+pub type Real = Ratio<i64>;
+/// The number type of a parsed [Value::Rational].
+///
+/// This is an [f64] because the `float-values` feature is enabled.
+/// Without it, it is an exact [`Ratio<i64>`].
+#[cfg(feature = "float-values")]
+pub type Real = f64;
+
+/// Parse a signed number that may have a decimal point, like `-1.5`, `1.`, `.5` or `-3`.
+///
+/// The grammar has already checked the syntax.
+#[cfg(not(feature = "float-values"))]
+pub(crate) fn parse_real(text: &str) -> Result<Real, &'static str> {
+    let (neg, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let (lhs, rhs) = match digits.find('.') {
+        Some(dot) => (&digits[..dot], &digits[dot + 1..]),
+        None => (digits, ""),
+    };
+    let integer_part = if lhs.is_empty() {
+        0
+    } else {
+        lhs.parse::<i64>()
+            .map_err(|_| "integer part does not fit in an i64")?
+    };
+    let value = if rhs.is_empty() {
+        Ratio::from_integer(integer_part)
+    } else {
+        let (fractional_part, denominator) = rhs
+            .parse::<i64>()
+            .ok()
+            .zip(10i64.checked_pow(rhs.len() as u32))
+            .ok_or("fractional part does not fit in an i64")?;
+        let numerator = integer_part
+            .checked_mul(denominator)
+            .and_then(|n| n.checked_add(fractional_part))
+            .ok_or("number does not fit in a Ratio<i64>")?;
+        Ratio::new(numerator, denominator)
+    };
+    Ok(if neg { -value } else { value })
+}
+
+/// Parse a signed number that may have a decimal point, like `-1.5`, `1.`, `.5` or `-3`.
+///
+/// The grammar has already checked the syntax.
+#[cfg(feature = "float-values")]
+pub(crate) fn parse_real(text: &str) -> Result<Real, &'static str> {
+    text.parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite())
+        // Adding zero turns `-0` into `0`, so that it has the same value as it would as a Ratio
+        .map(|value| value + 0.0)
+        .ok_or("number does not fit in an f64")
+}
+
+/// The [Value] of a number as [parse_real] accepts it, or a [Value::Raw] if it doesn't fit in a [Real].
+pub(crate) fn real_or_raw(text: Cow<'_, str>) -> Value<'_> {
+    match parse_real(&text) {
+        Ok(real) => Value::Rational(real),
+        Err(_) => Value::Raw(text),
+    }
+}
+
+/// Split a number as [parse_real] accepts it into the segments kept as its raw value:
+/// the sign, integer part, decimal point, and fractional part that are present.
+///
+/// A decimal point is always followed by a fractional part, which is empty for a number like `1.`.
+pub(crate) fn number_segments(text: &str) -> Vec<Cow<'_, str>> {
+    let mut segments = vec![];
+    let digits = match text.strip_prefix('-') {
+        Some(digits) => {
+            segments.push(Cow::Borrowed("-"));
+            digits
+        }
+        None => text,
+    };
+    match digits.find('.') {
+        Some(dot) => {
+            if dot > 0 {
+                segments.push(Cow::Borrowed(&digits[..dot]));
+            }
+            segments.push(Cow::Borrowed("."));
+            segments.push(Cow::Borrowed(&digits[dot + 1..]));
+        }
+        None => segments.push(Cow::Borrowed(digits)),
+    }
+    segments
+}
+
+/// A parsed [Real] as an exact fraction, for arithmetic that must not accumulate rounding errors.
+#[cfg(not(feature = "float-values"))]
+pub(crate) fn real_to_ratio(real: &Real) -> Option<Ratio<i64>> {
+    Some(*real)
+}
+
+/// A parsed [Real] as the fraction of its shortest decimal representation,
+/// for arithmetic that must not accumulate rounding errors.
+///
+/// This is exact for any number written with up to 15 significant digits.
+#[cfg(feature = "float-values")]
+pub(crate) fn real_to_ratio(real: &Real) -> Option<Ratio<i64>> {
+    crate::emit::Value::Float(*real).as_decimal()
+}
+
+/// The [Real] closest to a fraction, if it is in range.
+#[cfg(all(feature = "serde", not(feature = "float-values")))]
+pub(crate) fn real_from_ratio(ratio: Ratio<i64>) -> Option<Real> {
+    Some(ratio)
+}
+
+/// The [Real] closest to a fraction, if it is in range.
+#[cfg(all(feature = "serde", feature = "float-values"))]
+pub(crate) fn real_from_ratio(ratio: Ratio<i64>) -> Option<Real> {
+    use num::ToPrimitive;
+    ratio.to_f64()
+}
+
+/// The [Real] closest to `numer / denom`, for writing tests that hold for either number type.
+#[cfg(test)]
+pub(crate) fn real(numer: i64, denom: i64) -> Real {
+    #[cfg(not(feature = "float-values"))]
+    return Ratio::new(numer, denom);
+    #[cfg(feature = "float-values")]
+    return numer as f64 / denom as f64;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "float-values"), derive(Eq))]
 pub enum Value<'input> {
     /// A real number GCode value.
     ///
@@ -35,26 +219,45 @@ pub enum Value<'input> {
     /// that was converted to a string,
     /// it is parsed as a [Ratio<i64>] to
     /// ensure numerical stability.
-    Rational(Ratio<i64>),
-    /// An unsigned integer GCode value fitting in a [usize].
+    /// The `float-values` feature parses it as an [f64] instead (see [Real]),
+    /// while the raw text of the [Field] still keeps it exactly as written.
+    Rational(Real),
+    /// An unsigned integer GCode value fitting in a [u64], whatever the width of the target.
     /// For instance, this would be the 0 in G0.
-    Integer(usize),
+    ///
+    /// Larger integers are parsed as a [Value::Rational] instead, if they fit in a [Real],
+    /// and as a [Value::Raw] otherwise.
+    Integer(u64),
     /// A [string](str) GCode value.
     ///
     /// Delimiting quotes are included in the value
     /// and escaped quotes are NOT unescaped.
-    String(&'input str),
+    String(Cow<'input, str>),
+    /// A number too large for both [Value::Integer] and [Value::Rational], kept as text.
+    ///
+    /// This way a file with something like `N18446744073709551616` still parses,
+    /// even though the number can't be used as one.
+    Raw(Cow<'input, str>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Checksum {
     // Note for readers:
     // this is not stored as a str because any
-    // leading zeros do not affect the checksum
-    pub(crate) inner: u8,
+    // leading zeros do not affect the checksum.
+    //
+    // Checksums are a single byte, but any number of digits is accepted
+    // so that a bad checksum is reported by validation rather than failing the parse.
+    // Anything past u16::MAX is kept as u16::MAX, which is just as invalid.
+    pub(crate) inner: u16,
     pub(crate) span: Span,
 }
 
+/// The value of the digits of a checksum, or [u16::MAX] if there are too many of them.
+pub(crate) fn saturating_checksum(digits: &str) -> u16 {
+    digits.parse().unwrap_or(u16::MAX)
+}
+
 impl Spanned for Checksum {
     fn span(&self) -> Span {
         self.span
@@ -76,7 +279,7 @@ impl Spanned for Newline {
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// Any sequence of ASCII whitespace except for [Newline].
 pub struct Whitespace<'input> {
-    pub(crate) inner: &'input str,
+    pub(crate) inner: Cow<'input, str>,
     pub(crate) pos: usize,
 }
 
@@ -101,7 +304,7 @@ impl<'input> Spanned for Whitespace<'input> {
 /// Some machines/programs will display these comments
 /// as the GCode is executed.
 pub struct Comment<'input> {
-    pub(crate) inner: &'input str,
+    pub(crate) inner: Cow<'input, str>,
     pub(crate) pos: usize,
 }
 
@@ -124,7 +327,7 @@ impl<'input> Spanned for Comment<'input> {
 /// The parentheses are part of the inner representation.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InlineComment<'input> {
-    pub(crate) inner: &'input str,
+    pub(crate) inner: Cow<'input, str>,
     pub(crate) pos: usize,
 }
 
@@ -140,21 +343,273 @@ impl<'input> Spanned for InlineComment<'input> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Default)]
+/// A GRBL system command: a line starting with a dollar sign `$`, like `$H` or `$J=G91X10F500`.
+///
+/// Only parsed when [ParseOptions::allow_grbl_system_commands](super::ParseOptions::allow_grbl_system_commands) is set.
+/// The dollar sign is part of the inner representation, which runs to the end of the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemCommand<'input> {
+    pub(crate) inner: Cow<'input, str>,
+    pub(crate) pos: usize,
+}
+
+impl<'input> SystemCommand<'input> {
+    /// The command exactly as written, starting with the dollar sign.
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    /// Parse the GCode of a jog command (`$J=...`).
+    ///
+    /// This is [None] for any other command.
+    /// Spans in the snippet are relative to the start of the GCode, not the file.
+    pub fn jog(&self) -> Option<Result<super::ast::Snippet<'_>, super::ParseError>> {
+        self.inner
+            .strip_prefix("$J=")
+            .map(super::parser::g_code::snippet_parser)
+    }
+
+    pub(crate) fn byte_piece(&self, index: usize) -> Option<&[u8]> {
+        Some(self.inner.as_bytes()).filter(|_| index == 0)
+    }
+
+    pub fn iter_bytes(&'input self) -> impl Iterator<Item = &'input u8> {
+        self.inner.as_bytes().iter()
+    }
+}
+
+impl<'input> Spanned for SystemCommand<'input> {
+    fn span(&self) -> Span {
+        Span(self.pos, self.pos + self.inner.len())
+    }
+}
+
+/// A Klipper extended command, like `SET_PRESSURE_ADVANCE ADVANCE=0.05 EXTRUDER=extruder`:
+/// an uppercase name containing an underscore, followed by `KEY=VALUE` parameters.
+///
+/// Only parsed when [ParseOptions::allow_extended_commands](super::ParseOptions::allow_extended_commands) is set.
+/// Values are kept as they were written, including the quotes of a quoted value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedCommand<'input> {
+    pub(crate) name: Cow<'input, str>,
+    pub(crate) params: Vec<ExtendedParam<'input>>,
+    pub(crate) span: Span,
+}
+
+/// A `KEY=VALUE` parameter of an [ExtendedCommand], with the whitespace before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExtendedParam<'input> {
+    pub(crate) whitespace: Cow<'input, str>,
+    pub(crate) key: Cow<'input, str>,
+    pub(crate) value: Cow<'input, str>,
+}
+
+impl<'input> ExtendedCommand<'input> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Iterate over `(key, value)` parameters in the order they were written.
+    pub fn iter_params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params
+            .iter()
+            .map(|p| (p.key.as_ref(), p.value.as_ref()))
+    }
+
+    /// The value of the first parameter with this key.
+    ///
+    /// Like Klipper, keys are matched case-insensitively.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.iter_params()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    pub fn iter_bytes(&'input self) -> impl Iterator<Item = &'input u8> {
+        self.name
+            .as_bytes()
+            .iter()
+            .chain(self.params.iter().flat_map(|p| {
+                p.whitespace
+                    .as_bytes()
+                    .iter()
+                    .chain(p.key.as_bytes())
+                    .chain(b"=")
+                    .chain(p.value.as_bytes())
+            }))
+    }
+
+    /// The bytes of [iter_bytes](Self::iter_bytes) as slices: the name,
+    /// then the whitespace, key, `=`, and value of each parameter.
+    pub(crate) fn byte_piece(&self, index: usize) -> Option<&[u8]> {
+        if index == 0 {
+            return Some(self.name.as_bytes());
+        }
+        let param = self.params.get((index - 1) / 4)?;
+        Some(match (index - 1) % 4 {
+            0 => param.whitespace.as_bytes(),
+            1 => param.key.as_bytes(),
+            2 => b"=",
+            _ => param.value.as_bytes(),
+        })
+    }
+}
+
+impl<'input> Spanned for ExtendedCommand<'input> {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// A RepRapFirmware meta command, like `if move.axes[0].homed` or `set var.x = 3`:
+/// a lowercase keyword followed by an expression, which is kept as raw text.
+///
+/// Only parsed when [ParseOptions::allow_rrf_meta](super::ParseOptions::allow_rrf_meta) is set.
+/// RepRapFirmware uses indentation to delimit the blocks of `if` and `while`,
+/// so the whitespace before the keyword is kept as part of the command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaCommand<'input> {
+    pub(crate) indent: Cow<'input, str>,
+    pub(crate) keyword: Cow<'input, str>,
+    /// Everything after the keyword, including the whitespace separating them
+    pub(crate) expression: Cow<'input, str>,
+    pub(crate) span: Span,
+}
+
+impl<'input> MetaCommand<'input> {
+    /// The keywords that start a meta command.
+    pub const KEYWORDS: [&'static str; 11] = [
+        "abort", "break", "continue", "echo", "elif", "else", "global", "if", "set", "var", "while",
+    ];
+
+    /// The spaces and tabs before the keyword.
+    pub fn indent(&self) -> &str {
+        &self.indent
+    }
+
+    pub fn keyword(&self) -> &str {
+        &self.keyword
+    }
+
+    /// The text of the expression, or of the rest of the command for keywords
+    /// like `set` and `echo`, without the whitespace separating it from the keyword.
+    pub fn expression_text(&self) -> &str {
+        self.expression.trim_start_matches([' ', '\t'])
+    }
+
+    pub fn iter_bytes(&'input self) -> impl Iterator<Item = &'input u8> {
+        self.indent
+            .as_bytes()
+            .iter()
+            .chain(self.keyword.as_bytes())
+            .chain(self.expression.as_bytes())
+    }
+
+    pub(crate) fn byte_piece(&self, index: usize) -> Option<&[u8]> {
+        [&self.indent, &self.keyword, &self.expression]
+            .get(index)
+            .map(|s| s.as_bytes())
+    }
+}
+
+impl<'input> Spanned for MetaCommand<'input> {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// A GRBL realtime command, which is a single character that can appear anywhere in the input:
+/// `?` (status report), `!` (feed hold), or `~` (cycle start).
+///
+/// Only parsed when [ParseOptions::allow_grbl_system_commands](super::ParseOptions::allow_grbl_system_commands) is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RealtimeCommand {
+    pub(crate) inner: u8,
+    pub(crate) pos: usize,
+}
+
+impl RealtimeCommand {
+    /// The character of the command.
+    pub fn as_char(&self) -> char {
+        char::from(self.inner)
+    }
+
+    pub fn iter_bytes(&self) -> impl Iterator<Item = &u8> {
+        std::iter::once(&self.inner)
+    }
+}
+
+impl Spanned for RealtimeCommand {
+    fn span(&self) -> Span {
+        Span(self.pos, self.pos + 1)
+    }
+}
+
+/// Letters without a value following a command, like the `X` and `Y` in `G28 X Y`.
+///
+/// Only parsed after a `G` or `M` field, so a stray letter elsewhere is still an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flag<'input> {
+    pub(crate) letters: Cow<'input, str>,
+    pub(crate) pos: usize,
+}
+
+impl<'input> Flag<'input> {
+    /// The letters of the flag, as they were written.
+    pub fn letters(&self) -> &str {
+        &self.letters
+    }
+
+    pub fn iter_bytes(&'input self) -> impl Iterator<Item = &'input u8> {
+        self.letters.as_bytes().iter()
+    }
+}
+
+impl<'input> Spanned for Flag<'input> {
+    fn span(&self) -> Span {
+        Span(self.pos, self.pos + self.letters.len())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(not(feature = "float-values"), derive(Eq))]
 /// An internal structure used to make writing the [peg] parser easier.
 pub struct LineComponent<'input> {
     pub(crate) field: Option<Field<'input>>,
     pub(crate) whitespace: Option<Whitespace<'input>>,
     pub(crate) inline_comment: Option<InlineComment<'input>>,
+    pub(crate) realtime_command: Option<RealtimeCommand>,
+    pub(crate) flag: Option<Flag<'input>>,
 }
 
 impl<'input> LineComponent<'input> {
     pub fn iter_bytes(&'input self) -> impl Iterator<Item = &'input u8> + 'input {
         self.field
             .iter()
-            .map(|f| f.iter_bytes())
-            .flatten()
-            .chain(self.whitespace.iter().map(|w| w.iter_bytes()).flatten())
-            .chain(self.inline_comment.iter().map(|i| i.iter_bytes()).flatten())
+            .flat_map(|f| f.iter_bytes())
+            .chain(self.whitespace.iter().flat_map(|w| w.iter_bytes()))
+            .chain(self.inline_comment.iter().flat_map(|i| i.iter_bytes()))
+            .chain(self.realtime_command.iter().flat_map(|r| r.iter_bytes()))
+            .chain(self.flag.iter().flat_map(|f| f.iter_bytes()))
+    }
+
+    /// The bytes of [iter_bytes](Self::iter_bytes) as slices, in the same order.
+    pub(crate) fn byte_piece(&self, index: usize) -> Option<&[u8]> {
+        let field_pieces = self.field.as_ref().map_or(0, |f| 1 + f.raw_value.len());
+        if index < field_pieces {
+            return self.field.as_ref()?.byte_piece(index);
+        }
+        [
+            self.whitespace.as_ref().map(|w| w.inner.as_bytes()),
+            self.inline_comment.as_ref().map(|i| i.inner.as_bytes()),
+            self.realtime_command
+                .as_ref()
+                .map(|r| std::slice::from_ref(&r.inner)),
+            self.flag.as_ref().map(|f| f.letters.as_bytes()),
+        ]
+        .iter()
+        .flatten()
+        .nth(index - field_pieces)
+        .copied()
     }
 }
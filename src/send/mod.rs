@@ -0,0 +1,4 @@
+//! Talking to machine firmware over a serial link.
+//!
+//! [response](crate::send::response) parses what firmware writes back, and can be used on its own by monitoring tools.
+pub mod response;
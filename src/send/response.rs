@@ -0,0 +1,666 @@
+//! Recognizing the lines firmware writes back to a sender.
+//!
+//! Marlin, GRBL, and RepRapFirmware each acknowledge commands with `ok`,
+//! but differ in how they ask for a line to be sent again, report errors, and report status:
+//!
+//! ```
+//! use g_code::send::response::{parse, MachineState, Response};
+//!
+//! assert_eq!(parse("ok N12 P15 B3"), Response::Ok { line_number: Some(12), planner_free: Some(15), buffer_free: Some(3) });
+//! assert_eq!(parse("Resend: 12"), Response::Resend(12));
+//!
+//! match parse("<Run|MPos:10.000,2.500,0.000|FS:500,0>") {
+//!     Response::Status(status) => {
+//!         assert_eq!(status.state, MachineState::Run);
+//!         assert_eq!(status.machine_position, Some(vec![10., 2.5, 0.]));
+//!     }
+//!     other => panic!("not a status report: {:?}", other),
+//! }
+//! ```
+
+/// A line written back by firmware, as returned by [parse].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response<'a> {
+    /// `ok`: the command was accepted and the next one can be sent.
+    ///
+    /// Marlin's `ADVANCED_OK` adds the last line number received (`N`)
+    /// and the free space in the planner (`P`) and serial buffer (`B`).
+    /// Temperatures reported along with the `ok` are ignored.
+    Ok {
+        line_number: Option<u64>,
+        planner_free: Option<usize>,
+        buffer_free: Option<usize>,
+    },
+    /// `Resend: <n>` or `rs <n>`: the line numbered `n` was garbled and must be sent again,
+    /// along with every line after it.
+    Resend(u64),
+    /// `Error:<message>` from Marlin, or `error:<message>` from GRBL 0.9.
+    Error(&'a str),
+    /// `echo:busy: <reason>`: Marlin is still working on a command and has not forgotten about the sender.
+    Busy(&'a str),
+    /// Any other `echo:` message from Marlin.
+    Echo(&'a str),
+    /// `error:<code>` from GRBL 1.1: see [grbl_error_description].
+    GrblError(u8),
+    /// `ALARM:<code>` from GRBL: see [grbl_alarm_description].
+    GrblAlarm(u8),
+    /// A `<...>` status report from GRBL, sent in reply to `?`.
+    Status(StatusReport<'a>),
+    /// A `[...]` feedback message from GRBL, like `[MSG:Caution: Unlocked]`, without the brackets.
+    Feedback(&'a str),
+    /// A `{...}` JSON reply from RepRapFirmware, passed through as it is.
+    Json(&'a str),
+    /// Anything else, like start-up banners and temperature reports.
+    Other(&'a str),
+}
+
+/// Recognize a line written back by firmware.
+///
+/// Leading and trailing whitespace, including a line ending, is ignored.
+/// A line that looks like a response but doesn't parse as one, like a malformed status report,
+/// is [Response::Other].
+pub fn parse(line: &str) -> Response<'_> {
+    let line = line.trim();
+    if line == "ok" || line.starts_with("ok ") {
+        return parse_ok(&line[2..]);
+    }
+    if let Some(rest) = line
+        .strip_prefix("Resend:")
+        .or_else(|| line.strip_prefix("rs "))
+    {
+        return match rest.trim().parse::<u64>() {
+            Ok(line_number) => Response::Resend(line_number),
+            Err(_) => Response::Other(line),
+        };
+    }
+    if let Some(message) = line.strip_prefix("Error:") {
+        return Response::Error(message.trim());
+    }
+    if let Some(rest) = line.strip_prefix("error:") {
+        return match rest.trim().parse::<u8>() {
+            Ok(code) => Response::GrblError(code),
+            Err(_) => Response::Error(rest.trim()),
+        };
+    }
+    if let Some(rest) = line.strip_prefix("ALARM:") {
+        return match rest.trim().parse::<u8>() {
+            Ok(code) => Response::GrblAlarm(code),
+            Err(_) => Response::Other(line),
+        };
+    }
+    if let Some(message) = line.strip_prefix("echo:") {
+        return match message.strip_prefix("busy:") {
+            Some(reason) => Response::Busy(reason.trim()),
+            None => Response::Echo(message.trim()),
+        };
+    }
+    if let Some(body) = line.strip_prefix('<').and_then(|l| l.strip_suffix('>')) {
+        return match StatusReport::parse(body) {
+            Some(status) => Response::Status(status),
+            None => Response::Other(line),
+        };
+    }
+    if let Some(body) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+        return Response::Feedback(body);
+    }
+    if line.starts_with('{') && line.ends_with('}') {
+        return Response::Json(line);
+    }
+    Response::Other(line)
+}
+
+/// Parse what follows an `ok`, picking out Marlin's `ADVANCED_OK` counts.
+fn parse_ok(rest: &str) -> Response<'_> {
+    let (mut line_number, mut planner_free, mut buffer_free) = (None, None, None);
+    for word in rest.split_whitespace() {
+        if let Some(digits) = word.strip_prefix("N:").or_else(|| word.strip_prefix('N')) {
+            if let Ok(n) = digits.parse::<u64>() {
+                line_number = Some(n);
+            }
+            continue;
+        }
+        // Temperatures like `B:60.0` have a colon, while the buffer counts don't
+        let (target, digits) = if let Some(digits) = word.strip_prefix('P') {
+            (&mut planner_free, digits)
+        } else if let Some(digits) = word.strip_prefix('B') {
+            (&mut buffer_free, digits)
+        } else {
+            continue;
+        };
+        if let Ok(n) = digits.parse::<usize>() {
+            *target = Some(n);
+        }
+    }
+    Response::Ok {
+        line_number,
+        planner_free,
+        buffer_free,
+    }
+}
+
+/// The state of a GRBL machine, as given at the start of a [StatusReport].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineState<'a> {
+    Idle,
+    Run,
+    /// A feed hold, which is complete (`Hold:0`) or still decelerating (`Hold:1`).
+    /// GRBL 0.9 gives no substate.
+    Hold(Option<u8>),
+    Jog,
+    Alarm,
+    /// The safety door is open or closing, with a substate from `0` (ready to resume) to `3` (restoring).
+    /// GRBL 0.9 gives no substate.
+    Door(Option<u8>),
+    Check,
+    Home,
+    Sleep,
+    /// A state this parser doesn't know about, as it was written.
+    Other(&'a str),
+}
+
+impl<'a> MachineState<'a> {
+    fn parse(text: &'a str) -> Self {
+        let (name, substate) = match text.split_once(':') {
+            Some((name, substate)) => (name, substate.parse::<u8>().ok()),
+            None => (text, None),
+        };
+        match name {
+            "Idle" => Self::Idle,
+            "Run" => Self::Run,
+            "Hold" => Self::Hold(substate),
+            "Jog" => Self::Jog,
+            "Alarm" => Self::Alarm,
+            "Door" => Self::Door(substate),
+            "Check" => Self::Check,
+            "Home" => Self::Home,
+            "Sleep" => Self::Sleep,
+            _ => Self::Other(text),
+        }
+    }
+}
+
+/// How much room GRBL has for more commands, from the `Bf` field of a [StatusReport].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferState {
+    /// Free blocks in the planner buffer.
+    pub planner_blocks_free: usize,
+    /// Free bytes in the serial receive buffer.
+    pub rx_bytes_free: usize,
+}
+
+/// Override percentages, from the `Ov` field of a [StatusReport].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overrides {
+    pub feed: u16,
+    pub rapid: u16,
+    pub spindle: u16,
+}
+
+/// A GRBL status report, like `<Idle|MPos:0.000,0.000,0.000|FS:0,0>`.
+///
+/// Both the GRBL 1.1 format with `|` between fields and the GRBL 0.9 format with `,` between fields are understood.
+/// GRBL only sends the fields that are enabled by its `$10` setting and that have changed recently,
+/// so most of them are optional.
+/// Positions have as many axes as the firmware reports, which is usually three.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusReport<'a> {
+    pub state: MachineState<'a>,
+    /// `MPos`: the position in machine coordinates.
+    pub machine_position: Option<Vec<f64>>,
+    /// `WPos`: the position in work coordinates.
+    pub work_position: Option<Vec<f64>>,
+    /// `WCO`: the work coordinate offset, which is only sent every few reports.
+    pub work_offset: Option<Vec<f64>>,
+    /// `Bf`
+    pub buffer: Option<BufferState>,
+    /// `Ln`: the line number being executed.
+    pub line_number: Option<u64>,
+    /// `F` or the first value of `FS`: the current feed rate.
+    pub feed_rate: Option<f64>,
+    /// The second value of `FS`: the current spindle speed.
+    pub spindle_speed: Option<f64>,
+    /// `Pn`: the letters of the input pins that are triggered, like `XYZP` for limit switches and the probe.
+    pub pins: Option<&'a str>,
+    /// `Ov`
+    pub overrides: Option<Overrides>,
+    /// `A`: the letters of the accessories that are on, like `SFM` for spindle, flood and mist coolant.
+    pub accessories: Option<&'a str>,
+    /// Fields this parser doesn't know about, as their name and value.
+    pub other: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> StatusReport<'a> {
+    /// Parse the text between the angle brackets of a status report.
+    pub fn parse(body: &'a str) -> Option<Self> {
+        let mut report = StatusReport {
+            state: MachineState::Idle,
+            machine_position: None,
+            work_position: None,
+            work_offset: None,
+            buffer: None,
+            line_number: None,
+            feed_rate: None,
+            spindle_speed: None,
+            pins: None,
+            overrides: None,
+            accessories: None,
+            other: vec![],
+        };
+        let (state, fields) = if body.contains('|') {
+            let mut fields = body.split('|');
+            (
+                fields.next()?,
+                fields.map(|field| field.split_once(':')).collect(),
+            )
+        } else {
+            let (state, rest) = body.split_once(',').unwrap_or((body, ""));
+            (state, legacy_fields(rest))
+        };
+        if state.is_empty() {
+            return None;
+        }
+        report.state = MachineState::parse(state);
+        for field in fields {
+            let (name, value) = field?;
+            match name {
+                "MPos" => report.machine_position = Some(numbers(value)?),
+                "WPos" => report.work_position = Some(numbers(value)?),
+                "WCO" => report.work_offset = Some(numbers(value)?),
+                "Bf" => {
+                    let (planner, rx) = value.split_once(',')?;
+                    report.buffer = Some(BufferState {
+                        planner_blocks_free: planner.parse().ok()?,
+                        rx_bytes_free: rx.parse().ok()?,
+                    });
+                }
+                "Ln" => report.line_number = Some(value.parse().ok()?),
+                "F" => report.feed_rate = Some(value.parse().ok()?),
+                "FS" => {
+                    let (feed, speed) = value.split_once(',')?;
+                    report.feed_rate = Some(feed.parse().ok()?);
+                    report.spindle_speed = Some(speed.parse().ok()?);
+                }
+                "Pn" => report.pins = Some(value),
+                "Ov" => {
+                    let percentages = value
+                        .split(',')
+                        .map(|n| n.parse().ok())
+                        .collect::<Option<Vec<u16>>>()?;
+                    if let [feed, rapid, spindle] = percentages[..] {
+                        report.overrides = Some(Overrides {
+                            feed,
+                            rapid,
+                            spindle,
+                        });
+                    } else {
+                        return None;
+                    }
+                }
+                "A" => report.accessories = Some(value),
+                _ => report.other.push((name, value)),
+            }
+        }
+        Some(report)
+    }
+
+    /// The position in machine coordinates, worked out from the work position and offset if it wasn't given.
+    pub fn machine_position(&self) -> Option<Vec<f64>> {
+        self.machine_position
+            .clone()
+            .or_else(|| add_offset(self.work_position.as_ref()?, self.work_offset.as_ref()?, 1.))
+    }
+
+    /// The position in work coordinates, worked out from the machine position and offset if it wasn't given.
+    ///
+    /// GRBL 1.1 only sends the offset every few reports,
+    /// so a monitor should keep the last one it saw in [StatusReport::work_offset] before calling this.
+    pub fn work_position(&self) -> Option<Vec<f64>> {
+        self.work_position.clone().or_else(|| {
+            add_offset(
+                self.machine_position.as_ref()?,
+                self.work_offset.as_ref()?,
+                -1.,
+            )
+        })
+    }
+}
+
+/// Group the comma-separated fields of a GRBL 0.9 status report by name,
+/// where a field's values run up to the next item with a colon.
+fn legacy_fields(rest: &str) -> Vec<Option<(&str, &str)>> {
+    if rest.is_empty() {
+        return vec![];
+    }
+    let mut fields = vec![];
+    let mut current: Option<(&str, usize, usize)> = None;
+    let mut pos = 0;
+    for item in rest.split(',') {
+        let start = pos;
+        pos += item.len() + 1;
+        match (item.split_once(':'), current.as_mut()) {
+            (Some((name, _)), _) => {
+                if let Some((name, start, end)) = current {
+                    fields.push(Some((name, &rest[start..end])));
+                }
+                current = Some((name, start + name.len() + 1, start + item.len()));
+            }
+            (None, Some((_, _, end))) => *end = start + item.len(),
+            (None, None) => fields.push(None),
+        }
+    }
+    if let Some((name, start, end)) = current {
+        fields.push(Some((name, &rest[start..end])));
+    }
+    fields
+}
+
+fn numbers(value: &str) -> Option<Vec<f64>> {
+    value.split(',').map(|n| n.parse().ok()).collect()
+}
+
+fn add_offset(position: &[f64], offset: &[f64], sign: f64) -> Option<Vec<f64>> {
+    if position.len() != offset.len() {
+        return None;
+    }
+    Some(
+        position
+            .iter()
+            .zip(offset.iter())
+            .map(|(position, offset)| position + sign * offset)
+            .collect(),
+    )
+}
+
+/// What a GRBL 1.1 `error:<code>` means, if the code is a known one.
+pub fn grbl_error_description(code: u8) -> Option<&'static str> {
+    Some(match code {
+        1 => "G-code words consist of a letter and a value. Letter was not found.",
+        2 => "Numeric value format is not valid or missing an expected value.",
+        3 => "Grbl '$' system command was not recognized or supported.",
+        4 => "Negative value received for an expected positive value.",
+        5 => "Homing cycle is not enabled via settings.",
+        6 => "Minimum step pulse time must be greater than 3usec.",
+        7 => "EEPROM read failed. Reset and restored to default values.",
+        8 => "Grbl '$' command cannot be used unless Grbl is IDLE.",
+        9 => "G-code locked out during alarm or jog state.",
+        10 => "Soft limits cannot be enabled without homing also enabled.",
+        11 => "Max characters per line exceeded. Line was not processed and executed.",
+        12 => "Grbl '$' setting value exceeds the maximum step rate supported.",
+        13 => "Safety door detected as opened and door state initiated.",
+        14 => "Build info or startup line exceeded EEPROM line length limit.",
+        15 => "Jog target exceeds machine travel. Command ignored.",
+        16 => "Jog command with no '=' or contains prohibited g-code.",
+        17 => "Laser mode requires PWM output.",
+        20 => "Unsupported or invalid g-code command found in block.",
+        21 => "More than one g-code command from same modal group found in block.",
+        22 => "Feed rate has not yet been set or is undefined.",
+        23 => "G-code command in block requires an integer value.",
+        24 => "Two G-code commands that both require the use of the XYZ axis words were detected in the block.",
+        25 => "A G-code word was repeated in the block.",
+        26 => "A G-code command implicitly or explicitly requires XYZ axis words in the block, but none were detected.",
+        27 => "N line number value is not within the valid range of 1 - 9,999,999.",
+        28 => "A G-code command was sent, but is missing some required P or L value words in the line.",
+        29 => "Grbl supports six work coordinate systems G54-G59. G59.1, G59.2, and G59.3 are not supported.",
+        30 => "The G53 G-code command requires either a G0 seek or G1 feed motion mode to be active.",
+        31 => "There are unused axis words in the block and G80 motion mode cancel is active.",
+        32 => "A G2 or G3 arc was commanded but there are no XYZ axis words in the selected plane to trace the arc.",
+        33 => "The motion command has an invalid target.",
+        34 => "A G2 or G3 arc, traced with the radius definition, had a mathematical error when computing the arc geometry.",
+        35 => "A G2 or G3 arc, traced with the offset definition, is missing the IJK offset word in the selected plane to trace the arc.",
+        36 => "There are unused, leftover G-code words that aren't used by any command in the block.",
+        37 => "The G43.1 dynamic tool length offset command cannot apply an offset to an axis other than its configured axis.",
+        38 => "Tool number greater than max supported value.",
+        _ => return None,
+    })
+}
+
+/// What a GRBL `ALARM:<code>` means, if the code is a known one.
+pub fn grbl_alarm_description(code: u8) -> Option<&'static str> {
+    Some(match code {
+        1 => "Hard limit triggered. Machine position is likely lost due to sudden and immediate halt.",
+        2 => "G-code motion target exceeds machine travel. Machine position safely retained.",
+        3 => "Reset while in motion. Grbl cannot guarantee position. Lost steps are likely.",
+        4 => "Probe fail. The probe is not in the expected initial state before starting probe cycle.",
+        5 => "Probe fail. Probe did not contact the workpiece within the programmed travel.",
+        6 => "Homing fail. Reset during active homing cycle.",
+        7 => "Homing fail. Safety door was opened during active homing cycle.",
+        8 => "Homing fail. Cycle failed to clear limit switch when pulling off.",
+        9 => "Homing fail. Could not find limit switch within search distance.",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn ok(
+        line_number: Option<u64>,
+        planner_free: Option<usize>,
+        buffer_free: Option<usize>,
+    ) -> Response<'static> {
+        Response::Ok {
+            line_number,
+            planner_free,
+            buffer_free,
+        }
+    }
+
+    #[test]
+    fn marlin_responses_are_recognized() {
+        let cases = [
+            ("ok", ok(None, None, None)),
+            ("ok\r\n", ok(None, None, None)),
+            ("ok N10 P15 B3", ok(Some(10), Some(15), Some(3))),
+            ("ok N:10", ok(Some(10), None, None)),
+            (
+                "ok T:210.0 /210.0 B:60.0 /60.0 @:0 B@:0",
+                ok(None, None, None),
+            ),
+            ("Resend: 5", Response::Resend(5)),
+            ("Resend:5", Response::Resend(5)),
+            ("rs 5", Response::Resend(5)),
+            ("Resend: 4294967296", Response::Resend(1 << 32)),
+            ("ok N4294967297", ok(Some((1 << 32) + 1), None, None)),
+            (
+                "Error:Line Number is not Last Line Number+1, Last Line: 4",
+                Response::Error("Line Number is not Last Line Number+1, Last Line: 4"),
+            ),
+            (
+                "Error:checksum mismatch, Last Line: 4",
+                Response::Error("checksum mismatch, Last Line: 4"),
+            ),
+            ("echo:busy: processing", Response::Busy("processing")),
+            (
+                "echo:busy: paused for user",
+                Response::Busy("paused for user"),
+            ),
+            (
+                "echo:Unknown command: \"G9999\"",
+                Response::Echo("Unknown command: \"G9999\""),
+            ),
+            ("okay", Response::Other("okay")),
+            ("Resend: soon", Response::Other("Resend: soon")),
+            (
+                "T:21.3 /0.0 B:22.0 /0.0 @:0 B@:0",
+                Response::Other("T:21.3 /0.0 B:22.0 /0.0 @:0 B@:0"),
+            ),
+            ("start", Response::Other("start")),
+        ];
+        for (line, expected) in cases.iter() {
+            assert_eq!(&parse(line), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn grbl_responses_are_recognized() {
+        let cases = [
+            ("ok", ok(None, None, None)),
+            ("error:20", Response::GrblError(20)),
+            (
+                "error: Bad number format",
+                Response::Error("Bad number format"),
+            ),
+            ("ALARM:2", Response::GrblAlarm(2)),
+            (
+                "[MSG:Caution: Unlocked]",
+                Response::Feedback("MSG:Caution: Unlocked"),
+            ),
+            (
+                "[GC:G0 G54 G17 G21 G90 G94 M5 M9 T0 F0 S0]",
+                Response::Feedback("GC:G0 G54 G17 G21 G90 G94 M5 M9 T0 F0 S0"),
+            ),
+            (
+                "Grbl 1.1h ['$' for help]",
+                Response::Other("Grbl 1.1h ['$' for help]"),
+            ),
+            (
+                "<Idle|MPos:1.000,abc,0.000>",
+                Response::Other("<Idle|MPos:1.000,abc,0.000>"),
+            ),
+            ("<>", Response::Other("<>")),
+        ];
+        for (line, expected) in cases.iter() {
+            assert_eq!(&parse(line), expected, "{:?}", line);
+        }
+        assert_eq!(
+            grbl_error_description(20),
+            Some("Unsupported or invalid g-code command found in block.")
+        );
+        assert_eq!(grbl_error_description(19), None);
+        assert!(grbl_alarm_description(2)
+            .unwrap()
+            .contains("exceeds machine travel"));
+    }
+
+    #[test]
+    fn rrf_json_replies_are_passed_through() {
+        let reply =
+            r#"{"status":"I","coords":{"axesHomed":[1,1,1],"xyz":[10.000,20.000,5.000]},"seq":3}"#;
+        assert_eq!(parse(reply), Response::Json(reply));
+        assert_eq!(parse(&format!("  {}\n", reply)), Response::Json(reply));
+    }
+
+    fn status(line: &str) -> StatusReport<'_> {
+        match parse(line) {
+            Response::Status(status) => status,
+            other => panic!("{:?} is not a status report: {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn grbl_1_1_status_reports_are_parsed() {
+        let report = status(
+            "<Run|MPos:10.000,-2.500,0.125|Bf:15,128|Ln:99|FS:500,8000|Pn:XP|Ov:100,50,120|A:SF>",
+        );
+        assert_eq!(
+            report,
+            StatusReport {
+                state: MachineState::Run,
+                machine_position: Some(vec![10., -2.5, 0.125]),
+                work_position: None,
+                work_offset: None,
+                buffer: Some(BufferState {
+                    planner_blocks_free: 15,
+                    rx_bytes_free: 128,
+                }),
+                line_number: Some(99),
+                feed_rate: Some(500.),
+                spindle_speed: Some(8000.),
+                pins: Some("XP"),
+                overrides: Some(Overrides {
+                    feed: 100,
+                    rapid: 50,
+                    spindle: 120,
+                }),
+                accessories: Some("SF"),
+                other: vec![],
+            }
+        );
+        assert_eq!(report.work_position(), None);
+
+        let report = status("<Hold:1|WPos:1.000,2.000,3.000|F:200|WCO:10.000,20.000,-5.000|Xyz:1>");
+        assert_eq!(report.state, MachineState::Hold(Some(1)));
+        assert_eq!(report.feed_rate, Some(200.));
+        assert_eq!(report.spindle_speed, None);
+        assert_eq!(report.machine_position(), Some(vec![11., 22., -2.]));
+        assert_eq!(report.work_position(), Some(vec![1., 2., 3.]));
+        assert_eq!(report.other, [("Xyz", "1")]);
+
+        assert_eq!(
+            status("<Door:2|MPos:0.000,0.000,0.000>").state,
+            MachineState::Door(Some(2))
+        );
+        assert_eq!(status("<Sleep|MPos:0,0,0>").state, MachineState::Sleep);
+        assert_eq!(
+            status("<Tool|MPos:0,0,0>").state,
+            MachineState::Other("Tool")
+        );
+        // Four axes
+        assert_eq!(
+            status("<Jog|MPos:1,2,3,4|WCO:1,1,1,1>").work_position(),
+            Some(vec![0., 1., 2., 3.])
+        );
+    }
+
+    #[test]
+    fn grbl_0_9_status_reports_are_parsed() {
+        let report = status("<Idle,MPos:5.529,0.560,7.000,WPos:1.529,-5.440,-0.000,Buf:0,RX:0>");
+        assert_eq!(report.state, MachineState::Idle);
+        assert_eq!(report.machine_position, Some(vec![5.529, 0.56, 7.]));
+        assert_eq!(report.work_position, Some(vec![1.529, -5.44, -0.]));
+        assert_eq!(report.other, [("Buf", "0"), ("RX", "0")]);
+        assert_eq!(status("<Hold>").state, MachineState::Hold(None));
+        assert_eq!(
+            parse("<Idle,5,MPos:1,2,3>"),
+            Response::Other("<Idle,5,MPos:1,2,3>")
+        );
+    }
+
+    #[test]
+    fn captured_session_is_classified() {
+        let log = include_str!("../../tests/grbl_responses.log");
+        let mut statuses = vec![];
+        let mut oks = 0;
+        for line in log.lines() {
+            match parse(line) {
+                Response::Ok { .. } => oks += 1,
+                Response::Status(status) => statuses.push(status),
+                Response::Other(other) => assert!(other.starts_with("Grbl"), "{:?}", other),
+                _ => {}
+            }
+        }
+        assert_eq!(oks, 6);
+        let states = statuses.iter().map(|s| s.state).collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                MachineState::Alarm,
+                MachineState::Home,
+                MachineState::Idle,
+                MachineState::Jog,
+                MachineState::Jog,
+                MachineState::Idle,
+                MachineState::Run,
+                MachineState::Hold(Some(0)),
+                MachineState::Idle,
+            ]
+        );
+        // The offset is only sent now and then, so a monitor carries the last one forward
+        let mut offset = None;
+        let work_positions = statuses
+            .into_iter()
+            .map(|mut status| {
+                if status.work_offset.is_some() {
+                    offset = status.work_offset.clone();
+                }
+                status.work_offset = offset.clone();
+                status.work_position()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(work_positions[0], Some(vec![0., 0., 0.]));
+        assert_eq!(work_positions[4], Some(vec![10., 0., 0.]));
+        assert_eq!(work_positions[8], Some(vec![5., -5.5, 5.]));
+    }
+}
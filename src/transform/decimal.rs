@@ -0,0 +1,144 @@
+//! Rewriting values written with a decimal comma, as accepted with
+//! [ParseOptions::accept_comma_decimal](crate::parse::ParseOptions::accept_comma_decimal),
+//! into the decimal points firmware expects.
+use super::LineRewriter;
+use crate::emit::Token;
+use crate::parse::ast::{File, Line};
+
+/// Emit a file with every decimal comma written as a decimal point, so `X12,5` becomes `X12.5`.
+///
+/// Emitted fields are always written with decimal points, so this is mostly [File::iter_emit_tokens].
+/// The difference is in checksums: a line whose checksum was valid over its commas
+/// gets the checksum of the line with points instead, so it stays valid however it is formatted.
+/// Any other checksum is kept as it was.
+///
+/// ```
+/// use g_code::emit::{format_gcode_fmt, FormatOptions};
+/// use g_code::parse::{file_parser_with_options, ParseOptions};
+/// use g_code::transform::decimal::normalize_decimal_separators;
+///
+/// let options = ParseOptions { accept_comma_decimal: true, ..Default::default() };
+/// let file = file_parser_with_options("G1 X12,5 Y-0,25", &options).unwrap();
+/// let mut gcode = String::new();
+/// format_gcode_fmt(&normalize_decimal_separators(&file), FormatOptions::default(), &mut gcode).unwrap();
+/// assert_eq!(gcode, "G1 X12.5 Y-0.25\n");
+/// ```
+pub fn normalize_decimal_separators<'input>(file: &File<'input>) -> Vec<Token<'input>> {
+    let mut rewriter = LineRewriter::new(file);
+    for (_, line) in rewriter.lines() {
+        let checksum = corrected_checksum(line);
+        rewriter.push_line(
+            line.iter_emit_tokens_or_blank()
+                .map(|token| match (token, checksum) {
+                    (Token::Checksum(_), Some(corrected)) => Token::Checksum(corrected),
+                    (token, _) => token,
+                }),
+        );
+    }
+    rewriter.finish()
+}
+
+/// The checksum of a line once its commas are points, if it has any and its checksum was valid.
+fn corrected_checksum(line: &Line) -> Option<u8> {
+    let commas = line
+        .iter_fields()
+        .flat_map(|field| field.raw_segments())
+        .filter(|segment| *segment == ",")
+        .count();
+    let written = line.checksum.as_ref()?.inner;
+    let computed = line.compute_checksum();
+    if commas == 0 || written != u16::from(computed) {
+        return None;
+    }
+    // Each comma swapped for a point flips the same bits of the XOR
+    Some(if commas % 2 == 1 {
+        computed ^ b',' ^ b'.'
+    } else {
+        computed
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::{ChecksumPolicy, FormatOptions};
+    use crate::parse::token::Value;
+    use crate::parse::{file_parser, file_parser_with_options, ParseOptions};
+    use crate::transform::format_with;
+    #[cfg(not(feature = "float-values"))]
+    use num_rational::Ratio;
+    use pretty_assertions::assert_eq;
+
+    const COMMAS: ParseOptions = ParseOptions {
+        allow_grbl_system_commands: false,
+        allow_extended_commands: false,
+        allow_rrf_meta: false,
+        accept_comma_decimal: true,
+        max_inline_comment_depth: None,
+        max_line_length: None,
+        max_lines: None,
+    };
+
+    #[test]
+    fn comma_decimals_parse_behind_the_option() {
+        let file = file_parser_with_options("G1 X12,5 Y3,25 Z-1,0", &COMMAS).unwrap();
+        let fields = file.iter_fields().collect::<Vec<_>>();
+        assert_eq!(fields[1].raw_text(), "12,5");
+        assert_eq!(fields[2].raw_text(), "3,25");
+        assert_eq!(fields[3].raw_segments(), ["-", "1", ",", "0"]);
+        #[cfg(not(feature = "float-values"))]
+        {
+            assert_eq!(fields[1].value, Value::Rational(Ratio::new(25, 2)));
+            assert_eq!(fields[2].value, Value::Rational(Ratio::new(13, 4)));
+            assert_eq!(fields[3].value, Value::Rational(Ratio::from_integer(-1)));
+        }
+        #[cfg(feature = "float-values")]
+        {
+            assert_eq!(fields[1].value, Value::Rational(12.5));
+            assert_eq!(fields[2].value, Value::Rational(3.25));
+        }
+        assert!(file_parser("G1 X12,5").is_err());
+        assert!(file_parser_with_options("G1 X12,", &COMMAS).is_err());
+    }
+
+    #[test]
+    fn checksums_cover_the_commas() {
+        let file = file_parser_with_options("N1 X12,5 Y3,25*124", &COMMAS).unwrap();
+        let line = file.iter().next().unwrap();
+        assert_eq!(line.compute_checksum(), 124);
+    }
+
+    #[test]
+    fn normalized_values_use_decimal_points() {
+        let file = file_parser_with_options("G1 X12,5 Y3,25\nG0 Z1,5;up", &COMMAS).unwrap();
+        assert_eq!(
+            format_with(
+                &normalize_decimal_separators(&file),
+                FormatOptions::default()
+            ),
+            "G1 X12.5 Y3.25\nG0 Z1.5 ;up\n"
+        );
+    }
+
+    #[test]
+    fn valid_checksums_follow_the_points() {
+        let file =
+            file_parser_with_options("N1 X12,5 Y3,25*124\nN2 X1,5*44\nN3 X2,5*7", &COMMAS).unwrap();
+        let tokens = normalize_decimal_separators(&file);
+        let preserved = FormatOptions {
+            checksum_policy: ChecksumPolicy::PreserveValid,
+            ..Default::default()
+        };
+        let gcode = format_with(&tokens, preserved);
+        // The invalid checksum doesn't match the line as written either, so it is recomputed
+        assert_eq!(gcode, "N1 X12.5 Y3.25*124\nN2 X1.5*46\nN3 X2.5*44\n");
+        let reparsed = file_parser(&gcode).unwrap();
+        let checksums = reparsed
+            .iter()
+            .map(|line| {
+                line.checksum.as_ref().map(|c| c.inner) == Some(line.compute_checksum().into())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(checksums, [true, true, true]);
+    }
+}
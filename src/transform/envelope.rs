@@ -0,0 +1,222 @@
+//! Converting programs between the plain files printer firmware takes
+//! and the `%`-delimited "tape" format of Fanuc-style controls.
+//!
+//! See [Envelope](crate::analyze::Envelope) for what the two look like.
+use super::LineRewriter;
+use crate::analyze::{program_envelope, program_number_field};
+use crate::emit::{Field, Token, Value};
+use crate::parse::ast::File;
+
+/// How [normalize_envelope] wraps a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeStyle {
+    /// No `%` delimiters, program number, or end code, as printer firmware expects.
+    ///
+    /// Marlin takes `M30` as a request to delete a file from its SD card, so end codes are best left out.
+    Plain,
+    /// `%` delimiters, an `O` program number on the first line, and an end code on the last.
+    ///
+    /// The program number is only used if the file doesn't already have one,
+    /// and `M30` is only added if the file doesn't already end with `M2` or `M30`.
+    Tape { program_number: u64 },
+}
+
+/// Add or remove the `%` delimiters, program number, and end code of a file to match the [EnvelopeStyle],
+/// leaving every other field as it was.
+///
+/// Lines that lose a field also lose their checksum, so that formatters compute a fresh one.
+/// A line left without fields keeps its end-of-line comment, but nothing else.
+///
+/// ```
+/// use g_code::emit::{format_gcode_fmt, FormatOptions};
+/// use g_code::parse::file_parser;
+/// use g_code::transform::envelope::{normalize_envelope, EnvelopeStyle};
+///
+/// let file = file_parser("G28\nG1 X10 F3000").unwrap();
+/// let tokens = normalize_envelope(&file, EnvelopeStyle::Tape { program_number: 1234 });
+/// let mut tape = String::new();
+/// format_gcode_fmt(&tokens, FormatOptions::default(), &mut tape).unwrap();
+/// assert_eq!(tape, "%\nO1234\nG28\nG1 X10 F3000\nM30\n%");
+/// ```
+pub fn normalize_envelope<'input>(
+    file: &File<'input>,
+    target: EnvelopeStyle,
+) -> Vec<Token<'input>> {
+    let envelope = program_envelope(file);
+    let mut lines_with_fields = file
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.iter_fields().next().is_some())
+        .map(|(i, _)| i);
+    let first = lines_with_fields.next();
+    let last = lines_with_fields.last().or(first);
+
+    let tape = matches!(target, EnvelopeStyle::Tape { .. });
+    let mut rewriter = LineRewriter::with_percent(file, tape);
+    if let EnvelopeStyle::Tape { program_number } = target {
+        if envelope.program_number.is_none() {
+            rewriter.push_line(Some(integer_field("O", program_number)));
+        }
+    }
+    for (i, line) in rewriter.lines() {
+        let strip_program_number = target == EnvelopeStyle::Plain
+            && Some(i) == first
+            && program_number_field(line).is_some();
+        let strip_end_code = target == EnvelopeStyle::Plain && Some(i) == last;
+        if !strip_program_number && !strip_end_code {
+            rewriter.push_line(line.iter_emit_tokens_or_blank());
+            continue;
+        }
+        let mut program_number_stripped = false;
+        let kept = line
+            .iter_emit_tokens()
+            .filter(|token| match token {
+                Token::Field(field) if strip_program_number && !program_number_stripped => {
+                    program_number_stripped = is_program_number(field);
+                    !program_number_stripped
+                }
+                Token::Field(field) => !(strip_end_code && is_end_code(field)),
+                Token::Checksum(_) => false,
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+        if kept.iter().any(|token| matches!(token, Token::Field(_))) {
+            rewriter.push_line(kept);
+        } else {
+            let comment = kept.into_iter().find(|token| {
+                matches!(
+                    token,
+                    Token::Comment {
+                        is_inline: false,
+                        ..
+                    }
+                )
+            });
+            // The comment stays on a line of its own, rather than joining the line before
+            rewriter.push_line(comment);
+        }
+    }
+    if tape && envelope.end_code.is_none() {
+        rewriter.push_line(Some(integer_field("M", 30)));
+    }
+    rewriter.finish()
+}
+
+fn integer_field(letters: &'static str, value: u64) -> Token<'static> {
+    Token::Field(Field {
+        letters: letters.into(),
+        value: Value::Integer(value),
+    })
+}
+
+fn is_program_number(field: &Field) -> bool {
+    field.letters.eq_ignore_ascii_case("O") && matches!(field.value, Value::Integer(_))
+}
+
+fn is_end_code(field: &Field) -> bool {
+    field.letters.eq_ignore_ascii_case("M") && matches!(field.value, Value::Integer(2 | 30))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::{EndCode, Envelope};
+    use crate::emit::FormatOptions;
+    use crate::parse::file_parser;
+    use crate::transform::{format, format_with};
+    use pretty_assertions::assert_eq;
+
+    fn fields(file: &File) -> Vec<String> {
+        file.iter_fields()
+            .map(|field| Field::from(field).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn marlin_file_converts_to_tape_and_back() {
+        let marlin = file_parser(";sliced\nG28\nG1 X10 Y10 F3000 ;move\nM84").unwrap();
+        assert_eq!(
+            program_envelope(&marlin),
+            Envelope {
+                percent_delimited: false,
+                program_number: None,
+                end_code: None,
+            }
+        );
+
+        let tape = format(&normalize_envelope(
+            &marlin,
+            EnvelopeStyle::Tape { program_number: 42 },
+        ));
+        assert_eq!(
+            tape,
+            "%\nO42\n;sliced\nG28\nG1 X10 Y10 F3000 ;move\nM84\nM30\n%"
+        );
+        let tape = file_parser(&tape).unwrap();
+        assert_eq!(
+            program_envelope(&tape),
+            Envelope {
+                percent_delimited: true,
+                program_number: Some(42),
+                end_code: Some(EndCode::M30),
+            }
+        );
+        let mut expected = fields(&marlin);
+        expected.insert(0, "O42".to_string());
+        expected.push("M30".to_string());
+        assert_eq!(fields(&tape), expected);
+
+        let plain = format(&normalize_envelope(&tape, EnvelopeStyle::Plain));
+        let plain = file_parser(&plain).unwrap();
+        assert_eq!(program_envelope(&plain), program_envelope(&marlin));
+        assert_eq!(fields(&plain), fields(&marlin));
+    }
+
+    #[test]
+    fn existing_envelope_is_kept_for_tape() {
+        let gcode = "%\nO1234 (BRACKET)\nG0 X0 Y0\nM2\n%";
+        let file = file_parser(gcode).unwrap();
+        let tokens = normalize_envelope(&file, EnvelopeStyle::Tape { program_number: 1 });
+        assert_eq!(format(&tokens), "%\nO1234 (BRACKET)\nG0 X0 Y0\nM2\n%");
+    }
+
+    #[test]
+    fn plain_strips_fields_but_keeps_the_rest_of_their_lines() {
+        let file = file_parser("N1 O7 G90*12\nG0 X1\nM5 M30 ;done").unwrap();
+        let tokens = normalize_envelope(&file, EnvelopeStyle::Plain);
+        assert_eq!(format(&tokens), "N1 G90\nG0 X1\nM5 ;done\n");
+
+        let file = file_parser("O7\nG0 X1\nM30 ;done\n").unwrap();
+        let tokens = normalize_envelope(&file, EnvelopeStyle::Plain);
+        assert_eq!(format(&tokens), "G0 X1\n;done\n");
+    }
+
+    #[test]
+    fn added_line_breaks_are_not_blank_lines() {
+        let preserved = FormatOptions {
+            preserve_blank_lines: true,
+            ..Default::default()
+        };
+        let file = file_parser("G28\n\nG0 X1\nM30 ;done").unwrap();
+        let tape = normalize_envelope(&file, EnvelopeStyle::Tape { program_number: 5 });
+        assert_eq!(
+            format_with(&tape, preserved),
+            "%\nO5\nG28\n\nG0 X1\nM30 ;done\n%"
+        );
+
+        let file = file_parser("O5 ;part\nG0 X1\nM30 ;done").unwrap();
+        let plain = normalize_envelope(&file, EnvelopeStyle::Plain);
+        assert_eq!(format_with(&plain, preserved), ";part\nG0 X1\n;done\n");
+    }
+
+    #[test]
+    fn added_end_code_is_on_a_line_of_its_own() {
+        let file = file_parser("G0 X1\nN9").unwrap();
+        let tape = normalize_envelope(&file, EnvelopeStyle::Tape { program_number: 5 });
+        assert_eq!(
+            &tape[tape.len() - 3..],
+            &[Token::Newline, integer_field("M", 30), Token::Percent]
+        );
+        assert_eq!(format(&tape), "%\nO5\nG0 X1\nN9\nM30\n%");
+    }
+}
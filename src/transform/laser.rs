@@ -0,0 +1,142 @@
+//! Rewriting programs that set laser power like spindle speed for GRBL's laser mode.
+use super::LineRewriter;
+use crate::emit::{Field, Token, Value};
+use crate::parse::ast::File;
+
+/// Move the power set by `M3 S<power>` or `M4 S<power>` onto the cutting move after it,
+/// as in `G1 X10 S<power>`, for GRBL's laser mode (`$32=1`).
+///
+/// In laser mode, GRBL takes a new `S` on a move without stopping, while one on a line of its own
+/// waits for the moves before it to finish. `M3` and `M4` stay where they were, without their `S`,
+/// since they still turn the laser on. Power is modal, so only the first `G1`, `G2`, or `G3` move
+/// after a change gets an `S`, and a move that already has one keeps its own.
+/// Rapid `G0` moves are left alone, as GRBL turns the laser off for them anyway.
+///
+/// Lines that change lose their checksum, so that formatters compute a fresh one.
+///
+/// ```
+/// use g_code::emit::{format_gcode_fmt, FormatOptions};
+/// use g_code::parse::file_parser;
+/// use g_code::transform::laser::inline_laser_power;
+///
+/// let file = file_parser("G0 X0 Y0\nM3 S800\nG1 X10 F600\nG1 Y10\nM5").unwrap();
+/// let mut gcode = String::new();
+/// format_gcode_fmt(&inline_laser_power(&file), FormatOptions::default(), &mut gcode).unwrap();
+/// assert_eq!(gcode, "G0 X0 Y0\nM3\nG1 X10 F600 S800\nG1 Y10\nM5\n");
+/// ```
+pub fn inline_laser_power<'input>(file: &File<'input>) -> Vec<Token<'input>> {
+    let mut rewriter = LineRewriter::new(file);
+    // The modal motion mode, and the power waiting for the next cutting move
+    let mut motion = None;
+    let mut pending: Option<Value> = None;
+    for (_, line) in rewriter.lines() {
+        let fields = line.iter_fields().map(Field::from).collect::<Vec<_>>();
+        let turns_on = fields.iter().any(|field| {
+            field.letters.eq_ignore_ascii_case("M") && matches!(field.value.as_int(), Some(3 | 4))
+        });
+        if let Some(mode) = fields
+            .iter()
+            .filter(|field| field.letters.eq_ignore_ascii_case("G"))
+            .filter_map(|field| field.value.as_int())
+            .rfind(|n| (0..=3).contains(n))
+        {
+            motion = Some(mode);
+        }
+        let moves = fields.iter().any(|field| {
+            ["X", "Y", "Z", "A", "B", "C", "I", "J", "K", "R"]
+                .iter()
+                .any(|axis| field.letters.eq_ignore_ascii_case(axis))
+        });
+        let cuts = moves && matches!(motion, Some(1..=3));
+        let power = fields
+            .iter()
+            .find(|field| field.letters.eq_ignore_ascii_case("S"))
+            .map(|field| field.value.clone());
+
+        let edit = match (power, cuts) {
+            // Already inline, which is also what GRBL now holds
+            (Some(_), true) => {
+                pending = None;
+                None
+            }
+            (Some(power), false) if turns_on => {
+                pending = Some(power);
+                Some(Edit::RemovePower)
+            }
+            (None, true) => pending.take().map(Edit::AddPower),
+            _ => None,
+        };
+        let edit = match edit {
+            Some(edit) => edit,
+            None => {
+                rewriter.push_line(line.iter_emit_tokens_or_blank());
+                continue;
+            }
+        };
+        let removes_power = matches!(edit, Edit::RemovePower);
+        let line_tokens = line.iter_emit_tokens().filter(|token| match token {
+            Token::Checksum(_) => false,
+            Token::Field(field) => !(removes_power && field.letters.eq_ignore_ascii_case("S")),
+            _ => true,
+        });
+        match edit {
+            Edit::RemovePower => rewriter.push_line(line_tokens),
+            Edit::AddPower(power) => {
+                let mut line_tokens = line_tokens.collect::<Vec<_>>();
+                let after_fields = line_tokens
+                    .iter()
+                    .rposition(|token| matches!(token, Token::Field(_)))
+                    .map_or(0, |last| last + 1);
+                line_tokens.insert(
+                    after_fields,
+                    Token::Field(Field {
+                        letters: "S".into(),
+                        value: power,
+                    }),
+                );
+                rewriter.push_line(line_tokens);
+            }
+        }
+    }
+    rewriter.finish()
+}
+
+/// How [inline_laser_power] changes a line.
+enum Edit<'a> {
+    /// Drop the `S` of an `M3` or `M4`.
+    RemovePower,
+    /// Give a cutting move the power set before it.
+    AddPower(Value<'a>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use crate::transform::format;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn power_moves_onto_the_next_cutting_move() {
+        let file = file_parser(
+            "G21 G90\nM4 S1000 (engrave)\nG0 X5 Y5\nG1 X10 F1200 ;edge\nG1 Y10\n\
+             M3 S250*100\nG2 X0 Y0 I-5 J-5\nM5\nM3\nG1 X20 S900\nG1 Y20",
+        )
+        .unwrap();
+        assert_eq!(
+            format(&inline_laser_power(&file)),
+            "G21\nG90\nM4 (engrave)\nG0 X5 Y5\nG1 X10 F1200 S1000 ;edge\nG1 Y10\n\
+             M3\nG2 X0 Y0 I-5 J-5 S250\nM5\nM3\nG1 X20 S900\nG1 Y20\n"
+        );
+    }
+
+    #[test]
+    fn files_without_spindle_power_are_unchanged() {
+        let src = "%\nN1 G1 X1 Y1*37\nG0 Z5\n%";
+        let file = file_parser(src).unwrap();
+        assert_eq!(
+            inline_laser_power(&file),
+            file.iter_emit_tokens().collect::<Vec<_>>()
+        );
+    }
+}
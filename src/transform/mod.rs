@@ -0,0 +1,93 @@
+//! Passes that rewrite a parsed program into a new stream of emission tokens.
+pub mod decimal;
+pub mod envelope;
+pub mod laser;
+pub mod object_tags;
+pub mod remap;
+
+use crate::emit::Token;
+use crate::parse::ast::{File, Line};
+
+/// Emission tokens rewritten from a [File] a line at a time, laid out the way
+/// [File::iter_emit_tokens] lays them out.
+pub(crate) struct LineRewriter<'a, 'input> {
+    file: &'a File<'input>,
+    tokens: Vec<Token<'input>>,
+    end_percent: bool,
+}
+
+impl<'a, 'input> LineRewriter<'a, 'input> {
+    /// Start rewriting `file`, keeping its percent delimiters.
+    pub(crate) fn new(file: &'a File<'input>) -> Self {
+        Self {
+            file,
+            tokens: if file.start_percent {
+                vec![Token::Percent]
+            } else {
+                vec![]
+            },
+            end_percent: file.end_percent,
+        }
+    }
+
+    /// Start rewriting `file`, wrapped in percent delimiters if `delimited` whether or not it is.
+    pub(crate) fn with_percent(file: &'a File<'input>, delimited: bool) -> Self {
+        Self {
+            file,
+            tokens: if delimited {
+                vec![Token::Percent]
+            } else {
+                vec![]
+            },
+            end_percent: delimited,
+        }
+    }
+
+    /// The lines of the file with their index, less the empty remainder
+    /// of the line with the opening percent sign.
+    pub(crate) fn lines(&self) -> impl Iterator<Item = (usize, &'a Line<'input>)> + 'a {
+        let start_percent = self.file.start_percent;
+        self.file
+            .iter()
+            .enumerate()
+            .filter(move |(i, line)| !(start_percent && *i == 0 && line.is_empty()))
+    }
+
+    /// Add the tokens of a line, separated from the line before by a [Token::Newline].
+    ///
+    /// A line right after an opening [Token::Percent] needs no separator.
+    /// Lines with no tokens are left out entirely, so that dropping everything on a line
+    /// doesn't turn it into a blank line.
+    pub(crate) fn push_line(&mut self, line: impl IntoIterator<Item = Token<'input>>) {
+        let mut line = line.into_iter().peekable();
+        if line.peek().is_none() {
+            return;
+        }
+        if !matches!(self.tokens.last(), None | Some(Token::Percent)) {
+            self.tokens.push(Token::Newline);
+        }
+        self.tokens.extend(line);
+    }
+
+    /// The rewritten tokens, closed with a percent sign if the file is delimited.
+    pub(crate) fn finish(mut self) -> Vec<Token<'input>> {
+        if self.end_percent {
+            self.tokens.push(Token::Percent);
+        }
+        self.tokens
+    }
+}
+
+/// Format the tokens of a pass with the given options, to check its output in tests.
+#[cfg(test)]
+pub(crate) fn format_with(tokens: &[Token], opts: crate::emit::FormatOptions) -> String {
+    let mut acc = String::new();
+    crate::emit::format_gcode_fmt(tokens, opts, &mut acc).unwrap();
+    acc
+}
+
+/// Format the tokens of a pass with the default options, to check its output in tests.
+#[cfg(test)]
+pub(crate) fn format(tokens: &[Token]) -> String {
+    format_with(tokens, crate::emit::FormatOptions::default())
+}
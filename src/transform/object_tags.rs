@@ -0,0 +1,310 @@
+//! Tagging the moves of a program by the object they print, so that firmware can cancel one object
+//! and keep printing the others.
+//!
+//! Objects are given as [ObjectRegion]s: a name and a polygon in the XY plane.
+//! A move belongs to the first region containing its end point, as tracked by [State].
+//! Consecutive lines belonging to the same object are wrapped in start and end markers,
+//! and each object is defined at the top of the program.
+use num::ToPrimitive;
+use std::borrow::Cow;
+
+use super::LineRewriter;
+use crate::emit::{Field, Flavor, Token, Value};
+use crate::interpret::State;
+use crate::parse::ast::{File, InvalidComment, Line};
+use crate::parse::token::Value as ParsedValue;
+
+/// A named object, occupying a polygon in the XY plane.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectRegion {
+    name: String,
+    polygon: Vec<(f64, f64)>,
+}
+
+impl ObjectRegion {
+    /// Create a region from the vertices of a polygon, in order.
+    ///
+    /// The name is written into comments, so it must be non-empty printable ASCII without whitespace.
+    pub fn new(name: &str, polygon: Vec<(f64, f64)>) -> Result<Self, InvalidComment> {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(InvalidComment(name.to_string()));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            polygon,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the point lies inside the polygon, by the even-odd rule.
+    pub fn contains(&self, (x, y): (f64, f64)) -> bool {
+        let mut inside = false;
+        let mut previous = match self.polygon.last() {
+            Some(vertex) => *vertex,
+            None => return false,
+        };
+        for &(x1, y1) in self.polygon.iter() {
+            let (x0, y0) = previous;
+            if (y1 > y) != (y0 > y) && x < (x0 - x1) * (y - y1) / (y0 - y1) + x1 {
+                inside = !inside;
+            }
+            previous = (x1, y1);
+        }
+        inside
+    }
+}
+
+/// How object markers are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MarkerStyle {
+    /// `EXCLUDE_OBJECT_*` comments, as read by Klipper's preprocessors.
+    Comment,
+    /// `M486` fields, as read by Marlin.
+    M486,
+}
+
+impl From<Flavor> for MarkerStyle {
+    fn from(flavor: Flavor) -> Self {
+        match flavor {
+            Flavor::Marlin(_) => Self::M486,
+            Flavor::Generic | Flavor::Grbl(_) => Self::Comment,
+        }
+    }
+}
+
+/// Tag the moves of a file with the regions they fall in, using `EXCLUDE_OBJECT_*` comments.
+///
+/// See [tag_objects_with] for the details.
+pub fn tag_objects<'input>(file: &File<'input>, regions: &[ObjectRegion]) -> Vec<Token<'input>> {
+    tag_objects_with(file, regions, Flavor::Generic)
+}
+
+/// Tag the moves of a file with the regions they fall in, with markers suited to the [Flavor].
+///
+/// [Flavor::Marlin] gets `M486 T<count>` at the top, then `M486 S<index>` and `M486 S-1`
+/// around each object, where the index is the object's position in `regions`.
+/// Other flavors get `EXCLUDE_OBJECT_DEFINE NAME=<name> POLYGON=[[x,y],...]` comments at the top,
+/// then `EXCLUDE_OBJECT_START NAME=<name>` and `EXCLUDE_OBJECT_END NAME=<name>` around each object.
+/// Lines that are not moves, or are moves that stay put in X and Y, stay with the object before them.
+pub fn tag_objects_with<'input>(
+    file: &File<'input>,
+    regions: &[ObjectRegion],
+    flavor: Flavor,
+) -> Vec<Token<'input>> {
+    let style = MarkerStyle::from(flavor);
+    let mut rewriter = LineRewriter::new(file);
+    match style {
+        MarkerStyle::M486 => rewriter.push_line(m486("T", regions.len() as i64)),
+        MarkerStyle::Comment => {
+            for region in regions {
+                let polygon = region
+                    .polygon
+                    .iter()
+                    .map(|(x, y)| format!("[{},{}]", x, y))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                push_comment(
+                    &mut rewriter,
+                    format!(
+                        "EXCLUDE_OBJECT_DEFINE NAME={} POLYGON=[{}]",
+                        region.name, polygon
+                    ),
+                );
+            }
+        }
+    }
+
+    let mut state = State::default();
+    let mut current: Option<usize> = None;
+    for (_, line) in rewriter.lines() {
+        state.step(line);
+        if moves_in_xy(line) {
+            let position = (
+                state.position[0].to_f64().unwrap_or(f64::NAN),
+                state.position[1].to_f64().unwrap_or(f64::NAN),
+            );
+            let object = regions.iter().position(|r| r.contains(position));
+            if object != current {
+                if let Some(previous) = current {
+                    push_end_marker(&mut rewriter, style, regions, previous);
+                }
+                if let Some(next) = object {
+                    push_start_marker(&mut rewriter, style, regions, next);
+                }
+                current = object;
+            }
+        }
+        rewriter.push_line(line.iter_emit_tokens());
+    }
+    if let Some(previous) = current {
+        push_end_marker(&mut rewriter, style, regions, previous);
+    }
+    rewriter.finish()
+}
+
+fn moves_in_xy(line: &Line) -> bool {
+    let is_move = line.iter_fields().any(|f| {
+        f.letters.eq_ignore_ascii_case("G") && matches!(f.value, ParsedValue::Integer(0..=3))
+    });
+    is_move
+        && line
+            .iter_fields()
+            .any(|f| f.letters.eq_ignore_ascii_case("X") || f.letters.eq_ignore_ascii_case("Y"))
+}
+
+fn push_start_marker(
+    rewriter: &mut LineRewriter,
+    style: MarkerStyle,
+    regions: &[ObjectRegion],
+    index: usize,
+) {
+    match style {
+        MarkerStyle::M486 => rewriter.push_line(m486("S", index as i64)),
+        MarkerStyle::Comment => push_comment(
+            rewriter,
+            format!("EXCLUDE_OBJECT_START NAME={}", regions[index].name),
+        ),
+    }
+}
+
+fn push_end_marker(
+    rewriter: &mut LineRewriter,
+    style: MarkerStyle,
+    regions: &[ObjectRegion],
+    index: usize,
+) {
+    match style {
+        MarkerStyle::M486 => rewriter.push_line(m486("S", -1)),
+        MarkerStyle::Comment => push_comment(
+            rewriter,
+            format!("EXCLUDE_OBJECT_END NAME={}", regions[index].name),
+        ),
+    }
+}
+
+fn m486(letters: &'static str, value: i64) -> Vec<Token<'static>> {
+    let value = if value < 0 {
+        Value::Rational(value.into())
+    } else {
+        Value::Integer(value as u64)
+    };
+    vec![
+        Token::Field(Field {
+            letters: Cow::Borrowed("M"),
+            value: Value::Integer(486),
+        }),
+        Token::Field(Field {
+            letters: Cow::Borrowed(letters),
+            value,
+        }),
+    ]
+}
+
+/// Add a comment on a line of its own.
+fn push_comment(rewriter: &mut LineRewriter, inner: String) {
+    rewriter.push_line(Some(Token::Comment {
+        is_inline: false,
+        inner: Cow::Owned(inner),
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::FormatOptions;
+    use crate::parse::file_parser;
+    use crate::transform::{format, format_with};
+    use pretty_assertions::assert_eq;
+
+    fn square(name: &str, x: f64, y: f64, size: f64) -> ObjectRegion {
+        ObjectRegion::new(
+            name,
+            vec![(x, y), (x + size, y), (x + size, y + size), (x, y + size)],
+        )
+        .unwrap()
+    }
+
+    const PROGRAM: &str = "G21\nG0 X5 Y5\nG1 X6 Y5 E1\nG0 X25 Y5\nG1 X26 Y6 E2\nG1 Z1\nG91\nG0 X24 Y44\nG90\nG0 X5 Y6\nM2";
+
+    #[test]
+    fn wraps_moves_in_comment_markers() {
+        let file = file_parser(PROGRAM).unwrap();
+        let regions = [square("a", 0., 0., 10.), square("b", 20., 0., 10.)];
+        assert_eq!(
+            format(&tag_objects(&file, &regions)),
+            ";EXCLUDE_OBJECT_DEFINE NAME=a POLYGON=[[0,0],[10,0],[10,10],[0,10]]
+;EXCLUDE_OBJECT_DEFINE NAME=b POLYGON=[[20,0],[30,0],[30,10],[20,10]]
+G21
+;EXCLUDE_OBJECT_START NAME=a
+G0 X5 Y5
+G1 X6 Y5 E1
+;EXCLUDE_OBJECT_END NAME=a
+;EXCLUDE_OBJECT_START NAME=b
+G0 X25 Y5
+G1 X26 Y6 E2
+G1 Z1
+G91
+;EXCLUDE_OBJECT_END NAME=b
+G0 X24 Y44
+G90
+;EXCLUDE_OBJECT_START NAME=a
+G0 X5 Y6
+M2
+;EXCLUDE_OBJECT_END NAME=a
+"
+        );
+    }
+
+    #[test]
+    fn markers_add_no_blank_lines() {
+        let file = file_parser(PROGRAM).unwrap();
+        let regions = [square("a", 0., 0., 10.), square("b", 20., 0., 10.)];
+        let tokens = tag_objects(&file, &regions);
+        let preserved = FormatOptions {
+            preserve_blank_lines: true,
+            ..Default::default()
+        };
+        assert_eq!(format_with(&tokens, preserved), format(&tokens));
+    }
+
+    #[test]
+    fn wraps_moves_in_m486_for_marlin() {
+        let file = file_parser(PROGRAM).unwrap();
+        let regions = [square("a", 0., 0., 10.), square("b", 20., 0., 10.)];
+        assert_eq!(
+            format(&tag_objects_with(&file, &regions, Flavor::Marlin(Default::default()))),
+            "M486 T2\nG21\nM486 S0\nG0 X5 Y5\nG1 X6 Y5 E1\nM486 S-1\nM486 S1\nG0 X25 Y5\nG1 X26 Y6 E2\nG1 Z1\nG91\nM486 S-1\nG0 X24 Y44\nG90\nM486 S0\nG0 X5 Y6\nM2\nM486 S-1\n"
+        );
+    }
+
+    #[test]
+    fn overlapping_regions_go_to_the_first() {
+        let file = file_parser("%\nG0 X5 Y5\nG0 X12 Y12\nG0 X18 Y18\n%").unwrap();
+        let regions = [square("a", 0., 0., 15.), square("b", 10., 10., 10.)];
+        assert_eq!(
+            format(&tag_objects_with(
+                &file,
+                &regions,
+                Flavor::Marlin(Default::default())
+            )),
+            "%\nM486 T2\nM486 S0\nG0 X5 Y5\nG0 X12 Y12\nM486 S-1\nM486 S1\nG0 X18 Y18\nM486 S-1\n%"
+        );
+    }
+
+    #[test]
+    fn point_in_polygon() {
+        let triangle = ObjectRegion::new("t", vec![(0., 0.), (10., 0.), (0., 10.)]).unwrap();
+        assert!(triangle.contains((1., 1.)));
+        assert!(triangle.contains((4.9, 4.9)));
+        assert!(!triangle.contains((5.1, 5.1)));
+        assert!(!triangle.contains((-1., 1.)));
+        assert!(!ObjectRegion::new("empty", vec![])
+            .unwrap()
+            .contains((0., 0.)));
+        assert!(ObjectRegion::new("has space", vec![]).is_err());
+        assert!(ObjectRegion::new("", vec![]).is_err());
+    }
+}
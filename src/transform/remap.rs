@@ -0,0 +1,204 @@
+//! Renaming the letters of fields, for machines that call the same axis by different names.
+use std::borrow::Cow;
+use std::fmt;
+
+use super::LineRewriter;
+use crate::emit::{Field, Token};
+use crate::parse::ast::{File, Span, Spanned};
+use crate::parse::snippet_parser;
+
+/// Rename the letters of fields throughout a file, like `E` to `A` for firmware that calls the extruder `A`,
+/// or `A` to `B` for a mill whose rotary axis is `B`.
+///
+/// Each pair renames one letter to another, ignoring case, and a renamed field keeps the case it had.
+/// Fields with more than one letter, like `GOTO`, are left alone.
+/// Pairs are applied all at once, so `[('A', 'B'), ('B', 'A')]` swaps the two.
+///
+/// A line that would end up with two fields with the same letters, at least one of them renamed,
+/// is refused with a [RemapError::Conflict], since firmware would only take one of them.
+/// Each line with a renamed field is also checked to still parse, written with its fields alone,
+/// so the result doesn't depend on how it is formatted later.
+/// Renamed lines lose their checksum, so that formatters compute a fresh one.
+///
+/// ```
+/// use g_code::emit::{format_gcode_fmt, FormatOptions};
+/// use g_code::parse::file_parser;
+/// use g_code::transform::remap::remap_letters;
+///
+/// let file = file_parser("G1 X10 E1.5\nG92 E0").unwrap();
+/// let tokens = remap_letters(&file, &[('E', 'A')]).unwrap();
+/// let mut gcode = String::new();
+/// format_gcode_fmt(&tokens, FormatOptions::default(), &mut gcode).unwrap();
+/// assert_eq!(gcode, "G1 X10 A1.5\nG92 A0\n");
+///
+/// let file = file_parser("G1 X10 A90 E1.5").unwrap();
+/// assert!(remap_letters(&file, &[('E', 'A')]).is_err());
+/// ```
+pub fn remap_letters<'input>(
+    file: &File<'input>,
+    map: &[(char, char)],
+) -> Result<Vec<Token<'input>>, RemapError> {
+    if let Some((_, to)) = map.iter().find(|(_, to)| !to.is_ascii_alphabetic()) {
+        return Err(RemapError::InvalidLetter(*to));
+    }
+    let rename = |letters: &str| {
+        let mut chars = letters.chars();
+        let letter = chars.next().filter(|_| chars.next().is_none())?;
+        let (_, to) = map
+            .iter()
+            .find(|(from, _)| from.eq_ignore_ascii_case(&letter))?;
+        Some(if letter.is_ascii_lowercase() {
+            to.to_ascii_lowercase()
+        } else {
+            to.to_ascii_uppercase()
+        })
+    };
+
+    let mut rewriter = LineRewriter::new(file);
+    for (i, line) in rewriter.lines() {
+        let renamed = line
+            .iter_fields()
+            .map(|field| rename(&field.letters))
+            .collect::<Vec<_>>();
+        if renamed.iter().all(Option::is_none) {
+            rewriter.push_line(line.iter_emit_tokens_or_blank());
+            continue;
+        }
+        let spans = line.iter_fields().map(Spanned::span).collect::<Vec<_>>();
+        let fields = line
+            .iter_fields()
+            .zip(&renamed)
+            .map(|(field, to)| {
+                let mut field = Field::from(field);
+                if let Some(to) = to {
+                    field.letters = Cow::Owned(to.to_string());
+                }
+                field
+            })
+            .collect::<Vec<_>>();
+        for (j, field) in fields.iter().enumerate() {
+            let earlier = fields[..j]
+                .iter()
+                .position(|earlier| earlier.letters.eq_ignore_ascii_case(&field.letters));
+            if let Some(k) = earlier.filter(|k| renamed[j].is_some() || renamed[*k].is_some()) {
+                return Err(RemapError::Conflict(LetterConflict {
+                    letters: field.letters.to_string(),
+                    line: i,
+                    span: if renamed[j].is_some() {
+                        spans[j]
+                    } else {
+                        spans[k]
+                    },
+                }));
+            }
+        }
+        let text = fields
+            .iter()
+            .map(Field::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+        if snippet_parser(&text).is_err() {
+            return Err(RemapError::Unparseable { line: i, text });
+        }
+
+        let mut fields = fields.into_iter();
+        rewriter.push_line(line.iter_emit_tokens().filter_map(|token| match token {
+            Token::Field(_) => fields.next().map(Token::Field),
+            Token::Checksum(_) => None,
+            other => Some(other),
+        }));
+    }
+    Ok(rewriter.finish())
+}
+
+/// Reasons that [remap_letters] can refuse to rename.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemapError {
+    /// A letter was renamed to something other than an ASCII letter.
+    InvalidLetter(char),
+    /// A renamed field would share its letters with another field on its line.
+    Conflict(LetterConflict),
+    /// A renamed line no longer parses.
+    ///
+    /// Any letter can be followed by any value, so this is only a safeguard
+    /// against the parser treating some letters specially.
+    Unparseable {
+        /// Index of the line in the file
+        line: usize,
+        /// The fields of the line as renamed
+        text: String,
+    },
+}
+
+/// Two fields on a line with the same letters once renamed, as found by [remap_letters].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LetterConflict {
+    /// The letters the two fields would share, as written after renaming.
+    pub letters: String,
+    /// Index of the line in the file
+    pub line: usize,
+    /// Span of the renamed field in the input
+    pub span: Span,
+}
+
+impl fmt::Display for RemapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLetter(letter) => {
+                write!(
+                    f,
+                    "fields can only be renamed to a letter, not {:?}",
+                    letter
+                )
+            }
+            Self::Conflict(conflict) => write!(
+                f,
+                "renaming would put two {} fields on line {}",
+                conflict.letters, conflict.line
+            ),
+            Self::Unparseable { line, text } => {
+                write!(f, "line {} would no longer parse: {:?}", line, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RemapError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::file_parser;
+    use crate::transform::format;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn letters_are_renamed_and_swapped() {
+        let file = file_parser("%\nN1 G1 x1 A90 B45 (turn) E2*90;both\nG0 GOTO 5\n%").unwrap();
+        let tokens = remap_letters(&file, &[('A', 'B'), ('B', 'A'), ('X', 'U')]).unwrap();
+        let gcode = format(&tokens);
+        assert_eq!(gcode, "%\nN1 G1 u1 B90 A45 (turn) E2 ;both\nG0 GOTO5\n%");
+        assert!(file_parser(&gcode).is_ok());
+    }
+
+    #[test]
+    fn renaming_onto_a_used_letter_is_refused() {
+        let src = "G1 X1 E2\nG1 X1 A90 E2";
+        let file = file_parser(src).unwrap();
+        let err = remap_letters(&file, &[('E', 'A')]).unwrap_err();
+        assert_eq!(
+            err,
+            RemapError::Conflict(LetterConflict {
+                letters: "A".to_string(),
+                line: 1,
+                span: Span(19, 21),
+            })
+        );
+        assert_eq!(&src[19..21], "E2");
+
+        assert_eq!(
+            remap_letters(&file, &[('E', '1')]),
+            Err(RemapError::InvalidLetter('1'))
+        );
+    }
+}
@@ -0,0 +1,3 @@
+N1 G28*18
+N2 G1 X10*300
+N3 M107*0038
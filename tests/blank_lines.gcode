@@ -0,0 +1,14 @@
+;FLAVOR:Marlin
+G21
+G90
+
+;LAYER:0
+G1 X1 Y1 E0.5
+G1 X2 Y1 E1
+
+;LAYER:1
+G1 X2 Y2 E1.5
+G1 X1 Y2 E2
+
+;LAYER:2
+G1 X1 Y1 E2.5
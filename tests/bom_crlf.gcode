@@ -0,0 +1,5 @@
+﻿; saved on Windows
+G28
+G1 X10 Y-0.5 F3000 ; move
+
+M107
@@ -0,0 +1,5 @@
+N1 G28*18
+N2 G1 X10 Y10*43
+N3 G1 X20 Y10*14
+N4 G1 X20 Y20*45;corner
+N5 M107*32
@@ -0,0 +1,28 @@
+;FLAVOR:Marlin
+;TIME:1234
+;Filament used: 0.512m
+;Layer height: 0.2
+;Generated with Cura_SteamEngine 5.2.1
+M140 S60
+M104 S200
+M109 S200 ; wait for the hotend
+T0
+G28 ;Home
+G92 E0 ; Reset Extruder
+;LAYER_COUNT:2
+;LAYER:0
+M107
+;MESH:cube.stl
+G0 F6000 X10 Y10 Z0.2
+;TYPE:WALL-OUTER
+G1 F1500 X20 Y10 E0.33
+G1 X20 Y20 E0.66 ; corner
+;TYPE:FILL
+G1 X10 Y20 E1
+;@pause
+;LAYER:1
+G0 X10 Y10 Z0.4
+; @resume
+;TIME_ELAPSED:30.5
+M140 S0
+;End of Gcode
@@ -0,0 +1,18 @@
+; daemon.g: checked by RepRapFirmware every 10 seconds
+if !exists(global.lastLayer)
+  global lastLayer = 0
+if heat.heaters[1].current > 285
+  M568 P0 A0 ; hotend off
+  echo "Hotend over temperature; turned off"
+elif state.status == "processing" && job.layer != null
+  set global.lastLayer = job.layer
+else
+  M400
+var tries = 0
+while iterations < 5
+	if sensors.gpIn[0].value == 1
+		break
+	set var.tries = var.tries + 1   ; count it
+	G4 P100
+if var.tries == 5
+  abort "Filament sensor stuck"
@@ -0,0 +1,11 @@
+; Start g-code from a printer profile that shifts the bed with a home offset
+M206 X-5 Y-3 ; bed is mounted off center
+G28 ; home all axes
+G1 Z5 F3000
+G1 X20 Y20 F6000
+G92 X0 Y0 ; make the nozzle position the origin
+G92 E0
+G1 X60 E9 F1500 ; prime line
+G92 E0
+G1 X10 Y10 E1.5
+M84
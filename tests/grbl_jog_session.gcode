@@ -0,0 +1,13 @@
+$X
+$H
+?
+G21 G90
+$J=G91X10F500
+$J=G91 Y-5.5 F500
+!
+~
+?
+$J=G90X0Y0F1000
+G0 Z5 ; lift
+G1 X5 ?
+$$
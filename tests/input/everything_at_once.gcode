@@ -0,0 +1,11 @@
+%
+N10 M587 S"MYROUTER" (ssid) P"ABCxyz;"" 123"*56;wifi
+N11 (move) G1 X1.5 Y-2 (corner)F3000*127
+G4 P0.5 (no checksum) ;wait
+N12 M98 P"macro.g"(after the string) *67
+N13 G28 X0 Y0*32; home
+N14 M32 P"part (1).gcode" (select)*61
+N15 G1 X.5 (a)(b) Y2 E-.25*118;done
+N16 G28 X Y (both axes)*58;flags
+M18 E (extruder only) X
+%
\ No newline at end of file
@@ -0,0 +1 @@
+G1 X17.206685981491806 Y-0.0000000000000001
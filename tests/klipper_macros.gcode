@@ -0,0 +1,14 @@
+; generated for Klipper
+SET_PRESSURE_ADVANCE ADVANCE=0.05 EXTRUDER=extruder
+PRINT_START BED_TEMP=60 EXTRUDER_TEMP=210 ; from slicer
+G28
+BED_MESH_CALIBRATE
+SET_GCODE_OFFSET Z=0.02 MOVE=1
+EXCLUDE_OBJECT_DEFINE NAME=part_1 CENTER=10,10 POLYGON=[[0,0],[20,0],[20,20],[0,20]]
+SET_DISPLAY_TEXT MSG="Layer 2"
+G1 X10 Y10 F3000
+EXCLUDE_OBJECT_START NAME=part_1
+G1 X20 E1.5
+EXCLUDE_OBJECT_END NAME=part_1
+SET_VELOCITY_LIMIT ACCEL=3000 SQUARE_CORNER_VELOCITY=5
+PRINT_END
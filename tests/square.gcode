@@ -0,0 +1,10 @@
+%
+G21 (millimeters)
+G90
+G0 X0 Y0 ;start
+N10 G1 X10 Y0 F300*124
+G1 X10 Y10.5
+G1 X0 Y10.5*0
+G1 X-0 Y0
+M2
+%
\ No newline at end of file
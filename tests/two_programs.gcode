@@ -0,0 +1,13 @@
+%
+O1001 (FACE)
+G0 X0 Y0
+G1 X10 F100 (M30 IS BELOW)
+M30
+%
+
+%
+O1002 (DRILL)
+G81 X5 Y5 Z-2 R1 F50
+G80
+M30
+%